@@ -0,0 +1,244 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+use crossbeam::atomic::AtomicCell;
+use log::info;
+
+use super::{Sample, STREAM_CHUNK_SIZE};
+
+/// The sample buffers borrowed when decoding, shared by every loading worker
+pub static BUFFERS: BufferPool = BufferPool::new(BufferPool::DEFAULT_CAPACITY);
+
+/// The sample buffers borrowed by the playback thread, kept apart
+/// so a tick never waits on a worker that is holding the pool's lock.
+pub static PLAYBACK_BUFFERS: BufferPool = BufferPool::new(BufferPool::DEFAULT_CAPACITY);
+
+/// Keeps sample buffers that were used before, so the stages that
+/// process samples do not allocate new ones every time they run.
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<Sample>>>,
+    /// How many unused buffers are kept at most
+    capacity: AtomicCell<usize>,
+    /// How many times a buffer had to be allocated or grown
+    allocations: AtomicCell<usize>,
+}
+
+impl BufferPool {
+    pub const DEFAULT_CAPACITY: usize = 16;
+
+    /// Buffers that grew past this many samples are freed instead of kept,
+    /// so one large read does not hold on to its memory forever.
+    pub const MAX_BUFFER_SIZE: usize = STREAM_CHUNK_SIZE * 4;
+
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            free: Mutex::new(Vec::new()),
+            capacity: AtomicCell::new(capacity),
+            allocations: AtomicCell::new(0),
+        }
+    }
+
+    /// Borrows a buffer of `length` silent samples,
+    /// which is given back to the pool when it is dropped.
+    pub fn borrow(&self, length: usize) -> PooledBuffer<'_> {
+        let mut samples = self.free.lock().unwrap().pop().unwrap_or_default();
+
+        if samples.capacity() < length {
+            self.allocations.fetch_add(1);
+        }
+
+        // Whatever the previous borrower left behind must not be heard
+        samples.clear();
+        samples.resize(length, 0.);
+
+        PooledBuffer {
+            pool: self,
+            samples,
+        }
+    }
+
+    /// Sets how many unused buffers are kept, and allocates
+    /// that many ahead of time so the first ticks do not have to.
+    pub fn preallocate(&self, capacity: usize) {
+        self.capacity.store(capacity);
+
+        let mut free = self.free.lock().unwrap();
+        free.truncate(capacity);
+
+        while free.len() < capacity {
+            free.push(Vec::with_capacity(STREAM_CHUNK_SIZE));
+        }
+
+        info!("Preallocated {} sample buffers", capacity);
+    }
+
+    /// Returns how many times a buffer had to be allocated or grown
+    pub fn allocations(&self) -> usize {
+        self.allocations.load()
+    }
+
+    fn give_back(&self, samples: Vec<Sample>) {
+        if samples.capacity() > Self::MAX_BUFFER_SIZE {
+            return;
+        }
+
+        let mut free = self.free.lock().unwrap();
+
+        if free.len() < self.capacity.load() {
+            free.push(samples);
+        }
+    }
+}
+
+/// A buffer borrowed from a [BufferPool]
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    samples: Vec<Sample>,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = [Sample];
+
+    fn deref(&self) -> &Self::Target {
+        &self.samples
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.samples
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.samples));
+    }
+}
+
+/// Sets how many sample buffers are kept for reuse, allocating them ahead of time
+pub fn preallocate_buffers(capacity: usize) {
+    BUFFERS.preallocate(capacity);
+    PLAYBACK_BUFFERS.preallocate(capacity);
+}
+
+#[cfg(test)]
+mod test {
+    use std::{hint::black_box, time::Instant};
+
+    use super::BufferPool;
+    use crate::audio::{SAMPLES_PER_SEC, STREAM_CHUNK_SIZE};
+
+    #[test]
+    fn borrowed_buffers_are_cleared() {
+        let pool = BufferPool::new(4);
+
+        {
+            let mut buf = pool.borrow(8);
+            buf.fill(1.);
+        }
+
+        // A shorter borrow must not see the old length either
+        let buf = pool.borrow(4);
+        assert_eq!(&buf[..], &[0.; 4]);
+        drop(buf);
+
+        let buf = pool.borrow(8);
+        assert_eq!(&buf[..], &[0.; 8]);
+    }
+
+    #[test]
+    fn reused_buffers_are_not_allocated_again() {
+        let pool = BufferPool::new(4);
+        pool.preallocate(2);
+
+        // Like a tick, which borrows a chunk and a smaller buffer for fading
+        for _ in 0..1000 {
+            let chunk = pool.borrow(STREAM_CHUNK_SIZE);
+            let tail = pool.borrow(STREAM_CHUNK_SIZE / 2);
+
+            drop((chunk, tail));
+        }
+
+        assert_eq!(pool.allocations(), 0);
+
+        // Without a pool, every borrow would have been an allocation
+        let unpooled = BufferPool::new(0);
+
+        for _ in 0..1000 {
+            unpooled.borrow(STREAM_CHUNK_SIZE);
+        }
+
+        assert_eq!(unpooled.allocations(), 1000);
+    }
+
+    /// Compares decoding a minute in pooled buffers to allocating each of them,
+    /// run with `cargo test --release -- --ignored --nocapture buffer_pool_benchmark`
+    #[test]
+    #[ignore]
+    fn buffer_pool_benchmark() {
+        let reads = SAMPLES_PER_SEC * 60 / BufferPool::MAX_BUFFER_SIZE;
+
+        let pool = BufferPool::new(BufferPool::DEFAULT_CAPACITY);
+        let started = Instant::now();
+
+        for _ in 0..reads {
+            let mut buf = pool.borrow(BufferPool::MAX_BUFFER_SIZE);
+            buf.fill(1.);
+        }
+
+        let pooled = started.elapsed();
+        let started = Instant::now();
+
+        for _ in 0..reads {
+            let mut buf = vec![0.; BufferPool::MAX_BUFFER_SIZE];
+            buf.fill(1.);
+            black_box(buf);
+        }
+
+        let unpooled = started.elapsed();
+
+        println!(
+            "{} reads: pooled took {:?} with {} allocations, unpooled took {:?} with {}",
+            reads,
+            pooled,
+            pool.allocations(),
+            unpooled,
+            reads
+        );
+
+        assert_eq!(pool.allocations(), 1);
+    }
+
+    #[test]
+    fn unused_buffers_are_limited_by_the_capacity() {
+        let pool = BufferPool::new(1);
+
+        let first = pool.borrow(16);
+        let second = pool.borrow(16);
+        drop((first, second));
+
+        // Only one was kept, so the other has to be allocated again
+        let first = pool.borrow(16);
+        let second = pool.borrow(16);
+
+        assert_eq!(pool.allocations(), 3);
+        drop((first, second));
+    }
+
+    #[test]
+    fn oversized_buffers_are_not_kept() {
+        let pool = BufferPool::new(4);
+
+        drop(pool.borrow(BufferPool::MAX_BUFFER_SIZE * 2));
+        drop(pool.borrow(BufferPool::MAX_BUFFER_SIZE));
+
+        // The large buffer was freed, but the one within the limit was kept
+        drop(pool.borrow(16));
+        drop(pool.borrow(BufferPool::MAX_BUFFER_SIZE * 2));
+
+        assert_eq!(pool.allocations(), 3);
+    }
+}
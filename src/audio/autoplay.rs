@@ -0,0 +1,193 @@
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::Input;
+
+/// Creates an input to play, or `None` if it could not be created
+pub type InputFactory = Box<dyn Fn() -> Option<Input> + Send>;
+
+/// A pool of weighted entries to pick from when there is nothing left to play.
+pub struct Autoplay<T = InputFactory> {
+    entries: Vec<(T, u32)>,
+    enabled: bool,
+    /// The indices of the most recently picked entries, which cannot be picked
+    recent: VecDeque<usize>,
+    /// How many other entries must be picked before an entry can be picked again
+    window: usize,
+    random: Random,
+}
+
+impl<T> Autoplay<T> {
+    /// Creates a pool from entries and their weight
+    pub fn new(entries: Vec<(T, u32)>) -> Self {
+        Self {
+            entries,
+            enabled: true,
+            recent: Default::default(),
+            window: 1,
+            random: Random::from_time(),
+        }
+    }
+
+    /// Uses a fixed seed, so entries are picked in the same order every time
+    pub fn with_seed(self, seed: u64) -> Self {
+        Self {
+            random: Random::new(seed),
+            ..self
+        }
+    }
+
+    /// Sets how many other entries must be picked before an entry is picked again.
+    /// If there are not enough entries, the oldest picks become available again.
+    pub fn with_no_repeat_window(self, window: usize) -> Self {
+        Self { window, ..self }
+    }
+
+    pub fn set_no_repeat_window(&mut self, window: usize) {
+        self.window = window;
+    }
+
+    pub fn no_repeat_window(&self) -> usize {
+        self.window
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Picks an entry at random according to the weights,
+    /// never picking an entry that was picked within the window if there are others.
+    pub fn pick(&mut self) -> Option<&T> {
+        // The window is too large for the entries, so relax it
+        while self.candidates().next().is_none() && !self.recent.is_empty() {
+            self.recent.pop_front();
+        }
+
+        let total: u64 = self.candidates().map(|(_, weight)| weight).sum();
+
+        if total == 0 {
+            return None;
+        }
+
+        let mut target = self.random.next() % total;
+
+        let index = self.candidates().find_map(|(i, weight)| {
+            if target < weight {
+                return Some(i);
+            }
+
+            target -= weight;
+            None
+        })?;
+
+        self.recent.push_back(index);
+
+        while self.recent.len() > self.window {
+            self.recent.pop_front();
+        }
+
+        self.entries.get(index).map(|(entry, _)| entry)
+    }
+
+    fn candidates(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(move |(i, (_, weight))| *weight > 0 && !self.recent.contains(i))
+            .map(|(i, (_, weight))| (i, *weight as u64))
+    }
+}
+
+/// A small xorshift generator, as picking tracks does not need a good one
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift never leaves a state of zero
+        Self { state: seed | 1 }
+    }
+
+    /// Seeds the generator with the current time
+    pub fn from_time() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+
+        Self::new(seed)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        let mut x = self.state;
+
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+
+        self.state = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Autoplay;
+
+    #[test]
+    fn autoplay_picks_according_to_weights() {
+        let entries = || vec![("a", 6), ("b", 3), ("c", 1), ("never", 0)];
+
+        let mut autoplay = Autoplay::new(entries()).with_seed(7);
+        let picks: Vec<_> = (0..3000).map(|_| *autoplay.pick().unwrap()).collect();
+        let count = |name| picks.iter().filter(|p| **p == name).count();
+
+        assert!(count("a") > count("b"));
+        assert!(count("b") > count("c"));
+        assert_eq!(count("never"), 0);
+
+        // The same entry is never picked twice in a row
+        assert!(picks.windows(2).all(|w| w[0] != w[1]));
+
+        // The same seed picks the same entries
+        let mut again = Autoplay::new(entries()).with_seed(7);
+        let repeated: Vec<_> = (0..3000).map(|_| *again.pick().unwrap()).collect();
+        assert_eq!(picks, repeated);
+
+        // A single entry can be picked again
+        let mut single = Autoplay::new(vec![("only", 1)]);
+        assert_eq!(single.pick(), Some(&"only"));
+        assert_eq!(single.pick(), Some(&"only"));
+    }
+
+    #[test]
+    fn entries_do_not_repeat_within_the_window() {
+        let entries = vec![("a", 5), ("b", 1), ("c", 1), ("d", 1)];
+        let mut autoplay = Autoplay::new(entries).with_no_repeat_window(3).with_seed(3);
+
+        let picks: Vec<_> = (0..100).map(|_| *autoplay.pick().unwrap()).collect();
+
+        for window in picks.windows(4) {
+            let mut window = window.to_vec();
+            window.sort_unstable();
+            window.dedup();
+
+            assert_eq!(window.len(), 4);
+        }
+
+        // Fewer entries than the window, so repeats are unavoidable
+        let mut small = Autoplay::new(vec![("a", 1), ("b", 1)])
+            .with_no_repeat_window(3)
+            .with_seed(3);
+
+        let picks: Vec<_> = (0..10).map(|_| *small.pick().unwrap()).collect();
+        assert!(picks.windows(2).all(|w| w[0] != w[1]));
+    }
+}
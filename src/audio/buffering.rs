@@ -1,19 +1,22 @@
 use std::{
-    fmt::Debug,
-    io::Read,
+    error::Error,
+    fmt::{Debug, Display},
+    io::{Cursor, Read, Seek, SeekFrom},
     ops::Range,
     sync::{Arc, Mutex, Weak},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use crossbeam::atomic::AtomicCell;
 use ringbuf::{Consumer, Producer, RingBuffer};
 
 use crate::util::merge_ranges;
 
 use super::{
-    config::{BYTES_PER_SAMPLE, CHANNEL_COUNT, SAMPLES_PER_SEC, SAMPLE_RATE},
-    Sample,
+    config::{AudioConfig, CHANNEL_COUNT, SAMPLES_PER_SEC, SAMPLE_RATE},
+    util::pcm::PcmCodec,
+    BitrateMeter, Headroom, Sample, SampleFormat,
 };
 
 /// Keep track of buffer consumers and remove orphaned ones.
@@ -23,30 +26,177 @@ use super::{
 /// it will never be read from again, this is essentially a deadlock.
 pub struct BufferRegistry {
     entries: Mutex<Vec<AudioBufferProducer>>,
+    /// The format of the samples that are written, which consumers are told about
+    config: AudioConfig,
+    /// The format given to consumers that do not request a specific one
+    format: AtomicCell<SampleFormat>,
+    headroom: AtomicCell<Headroom>,
+    /// Measures the bytes written in the default format
+    bitrate: Mutex<BitrateMeter>,
+    served_samples: AtomicCell<usize>,
+    served_bytes: AtomicCell<usize>,
+}
+
+/// How much has been written to the consumers of a registry since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServedStats {
+    /// Samples written, regardless of how many consumers received them
+    pub samples: usize,
+    /// Bytes pushed to consumers, in the format of each
+    pub bytes: usize,
+    /// How long the samples play for
+    pub duration: Duration,
 }
 
 impl BufferRegistry {
     pub fn new() -> Self {
+        Self::with_config(AudioConfig::DEFAULT)
+    }
+
+    /// Creates a registry that samples in the format of the config are written to
+    pub fn with_config(config: AudioConfig) -> Self {
         Self {
             entries: Default::default(),
+            config,
+            format: AtomicCell::new(SampleFormat::Float32),
+            headroom: Default::default(),
+            bitrate: Default::default(),
+            served_samples: Default::default(),
+            served_bytes: Default::default(),
         }
     }
 
+    /// The size of consumer buffers in bytes, unless another size is requested
+    pub const DEFAULT_BUFFER_SIZE: usize = SAMPLES_PER_SEC;
+
     pub fn get_consumer(&self) -> AudioBufferConsumer {
+        self.create_consumer(self.format.load(), false, Self::DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Returns a consumer that always receives samples in the given format,
+    /// regardless of changes to the default format.
+    pub fn get_consumer_with_format(&self, format: SampleFormat) -> AudioBufferConsumer {
+        self.create_consumer(format, true, Self::DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Returns a consumer that receives samples in the given format,
+    /// but ends like a default consumer when the output is invalidated.
+    pub fn get_following_consumer(&self, format: SampleFormat) -> AudioBufferConsumer {
+        self.create_consumer(format, false, Self::DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Returns a consumer with a buffer of `bytes` bytes.
+    ///
+    /// Larger buffers are more resilient to a slow reader, smaller buffers
+    /// have less latency. The buffer must fit at least one stream chunk.
+    pub fn get_consumer_sized(&self, bytes: usize) -> Result<AudioBufferConsumer, BufferTooSmall> {
+        let format = self.format.load();
+        let minimum = self.config.chunk_size() * format.sample_size();
+
+        if bytes < minimum {
+            return Err(BufferTooSmall {
+                size: bytes,
+                minimum,
+            });
+        }
+
+        Ok(self.create_consumer(format, false, bytes))
+    }
+
+    fn create_consumer(
+        &self,
+        format: SampleFormat,
+        is_pinned: bool,
+        size: usize,
+    ) -> AudioBufferConsumer {
         let mut entries = self.entries.lock().unwrap();
 
-        let buffer = RingBuffer::new(SAMPLES_PER_SEC);
+        let buffer = RingBuffer::new(size);
         let (producer, consumer) = buffer.split();
 
-        let consumer = AudioBufferConsumer::new(consumer);
+        let consumer = AudioBufferConsumer::new(consumer, format, self.config);
         let state = Arc::downgrade(&consumer.state);
+        let flush = consumer.flush.clone();
 
-        let producer = AudioBufferProducer::new(producer, state);
+        let producer = AudioBufferProducer::new(producer, state, flush, format, is_pinned);
         entries.push(producer);
 
         consumer
     }
 
+    pub fn format(&self) -> SampleFormat {
+        self.format.load()
+    }
+
+    pub fn config(&self) -> AudioConfig {
+        self.config
+    }
+
+    /// Changes the default format.
+    ///
+    /// Consumers which did not request a specific format are marked as stale,
+    /// they will end after the samples they have already received are read,
+    /// and must be re-created to receive the new format.
+    pub fn set_format(&self, format: SampleFormat) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if self.format.swap(format) == format {
+            return;
+        }
+
+        self.bitrate.lock().unwrap().reset();
+        Self::mark_stale(&mut entries);
+    }
+
+    /// Ends every consumer that is not pinned to a format once it has been read,
+    /// so it must be re-created.
+    pub fn invalidate(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        Self::mark_stale(&mut entries);
+    }
+
+    fn mark_stale(entries: &mut Vec<AudioBufferProducer>) {
+        entries.retain(|e| {
+            if e.is_pinned {
+                return true;
+            }
+
+            if let Some(arc) = e.state.upgrade() {
+                let mut state = arc.lock().unwrap();
+                *state = ProducerState::Stale;
+            }
+
+            false
+        });
+    }
+
+    /// Returns the bitrate measured from the bytes written in the default format,
+    /// or `None` if the format is raw or nothing has been written to a consumer of it.
+    pub fn measured_bitrate(&self) -> Option<u32> {
+        if self.format.load().is_raw() {
+            return None;
+        }
+
+        self.bitrate.lock().unwrap().bitrate()
+    }
+
+    /// Returns how much has been written since the registry was created
+    pub fn served(&self) -> ServedStats {
+        let samples = self.served_samples.load();
+        let seconds = samples as f64 / self.config.samples_per_sec() as f64;
+
+        ServedStats {
+            samples,
+            bytes: self.served_bytes.load(),
+            duration: Duration::from_secs_f64(seconds),
+        }
+    }
+
+    /// Sets the headroom applied to formats that clip
+    pub fn set_headroom(&self, headroom: Headroom) {
+        self.headroom.store(headroom);
+    }
+
     /// Remove dead buffers
     pub fn recycle(&self) {
         let mut entries = self.entries.lock().unwrap();
@@ -60,32 +210,122 @@ impl BufferRegistry {
         });
     }
 
+    /// Discards everything consumers have not read yet.
+    /// The samples are dropped the next time each consumer reads,
+    /// anything written after this is kept.
+    pub fn flush(&self) {
+        let entries = self.entries.lock().unwrap();
+
+        for entry in entries.iter() {
+            entry.flush.store(entry.underlying.len());
+        }
+    }
+
+    /// Returns how many consumers are still reading
+    pub fn consumer_count(&self) -> usize {
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .iter()
+            .filter(|e| match e.state.upgrade() {
+                Some(arc) => matches!(*arc.lock().unwrap(), ProducerState::Alive),
+                None => false,
+            })
+            .count()
+    }
+
     /// Returns how many samples can be pushed before
     /// one of the buffers will be full
     pub fn samples_remaining(&self) -> usize {
         let entries = self.entries.lock().unwrap();
 
-        let remaining = entries
+        entries
             .iter()
-            .map(|p| p.underlying.remaining())
+            .map(|p| p.underlying.remaining() / p.format.sample_size())
             .min()
-            .unwrap_or(0);
+            .unwrap_or(0)
+    }
 
-        remaining / BYTES_PER_SAMPLE
+    /// Returns the average amount of samples waiting to be read by live consumers
+    pub fn average_fill(&self) -> usize {
+        let entries = self.entries.lock().unwrap();
+
+        let fills: Vec<_> = entries
+            .iter()
+            .filter(|e| match e.state.upgrade() {
+                Some(arc) => matches!(*arc.lock().unwrap(), ProducerState::Alive),
+                None => false,
+            })
+            .map(|p| p.underlying.len() / p.format.sample_size())
+            .collect();
+
+        match fills.len() {
+            0 => 0,
+            count => fills.iter().sum::<usize>() / count,
+        }
     }
 
-    pub fn write_byte_samples(&self, data: &[u8]) {
+    /// Writes samples to every buffer in the format of its consumer
+    pub fn write_samples(&self, samples: &[Sample]) {
         let mut entries = self.entries.lock().unwrap();
+        let headroom = self.headroom.load();
+
+        // Free the buffers of consumers that were dropped, such as when a client disconnects
+        entries.retain(|e| e.state.strong_count() > 0);
+
+        let mut encoded: Vec<(SampleFormat, Vec<u8>)> = vec![];
 
         for entry in entries.iter_mut() {
-            entry.underlying.push_slice(data);
+            let index = match encoded.iter().position(|(f, _)| *f == entry.format) {
+                Some(index) => index,
+                None => {
+                    let bytes = entry.format.encode_with_headroom(samples, headroom);
+                    encoded.push((entry.format, bytes));
+                    encoded.len() - 1
+                }
+            };
+
+            let pushed = entry.underlying.push_slice(&encoded[index].1);
+            self.served_bytes.fetch_add(pushed);
         }
+
+        self.served_samples.fetch_add(samples.len());
+
+        let format = self.format.load();
+
+        if let Some((_, bytes)) = encoded.iter().find(|(f, _)| *f == format) {
+            self.bitrate
+                .lock()
+                .unwrap()
+                .record(Instant::now(), bytes.len());
+        }
+    }
+}
+
+/// A consumer buffer was requested that cannot fit a stream chunk
+#[derive(Debug)]
+pub struct BufferTooSmall {
+    pub size: usize,
+    pub minimum: usize,
+}
+
+impl Display for BufferTooSmall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "A buffer of {} bytes is smaller than the minimum of {} bytes",
+            self.size, self.minimum
+        )
     }
 }
 
+impl Error for BufferTooSmall {}
+
 pub enum ProducerState {
     /// The consumer for the buffer is still consuming
     Alive,
+    /// The format changed, so the consumer will not receive more data
+    Stale,
     /// The consumer has been dropped and we need to clean this up
     Dead,
 }
@@ -93,11 +333,28 @@ pub enum ProducerState {
 pub struct AudioBufferProducer {
     state: Weak<Mutex<ProducerState>>,
     underlying: Producer<u8>,
+    /// How many bytes the consumer has to discard
+    flush: Arc<AtomicCell<usize>>,
+    format: SampleFormat,
+    /// The format of this producer does not follow the default format
+    is_pinned: bool,
 }
 
 impl AudioBufferProducer {
-    fn new(underlying: Producer<u8>, state: Weak<Mutex<ProducerState>>) -> Self {
-        Self { underlying, state }
+    fn new(
+        underlying: Producer<u8>,
+        state: Weak<Mutex<ProducerState>>,
+        flush: Arc<AtomicCell<usize>>,
+        format: SampleFormat,
+        is_pinned: bool,
+    ) -> Self {
+        Self {
+            underlying,
+            state,
+            flush,
+            format,
+            is_pinned,
+        }
     }
 }
 
@@ -108,19 +365,82 @@ impl AudioBufferProducer {
 pub struct AudioBufferConsumer {
     state: Arc<Mutex<ProducerState>>,
     underlying: Consumer<u8>,
+    flush: Arc<AtomicCell<usize>>,
+    format: SampleFormat,
+    config: AudioConfig,
 }
 
 impl AudioBufferConsumer {
     pub fn wait_for_buffer(&self, samples_to_wait_for: usize) {
-        let seconds_per_sample = 1. / SAMPLES_PER_SEC as f32;
+        let seconds_per_sample = 1. / self.config.samples_per_sec() as f32;
         let seconds_to_wait = (samples_to_wait_for as f32) * seconds_per_sample;
 
         spin_sleep::sleep(Duration::from_secs_f32(seconds_to_wait));
     }
 
-    fn new(underlying: Consumer<u8>) -> Self {
+    /// Returns every whole sample that has been received, without waiting
+    pub fn read_available(&mut self) -> Vec<u8> {
+        self.discard_if_flushed();
+
+        let sample_size = self.format.sample_size();
+        let available = self.underlying.len() / sample_size * sample_size;
+
+        let mut bytes = vec![0; available];
+        let amount = self.underlying.pop_slice(&mut bytes);
+
+        bytes.truncate(amount);
+        bytes
+    }
+
+    /// Records the next `samples` samples of the live stream
+    /// into a finite buffer that can be sought in.
+    pub fn record(&mut self, samples: usize) -> FiniteBufferConsumer {
+        let mut bytes = vec![0; samples * self.format.sample_size()];
+
+        // This will be less than requested if the consumer became stale
+        let amount = self
+            .read(&mut bytes)
+            .expect("Reading from a live buffer does not fail");
+
+        bytes.truncate(amount);
+        FiniteBufferConsumer::new(bytes)
+    }
+
+    pub fn format(&self) -> SampleFormat {
+        self.format
+    }
+
+    /// Returns the sample rate and channels of what is received
+    pub fn config(&self) -> AudioConfig {
+        self.config
+    }
+
+    /// Returns the size of the buffer in bytes
+    pub fn capacity(&self) -> usize {
+        self.underlying.capacity()
+    }
+
+    /// Returns true if the format changed and this consumer will not receive more data
+    pub fn is_stale(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        matches!(*state, ProducerState::Stale)
+    }
+
+    /// Drops everything received before [BufferRegistry::flush] was called
+    fn discard_if_flushed(&mut self) {
+        let amount = self.flush.swap(0);
+
+        if amount > 0 {
+            self.underlying.discard(amount);
+        }
+    }
+
+    fn new(underlying: Consumer<u8>, format: SampleFormat, config: AudioConfig) -> Self {
         Self {
             underlying,
+            format,
+            config,
+            flush: Default::default(),
             state: Arc::new(ProducerState::Alive.into()),
         }
     }
@@ -132,8 +452,15 @@ impl Read for AudioBufferConsumer {
         let mut bytes_read = 0;
 
         while bytes_read < requested_len {
+            self.discard_if_flushed();
             bytes_read += self.underlying.pop_slice(&mut buf[bytes_read..]);
 
+            // No more data will arrive, so end the stream
+            if bytes_read < requested_len && self.is_stale() {
+                bytes_read += self.underlying.pop_slice(&mut buf[bytes_read..]);
+                return Ok(bytes_read);
+            }
+
             if bytes_read < requested_len {
                 let remaining = requested_len - bytes_read;
 
@@ -154,6 +481,48 @@ impl Drop for AudioBufferConsumer {
     }
 }
 
+/// A consumer of a fully rendered, finite buffer of audio.
+///
+/// Unlike [AudioBufferConsumer], which is an infinite live stream,
+/// this has a known length and can be sought in independently
+/// of any other consumer.
+pub struct FiniteBufferConsumer {
+    underlying: Cursor<Vec<u8>>,
+}
+
+impl FiniteBufferConsumer {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            underlying: Cursor::new(bytes),
+        }
+    }
+
+    pub fn from_samples(samples: &[Sample]) -> Self {
+        Self::new(PcmCodec::FLOAT.encode(samples))
+    }
+
+    /// Returns the length of the buffer in bytes
+    pub fn len(&self) -> usize {
+        self.underlying.get_ref().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Read for FiniteBufferConsumer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.underlying.read(buf)
+    }
+}
+
+impl Seek for FiniteBufferConsumer {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.underlying.seek(pos)
+    }
+}
+
 /// A buffer of concatenated audio
 pub struct DynamicBuffer<Id> {
     samples: Mutex<Vec<Sample>>,
@@ -400,7 +769,25 @@ impl<Id> DynamicBufferAllocation<Id> {
 
 #[cfg(test)]
 mod test {
-    use super::{DynamicBuffer, ReadBufferSamplesResult};
+    use std::io::{Read, Seek, SeekFrom};
+
+    use super::{BufferRegistry, DynamicBuffer, FiniteBufferConsumer, ReadBufferSamplesResult};
+    use crate::audio::{SampleFormat, STREAM_CHUNK_SIZE};
+
+    #[test]
+    fn dropped_consumers_are_freed() {
+        let registry = BufferRegistry::new();
+
+        let kept = registry.get_consumer();
+        let dropped = registry.get_consumer();
+        drop(dropped);
+
+        registry.write_samples(&[0.; STREAM_CHUNK_SIZE]);
+
+        assert_eq!(registry.entries.lock().unwrap().len(), 1);
+        assert_eq!(registry.consumer_count(), 1);
+        drop(kept);
+    }
 
     #[test]
     fn dynamic_buffers_are_read_correctly_at_end() {
@@ -417,4 +804,85 @@ mod test {
 
         assert_eq!(amount, ReadBufferSamplesResult::End { samples_read: 5 });
     }
+
+    #[test]
+    fn finite_buffers_can_be_sought_in() {
+        let bytes: Vec<u8> = (0..32).collect();
+        let mut consumer = FiniteBufferConsumer::new(bytes);
+
+        let mut buf = [0; 4];
+
+        consumer.seek(SeekFrom::Start(20)).unwrap();
+        consumer.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [20, 21, 22, 23]);
+
+        consumer.seek(SeekFrom::Current(-16)).unwrap();
+        consumer.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [8, 9, 10, 11]);
+
+        consumer.seek(SeekFrom::End(-2)).unwrap();
+        let amount = consumer.read(&mut buf).unwrap();
+        assert_eq!(&buf[..amount], &[30, 31]);
+    }
+
+    #[test]
+    fn changing_format_makes_consumers_stale() {
+        let registry = BufferRegistry::new();
+
+        let mut old = registry.get_consumer();
+        let mut pinned = registry.get_consumer_with_format(SampleFormat::Float32);
+
+        registry.write_samples(&[0.5, -0.5]);
+        registry.set_format(SampleFormat::Int16);
+
+        let mut new = registry.get_consumer();
+        registry.write_samples(&[1., -1.]);
+
+        // Old consumers receive what was written before they became stale, then end
+        let mut buf = [0; 16];
+        assert!(old.is_stale());
+        assert_eq!(old.read(&mut buf).unwrap(), 8);
+        assert_eq!(old.read(&mut buf).unwrap(), 0);
+
+        // Pinned consumers are unaffected
+        assert!(!pinned.is_stale());
+        pinned.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..4], &0.5_f32.to_le_bytes());
+        assert_eq!(&buf[12..], &(-1_f32).to_le_bytes());
+
+        // New consumers use the new format
+        let mut buf = [0; 4];
+        assert_eq!(new.format(), SampleFormat::Int16);
+        new.read_exact(&mut buf).unwrap();
+        assert!(i16::from_le_bytes([buf[0], buf[1]]) > 0);
+        assert!(i16::from_le_bytes([buf[2], buf[3]]) < 0);
+    }
+
+    #[test]
+    fn consumers_can_have_different_buffer_sizes() {
+        let registry = BufferRegistry::new();
+        let chunk_bytes = STREAM_CHUNK_SIZE * 4;
+
+        assert!(registry.get_consumer_sized(chunk_bytes - 1).is_err());
+
+        let mut small = registry.get_consumer_sized(chunk_bytes).unwrap();
+        let mut large = registry.get_consumer_sized(chunk_bytes * 10).unwrap();
+
+        assert_eq!(small.capacity(), chunk_bytes);
+        assert_eq!(large.capacity(), chunk_bytes * 10);
+
+        let samples: Vec<_> = (0..STREAM_CHUNK_SIZE).map(|i| i as f32).collect();
+        registry.write_samples(&samples);
+
+        for consumer in [&mut small, &mut large] {
+            let mut buf = vec![0; chunk_bytes];
+            consumer.read_exact(&mut buf).unwrap();
+
+            assert_eq!(&buf[4..8], &1_f32.to_le_bytes());
+            assert_eq!(
+                &buf[chunk_bytes - 4..],
+                &samples[STREAM_CHUNK_SIZE - 1].to_le_bytes()
+            );
+        }
+    }
 }
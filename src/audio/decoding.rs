@@ -1,12 +1,108 @@
 use std::{
+    error::Error,
+    fmt::Display,
     fs::File,
-    io::{self, Read, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
     process::{Command, Stdio},
+    sync::{Arc, Mutex},
     thread,
+    time::Instant,
 };
 
-use super::{Sample, SAMPLE_IN_BYTES};
+use log::info;
+use symphonia::core::{
+    audio::Channels,
+    codecs::{
+        CodecParameters, CodecType, DecoderOptions, CODEC_TYPE_MP3, CODEC_TYPE_PCM_F32LE,
+        CODEC_TYPE_PCM_S16LE,
+    },
+    errors::Error as SymphoniaError,
+};
+
+use super::{
+    input::{FileError, SymphoniaDecoder},
+    pipeline::{SampleReader, SampleSource, SamplesRead},
+    track::Metadata,
+    util::pcm::PcmCodec,
+    Sample, SAMPLE_IN_BYTES, SAMPLE_RATE, STREAM_CHUNK_SIZE,
+};
+
+/// The decoders files are opened with, shared by every input
+pub static DECODERS: DecoderRegistry = DecoderRegistry::new();
+
+/// Decodes audio files into interleaved samples, such as with Symphonia or ffmpeg
+pub trait Decoder: Send + Sync {
+    /// Reads what is known about a file, failing if it cannot be decoded
+    fn probe(&self, path: &Path) -> Result<ProbedFile, FileError>;
+
+    /// Opens a file for decoding, which reads samples with the rate and channels it was probed with
+    fn open(&self, path: &Path) -> Result<SampleSource, FileError>;
+}
+
+/// What a [Decoder] found out about a file
+#[derive(Debug, Clone, Default)]
+pub struct ProbedFile {
+    pub sample_rate: usize,
+    pub channels: usize,
+    /// How many frames there are, if the file says
+    pub frames: Option<u64>,
+    /// The title it is tagged with
+    pub title: Option<String>,
+    pub metadata: Metadata,
+}
+
+/// Selects the decoder of a file by its extension, using Symphonia for the formats
+/// that have no other decoder registered.
+pub struct DecoderRegistry {
+    backends: Mutex<Vec<(String, Arc<dyn Decoder>)>>,
+}
+
+impl DecoderRegistry {
+    pub const fn new() -> Self {
+        Self {
+            backends: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Decodes files with an extension, such as "ape", with a decoder instead of Symphonia.
+    /// Registering the same extension again replaces its decoder.
+    pub fn register<D: 'static + Decoder>(&self, extension: &str, decoder: D) {
+        let extension = extension.trim_start_matches('.').to_lowercase();
+        let mut backends = self.backends.lock().unwrap();
+
+        backends.retain(|(e, _)| *e != extension);
+        backends.push((extension, Arc::new(decoder)));
+    }
+
+    /// Returns the decoder for the format of a file
+    pub fn get(&self, path: &Path) -> Arc<dyn Decoder> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+
+        self.backends
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(e, _)| *e == extension)
+            .map(|(_, decoder)| decoder.clone())
+            .unwrap_or_else(|| Arc::new(SymphoniaDecoder))
+    }
+}
+
+impl Default for DecoderRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decodes files with an extension with a decoder instead of Symphonia
+pub fn register_decoder<D: 'static + Decoder>(extension: &str, decoder: D) {
+    DECODERS.register(extension, decoder);
+}
 
 /// Decode any audio to raw 32-bit floating point.
 pub fn decode_to_raw<T: 'static + Read + Send + Sync>(mut input: T, name: &str) -> PathBuf {
@@ -53,11 +149,197 @@ pub fn decode_to_raw<T: 'static + Read + Send + Sync>(mut input: T, name: &str)
 
 /// Converts a slice of bytes into a vec of [Sample].
 pub fn raw_samples_from_bytes(bytes: &[u8]) -> Vec<Sample> {
-    bytes
-        .chunks_exact(SAMPLE_IN_BYTES)
-        .map(|b| {
-            let arr: [u8; SAMPLE_IN_BYTES] = [b[0], b[1], b[2], b[3]];
-            Sample::from_le_bytes(arr)
+    PcmCodec::FLOAT.decode(bytes)
+}
+
+/// Reads raw samples from a file, such as one written by [decode_to_raw] or the cache.
+/// Unlike a stream, it can seek without reading the samples before the offset.
+pub struct RawFile {
+    file: File,
+    /// Where the samples start, after any header
+    start: u64,
+    length: usize,
+}
+
+impl RawFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len() as usize / SAMPLE_IN_BYTES;
+
+        Ok(Self {
+            file,
+            start: 0,
+            length,
+        })
+    }
+
+    /// Reads the samples of a file that is positioned after its header
+    pub fn from_file(mut file: File, length: usize) -> io::Result<Self> {
+        let start = file.stream_position()?;
+
+        Ok(Self {
+            file,
+            start,
+            length,
         })
-        .collect()
+    }
+
+    /// Returns the amount of samples in the file
+    pub fn length(&self) -> usize {
+        self.length
+    }
+}
+
+impl SampleReader for RawFile {
+    fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+        self.file.read_samples(buf)
+    }
+
+    fn length(&self) -> Option<usize> {
+        Some(self.length)
+    }
+
+    fn seek_samples(&mut self, offset: usize) -> bool {
+        let position = self.start + (offset.min(self.length) * SAMPLE_IN_BYTES) as u64;
+        self.file.seek(SeekFrom::Start(position)).is_ok()
+    }
+}
+
+/// Initializes the Symphonia probe and the decoder for a mime type ahead of time, as they
+/// set up their tables lazily, which otherwise delays the first local file in that format.
+/// The time it took is logged, which is roughly what the first play saves.
+pub fn prewarm_codec(mime: &str) -> Result<(), PrewarmError> {
+    let (codec, bits) =
+        codec_for_mime(mime).ok_or_else(|| PrewarmError::Unsupported(mime.to_string()))?;
+
+    let start = Instant::now();
+
+    // The same registries that local files are probed and decoded with
+    symphonia::default::get_probe();
+    let mut params = CodecParameters::new();
+
+    params
+        .for_codec(codec)
+        .with_sample_rate(SAMPLE_RATE as u32)
+        .with_channels(Channels::FRONT_LEFT | Channels::FRONT_RIGHT)
+        .with_bits_per_sample(bits)
+        .with_max_frames_per_packet(STREAM_CHUNK_SIZE as u64);
+
+    symphonia::default::get_codecs()
+        .make(&params, &DecoderOptions::default())
+        .map_err(PrewarmError::Failed)?;
+
+    info!("Prewarmed decoder for {} in {:?}", mime, start.elapsed());
+    Ok(())
+}
+
+/// Returns the codec and bits per sample of the formats that can be decoded
+fn codec_for_mime(mime: &str) -> Option<(CodecType, u32)> {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+
+    match essence.to_lowercase().as_str() {
+        "audio/mpeg" | "audio/mp3" => Some((CODEC_TYPE_MP3, 16)),
+        "audio/wav" | "audio/wave" | "audio/x-wav" => Some((CODEC_TYPE_PCM_S16LE, 16)),
+        "audio/pcm" => Some((CODEC_TYPE_PCM_F32LE, 32)),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum PrewarmError {
+    /// There is no decoder for the mime type
+    Unsupported(String),
+    /// The decoder could not be created
+    Failed(SymphoniaError),
+}
+
+impl Display for PrewarmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrewarmError::Unsupported(mime) => write!(f, "No decoder supports {}", mime),
+            PrewarmError::Failed(err) => write!(f, "Failed to create decoder: {}", err),
+        }
+    }
+}
+
+impl Error for PrewarmError {}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, path::Path, process};
+
+    use super::{prewarm_codec, register_decoder, Decoder, PrewarmError, ProbedFile};
+    use crate::audio::{
+        pipeline::{IntoSampleReader, SampleReader, SampleSource},
+        track::Metadata,
+        FileError, Input, CHANNEL_COUNT, SAMPLE_RATE,
+    };
+
+    /// Plays every byte of a file as a frame at the level of the byte
+    struct ByteDecoder;
+
+    impl Decoder for ByteDecoder {
+        fn probe(&self, path: &Path) -> Result<ProbedFile, FileError> {
+            let bytes = fs::read(path).map_err(FileError::Io)?;
+
+            Ok(ProbedFile {
+                sample_rate: SAMPLE_RATE,
+                channels: 1,
+                frames: Some(bytes.len() as u64),
+                title: Some("Bytes".to_string()),
+                metadata: Metadata {
+                    codec: Some("bytes".to_string()),
+                    ..Default::default()
+                },
+            })
+        }
+
+        fn open(&self, path: &Path) -> Result<SampleSource, FileError> {
+            let bytes = fs::read(path).map_err(FileError::Io)?;
+            let samples: Vec<_> = bytes.into_iter().map(|b| b as f32 / 255.).collect();
+
+            Ok(samples.into_sample_reader().wrap())
+        }
+    }
+
+    #[test]
+    fn only_supported_codecs_are_prewarmed() {
+        for mime in ["audio/mpeg", "audio/wav", "audio/pcm;rate=44100"] {
+            assert!(prewarm_codec(mime).is_ok(), "{} failed", mime);
+        }
+
+        assert!(matches!(
+            prewarm_codec("audio/flac"),
+            Err(PrewarmError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn registered_decoders_are_used_for_their_format() {
+        let dir = env::temp_dir().join(format!("gct-decoder-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("levels.bytes");
+        fs::write(&path, [0, 51, 255]).unwrap();
+
+        // Symphonia does not know the format
+        assert!(matches!(
+            Input::from_path(&path),
+            Err(FileError::Unsupported(_))
+        ));
+
+        register_decoder("BYTES", ByteDecoder);
+
+        let input = Input::from_path(&path).unwrap();
+        assert_eq!(input.to_string(), "Bytes");
+        assert_eq!(input.metadata().codec.as_deref(), Some("bytes"));
+
+        let mut samples = vec![0.; 3 * CHANNEL_COUNT];
+        let mut reader = input.into_sample_reader();
+
+        assert_eq!(reader.read_samples(&mut samples).amount(), samples.len());
+        assert_eq!(samples, vec![0., 0., 0.2, 0.2, 1., 1.]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
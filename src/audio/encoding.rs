@@ -1,5 +1,163 @@
-use super::AudioBufferConsumer;
-use std::io::Read;
+use super::{
+    resampling::{Resampler, RESAMPLERS},
+    util::pcm::PcmCodec,
+    AudioBufferConsumer, AudioConfig, Sample, CHANNEL_COUNT, SAMPLE_RATE,
+};
+use audiopus::{coder::Encoder, Application, Channels, SampleRate};
+use log::error;
+use std::{
+    collections::VecDeque,
+    io::{Cursor, Read},
+    time::{Duration, Instant},
+};
+
+/// The format samples are written to consumers in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// 32-bit floating point
+    Float32,
+    /// 16-bit signed integer
+    Int16,
+}
+
+impl SampleFormat {
+    /// Returns the size of a sample in bytes
+    pub const fn sample_size(&self) -> usize {
+        match self {
+            SampleFormat::Float32 => 4,
+            SampleFormat::Int16 => 2,
+        }
+    }
+
+    /// Converts samples to interleaved little endian bytes
+    pub fn encode(&self, samples: &[Sample]) -> Vec<u8> {
+        PcmCodec::new(*self, CHANNEL_COUNT).encode(samples)
+    }
+
+    /// Converts interleaved little endian bytes back to samples
+    pub fn decode(&self, bytes: &[u8]) -> Vec<Sample> {
+        PcmCodec::new(*self, CHANNEL_COUNT).decode(bytes)
+    }
+
+    /// Converts samples to bytes, applying the headroom
+    /// first if this format would clip full scale peaks.
+    pub fn encode_with_headroom(&self, samples: &[Sample], headroom: Headroom) -> Vec<u8> {
+        if !self.clips() {
+            return self.encode(samples);
+        }
+
+        let mut samples = samples.to_vec();
+        headroom.apply(&mut samples);
+
+        self.encode(&samples)
+    }
+
+    /// Returns the bits per second of a stream in this format
+    pub const fn bitrate(&self) -> u32 {
+        (SAMPLE_RATE * CHANNEL_COUNT * self.sample_size() * 8) as u32
+    }
+
+    /// Returns true if samples are written as they are processed, without converting them
+    pub const fn is_raw(&self) -> bool {
+        matches!(self, SampleFormat::Float32)
+    }
+
+    /// Returns true if the format cannot represent samples beyond full scale
+    pub const fn clips(&self) -> bool {
+        matches!(self, SampleFormat::Int16)
+    }
+
+    /// The WAVE format tag, PCM = 1 and IEEE float = 3
+    const fn wave_format(&self) -> u16 {
+        match self {
+            SampleFormat::Float32 => 3,
+            SampleFormat::Int16 => 1,
+        }
+    }
+}
+
+/// A gain applied before encoding, so inter-sample peaks
+/// do not clip formats that cannot represent them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Headroom {
+    /// The peak level in dBFS
+    ceiling: f32,
+}
+
+impl Headroom {
+    pub const DEFAULT: Self = Self { ceiling: -1. };
+
+    /// Creates a headroom with a peak level in dBFS, such as -1.0
+    pub fn new(ceiling: f32) -> Self {
+        Self {
+            ceiling: ceiling.min(0.),
+        }
+    }
+
+    pub fn ceiling(&self) -> f32 {
+        self.ceiling
+    }
+
+    pub fn gain(&self) -> f32 {
+        10_f32.powf(self.ceiling / 20.)
+    }
+
+    pub fn apply(&self, samples: &mut [Sample]) {
+        let gain = self.gain();
+
+        for sample in samples.iter_mut() {
+            *sample *= gain;
+        }
+    }
+}
+
+impl Default for Headroom {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Measures the bitrate of encoded output from the bytes produced over a recent window
+#[derive(Debug, Default)]
+pub struct BitrateMeter {
+    /// When bytes were produced, and how many
+    records: VecDeque<(Instant, usize)>,
+}
+
+impl BitrateMeter {
+    /// How far back bytes are taken into account
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    pub fn record(&mut self, now: Instant, bytes: usize) {
+        self.records.push_back((now, bytes));
+
+        while let Some((then, _)) = self.records.front() {
+            match now.duration_since(*then) > Self::WINDOW {
+                true => self.records.pop_front(),
+                false => break,
+            };
+        }
+    }
+
+    /// Returns the bits per second, or `None` if not enough has been produced to tell
+    pub fn bitrate(&self) -> Option<u32> {
+        let (first, _) = self.records.front()?;
+        let (last, _) = self.records.back()?;
+        let elapsed = last.duration_since(*first).as_secs_f64();
+
+        if elapsed <= 0. {
+            return None;
+        }
+
+        // The first bytes were produced before the window started
+        let bytes: usize = self.records.iter().skip(1).map(|(_, b)| b).sum();
+        Some((bytes as f64 * 8. / elapsed).round() as u32)
+    }
+
+    pub fn reset(&mut self) {
+        self.records.clear();
+    }
+}
 
 /// Implements streaming a .wav file
 pub struct WaveStream {
@@ -8,41 +166,6 @@ pub struct WaveStream {
 }
 
 impl WaveStream {
-    /// WAVE file header.
-    /// Specifies 2 channel interleaved 32-bit floating point
-    const HEADER: [u8; 44] = [
-        /*
-        // ChunkID: Contains the letters "RIFF" in ASCII form, change last number to 80 if "RIFX" is used
-        82, 73, 70, 70,
-        // ChunkSize: 36 + SubChunk2Size
-        36, 0, 0, 0,
-        // Format: Contains the letters "WAVE"
-        87, 65, 86, 69,
-        // Subchunk1ID: Contains the letters "fmt "
-        102, 109, 116, 32,
-        // Subchunk1Size: 16 for PCM.
-        16, 0, 0, 0,
-        // AudioFormat: PCM = 1
-        1, 0,
-        // NumChannels: Mono = 1, Stereo = 2, etc.
-        2, 0,
-        // SampleRate: 8000, 44100, etc.
-        68, 212, 0, 0,
-        // ByteRate: SampleRate * NumChannels * BitsPerSample/8
-        32, 98, 5, 0,
-        // BlockAlign: == NumChannels * BitsPerSample / 8
-        8, 0,
-        // BitsPerSample: 8 bits = 8, 16 bits = 16, etc.
-        32, 0,
-        // Subchunk2ID: Contains the letters "data"
-        100, 97, 116, 97,
-        // Subchunk2Size: NumSamples * NumChannels * BitsPerSample / 8
-        0, 0, 0, 0
-        */
-        82, 73, 70, 70, 36, 0, 0, 0, 87, 65, 86, 69, 102, 109, 116, 32, 16, 0, 0, 0, 3, 0, 2, 0, 68,
-        172, 0, 0, 32, 98, 5, 0, 8, 0, 32, 0, 100, 97, 116, 97, 0, 0, 0, 0,
-    ];
-
     pub const MIME: &'static str = "audio/wav";
 
     pub fn new(underlying: AudioBufferConsumer) -> Self {
@@ -51,11 +174,51 @@ impl WaveStream {
             did_write_header: false,
         }
     }
+
+    /// WAVE file header.
+    /// Specifies interleaved samples in the format of the consumer.
+    fn header(format: SampleFormat, config: AudioConfig) -> Vec<u8> {
+        let channels = config.channel_count() as u16;
+        let sample_rate = config.sample_rate() as u32;
+        let bits_per_sample = (format.sample_size() * 8) as u16;
+        let block_align = channels * format.sample_size() as u16;
+        let byte_rate = sample_rate * block_align as u32;
+
+        [
+            // ChunkID: Contains the letters "RIFF" in ASCII form
+            &b"RIFF"[..],
+            // ChunkSize: 36 + SubChunk2Size
+            &36_u32.to_le_bytes(),
+            // Format: Contains the letters "WAVE"
+            b"WAVE",
+            // Subchunk1ID: Contains the letters "fmt "
+            b"fmt ",
+            // Subchunk1Size: 16 for PCM.
+            &16_u32.to_le_bytes(),
+            // AudioFormat: PCM = 1, IEEE float = 3
+            &format.wave_format().to_le_bytes(),
+            // NumChannels: Mono = 1, Stereo = 2, etc.
+            &channels.to_le_bytes(),
+            // SampleRate: 8000, 44100, etc.
+            &sample_rate.to_le_bytes(),
+            // ByteRate: SampleRate * NumChannels * BitsPerSample/8
+            &byte_rate.to_le_bytes(),
+            // BlockAlign: == NumChannels * BitsPerSample / 8
+            &block_align.to_le_bytes(),
+            // BitsPerSample: 8 bits = 8, 16 bits = 16, etc.
+            &bits_per_sample.to_le_bytes(),
+            // Subchunk2ID: Contains the letters "data"
+            b"data",
+            // Subchunk2Size: NumSamples * NumChannels * BitsPerSample / 8
+            &0_u32.to_le_bytes(),
+        ]
+        .concat()
+    }
 }
 
 impl Read for WaveStream {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let header = Self::HEADER;
+        let header = Self::header(self.underlying.format(), self.underlying.config());
         let header_len = header.len();
 
         let mut bytes_written = 0;
@@ -71,3 +234,374 @@ impl Read for WaveStream {
         Ok(bytes_written)
     }
 }
+
+/// The sample rate of Opus streams, as Opus does not support 44.1 kHz
+pub const OPUS_SAMPLE_RATE: usize = 48000;
+
+/// Encodes a stream to Opus frames at [OPUS_SAMPLE_RATE], resampling it as it is read
+pub struct OpusEncoder {
+    underlying: AudioBufferConsumer,
+    /// The channels of the consumer, of which at most two are encoded
+    channels: usize,
+    resampler: Resampler,
+    encoder: Encoder,
+    /// Resampled samples that do not make up a whole frame yet
+    pending: Vec<Sample>,
+}
+
+impl OpusEncoder {
+    /// The duration of audio in a single frame, as used by Discord
+    pub const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+    /// The largest packet a frame is encoded to, as recommended by libopus
+    const MAX_PACKET_SIZE: usize = 4000;
+
+    /// Wraps a consumer, which must receive samples as [SampleFormat::Float32]
+    pub fn new(underlying: AudioBufferConsumer) -> Self {
+        debug_assert_eq!(underlying.format(), SampleFormat::Float32);
+
+        let config = underlying.config();
+        let channels = config.channel_count();
+
+        // Opus only encodes mono and stereo, so only the front channels of surround are kept
+        let (opus_channels, encoded) = match channels {
+            1 => (Channels::Mono, 1),
+            _ => (Channels::Stereo, 2),
+        };
+
+        let encoder = Encoder::new(SampleRate::Hz48000, opus_channels, Application::Audio)
+            .expect("Opus encoder settings are valid");
+
+        Self {
+            underlying,
+            channels,
+            resampler: RESAMPLERS.get(config.sample_rate(), OPUS_SAMPLE_RATE, encoded),
+            encoder,
+            pending: vec![],
+        }
+    }
+
+    /// Returns the amount of samples in a frame at the given rate
+    fn frame_size(rate: usize, channels: usize) -> usize {
+        rate * Self::FRAME_DURATION.as_millis() as usize / 1000 * channels
+    }
+
+    /// Waits for the next frame, or returns `None` if the stream ended.
+    /// Samples that do not make up a whole frame when the stream ends are discarded.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let encoded = self.resampler.key().channels;
+        let frame_size = Self::frame_size(OPUS_SAMPLE_RATE, encoded);
+
+        let rate = self.underlying.config().sample_rate();
+        let read_size = Self::frame_size(rate, self.channels) * SampleFormat::Float32.sample_size();
+
+        let mut bytes = vec![0; read_size];
+
+        while self.pending.len() < frame_size {
+            let amount = self
+                .underlying
+                .read(&mut bytes)
+                .expect("Reading from a live buffer does not fail");
+
+            // Less is only read once the stream is stale
+            if amount < read_size {
+                return None;
+            }
+
+            let mut samples = SampleFormat::Float32.decode(&bytes);
+
+            if self.channels > encoded {
+                samples = samples
+                    .chunks_exact(self.channels)
+                    .flat_map(|frame| &frame[..encoded])
+                    .copied()
+                    .collect();
+            }
+
+            self.resampler.process(&samples, &mut self.pending);
+        }
+
+        let mut packet = vec![0; Self::MAX_PACKET_SIZE];
+
+        match self
+            .encoder
+            .encode_float(&self.pending[..frame_size], &mut packet)
+        {
+            Ok(size) => {
+                self.pending.drain(..frame_size);
+                packet.truncate(size);
+
+                Some(packet)
+            }
+            Err(err) => {
+                error!("Opus encoding failed: {}", err);
+                None
+            }
+        }
+    }
+}
+
+impl Iterator for OpusEncoder {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame()
+    }
+}
+
+/// Reads Opus packets one at a time instead of as a stream of bytes,
+/// so every packet can be forwarded and decoded on its own.
+pub struct PacketConsumer {
+    encoder: OpusEncoder,
+}
+
+impl PacketConsumer {
+    /// The duration of audio in a single packet, as used by Discord
+    pub const PACKET_DURATION: Duration = OpusEncoder::FRAME_DURATION;
+
+    pub fn new(encoder: OpusEncoder) -> Self {
+        Self { encoder }
+    }
+
+    /// Waits for the next packet, or returns `None` if the stream ended.
+    /// Samples that do not make up a whole packet when the stream ends are discarded.
+    pub fn next_packet(&mut self) -> Option<Vec<u8>> {
+        self.encoder.next_frame()
+    }
+}
+
+impl Iterator for PacketConsumer {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet()
+    }
+}
+
+/// Reads Opus frames as a stream of bytes,
+/// where every frame follows its size as a little endian u16.
+pub struct OpusConsumer {
+    encoder: OpusEncoder,
+    /// The rest of the frame that was not read yet
+    pending: Cursor<Vec<u8>>,
+}
+
+impl OpusConsumer {
+    pub fn new(encoder: OpusEncoder) -> Self {
+        Self {
+            encoder,
+            pending: Default::default(),
+        }
+    }
+}
+
+impl Read for OpusConsumer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.position() as usize == self.pending.get_ref().len() {
+            let frame = match self.encoder.next_frame() {
+                Some(frame) => frame,
+                None => return Ok(0),
+            };
+
+            let mut bytes = (frame.len() as u16).to_le_bytes().to_vec();
+            bytes.extend(frame);
+
+            self.pending = Cursor::new(bytes);
+        }
+
+        self.pending.read(buf)
+    }
+}
+
+/// What the output is streamed as, see [super::AudioSystem::set_output_format]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Samples in a [SampleFormat]
+    Pcm,
+    /// Opus frames at [OPUS_SAMPLE_RATE], see [OpusConsumer]
+    Opus,
+}
+
+impl OutputFormat {
+    pub fn parse(str: &str) -> Option<Self> {
+        match str {
+            "pcm" => Some(Self::Pcm),
+            "opus" => Some(Self::Opus),
+            _ => None,
+        }
+    }
+}
+
+/// A stream of the output in the [OutputFormat] it was created with.
+/// It ends once the output format changes, and must be re-created.
+pub enum OutputStream {
+    Pcm(AudioBufferConsumer),
+    Opus(OpusConsumer),
+}
+
+impl OutputStream {
+    pub fn format(&self) -> OutputFormat {
+        match self {
+            OutputStream::Pcm(_) => OutputFormat::Pcm,
+            OutputStream::Opus(_) => OutputFormat::Opus,
+        }
+    }
+}
+
+impl Read for OutputStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            OutputStream::Pcm(stream) => stream.read(buf),
+            OutputStream::Opus(stream) => stream.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use audiopus::{coder::Decoder, Channels, SampleRate};
+
+    use super::{BitrateMeter, Headroom, OpusEncoder, PacketConsumer, SampleFormat, WaveStream};
+    use crate::audio::{AudioConfig, BufferRegistry, STREAM_CHUNK_DURATION, STREAM_CHUNK_SIZE};
+
+    #[test]
+    fn headroom_reduces_peaks_before_encoding() {
+        let headroom = Headroom::new(-1.);
+        let bytes = SampleFormat::Int16.encode_with_headroom(&[1., -1.], headroom);
+
+        let peak = bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]).unsigned_abs())
+            .max()
+            .unwrap();
+
+        let expected = (0.891 * i16::MAX as f32) as u16;
+        assert!(peak.abs_diff(expected) < 10, "Peak was {}", peak);
+
+        // Floating point does not clip, so it is left untouched
+        let bytes = SampleFormat::Float32.encode_with_headroom(&[1.], headroom);
+        assert_eq!(bytes, 1_f32.to_le_bytes());
+    }
+
+    #[test]
+    fn measured_bitrate_matches_the_format() {
+        let mut meter = BitrateMeter::default();
+        let start = Instant::now();
+        let format = SampleFormat::Int16;
+
+        assert_eq!(meter.bitrate(), None);
+
+        // Chunks are not written exactly on time
+        for i in 0..20 {
+            let jitter = Duration::from_millis(i % 3);
+            let bytes = format.encode(&[0.5; STREAM_CHUNK_SIZE]);
+
+            meter.record(
+                start + STREAM_CHUNK_DURATION * i as u32 + jitter,
+                bytes.len(),
+            );
+        }
+
+        let measured = meter.bitrate().unwrap() as f64;
+        let expected = format.bitrate() as f64;
+
+        assert_eq!(format.bitrate(), 1_411_200);
+        assert!(
+            (measured - expected).abs() / expected < 0.01,
+            "Measured {} bit/s",
+            measured
+        );
+
+        meter.reset();
+        assert_eq!(meter.bitrate(), None);
+    }
+
+    #[test]
+    fn float_header_is_unchanged() {
+        let expected = [
+            82, 73, 70, 70, 36, 0, 0, 0, 87, 65, 86, 69, 102, 109, 116, 32, 16, 0, 0, 0, 3, 0, 2,
+            0, 68, 172, 0, 0, 32, 98, 5, 0, 8, 0, 32, 0, 100, 97, 116, 97, 0, 0, 0, 0,
+        ];
+
+        assert_eq!(
+            WaveStream::header(SampleFormat::Float32, AudioConfig::DEFAULT),
+            expected
+        );
+    }
+
+    #[test]
+    fn header_describes_the_configured_format() {
+        let config = AudioConfig::new(48000, 1, Duration::from_millis(100)).unwrap();
+        let header = WaveStream::header(SampleFormat::Int16, config);
+
+        assert_eq!(&header[22..24], &1_u16.to_le_bytes());
+        assert_eq!(&header[24..28], &48000_u32.to_le_bytes());
+        assert_eq!(&header[28..32], &96000_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn opus_frames_are_resampled_to_48khz() {
+        let registry = BufferRegistry::new();
+        let consumer = registry.get_consumer_with_format(SampleFormat::Float32);
+        let mut encoder = OpusEncoder::new(consumer);
+
+        registry.write_samples(&[0.25; STREAM_CHUNK_SIZE]);
+
+        let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+        let mut output = vec![0.; 5760 * 2];
+
+        // The resampler holds back a few samples, so the fifth frame is not complete yet
+        for _ in 0..4 {
+            let packet = encoder.next_frame().unwrap();
+
+            let frames = decoder
+                .decode_float(
+                    Some((&packet[..]).try_into().unwrap()),
+                    (&mut output[..]).try_into().unwrap(),
+                    false,
+                )
+                .unwrap();
+
+            assert_eq!(frames, 960);
+        }
+    }
+
+    #[test]
+    fn packets_decode_on_their_own() {
+        let registry = BufferRegistry::new();
+        let consumer = registry.get_following_consumer(SampleFormat::Float32);
+        let mut packets = PacketConsumer::new(OpusEncoder::new(consumer));
+
+        let samples: Vec<_> = (0..STREAM_CHUNK_SIZE)
+            .map(|i| ((i / 2) as f32 * 0.05).sin() * 0.5)
+            .collect();
+
+        registry.write_samples(&samples);
+
+        // End the stream, discarding the frame that is not complete
+        registry.invalidate();
+
+        let received: Vec<_> = packets.by_ref().collect();
+        assert_eq!(received.len(), 4);
+
+        for packet in received {
+            // A new decoder for every packet, as a client that joins late would have
+            let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+            let mut output = vec![0.; 5760 * 2];
+
+            let frames = decoder
+                .decode_float(
+                    Some((&packet[..]).try_into().unwrap()),
+                    (&mut output[..]).try_into().unwrap(),
+                    false,
+                )
+                .unwrap();
+
+            assert_eq!(frames, 960);
+        }
+
+        assert_eq!(packets.next_packet(), None);
+    }
+}
@@ -1,44 +1,121 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use crossbeam::{
+    atomic::AtomicCell,
+    channel::{bounded, Receiver, Sender, TrySendError},
+};
 
-use super::queuing::QueueEvent;
+use super::{
+    queuing::{QueueEvent, RepeatMode},
+    OutputFormat, PlaybackState, TrackId, TrackInfo,
+};
 
 #[derive(Debug, Clone)]
 pub enum AudioEvent {
     Queue(QueueEvent),
+    /// A different track became the current one
+    TrackStarted(TrackInfo),
+    /// A track played until its end, which is emitted before the next one starts
+    TrackEnded(TrackId),
+    /// How far into the current track playback is, emitted at most once a second
+    PositionUpdated(Duration),
+    /// Playback ran out of loaded samples, so silence is heard until loading catches up.
+    /// The track is `None` if it is not in the queue, such as a preview.
+    BufferUnderrun {
+        track_id: Option<TrackId>,
+        /// How many samples of the chunk were silence
+        missing: usize,
+    },
+    /// Playback was paused or resumed
+    PlaybackState(PlaybackState),
+    /// The repeat mode was changed
+    Repeat(RepeatMode),
+    /// The output or stream format changed, so existing streams end and must be re-created
+    OutputFormatChanged(OutputFormat),
+    /// The track that was to replace the current one could not be loaded,
+    /// see [super::AudioSystem::replace_current]
+    ReplacementFailed(TrackInfo),
+    /// Playback was stopped immediately, see [super::AudioSystem::panic_stop]
+    PanicStopped,
+    /// There have been no streams for a while, so processing paused
+    NoConsumers,
+    /// A stream was created after [AudioEvent::NoConsumers], so processing continues
+    ConsumersPresent,
+    /// The queue finished playing after a drain, and the system stopped
+    Drained,
+    /// Processing fell behind real-time by this much
+    Desync {
+        behind: Duration,
+    },
+    /// A track ended far from the duration it declared, so the actual one is used instead
+    DurationCorrected {
+        track_id: TrackId,
+        declared: Duration,
+        actual: Duration,
+    },
 }
 
+/// How many events a channel holds before the oldest ones are dropped
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+
 #[derive(Debug)]
 pub struct AudioEventChannel {
     id: usize,
     broadcaster: Arc<AudioEventBroadcast>,
     receiver: Receiver<AudioEvent>,
+    dropped: Arc<AtomicCell<usize>>,
 }
 
 #[derive(Debug)]
 struct AudioEventBroadcast {
-    channels: Mutex<Vec<(usize, Sender<AudioEvent>)>>,
+    channels: Mutex<Vec<Subscriber>>,
+    capacity: usize,
+}
+
+/// The sending side of a channel
+#[derive(Debug)]
+struct Subscriber {
+    id: usize,
+    sender: Sender<AudioEvent>,
+    /// Used to drop the oldest event when the channel is full
+    receiver: Receiver<AudioEvent>,
+    dropped: Arc<AtomicCell<usize>>,
 }
 
 impl AudioEventChannel {
     pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_EVENT_CAPACITY)
+    }
+
+    /// Creates a channel where each subscriber holds at most `capacity` events.
+    /// Emitting never blocks, instead the oldest events of a full subscriber are dropped.
+    pub fn with_capacity(capacity: usize) -> Self {
         // Hahahahaha
-        AudioEventBroadcast::channel(Arc::new(AudioEventBroadcast::new()))
+        AudioEventBroadcast::channel(Arc::new(AudioEventBroadcast::new(capacity.max(1))))
     }
 
-    fn internal_new(
-        id: usize,
-        broadcaster: Arc<AudioEventBroadcast>,
-    ) -> (Self, Sender<AudioEvent>) {
-        let (sender, receiver) = unbounded();
+    fn internal_new(id: usize, broadcaster: Arc<AudioEventBroadcast>) -> (Self, Subscriber) {
+        let (sender, receiver) = bounded(broadcaster.capacity);
+        let dropped = Arc::new(AtomicCell::new(0));
+
+        let subscriber = Subscriber {
+            id,
+            sender,
+            receiver: receiver.clone(),
+            dropped: dropped.clone(),
+        };
+
         let channel = Self {
             id,
             broadcaster,
             receiver,
+            dropped,
         };
 
-        (channel, sender)
+        (channel, subscriber)
     }
 
     pub fn emit<T: Into<AudioEvent>>(&self, event: T) {
@@ -48,36 +125,59 @@ impl AudioEventChannel {
     pub fn wait(&self) -> AudioEvent {
         self.receiver.recv().expect("No error on event")
     }
+
+    /// Returns the next event if one has been emitted, without waiting
+    pub fn try_wait(&self) -> Option<AudioEvent> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Returns how many events were dropped because this channel was not read in time
+    pub fn dropped(&self) -> usize {
+        self.dropped.load()
+    }
 }
 
 impl AudioEventBroadcast {
-    fn new() -> Self {
+    fn new(capacity: usize) -> Self {
         Self {
             channels: Default::default(),
+            capacity,
         }
     }
 
     fn channel(broadcaster: Arc<Self>) -> AudioEventChannel {
         let mut channels = broadcaster.channels.lock().unwrap();
 
-        let id = channels.iter().fold(0, |acc, c| acc + c.0) + 1;
-        let (new_channel, sender) = AudioEventChannel::internal_new(id, broadcaster.clone());
+        let id = channels.iter().fold(0, |acc, c| acc + c.id) + 1;
+        let (new_channel, subscriber) = AudioEventChannel::internal_new(id, broadcaster.clone());
 
-        channels.push((id, sender));
+        channels.push(subscriber);
         new_channel
     }
 
     fn remove(&self, id: usize) {
         let mut channels = self.channels.lock().unwrap();
-
-        *channels = channels.iter().filter(|c| c.0 != id).cloned().collect();
+        channels.retain(|c| c.id != id);
     }
 
     fn broadcast(&self, event: AudioEvent) {
         let channels = self.channels.lock().unwrap();
 
-        for (_, sender) in channels.iter() {
-            sender.send(event.clone()).expect("Broadcasts event");
+        for subscriber in channels.iter() {
+            subscriber.send(event.clone());
+        }
+    }
+}
+
+impl Subscriber {
+    /// Sends without blocking, dropping the oldest event if the channel is full
+    fn send(&self, mut event: AudioEvent) {
+        while let Err(TrySendError::Full(rejected)) = self.sender.try_send(event) {
+            if self.receiver.try_recv().is_ok() {
+                self.dropped.fetch_add(1);
+            }
+
+            event = rejected;
         }
     }
 }
@@ -93,3 +193,34 @@ impl Clone for AudioEventChannel {
         AudioEventBroadcast::channel(self.broadcaster.clone())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{AudioEvent, AudioEventChannel};
+
+    #[test]
+    fn slow_subscribers_drop_the_oldest_events() {
+        let producer = AudioEventChannel::with_capacity(4);
+        let subscriber = producer.clone();
+
+        // Nobody reads, but emitting does not block
+        for i in 0..10 {
+            producer.emit(AudioEvent::Desync {
+                behind: Duration::from_millis(i),
+            });
+        }
+
+        assert_eq!(subscriber.dropped(), 6);
+
+        let received: Vec<_> = std::iter::from_fn(|| subscriber.try_wait())
+            .map(|e| match e {
+                AudioEvent::Desync { behind } => behind.as_millis(),
+                _ => unreachable!(),
+            })
+            .collect();
+
+        assert_eq!(received, vec![6, 7, 8, 9]);
+    }
+}
@@ -0,0 +1,189 @@
+use std::time::{Duration, Instant};
+
+use crossbeam::atomic::AtomicCell;
+use json::{object, JsonValue};
+
+use super::AudioSystem;
+
+/// The playback thread is considered stalled if it has not ticked for this long
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// More ticks than this, from 0 to 1, running out of loaded samples is unhealthy
+pub const MAX_UNDERRUN_RATE: f32 = 0.2;
+
+/// How often the position of the current track is reported at most
+pub const POSITION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps track of what the playback thread has been doing
+pub struct Activity {
+    last_tick: AtomicCell<Option<Instant>>,
+    /// A rolling average of how many ticks ran out of loaded samples
+    underrun_rate: AtomicCell<f32>,
+    /// The last read ran out of loaded samples
+    underrunning: AtomicCell<bool>,
+    /// When the position of the current track was last reported
+    position_reported: AtomicCell<Option<Instant>>,
+}
+
+impl Activity {
+    /// How much a single tick affects the underrun rate
+    const SMOOTHING: f32 = 0.05;
+
+    pub fn new() -> Self {
+        Self {
+            last_tick: Default::default(),
+            underrun_rate: AtomicCell::new(0.),
+            underrunning: AtomicCell::new(false),
+            position_reported: Default::default(),
+        }
+    }
+
+    pub fn record_tick(&self, now: Instant) {
+        self.last_tick.store(Some(now));
+    }
+
+    /// Records whether a read of samples to play ran out of loaded samples.
+    /// Returns true if the previous read did not, so an underrun just started.
+    pub fn record_read(&self, underran: bool) -> bool {
+        let current = if underran { 1. } else { 0. };
+        let rate = self.underrun_rate.load();
+
+        self.underrun_rate
+            .store(rate + (current - rate) * Self::SMOOTHING);
+
+        underran && !self.underrunning.swap(underran)
+    }
+
+    /// Returns true if the position has not been reported for [POSITION_INTERVAL],
+    /// in which case it counts as reported now.
+    pub fn should_report_position(&self, now: Instant) -> bool {
+        let due = match self.position_reported.load() {
+            Some(then) => now.duration_since(then) >= POSITION_INTERVAL,
+            None => true,
+        };
+
+        if due {
+            self.position_reported.store(Some(now));
+        }
+
+        due
+    }
+
+    pub fn last_tick(&self) -> Option<Instant> {
+        self.last_tick.load()
+    }
+
+    pub fn underrun_rate(&self) -> f32 {
+        self.underrun_rate.load()
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether the system is working, for orchestration such as a health endpoint
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthStatus {
+    /// A description of everything that is wrong, empty if healthy
+    pub problems: Vec<String>,
+    pub since_last_tick: Option<Duration>,
+    pub underrun_rate: f32,
+}
+
+impl HealthStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        object! {
+            "healthy" => self.is_healthy(),
+            "problems" => self.problems.clone(),
+            "since_last_tick" => self.since_last_tick.map(|d| d.as_secs_f64()),
+            "underrun_rate" => self.underrun_rate,
+        }
+    }
+}
+
+impl AudioSystem {
+    /// Checks that the threads are alive and playback keeps up
+    pub async fn healthcheck(&self) -> HealthStatus {
+        self.health()
+    }
+
+    /// The same as [AudioSystem::healthcheck], for callers that are not async
+    pub fn health(&self) -> HealthStatus {
+        self.health_at(Instant::now())
+    }
+
+    fn health_at(&self, now: Instant) -> HealthStatus {
+        let mut problems = vec![];
+
+        let since_last_tick = self
+            .activity
+            .last_tick()
+            .map(|tick| now.saturating_duration_since(tick));
+
+        let underrun_rate = self.activity.underrun_rate();
+
+        if !self.threads.is_running() {
+            problems.push("The audio system is not running".to_string());
+        } else {
+            if self.threads.any_finished() {
+                problems.push("An audio thread has stopped".to_string());
+            }
+
+            if since_last_tick.map_or(true, |since| since > STALL_TIMEOUT) {
+                problems.push(format!(
+                    "Playback has not ticked for more than {}s",
+                    STALL_TIMEOUT.as_secs()
+                ));
+            }
+        }
+
+        if underrun_rate > MAX_UNDERRUN_RATE {
+            problems.push(format!(
+                "{:.0}% of ticks ran out of loaded samples",
+                underrun_rate * 100.
+            ));
+        }
+
+        HealthStatus {
+            problems,
+            since_last_tick,
+            underrun_rate,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::STALL_TIMEOUT;
+    use crate::audio::AudioSystem;
+
+    #[test]
+    fn stalled_playback_is_unhealthy() {
+        let system = AudioSystem::new();
+        assert!(!system.health().is_healthy());
+
+        system.start();
+        thread::sleep(Duration::from_millis(300));
+
+        let health = system.health();
+        assert!(health.is_healthy(), "{:?}", health.problems);
+
+        // Nothing ticks while time moves on, as if the thread was stuck
+        let later = Instant::now() + STALL_TIMEOUT * 2;
+        assert_eq!(system.health_at(later).problems.len(), 1);
+
+        system.shutdown();
+    }
+}
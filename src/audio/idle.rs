@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+use crossbeam::atomic::AtomicCell;
+use log::info;
+
+use super::{AudioEvent, AudioSystem};
+
+/// Keeps track of whether anyone is listening, so work can stop while nobody is
+#[derive(Default)]
+pub struct PowerSaving {
+    /// How long there have to be no consumers before pausing, or None to never pause
+    grace: AtomicCell<Option<Duration>>,
+    /// When the last consumer went away
+    empty_since: AtomicCell<Option<Instant>>,
+    idle: AtomicCell<bool>,
+}
+
+impl AudioSystem {
+    /// Pauses processing and loading once there have been no streams for the grace period,
+    /// and continues where it was when a stream is created. None disables this.
+    pub fn set_power_saving(&self, grace: Option<Duration>) {
+        self.power_saving.grace.store(grace);
+
+        if grace.is_none() {
+            self.power_saving.idle.store(false);
+        }
+    }
+
+    /// Returns true if processing is paused because nobody is listening
+    pub fn is_idle(&self) -> bool {
+        self.power_saving.idle.load()
+    }
+
+    /// Checks if anyone is listening, returning true if processing should pause
+    pub(super) fn update_idle(&self, now: Instant) -> bool {
+        let power_saving = &self.power_saving;
+
+        if self.registry.consumer_count() > 0 {
+            power_saving.empty_since.store(None);
+
+            if power_saving.idle.swap(false) {
+                info!("A stream was created, resuming processing");
+                self.events.emit(AudioEvent::ConsumersPresent);
+            }
+
+            return false;
+        }
+
+        let since = power_saving.empty_since.load().unwrap_or(now);
+        power_saving.empty_since.store(Some(since));
+
+        let should_idle = power_saving
+            .grace
+            .load()
+            .map_or(false, |grace| now.saturating_duration_since(since) >= grace);
+
+        if should_idle && !power_saving.idle.swap(true) {
+            info!("Nobody is listening, pausing processing");
+            self.events.emit(AudioEvent::NoConsumers);
+        }
+
+        self.is_idle()
+    }
+}
@@ -1,10 +1,25 @@
-use super::pipeline::{IntoSampleReader, SampleSource};
-use std::fmt::{Debug, Display};
+use super::{
+    pipeline::{IntoSampleReader, SampleSource},
+    processing::ffmpeg::UnsupportedSampleRate,
+    track::Metadata,
+};
+use log::warn;
+use std::{
+    error::Error,
+    fmt::{Debug, Display},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The extensions of audio files that are played from a directory
+const SUPPORTED_EXTENSIONS: [&str; 7] = ["mp3", "ogg", "opus", "wav", "flac", "m4a", "aac"];
 
 #[derive(Debug, Clone)]
 pub enum Input {
     YouTube(YouTubeVideo),
     Url(Url),
+    Remote(RemoteStream),
+    File(LocalFile),
 }
 
 impl Input {
@@ -14,6 +29,8 @@ impl Input {
         match self {
             Input::YouTube(v) => v.fingerprint(),
             Input::Url(x) => x.fingerprint(),
+            Input::Remote(x) => x.fingerprint(),
+            Input::File(x) => x.fingerprint(),
         }
     }
 
@@ -21,24 +38,168 @@ impl Input {
         match self {
             Input::YouTube(v) => v.duration(),
             Input::Url(x) => x.duration(),
+            Input::Remote(x) => x.duration(),
+            Input::File(x) => x.duration(),
+        }
+    }
+
+    /// Returns what the tags of the input say, where every field is None for raw streams
+    pub fn metadata(&self) -> Metadata {
+        match self {
+            Input::YouTube(v) => v.metadata(),
+            Input::Url(x) => x.metadata(),
+            Input::Remote(x) => x.metadata(),
+            Input::File(x) => x.metadata(),
         }
     }
 
-    pub fn parse(str: &str) -> Option<Self> {
-        let predicates = [
-            |url| YouTubeVideo::from_url(url).map(Self::YouTube),
-            |url| Url::from_url(url).map(Self::Url),
-        ];
+    /// Returns true if the input is downloaded as it is read, sharing the download limit
+    pub fn is_download(&self) -> bool {
+        match self {
+            Input::Url(x) => x.is_download(),
+            Input::File(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Creates an input from what a user gave, trying each kind of input in turn.
+    /// An input whose sample rate is rejected is an error instead of trying the next kind.
+    pub fn parse(str: &str) -> Result<Self, ParseError> {
+        if let Some(video) = YouTubeVideo::from_url(str) {
+            return Ok(Self::YouTube(video));
+        }
+
+        match Url::from_url(str) {
+            Ok(url) => return Ok(Self::Url(url)),
+            Err(UrlError::SampleRate(err)) => return Err(ParseError::SampleRate(err)),
+            Err(UrlError::NotAudio | UrlError::Scheme) => {}
+        }
+
+        match Self::from_url(str) {
+            Ok(input) => Ok(input),
+            Err(ResolveError::SampleRate(err)) => Err(ParseError::SampleRate(err)),
+            Err(err) => {
+                warn!("Cannot resolve {}: {}", str, err);
+                Err(ParseError::Unrecognized)
+            }
+        }
+    }
+
+    /// Resolves the audio stream of a page with yt-dlp, such as a video on any site it supports.
+    /// This waits for yt-dlp, so async code has to call it in `spawn_blocking`,
+    /// but decoding only starts once the track is loaded.
+    pub fn from_url(url: &str) -> Result<Self, ResolveError> {
+        RemoteStream::resolve(url).map(Self::Remote)
+    }
+
+    /// Opens an audio file, detecting its format from the contents and extension. It is decoded
+    /// with the decoder of its format and converted to the format of the engine when loaded.
+    /// The path must be trusted, such as one found in the music directory, never one a user gave.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, FileError> {
+        LocalFile::open(path.as_ref()).map(Self::File)
+    }
+
+    /// Creates inputs for the audio files in a directory, sorted by their path.
+    /// Files that are not supported or cannot be probed are skipped with a warning.
+    pub fn from_directory(path: &Path, recursive: bool) -> io::Result<Vec<Self>> {
+        let inputs = audio_files(path, recursive)?
+            .into_iter()
+            .filter_map(|file| match Self::from_path(&file) {
+                Ok(input) => Some(input),
+                Err(FileError::SampleRate(err)) => {
+                    warn!("Skipping {}: {}", file.display(), err);
+                    None
+                }
+                // Files Symphonia cannot decode are still played through ffmpeg
+                Err(_) => match file.to_str().map(Url::from_file) {
+                    Some(Ok(url)) => Some(Self::Url(url)),
+                    Some(Err(err)) => {
+                        warn!("Skipping {}: {}", file.display(), err);
+                        None
+                    }
+                    None => {
+                        warn!(
+                            "Skipping {}, as its path is not valid UTF-8",
+                            file.display()
+                        );
+                        None
+                    }
+                },
+            })
+            .collect();
 
-        predicates.into_iter().find_map(|f| f(str))
+        Ok(inputs)
     }
 }
 
+/// Returns true if this is an http or https url, which are the only ones a user can play,
+/// as ffmpeg and yt-dlp would otherwise open local files or read it as an option.
+fn is_web_url(url: &str) -> bool {
+    let url = url.trim_start().to_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Returns the files in a directory with a supported extension, sorted by their path
+fn audio_files(path: &Path, recursive: bool) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            if recursive {
+                files.extend(audio_files(&path, recursive)?);
+            }
+
+            continue;
+        }
+
+        let supported = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| SUPPORTED_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+            .unwrap_or_default();
+
+        match supported {
+            true => files.push(path),
+            false => warn!(
+                "Skipping {}, as it is not a supported format",
+                path.display()
+            ),
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// No input could be created from what was given
+#[derive(Debug)]
+pub enum ParseError {
+    /// It is not something that can be played
+    Unrecognized,
+    /// It can be played, but its sample rate is rejected
+    SampleRate(UnsupportedSampleRate),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Unrecognized => write!(f, "No suitable source was found."),
+            ParseError::SampleRate(err) => write!(f, "{}.", err),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
 impl Display for Input {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
             Input::YouTube(x) => std::fmt::Display::fmt(&x, f),
             Input::Url(x) => std::fmt::Display::fmt(&x, f),
+            Input::Remote(x) => std::fmt::Display::fmt(&x, f),
+            Input::File(x) => std::fmt::Display::fmt(&x, f),
         }
     }
 }
@@ -56,35 +217,73 @@ impl IntoSampleReader for Input {
         match self {
             Input::YouTube(x) => x.into_sample_reader(),
             Input::Url(x) => x.into_sample_reader(),
+            Input::Remote(x) => x.into_sample_reader(),
+            Input::File(x) => x.into_sample_reader(),
         }
     }
 }
 
-pub use url::Url;
+pub use url::{Url, UrlError};
 mod url {
-    use std::fmt::Display;
+    use std::{error::Error, fmt::Display};
 
     use crate::audio::{
         pipeline::{IntoSampleReader, SampleReader, SampleSource},
-        processing::ffmpeg,
+        processing::ffmpeg::{self, Resampling, UnsupportedSampleRate},
+        track::Metadata,
     };
 
     #[derive(Debug, Clone)]
     pub struct Url {
         url: String,
+        title: Option<String>,
         duration: f32,
+        metadata: Metadata,
+        resampling: Resampling,
     }
 
     impl Url {
-        pub fn from_url(url: &str) -> Option<Self> {
-            let probe = ffmpeg::probe(url);
+        /// Probes the url a user gave, failing if it is not an http or https url,
+        /// it is not audio or its sample rate is rejected
+        pub fn from_url(url: &str) -> Result<Self, UrlError> {
+            if !super::is_web_url(url) {
+                return Err(UrlError::Scheme);
+            }
+
+            Self::from_file(url)
+        }
+
+        /// Probes a local file, which must be from a trusted place such as the music directory
+        pub fn from_file(url: &str) -> Result<Self, UrlError> {
+            let probe = ffmpeg::probe(url).ok_or(UrlError::NotAudio)?;
+
+            let resampling = match probe.sample_rate {
+                Some(rate) => ffmpeg::check_sample_rate(rate, ffmpeg::sample_rate_policy())
+                    .map_err(UrlError::SampleRate)?,
+                None => Resampling::Direct,
+            };
+
+            let metadata = Metadata {
+                artist: probe.tag("artist"),
+                album: probe.tag("album"),
+                artwork: None,
+                codec: probe.codec.clone(),
+            };
 
             let me = Self {
                 duration: probe.duration,
+                title: probe.tag("title"),
                 url: url.to_string(),
+                metadata,
+                resampling,
             };
 
-            Some(me)
+            Ok(me)
+        }
+
+        /// Returns false for files from the music directory
+        pub fn is_download(&self) -> bool {
+            super::is_web_url(&self.url)
         }
 
         pub fn duration(&self) -> f32 {
@@ -94,13 +293,17 @@ mod url {
         pub fn fingerprint(&self) -> String {
             self.url.to_owned()
         }
+
+        pub fn metadata(&self) -> Metadata {
+            self.metadata.clone()
+        }
     }
 
     impl IntoSampleReader for Url {
         type Output = SampleSource;
 
         fn into_sample_reader(self) -> Self::Output {
-            ffmpeg::Process::new(ffmpeg::Operation::ToRaw(self.url))
+            ffmpeg::Process::new(ffmpeg::Operation::to_raw(self.url, self.resampling))
                 .unwrap()
                 .wrap()
         }
@@ -108,7 +311,767 @@ mod url {
 
     impl Display for Url {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(f, "{}", self.url)
+            write!(f, "{}", self.title.as_ref().unwrap_or(&self.url))
+        }
+    }
+
+    /// A url could not be played
+    #[derive(Debug)]
+    pub enum UrlError {
+        /// ffprobe found no audio at the url
+        NotAudio,
+        /// It is not an http or https url
+        Scheme,
+        SampleRate(UnsupportedSampleRate),
+    }
+
+    impl Display for UrlError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                UrlError::NotAudio => write!(f, "It could not be probed as audio"),
+                UrlError::Scheme => write!(f, "Only http and https urls can be played"),
+                UrlError::SampleRate(err) => write!(f, "{}", err),
+            }
+        }
+    }
+
+    impl Error for UrlError {}
+}
+
+pub use file::{FileError, LocalFile, SymphoniaDecoder};
+mod file {
+    use std::{
+        error::Error,
+        fmt::Display,
+        fs::File,
+        io,
+        path::{Path, PathBuf},
+    };
+
+    use log::{error, warn};
+    use symphonia::core::{
+        audio::SampleBuffer,
+        codecs::{Decoder as CodecDecoder, DecoderOptions},
+        errors::Error as SymphoniaError,
+        formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+        io::MediaSourceStream,
+        meta::{MetadataOptions, StandardTagKey, Tag},
+        probe::Hint,
+    };
+
+    use crate::audio::{
+        decoding::{Decoder, ProbedFile, DECODERS},
+        pipeline::{IntoSampleReader, SampleReader, SampleSource, SamplesRead, Stereo},
+        processing::ffmpeg::{self, SampleRatePolicy, UnsupportedSampleRate},
+        resampling::{Resampled, RESAMPLERS},
+        track::Metadata,
+        Sample, CHANNEL_COUNT, SAMPLE_RATE,
+    };
+
+    /// An audio file on disk, decoded with the decoder registered for its format
+    #[derive(Debug, Clone)]
+    pub struct LocalFile {
+        path: PathBuf,
+        /// The title it is tagged with, otherwise the file name is used
+        title: Option<String>,
+        metadata: Metadata,
+        duration: f32,
+        sample_rate: usize,
+        channels: usize,
+    }
+
+    impl LocalFile {
+        /// Probes the file with the decoder of its format,
+        /// making sure it can be decoded before it is queued.
+        pub fn open(path: &Path) -> Result<Self, FileError> {
+            let probed = DECODERS.get(path).probe(path)?;
+            let (sample_rate, channels) = (probed.sample_rate, probed.channels);
+
+            if sample_rate == 0 || channels == 0 {
+                return Err(FileError::NoAudio);
+            }
+
+            // Decoders resample nothing, so the rate has to be one the resampler can handle
+            ffmpeg::check_sample_rate(sample_rate, SampleRatePolicy::Reject)
+                .map_err(FileError::SampleRate)?;
+
+            Ok(Self {
+                path: path.to_path_buf(),
+                title: probed.title,
+                metadata: probed.metadata,
+                duration: probed.frames.unwrap_or_default() as f32 / sample_rate as f32,
+                sample_rate,
+                channels,
+            })
+        }
+
+        pub fn duration(&self) -> f32 {
+            self.duration
+        }
+
+        pub fn fingerprint(&self) -> String {
+            self.path.to_string_lossy().into_owned()
+        }
+
+        pub fn metadata(&self) -> Metadata {
+            self.metadata.clone()
+        }
+    }
+
+    impl IntoSampleReader for LocalFile {
+        type Output = SampleSource;
+
+        fn into_sample_reader(self) -> Self::Output {
+            // The file is opened again, as decoders cannot be cloned along with the input
+            let decoder = match DECODERS.get(&self.path).open(&self.path) {
+                Ok(decoder) => decoder,
+                Err(err) => {
+                    error!("Failed to open {}: {}", self.path.display(), err);
+                    return vec![].into_sample_reader().wrap();
+                }
+            };
+
+            let stereo = Stereo::new(decoder, self.channels).expect("Channels were checked");
+
+            if self.sample_rate == SAMPLE_RATE {
+                return stereo.wrap();
+            }
+
+            let resampler = RESAMPLERS.get(self.sample_rate, SAMPLE_RATE, CHANNEL_COUNT);
+            Resampled::new(stereo, resampler).wrap()
+        }
+    }
+
+    impl Display for LocalFile {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            if let Some(title) = &self.title {
+                return write!(f, "{}", title);
+            }
+
+            let name = self.path.file_stem().unwrap_or(self.path.as_os_str());
+            write!(f, "{}", name.to_string_lossy())
+        }
+    }
+
+    /// The default [Decoder], which supports the formats Symphonia does
+    pub struct SymphoniaDecoder;
+
+    impl Decoder for SymphoniaDecoder {
+        fn probe(&self, path: &Path) -> Result<ProbedFile, FileError> {
+            let decoder = FileDecoder::open(path)?;
+
+            Ok(ProbedFile {
+                sample_rate: decoder.sample_rate,
+                channels: decoder.channels,
+                frames: decoder.frames,
+                title: decoder.tag(StandardTagKey::TrackTitle),
+                metadata: Metadata {
+                    artist: decoder.tag(StandardTagKey::Artist),
+                    album: decoder.tag(StandardTagKey::Album),
+                    artwork: None,
+                    codec: decoder.codec.clone(),
+                },
+            })
+        }
+
+        fn open(&self, path: &Path) -> Result<SampleSource, FileError> {
+            FileDecoder::open(path).map(SampleReader::wrap)
+        }
+    }
+
+    /// Reads interleaved samples from the default track of a file,
+    /// with the sample rate and channels the file has.
+    struct FileDecoder {
+        format: Box<dyn FormatReader>,
+        decoder: Box<dyn CodecDecoder>,
+        track: u32,
+        sample_rate: usize,
+        channels: usize,
+        frames: Option<u64>,
+        /// The short name of the codec
+        codec: Option<String>,
+        /// The tags found when probing, and those in the container
+        tags: Vec<Tag>,
+        decoded: Vec<Sample>,
+        /// Samples decoded before the offset of a seek, which are discarded
+        overshoot: usize,
+        ended: bool,
+    }
+
+    impl FileDecoder {
+        fn open(path: &Path) -> Result<Self, FileError> {
+            let file = File::open(path).map_err(FileError::Io)?;
+            let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+            let mut hint = Hint::new();
+            if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+                hint.with_extension(extension);
+            }
+
+            let mut probed = symphonia::default::get_probe()
+                .format(
+                    &hint,
+                    stream,
+                    &FormatOptions::default(),
+                    &MetadataOptions::default(),
+                )
+                .map_err(FileError::from)?;
+
+            let track = probed.format.default_track().ok_or(FileError::NoAudio)?;
+            let params = track.codec_params.clone();
+
+            let sample_rate = params.sample_rate.ok_or(FileError::NoAudio)? as usize;
+            let channels = params.channels.ok_or(FileError::NoAudio)?.count();
+
+            let codecs = symphonia::default::get_codecs();
+            let decoder = codecs
+                .make(&params, &DecoderOptions::default())
+                .map_err(FileError::from)?;
+
+            let codec = codecs
+                .get_codec(params.codec)
+                .map(|c| c.short_name.to_string());
+
+            let track = track.id;
+            let mut tags = vec![];
+
+            // Tags such as ID3 are found before the container, others are part of it
+            if let Some(metadata) = probed.metadata.get() {
+                tags.extend(
+                    metadata
+                        .current()
+                        .into_iter()
+                        .flat_map(|r| r.tags())
+                        .cloned(),
+                );
+            }
+
+            let metadata = probed.format.metadata();
+            tags.extend(
+                metadata
+                    .current()
+                    .into_iter()
+                    .flat_map(|r| r.tags())
+                    .cloned(),
+            );
+
+            Ok(Self {
+                track,
+                format: probed.format,
+                decoder,
+                sample_rate,
+                channels,
+                frames: params.n_frames,
+                codec,
+                tags,
+                decoded: vec![],
+                overshoot: 0,
+                ended: false,
+            })
+        }
+
+        /// Returns the value of the first tag with a key, if the file is tagged with it
+        fn tag(&self, key: StandardTagKey) -> Option<String> {
+            self.tags
+                .iter()
+                .find(|tag| tag.std_key == Some(key))
+                .map(|tag| tag.value.to_string())
+        }
+
+        /// Decodes the next packet of the track, ending at the end of the file or an error
+        fn decode_packet(&mut self) {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.ended = true;
+                    return;
+                }
+                Err(err) => {
+                    error!("Failed to read a packet: {}", err);
+                    self.ended = true;
+                    return;
+                }
+            };
+
+            if packet.track_id() != self.track {
+                return;
+            }
+
+            match self.decoder.decode(&packet) {
+                Ok(audio) => {
+                    let mut samples =
+                        SampleBuffer::<Sample>::new(audio.capacity() as u64, *audio.spec());
+
+                    samples.copy_interleaved_ref(audio);
+
+                    let samples = samples.samples();
+                    let discarded = self.overshoot.min(samples.len());
+
+                    self.overshoot -= discarded;
+                    self.decoded.extend_from_slice(&samples[discarded..]);
+                }
+                // A corrupt packet is skipped instead of ending the track
+                Err(SymphoniaError::DecodeError(err)) => {
+                    warn!("Skipping a packet that could not be decoded: {}", err)
+                }
+                Err(err) => {
+                    error!("Failed to decode a packet: {}", err);
+                    self.ended = true;
+                }
+            }
+        }
+    }
+
+    impl SampleReader for FileDecoder {
+        fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+            while self.decoded.len() < buf.len() && !self.ended {
+                self.decode_packet();
+            }
+
+            let amount = buf.len().min(self.decoded.len()) / self.channels * self.channels;
+
+            buf[..amount].copy_from_slice(&self.decoded[..amount]);
+            self.decoded.drain(..amount);
+
+            SamplesRead::empty_if(self.ended && self.decoded.is_empty(), amount)
+        }
+
+        /// Seeks to the packet that contains the offset, so the packets before it are not decoded
+        fn seek_samples(&mut self, offset: usize) -> bool {
+            let to = SeekTo::TimeStamp {
+                ts: (offset / self.channels) as u64,
+                track_id: self.track,
+            };
+
+            let seeked = match self.format.seek(SeekMode::Accurate, to) {
+                Ok(seeked) => seeked,
+                Err(err) => {
+                    warn!("Failed to seek within a file: {}", err);
+                    return false;
+                }
+            };
+
+            // Packets start before the offset, so some of the first one is discarded
+            let frames = seeked.required_ts.saturating_sub(seeked.actual_ts);
+
+            self.decoder.reset();
+            self.decoded.clear();
+            self.overshoot = frames as usize * self.channels;
+            self.ended = false;
+
+            true
+        }
+    }
+
+    /// A file could not be opened for playback
+    #[derive(Debug)]
+    pub enum FileError {
+        Io(io::Error),
+        /// Symphonia does not support the format or codec, and what it said about it
+        Unsupported(&'static str),
+        /// The file could not be read as the format it was detected as
+        Invalid(SymphoniaError),
+        /// The file has no track with a known sample rate and channel layout
+        NoAudio,
+        SampleRate(UnsupportedSampleRate),
+    }
+
+    impl From<SymphoniaError> for FileError {
+        fn from(err: SymphoniaError) -> Self {
+            match err {
+                SymphoniaError::IoError(err) => FileError::Io(err),
+                SymphoniaError::Unsupported(what) => FileError::Unsupported(what),
+                err => FileError::Invalid(err),
+            }
+        }
+    }
+
+    impl Display for FileError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                FileError::Io(err) => write!(f, "The file could not be read: {}", err),
+                FileError::Unsupported(what) => {
+                    write!(f, "The format or codec is not supported ({})", what)
+                }
+                FileError::Invalid(err) => write!(f, "The file is invalid: {}", err),
+                FileError::NoAudio => write!(f, "The file has no playable audio track"),
+                FileError::SampleRate(err) => write!(f, "{}", err),
+            }
+        }
+    }
+
+    impl Error for FileError {}
+
+    #[cfg(test)]
+    mod test {
+        use std::{
+            env, fs, process,
+            sync::{
+                mpsc::{self, Receiver, Sender},
+                Mutex,
+            },
+            thread,
+            time::Duration,
+        };
+
+        use super::{FileError, LocalFile};
+        use crate::audio::{
+            pipeline::{IntoSampleReader, SampleReader, SamplesRead},
+            testing::wave,
+            AudioSystem, Input, SAMPLE_RATE,
+        };
+
+        #[test]
+        fn files_are_converted_to_the_engine_format() {
+            let dir = env::temp_dir().join(format!("gct-file-{}", process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let path = dir.join("Half a second.wav");
+            fs::write(&path, wave(22050, &[i16::MAX / 2; 11025], &[])).unwrap();
+
+            let file = LocalFile::open(&path).unwrap();
+            assert_eq!(file.duration(), 0.5);
+            assert_eq!(file.to_string(), "Half a second");
+
+            let mut reader = file.into_sample_reader();
+            let mut samples = vec![0.; SAMPLE_RATE * 2];
+            let amount = reader.read_samples(&mut samples).amount();
+
+            // Resampled to half a second of stereo, give or take the filter's delay
+            assert!(
+                amount.abs_diff(SAMPLE_RATE) < 256,
+                "Read {} samples",
+                amount
+            );
+
+            let middle = &samples[amount / 2..amount / 2 + 2];
+            assert!(
+                middle.iter().all(|s| (s - 0.5).abs() < 0.01),
+                "{:?}",
+                middle
+            );
+
+            let text = dir.join("notes.txt");
+            fs::write(&text, "Not audio").unwrap();
+
+            assert!(matches!(
+                LocalFile::open(&text),
+                Err(FileError::Unsupported(_))
+            ));
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn tags_are_read_from_files() {
+            let dir = env::temp_dir().join(format!("gct-tags-{}", process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let info = [
+                (b"INAM", "Tagged title"),
+                (b"IART", "Tagged artist"),
+                (b"IPRD", "Tagged album"),
+            ];
+
+            let tagged = dir.join("tagged.wav");
+            fs::write(&tagged, wave(SAMPLE_RATE as u32, &[0; 64], &info)).unwrap();
+
+            let file = LocalFile::open(&tagged).unwrap();
+            let metadata = file.metadata();
+
+            assert_eq!(file.to_string(), "Tagged title");
+            assert_eq!(metadata.artist.as_deref(), Some("Tagged artist"));
+            assert_eq!(metadata.album.as_deref(), Some("Tagged album"));
+            assert!(metadata.codec.is_some());
+
+            // Without tags, only the codec is known
+            let untagged = dir.join("untagged.wav");
+            fs::write(&untagged, wave(SAMPLE_RATE as u32, &[0; 64], &[])).unwrap();
+
+            let file = LocalFile::open(&untagged).unwrap();
+            let metadata = file.metadata();
+
+            assert_eq!(file.to_string(), "untagged");
+            assert_eq!((metadata.artist, metadata.album), (None, None));
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn only_trusted_paths_are_opened_as_files() {
+            let dir = env::temp_dir().join(format!("gct-trusted-{}", process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let path = dir.join("Secret.wav");
+            fs::write(&path, wave(SAMPLE_RATE as u32, &[0; 64], &[])).unwrap();
+
+            // A user must not be able to play whatever is on the disk, or pass options
+            let file_url = format!("file://{}", path.display());
+
+            for input in [path.to_str().unwrap(), &file_url, "-i/etc/passwd"] {
+                assert!(Input::parse(input).is_err(), "{} was parsed", input);
+            }
+
+            let inputs = Input::from_directory(&dir, false).unwrap();
+            assert!(matches!(inputs[..], [Input::File(_)]));
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn files_load_while_every_download_is_taken() {
+            /// Keeps its download going until it is told to end
+            struct Stalled(Mutex<(Sender<()>, Receiver<()>)>);
+
+            impl SampleReader for Stalled {
+                fn read_samples(&mut self, buf: &mut [f32]) -> SamplesRead {
+                    let (started, end) = &*self.0.lock().unwrap();
+                    started.send(()).ok();
+                    end.recv().ok();
+
+                    SamplesRead::Empty(buf.len())
+                }
+            }
+
+            let dir = env::temp_dir().join(format!("gct-permits-{}", process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let path = dir.join("Local.wav");
+            fs::write(&path, wave(SAMPLE_RATE as u32, &[0; 64], &[])).unwrap();
+
+            let system = AudioSystem::new();
+            system.pool.set_max_downloads(1);
+
+            let (started, stalled) = mpsc::channel();
+            let (end, ended) = mpsc::channel();
+            let download = system
+                .pool
+                .add_download(Stalled(Mutex::new((started, ended))), 100);
+
+            let downloading = thread::spawn(move || download.load(100));
+            stalled.recv().unwrap();
+
+            let input = Input::from_path(&path).unwrap();
+            assert!(!input.is_download());
+
+            let track = system.track_from_input(input, None).unwrap();
+            let (loaded, result) = mpsc::channel();
+
+            let loader = track.loader.clone();
+            thread::spawn(move || loaded.send(loader.load(SAMPLE_RATE * 2)).unwrap());
+
+            // The file does not wait for the download to end
+            assert!(result.recv_timeout(Duration::from_secs(5)).is_ok());
+            assert!(track.loader.available() > 0);
+
+            end.send(()).unwrap();
+            downloading.join().unwrap();
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
+
+pub use remote::{RemoteStream, ResolveError};
+mod remote {
+    use std::{error::Error, fmt::Display};
+
+    use json::JsonValue;
+    use log::error;
+
+    use crate::{
+        audio::{
+            pipeline::{IntoSampleReader, SampleReader, SampleSource, SamplesRead},
+            processing::ffmpeg::{self, Resampling, UnsupportedSampleRate},
+            track::Metadata,
+            Sample,
+        },
+        ytdl::{self, FetchError},
+    };
+
+    /// The audio stream of a page, resolved by yt-dlp
+    #[derive(Debug, Clone)]
+    pub struct RemoteStream {
+        url: String,
+        title: String,
+        uploader: Option<String>,
+        thumbnail: Option<String>,
+        codec: Option<String>,
+        duration: f32,
+        stream_url: String,
+        resampling: Resampling,
+    }
+
+    impl RemoteStream {
+        /// Asks yt-dlp for the best audio stream of a page
+        pub fn resolve(url: &str) -> Result<Self, ResolveError> {
+            if !super::is_web_url(url) {
+                return Err(ResolveError::Scheme);
+            }
+
+            let info = ytdl::fetch(url).map_err(ResolveError::Fetch)?;
+            Self::from_json(url, &info)
+        }
+
+        /// Reads the metadata yt-dlp outputs for a page
+        fn from_json(url: &str, info: &JsonValue) -> Result<Self, ResolveError> {
+            let stream_url = info["url"]
+                .as_str()
+                .ok_or(ResolveError::Invalid("there is no stream url"))?;
+
+            let resampling = match info["asr"].as_usize() {
+                Some(rate) => ffmpeg::check_sample_rate(rate, ffmpeg::sample_rate_policy())
+                    .map_err(ResolveError::SampleRate)?,
+                None => Resampling::Direct,
+            };
+
+            Ok(Self {
+                url: url.to_string(),
+                title: info["title"].as_str().unwrap_or(url).to_string(),
+                uploader: info["uploader"].as_str().map(str::to_string),
+                thumbnail: info["thumbnail"].as_str().map(str::to_string),
+                codec: info["acodec"].as_str().map(str::to_string),
+                // Live streams do not have one
+                duration: info["duration"].as_f32().unwrap_or_default(),
+                stream_url: stream_url.to_string(),
+                resampling,
+            })
+        }
+
+        pub fn duration(&self) -> f32 {
+            self.duration
+        }
+
+        /// The stream url expires, so the page is used instead
+        pub fn fingerprint(&self) -> String {
+            self.url.to_owned()
+        }
+
+        pub fn metadata(&self) -> Metadata {
+            Metadata {
+                artist: self.uploader.clone(),
+                album: None,
+                artwork: self.thumbnail.clone(),
+                codec: self.codec.clone(),
+            }
+        }
+    }
+
+    impl IntoSampleReader for RemoteStream {
+        type Output = SampleSource;
+
+        fn into_sample_reader(self) -> Self::Output {
+            let operation = ffmpeg::Operation::to_raw(self.stream_url, self.resampling);
+
+            Deferred {
+                operation: Some(operation),
+                process: None,
+            }
+            .wrap()
+        }
+    }
+
+    impl Display for RemoteStream {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match &self.uploader {
+                Some(uploader) => write!(f, "{} by {}", self.title, uploader),
+                None => write!(f, "{}", self.title),
+            }
+        }
+    }
+
+    /// Starts ffmpeg when it is first read from, which happens on a loading thread,
+    /// so neither the caller nor playback waits for the process to start.
+    struct Deferred {
+        operation: Option<ffmpeg::Operation>,
+        process: Option<SampleSource>,
+    }
+
+    impl SampleReader for Deferred {
+        fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+            if let Some(operation) = self.operation.take() {
+                match ffmpeg::Process::new(operation) {
+                    Ok(process) => self.process = Some(process.wrap()),
+                    Err(err) => error!("Failed to start decoding: {}", err),
+                }
+            }
+
+            match self.process.as_mut() {
+                Some(process) => process.read_samples(buf),
+                None => SamplesRead::Empty(0),
+            }
+        }
+    }
+
+    /// The stream of a page could not be resolved
+    #[derive(Debug)]
+    pub enum ResolveError {
+        /// yt-dlp did not output the metadata of the page
+        Fetch(FetchError),
+        /// It is not an http or https url
+        Scheme,
+        /// What yt-dlp output could not be used, and why
+        Invalid(&'static str),
+        SampleRate(UnsupportedSampleRate),
+    }
+
+    impl Display for ResolveError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ResolveError::Fetch(err) => write!(f, "{}", err),
+                ResolveError::Scheme => write!(f, "Only http and https urls can be played"),
+                ResolveError::Invalid(reason) => {
+                    write!(f, "The output of yt-dlp is invalid, as {}", reason)
+                }
+                ResolveError::SampleRate(err) => write!(f, "{}", err),
+            }
+        }
+    }
+
+    impl Error for ResolveError {}
+
+    #[cfg(test)]
+    mod test {
+        use super::{RemoteStream, ResolveError};
+
+        #[test]
+        fn metadata_is_read_from_yt_dlp() {
+            let json = r#"{
+                "title": "A set",
+                "uploader": "Someone",
+                "duration": 3600.5,
+                "asr": 48000,
+                "acodec": "opus",
+                "thumbnail": "https://example.com/set.jpg",
+                "url": "https://example.com/stream.webm"
+            }"#;
+
+            let json = json::parse(json).unwrap();
+            let stream = RemoteStream::from_json("https://example.com/set", &json).unwrap();
+
+            assert_eq!(stream.duration(), 3600.5);
+            assert_eq!(stream.fingerprint(), "https://example.com/set");
+            assert_eq!(stream.to_string(), "A set by Someone");
+
+            let metadata = stream.metadata();
+            assert_eq!(metadata.artist.as_deref(), Some("Someone"));
+            assert_eq!(
+                metadata.artwork.as_deref(),
+                Some("https://example.com/set.jpg")
+            );
+            assert_eq!(metadata.codec.as_deref(), Some("opus"));
+
+            // Live streams have no duration
+            let live = r#"{ "title": "Live", "url": "https://example.com/live.m3u8" }"#;
+            let live = json::parse(live).unwrap();
+            let stream = RemoteStream::from_json("https://example.com/live", &live).unwrap();
+
+            assert_eq!(stream.duration(), 0.);
+            assert_eq!(stream.to_string(), "Live");
+
+            let missing = json::parse(r#"{ "title": "?" }"#).unwrap();
+            let missing = RemoteStream::from_json("https://example.com", &missing);
+            assert!(matches!(missing, Err(ResolveError::Invalid(_))));
         }
     }
 }
@@ -118,11 +1081,14 @@ mod youtube {
     use std::fmt::Display;
 
     use log::error;
-    use youtube_dl::{YoutubeDl, YoutubeDlOutput};
 
-    use crate::audio::{
-        pipeline::{IntoSampleReader, SampleReader, SampleSource},
-        processing::ffmpeg,
+    use crate::{
+        audio::{
+            pipeline::{IntoSampleReader, SampleReader, SampleSource},
+            processing::ffmpeg,
+            track::Metadata,
+        },
+        ytdl,
     };
 
     /// Parsed from youtube-dl
@@ -144,6 +1110,13 @@ mod youtube {
             self.duration
         }
 
+        pub fn metadata(&self) -> Metadata {
+            Metadata {
+                artist: Some(self.channel.clone()),
+                ..Default::default()
+            }
+        }
+
         pub fn from_url(url: &str) -> Option<Self> {
             if !Self::is_valid_url(url) {
                 return None;
@@ -182,47 +1155,22 @@ mod youtube {
         }
     }
 
-    /// Tries to fetch the video via youtube-dl, returning None if important
+    /// Tries to fetch the video via yt-dlp, returning None if important
     /// fields are missing or the fetch failed.
     pub fn parse_from_url(url: &str) -> Option<YouTubeVideo> {
-        let output = YoutubeDl::new(url)
-            .socket_timeout("15")
-            .extra_arg("-f")
-            .extra_arg("bestaudio")
-            .run();
-
-        output
+        let info = ytdl::fetch(url)
             .map_err(|err| {
-                error!("Failed to fetch YouTube video: {}", err.to_string());
-            })
-            .ok()
-            .and_then(|o| match o {
-                YoutubeDlOutput::SingleVideo(video) => Some(video),
-                YoutubeDlOutput::Playlist(_) => None,
-            })
-            .and_then(|video| {
-                let id = video.id;
-                let title = video.title;
-                let duration = video.duration.unwrap().as_f64().unwrap() as f32;
-                let channel = video.channel.unwrap_or_else(|| "Unknown".to_string());
-
-                let format_name = video.format.as_ref();
-                let format = video.formats.and_then(|formats| {
-                    formats
-                        .into_iter()
-                        .find(|f| f.format.as_ref() == format_name)
-                });
-
-                format
-                    .and_then(|format| format.url)
-                    .map(|audio_stream_url| YouTubeVideo {
-                        id,
-                        title,
-                        channel,
-                        duration,
-                        audio_stream_url,
-                    })
+                error!("Failed to fetch YouTube video: {}", err);
             })
+            .ok()?;
+
+        Some(YouTubeVideo {
+            id: info["id"].as_str()?.to_string(),
+            title: info["title"].as_str()?.to_string(),
+            duration: info["duration"].as_f32()?,
+            channel: info["channel"].as_str().unwrap_or("Unknown").to_string(),
+            audio_stream_url: info["url"].as_str()?.to_string(),
+        })
     }
 
     impl IntoSampleReader for YouTubeVideo {
@@ -259,3 +1207,39 @@ mod youtube {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use super::audio_files;
+
+    #[test]
+    fn only_supported_files_are_found_in_order() {
+        let dir = env::temp_dir().join(format!("gct-directory-{}", process::id()));
+        let nested = dir.join("Disc 2");
+        fs::create_dir_all(&nested).unwrap();
+
+        for name in ["02 Second.flac", "01 First.MP3", "cover.jpg", "notes.txt"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        fs::write(nested.join("01 Third.ogg"), b"").unwrap();
+
+        let names = |recursive| -> Vec<_> {
+            audio_files(&dir, recursive)
+                .unwrap()
+                .iter()
+                .map(|f| f.strip_prefix(&dir).unwrap().to_str().unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(names(false), ["01 First.MP3", "02 Second.flac"]);
+        assert_eq!(
+            names(true),
+            ["01 First.MP3", "02 Second.flac", "Disc 2/01 Third.ogg"]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,17 +1,29 @@
 use colored::Colorize;
 use crossbeam::atomic::AtomicCell;
-use log::trace;
+use log::{trace, warn};
 
-use super::{Sample, SAMPLES_PER_SEC};
+use super::{
+    allocation::{BufferPool, BUFFERS},
+    metering,
+    processing::loudness::{self, LoudnessMeter},
+    source::{Cache, CacheWriter},
+    Sample, CHANNEL_COUNT, SAMPLES_PER_SEC, SAMPLE_IN_BYTES,
+};
 use crate::{
     audio::{
-        pipeline::{SampleReader, SampleSource, SamplesRead},
+        pipeline::{SampleReader, SampleSource, SamplesRead, Silence},
         util::Buffer,
     },
     logging::LogColor,
     util::model::{Id, Identified, Store},
 };
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    ops::Range,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
 
 pub type LoaderId = Id<Loader>;
 
@@ -22,6 +34,58 @@ pub struct Loader {
     buffer: Buffer,
     source: Mutex<SampleSource>,
     length: AtomicCell<usize>,
+    /// The length the loader was created with, which the source may turn out not to have
+    declared: usize,
+    /// The declared and actual length, if the source ended far from what it declared
+    correction: AtomicCell<Option<(usize, usize)>>,
+    /// The offset of the first loaded sample, which is not 0 if the source was seeked
+    start: AtomicCell<usize>,
+    /// Where the first load starts, if it is not at the beginning
+    pending_start: AtomicCell<Option<usize>>,
+    rate: Mutex<LoadRate>,
+    /// Limits how many sources are downloading at once, if this source downloads
+    downloads: Option<Arc<DownloadLimit>>,
+    /// Measures the samples as they are loaded, until everything is loaded
+    /// or the source seeks past samples that were never measured.
+    meter: Mutex<Option<LoudnessMeter>>,
+    /// The integrated loudness in LUFS, once the whole source was measured
+    loudness: AtomicCell<Option<f32>>,
+    /// The normalization gain the loader was last played with, which the next read ramps from
+    applied_gain: AtomicCell<Option<f32>>,
+    /// When loading was first requested
+    first_load: AtomicCell<Option<Instant>>,
+    failure: AtomicCell<Option<LoadFailure>>,
+    /// A source that is looked up in the cache when the loader first loads
+    deferred: Mutex<Option<DeferredSource>>,
+}
+
+/// The source of a loader that may already be cached, see [Pool::add_fingerprinted].
+/// It is only created if it is not, so a cached source is not decoded again.
+struct DeferredSource {
+    cache: Arc<Mutex<Cache>>,
+    fingerprint: String,
+    create: Box<dyn FnOnce() -> SampleSource + Send>,
+}
+
+/// Why a source could not be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadFailure {
+    /// The source ended without any samples, such as when it is corrupt
+    Decode,
+    /// No samples arrived within [LOAD_TIMEOUT]
+    Timeout,
+    /// A download ended long before the expected length
+    Network,
+}
+
+impl Display for LoadFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadFailure::Decode => write!(f, "it could not be decoded"),
+            LoadFailure::Timeout => write!(f, "it took too long to load"),
+            LoadFailure::Network => write!(f, "the download was cut off"),
+        }
+    }
 }
 
 impl Identified for Loader {
@@ -34,21 +98,48 @@ impl Identified for Loader {
 
 impl Loader {
     pub fn load(&self, amount: usize) -> SamplesRead {
+        self.resolve_deferred();
+
+        let _download = self.downloads.as_ref().map(|d| d.acquire());
         let mut source = self.source.lock().unwrap();
 
+        if let Some(offset) = self.pending_start.take() {
+            self.start_from(&mut source, offset);
+        }
+
         trace!(
             "{}: {}",
             self.id,
             format!("Loading {} samples", amount).color(LogColor::White),
         );
 
-        let (result, buf) = source.read_samples_to_vec(amount);
-        self.buffer.write_at_end(&buf[..result.amount()]);
+        let first_load = self.first_load.load().unwrap_or_else(Instant::now);
+        self.first_load.store(Some(first_load));
+
+        let expected = self.expected();
+        let mut result = self.read_into_buffer(&mut source, amount);
+
+        // Reading a bit further right away tells whether a source that got to its
+        // expected length ends there, so the length is not left longer than it is.
+        if self.expected() > expected {
+            result = match self.read_into_buffer(&mut source, OVERRUN_STEP) {
+                SamplesRead::More(amount) => SamplesRead::More(result.amount() + amount),
+                SamplesRead::Empty(amount) => SamplesRead::Empty(result.amount() + amount),
+            };
+        }
+
+        self.check_failure(&result, first_load);
+
+        self.rate
+            .lock()
+            .unwrap()
+            .record(Instant::now(), self.available());
 
         // The data might have ended earlier than expected,
         // so we change the size to ensure correctness.
         if let SamplesRead::Empty(_) = result {
-            self.length.store(self.buffer.length());
+            self.length.store(self.available());
+            self.check_length();
 
             trace!(
                 "{}: {}",
@@ -68,28 +159,341 @@ impl Loader {
         result
     }
 
+    /// Reads the source from the cache if it was decoded before, otherwise the source is
+    /// created and written to the cache as it loads. This only happens on the first load.
+    fn resolve_deferred(&self) {
+        let deferred = match self.deferred.lock().unwrap().take() {
+            Some(deferred) => deferred,
+            None => return,
+        };
+
+        let cached = deferred.cache.lock().unwrap().open(&deferred.fingerprint);
+        let mut source = self.source.lock().unwrap();
+
+        match cached {
+            Some(cached) => {
+                let length = cached.length();
+
+                *source = cached.wrap();
+                self.length.store(length);
+                self.buffer.grow(length);
+            }
+            None => {
+                let writer = CacheWriter::new(
+                    (deferred.create)(),
+                    deferred.cache,
+                    &deferred.fingerprint,
+                    self.expected(),
+                );
+
+                *source = writer.wrap();
+            }
+        }
+    }
+
+    /// Reads from the source to the end of the buffer, a pooled buffer at a time
+    /// so loads of several seconds reuse the same memory.
+    fn read_into_buffer(&self, source: &mut SampleSource, amount: usize) -> SamplesRead {
+        let mut read = 0;
+
+        loop {
+            let chunk = (amount - read).min(BufferPool::MAX_BUFFER_SIZE);
+            let result = self.read_chunk(source, chunk);
+            read += result.amount();
+
+            if result.is_empty() {
+                return SamplesRead::Empty(read);
+            }
+
+            // Less than asked for means nothing more is available right now
+            if read >= amount || result.amount() < chunk {
+                return SamplesRead::More(read);
+            }
+        }
+    }
+
+    /// Reads a chunk from the source to the end of the buffer. A source that goes on
+    /// past the expected length is expected to end a bit further, until it does.
+    fn read_chunk(&self, source: &mut SampleSource, amount: usize) -> SamplesRead {
+        let mut buf = BUFFERS.borrow(amount);
+        let result = source.read_samples(&mut buf);
+        self.buffer.write_at_end(&buf[..result.amount()]);
+
+        if !result.is_empty() && self.declared > 0 && self.available() >= self.expected() {
+            self.length.store(self.available() + OVERRUN_STEP);
+            self.buffer.grow(self.expected());
+        }
+
+        self.measure(&buf[..result.amount()], result.is_empty());
+        result
+    }
+
+    /// Gives up on a source that produced nothing in time, and notes why a source that ended
+    /// did so early. Sources that give up end where they are, so they are skipped.
+    fn check_failure(&self, result: &SamplesRead, first_load: Instant) {
+        if self.failure.load().is_some() {
+            return;
+        }
+
+        let nothing_loaded = self.available() == self.start.load();
+
+        let failure = match result {
+            SamplesRead::Empty(_) if nothing_loaded && self.expected() > 0 => LoadFailure::Decode,
+            SamplesRead::Empty(_)
+                if self.downloads.is_some()
+                    && self.available() + TRUNCATION_TOLERANCE < self.expected() =>
+            {
+                LoadFailure::Network
+            }
+            SamplesRead::More(_) if nothing_loaded && first_load.elapsed() > LOAD_TIMEOUT => {
+                self.length.store(self.available());
+                LoadFailure::Timeout
+            }
+            _ => return,
+        };
+
+        warn!("{}: Failed to load, as {}", self.id, failure);
+        self.failure.store(Some(failure));
+    }
+
+    /// Notes when a source that ended is far from the length it declared,
+    /// unless it failed, in which case it did not get to its real end.
+    fn check_length(&self) {
+        let actual = self.available();
+
+        if self.failure.load().is_some() || actual.abs_diff(self.declared) < DURATION_TOLERANCE {
+            return;
+        }
+
+        let seconds = |samples: usize| samples as f32 / SAMPLES_PER_SEC as f32;
+        warn!(
+            "{}: Declared a length of {:.1}s, but is {:.1}s long",
+            self.id,
+            seconds(self.declared),
+            seconds(actual)
+        );
+
+        self.correction.store(Some((self.declared, actual)));
+    }
+
+    /// Returns the declared and actual length in samples,
+    /// if the source ended far from the length it declared
+    pub fn correction(&self) -> Option<(usize, usize)> {
+        self.correction.load()
+    }
+
+    /// Returns why the source could not be loaded, if it could not
+    pub fn failure(&self) -> Option<LoadFailure> {
+        self.failure.load()
+    }
+
+    /// Measures loaded samples, storing the loudness once the source has been loaded in full
+    fn measure(&self, samples: &[Sample], ended: bool) {
+        let mut meter = self.meter.lock().unwrap();
+
+        if let Some(meter) = meter.as_mut() {
+            meter.measure(samples);
+        }
+
+        if ended || self.available() >= self.expected() {
+            if let Some(meter) = meter.take() {
+                self.loudness.store(meter.integrated());
+            }
+        }
+    }
+
+    /// Returns the integrated loudness in LUFS, or `None` if it is not known yet
+    pub fn loudness(&self) -> Option<f32> {
+        self.loudness.load()
+    }
+
     pub fn read(&self, offset: usize, buf: &mut [Sample]) -> usize {
-        self.buffer.read(offset, buf)
+        offset
+            .checked_sub(self.start.load())
+            .map(|offset| self.buffer.read(offset, buf))
+            .unwrap_or_default()
     }
 
+    /// Returns the offset at which there is nothing more loaded
     pub fn available(&self) -> usize {
-        self.buffer.length()
+        self.start.load() + self.buffer.length()
+    }
+
+    /// Returns the range of samples that can be read
+    pub fn loaded(&self) -> Range<usize> {
+        self.start.load()..self.available()
+    }
+
+    /// Makes the samples at an offset available to read.
+    /// Seekable sources jump straight to the offset, discarding what was loaded,
+    /// while other sources are left to be decoded up to the offset when loading next.
+    /// Returns true if the source was seeked.
+    pub fn seek(&self, offset: usize) -> bool {
+        self.resolve_deferred();
+
+        let offset = offset.min(self.expected());
+
+        if self.loaded().contains(&offset) || offset == self.expected() {
+            return false;
+        }
+
+        let seeked = self.source.lock().unwrap().seek_samples(offset);
+
+        if seeked {
+            // What was skipped cannot be measured
+            self.meter.lock().unwrap().take();
+
+            self.buffer.clear();
+            self.start.store(offset);
+            *self.rate.lock().unwrap() = LoadRate::default();
+
+            trace!(
+                "{}: {}",
+                self.id,
+                format!("Seeked to {} samples", offset).color(LogColor::White),
+            );
+
+            self.load(READY_AMOUNT);
+        }
+
+        seeked
+    }
+
+    /// Makes the first load start at an offset, such as after an intro, if nothing is loaded.
+    /// Unlike seeking this does not load anything, so it is left to the loading thread.
+    /// Sources that cannot seek are loaded from the beginning.
+    pub fn start_at(&self, offset: usize) {
+        if self.available() == 0 {
+            self.pending_start.store(Some(offset));
+        }
+    }
+
+    fn start_from(&self, source: &mut SampleSource, offset: usize) {
+        let offset = offset.min(self.expected());
+
+        if self.available() > 0 || !source.seek_samples(offset) {
+            return;
+        }
+
+        // What was skipped cannot be measured
+        self.meter.lock().unwrap().take();
+        self.start.store(offset);
+
+        trace!(
+            "{}: {}",
+            self.id,
+            format!("Starting at {} samples", offset).color(LogColor::White),
+        );
     }
 
     pub fn expected(&self) -> usize {
         self.length.load()
     }
+
+    /// Frees the loaded samples, such as for a track that played a while ago.
+    /// Sources that can seek back to their start load again when they are played,
+    /// while other sources cannot be played again, so they end right away.
+    pub fn evict(&self) {
+        if self.buffer.allocated() == 0 {
+            return;
+        }
+
+        let rewound = self.source.lock().unwrap().seek_samples(0);
+
+        if !rewound {
+            self.length.store(0);
+        }
+
+        // The loudness is kept, as the samples would measure the same
+        self.meter.lock().unwrap().take();
+
+        self.buffer.free();
+        self.start.store(0);
+        *self.rate.lock().unwrap() = LoadRate::default();
+
+        trace!(
+            "{}: {}",
+            self.id,
+            "Evicted the loaded samples".color(LogColor::White),
+        );
+    }
+
+    /// Returns how much memory the loaded samples take up
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffer.allocated() * SAMPLE_IN_BYTES
+    }
+
+    /// Returns the estimated time until enough samples are loaded to start playing,
+    /// or `None` if that is already the case or the load rate is not known yet.
+    pub fn ready_eta(&self) -> Option<Duration> {
+        let target = READY_AMOUNT.min(self.expected());
+        self.rate.lock().unwrap().eta(self.available(), target)
+    }
+
+    /// Returns the RMS level of each window of `window` samples up to what is loaded,
+    /// where the last window may be shorter. What was skipped by seeking is silent.
+    pub fn rms_envelope(&self, window: usize) -> Vec<f32> {
+        let window = window.max(CHANNEL_COUNT);
+        let available = self.available();
+        let mut buf = BUFFERS.borrow(window);
+
+        (0..available)
+            .step_by(window)
+            .map(|offset| {
+                let length = window.min(available - offset);
+                let samples = &mut buf[..length];
+
+                samples.fill(0.);
+                self.read(offset, samples);
+
+                metering::rms(samples)
+            })
+            .collect()
+    }
+
+    /// Returns the amount of samples at the start and the end of the range
+    /// that are quieter than [SILENCE_THRESHOLD], see [Buffer::silence_in]
+    pub fn silence_in(&self, range: Range<usize>) -> (usize, usize) {
+        let start = self.start.load();
+        let threshold = 10_f32.powf(SILENCE_THRESHOLD / 20.);
+
+        self.buffer.silence_in(
+            range.start.saturating_sub(start)..range.end.saturating_sub(start),
+            threshold,
+        )
+    }
 }
 
 /// Manages all loaders
 pub struct Pool {
     store: Store<Loader>,
+    cache: Mutex<Option<Arc<Mutex<Cache>>>>,
+    downloads: Arc<DownloadLimit>,
+    /// Loaders that failed recently and have not been reported yet, with when they failed
+    failures: Mutex<VecDeque<(LoaderId, LoadFailure, Instant)>>,
+    /// Loaders that ended far from their declared length and have not been reported yet
+    corrections: Mutex<VecDeque<(LoaderId, usize, usize)>>,
+    /// The loudness in LUFS that scheduled loaders are brought to instead of the default
+    loudness_targets: Mutex<Vec<(LoaderId, f32)>>,
 }
 
 impl Pool {
     pub fn new() -> Self {
         Self {
             store: Store::new(),
+            cache: Default::default(),
+            downloads: Default::default(),
+            failures: Default::default(),
+            corrections: Default::default(),
+            loudness_targets: Default::default(),
+        }
+    }
+
+    /// Creates a pool that can cache decoded sources on disk
+    pub fn with_cache(cache: Cache) -> Self {
+        Self {
+            cache: Mutex::new(Some(Arc::new(cache.into()))),
+            ..Self::new()
         }
     }
 
@@ -97,32 +501,611 @@ impl Pool {
         &self,
         reader: R,
         length: usize,
+    ) -> Arc<Loader> {
+        self.insert(reader.wrap(), length, None)
+    }
+
+    /// Adds a loader for a source that downloads as it is read,
+    /// which waits for its turn if too many sources are downloading.
+    pub fn add_download<R: 'static + SampleReader + Send + Sync>(
+        &self,
+        reader: R,
+        length: usize,
+    ) -> Arc<Loader> {
+        self.insert(reader.wrap(), length, Some(self.downloads.clone()))
+    }
+
+    fn insert(
+        &self,
+        source: SampleSource,
+        length: usize,
+        downloads: Option<Arc<DownloadLimit>>,
     ) -> Arc<Loader> {
         let loader = Loader {
             id: LoaderId::new(),
             buffer: Buffer::new(length),
-            source: Mutex::new(reader.wrap()),
+            source: Mutex::new(source),
             length: length.into(),
+            declared: length,
+            correction: Default::default(),
+            start: Default::default(),
+            pending_start: Default::default(),
+            rate: Default::default(),
+            downloads,
+            meter: Mutex::new(Some(LoudnessMeter::new())),
+            loudness: Default::default(),
+            applied_gain: Default::default(),
+            first_load: Default::default(),
+            failure: Default::default(),
+            deferred: Default::default(),
         };
 
         let id = self.store.insert(loader);
         self.store.get_expect(id)
     }
 
+    /// Sets how many sources can download at once, at least 1
+    pub fn set_max_downloads(&self, max: usize) {
+        self.downloads.set_limit(max);
+    }
+
+    /// Sets the cache that fingerprinted sources are read from and written to,
+    /// or stops caching with `None`. Loaders that were added before are not affected.
+    pub fn set_cache(&self, cache: Option<Cache>) {
+        *self.cache.lock().unwrap() = cache.map(|c| Arc::new(c.into()));
+    }
+
+    /// Adds a loader for a source that is identified by its fingerprint. When it first loads,
+    /// it reads from the cache if the source was decoded before, otherwise the reader is
+    /// created and its samples are cached as they are loaded.
+    pub fn add_fingerprinted<R, F>(
+        &self,
+        fingerprint: &str,
+        create_reader: F,
+        length: usize,
+    ) -> Arc<Loader>
+    where
+        R: 'static + SampleReader + Send + Sync,
+        F: 'static + FnOnce() -> R + Send,
+    {
+        let cache = match self.cache.lock().unwrap().clone() {
+            Some(cache) => cache,
+            None => return self.add_download(create_reader(), length),
+        };
+
+        // Sources worth caching are the ones that have to be downloaded
+        let loader = self.add_download(Silence::new(0), length);
+
+        *loader.deferred.lock().unwrap() = Some(DeferredSource {
+            cache,
+            fingerprint: fingerprint.to_string(),
+            create: Box::new(move || create_reader().wrap()),
+        });
+
+        loader
+    }
+
+    /// Loads more of a loader, returning how much is available.
+    /// Nothing is loaded if the loader was released while the request waited.
     pub fn load(&self, id: LoaderId, amount: usize) -> usize {
-        let loader = self.store.get_expect(id);
+        match self.store.get(id) {
+            Some(loader) => {
+                let failed = loader.failure().is_some();
+                let corrected = loader.correction().is_some();
+                loader.load(amount);
 
-        loader.load(amount);
-        loader.available()
+                if let Some(failure) = loader.failure().filter(|_| !failed) {
+                    self.record_failure(id, failure);
+                }
+
+                if let Some((declared, actual)) = loader.correction().filter(|_| !corrected) {
+                    let mut corrections = self.corrections.lock().unwrap();
+                    corrections.push_back((id, declared, actual));
+
+                    if corrections.len() > MAX_FAILURES {
+                        corrections.pop_front();
+                    }
+                }
+
+                loader.available()
+            }
+            None => 0,
+        }
     }
 
     pub fn read(&self, id: LoaderId, offset: usize, buf: &mut [Sample]) -> usize {
-        self.store.get_expect(id).read(offset, buf)
+        self.store
+            .get(id)
+            .map(|loader| loader.read(offset, buf))
+            .unwrap_or_default()
     }
+
+    fn record_failure(&self, id: LoaderId, failure: LoadFailure) {
+        let mut failures = self.failures.lock().unwrap();
+
+        failures.push_back((id, failure, Instant::now()));
+
+        if failures.len() > MAX_FAILURES {
+            failures.pop_front();
+        }
+    }
+
+    /// Returns the loaders that failed within [FAILURE_TTL] since this was last called
+    pub fn take_failures(&self) -> Vec<(LoaderId, LoadFailure)> {
+        let mut failures = self.failures.lock().unwrap();
+
+        failures
+            .drain(..)
+            .filter(|(_, _, at)| at.elapsed() < FAILURE_TTL)
+            .map(|(id, failure, _)| (id, failure))
+            .collect()
+    }
+
+    /// Returns the loaders that ended far from their declared length since this was last called,
+    /// with the declared and actual length in samples
+    pub fn take_corrections(&self) -> Vec<(LoaderId, usize, usize)> {
+        self.corrections.lock().unwrap().drain(..).collect()
+    }
+
+    /// Returns the integrated loudness of a loader in LUFS, if it has been measured
+    pub fn loudness(&self, id: LoaderId) -> Option<f32> {
+        self.store.get(id).and_then(|loader| loader.loudness())
+    }
+
+    /// Stores the gain a loader is played with,
+    /// returning the one it was played with before, if it was played yet.
+    pub fn swap_applied_gain(&self, id: LoaderId, gain: f32) -> Option<f32> {
+        self.store
+            .get(id)
+            .and_then(|loader| loader.applied_gain.swap(Some(gain)))
+    }
+
+    /// Sets the loudness targets of the loaders that are scheduled, replacing the previous ones
+    pub fn set_loudness_targets(&self, targets: Vec<(LoaderId, f32)>) {
+        *self.loudness_targets.lock().unwrap() = targets;
+    }
+
+    /// Returns the gain that brings a loader to its own target, or to `default` if it has none.
+    /// This is unity without a target, or while the loader is not measured yet.
+    pub fn gain(&self, id: LoaderId, default: Option<f32>) -> f32 {
+        let target = self
+            .loudness_targets
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(loader, _)| *loader == id)
+            .map(|(_, target)| *target)
+            .or(default);
+
+        match target.zip(self.loudness(id)) {
+            Some((target, loudness)) => loudness::track_gain(loudness, target),
+            None => 1.,
+        }
+    }
+
+    /// Returns the RMS level of a loader per window of `window_ms`,
+    /// such as for showing its loudness over time. Only what is loaded is measured.
+    pub fn rms_envelope(&self, id: LoaderId, window_ms: u64) -> Vec<f32> {
+        let window = (SAMPLES_PER_SEC as u64 * window_ms / 1000) as usize;
+        let window = window - window % CHANNEL_COUNT;
+
+        self.store
+            .get(id)
+            .map(|loader| loader.rms_envelope(window))
+            .unwrap_or_default()
+    }
+
+    /// Frees the samples of a loader that is kept around, see [Loader::evict]
+    pub fn evict(&self, id: LoaderId) {
+        if let Some(loader) = self.store.get(id) {
+            loader.evict();
+        }
+    }
+
+    /// Returns how much memory the samples of every loader take up
+    pub fn buffered_bytes(&self) -> usize {
+        self.store.all().iter().map(|l| l.buffered_bytes()).sum()
+    }
+
+    /// Removes a loader from the pool, so its samples are freed
+    /// once nothing else holds on to it.
+    pub fn release(&self, id: LoaderId) {
+        if self.store.get(id).is_some() {
+            self.store.delete(id);
+        }
+    }
+}
+
+/// Estimates how fast a loader loads samples using a rolling average
+#[derive(Debug, Default)]
+pub struct LoadRate {
+    last: Option<(Instant, usize)>,
+    /// Samples loaded per second
+    rate: Option<f64>,
 }
 
+impl LoadRate {
+    /// How much a new measurement affects the average
+    const SMOOTHING: f64 = 0.3;
+
+    /// Records the amount of samples available at a point in time
+    pub fn record(&mut self, now: Instant, available: usize) {
+        if let Some((then, before)) = self.last {
+            let elapsed = now.duration_since(then).as_secs_f64();
+
+            if elapsed > 0. {
+                let current = available.saturating_sub(before) as f64 / elapsed;
+
+                self.rate = Some(match self.rate {
+                    Some(rate) => rate + (current - rate) * Self::SMOOTHING,
+                    None => current,
+                });
+            }
+        }
+
+        self.last = Some((now, available));
+    }
+
+    /// Returns the estimated time until `target` samples are available
+    pub fn eta(&self, available: usize, target: usize) -> Option<Duration> {
+        if available >= target {
+            return None;
+        }
+
+        let rate = self.rate.filter(|r| *r > 0.)?;
+        Some(Duration::from_secs_f64((target - available) as f64 / rate))
+    }
+}
+
+/// Limits how many sources download at once, so hosts do not rate limit us.
+/// Loads beyond the limit wait until another one finishes.
+pub struct DownloadLimit {
+    limit: AtomicCell<usize>,
+    active: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl DownloadLimit {
+    pub const DEFAULT_LIMIT: usize = 2;
+
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: limit.max(1).into(),
+            active: Mutex::new(0),
+            finished: Condvar::new(),
+        }
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit.max(1));
+        self.finished.notify_all();
+    }
+
+    /// Waits until a download can start, which ends when the permit is dropped
+    pub fn acquire(&self) -> DownloadPermit<'_> {
+        let mut active = self.active.lock().unwrap();
+
+        while *active >= self.limit.load() {
+            active = self.finished.wait(active).unwrap();
+        }
+
+        *active += 1;
+        DownloadPermit(self)
+    }
+}
+
+impl Default for DownloadLimit {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_LIMIT)
+    }
+}
+
+/// Allows a single download, see [DownloadLimit::acquire]
+pub struct DownloadPermit<'a>(&'a DownloadLimit);
+
+impl Drop for DownloadPermit<'_> {
+    fn drop(&mut self) {
+        *self.0.active.lock().unwrap() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+/// A loader that has not received anything for this long gives up
+pub const LOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A download that ends this many samples short of the expected length was cut off
+pub const TRUNCATION_TOLERANCE: usize = SAMPLES_PER_SEC * 5;
+
+/// How many failures, and length corrections, are kept until they are reported
+pub const MAX_FAILURES: usize = 32;
+
+/// A source that ends this many samples away from its declared length is reported
+pub const DURATION_TOLERANCE: usize = SAMPLES_PER_SEC;
+
+/// How far past what is loaded a source that goes on past its declared length is expected
+/// to end, each time it gets there. This is below [TRUNCATION_TOLERANCE], so a source that
+/// ends right after is not mistaken for a download that was cut off.
+pub const OVERRUN_STEP: usize = SAMPLES_PER_SEC * 2;
+
+/// How long a failure is kept if it is not reported
+pub const FAILURE_TTL: Duration = Duration::from_secs(60 * 10);
+
+/// How many samples a loader needs before it is considered ready to play
+pub const READY_AMOUNT: usize = SAMPLES_PER_SEC * 5;
+
 /// How many samples to load after hitting the threshold.
 pub const PRELOAD_AMOUNT: usize = SAMPLES_PER_SEC * 30;
 
 /// The threshold at which loading more samples happens
 pub const PRELOAD_THRESHOLD: usize = SAMPLES_PER_SEC * 120;
+
+/// The level in dBFS samples are considered silent at, which is below dithering and noise floors
+pub const SILENCE_THRESHOLD: f32 = -60.;
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs, process,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use crossbeam::atomic::AtomicCell;
+
+    use super::{LoadRate, Pool, READY_AMOUNT};
+    use crate::{
+        audio::{
+            allocation::BufferPool,
+            pipeline::{Clip, IntoSampleReader, SampleReader, SamplesRead},
+            util::pcm::PcmCodec,
+            RawFile, Sample, SAMPLES_PER_SEC, SAMPLE_IN_BYTES,
+        },
+        util::model::Identified,
+    };
+
+    /// A seekable source where every sample is its offset, which counts how many it decoded
+    struct Counting {
+        offset: usize,
+        length: usize,
+        decoded: Arc<AtomicCell<usize>>,
+    }
+
+    impl SampleReader for Counting {
+        fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+            let amount = buf.len().min(self.length - self.offset);
+
+            for (i, sample) in buf[..amount].iter_mut().enumerate() {
+                *sample = (self.offset + i) as Sample;
+            }
+
+            self.offset += amount;
+            self.decoded.fetch_add(amount);
+
+            SamplesRead::empty_if(self.offset == self.length, amount)
+        }
+
+        fn length(&self) -> Option<usize> {
+            Some(self.length)
+        }
+
+        fn seek_samples(&mut self, offset: usize) -> bool {
+            self.offset = offset.min(self.length);
+            true
+        }
+    }
+
+    #[test]
+    fn eta_decreases_while_loading() {
+        let mut rate = LoadRate::default();
+        let start = Instant::now();
+
+        // Loads 1000 samples every 100ms
+        let etas: Vec<_> = (0..=10)
+            .map(|i| {
+                rate.record(start + Duration::from_millis(i * 100), i as usize * 1000);
+                rate.eta(i as usize * 1000, 10000)
+            })
+            .collect();
+
+        // The rate is unknown until there are two measurements
+        assert_eq!(etas[0], None);
+        assert_eq!(etas[1], Some(Duration::from_millis(900)));
+
+        let known: Vec<_> = etas[1..10].iter().map(|e| e.unwrap()).collect();
+        assert!(known.windows(2).all(|w| w[1] < w[0]));
+
+        // Everything needed is loaded
+        assert_eq!(etas[10], None);
+    }
+
+    #[test]
+    fn long_loads_are_read_in_pooled_buffers() {
+        /// Notes the largest buffer it was asked to fill
+        struct Largest(usize, Arc<AtomicCell<usize>>);
+
+        impl SampleReader for Largest {
+            fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+                let amount = buf.len().min(self.0);
+                self.0 -= amount;
+                self.1.fetch_max(buf.len());
+
+                SamplesRead::empty_if(self.0 == 0, amount)
+            }
+        }
+
+        let largest: Arc<AtomicCell<usize>> = Default::default();
+        let length = SAMPLES_PER_SEC * 10;
+
+        let pool = Pool::new();
+        let loader = pool.add(Largest(length, largest.clone()), length);
+
+        assert_eq!(loader.load(length / 2), SamplesRead::More(length / 2));
+        assert_eq!(loader.load(length), SamplesRead::Empty(length / 2));
+
+        assert_eq!(loader.available(), length);
+        assert!(largest.load() <= BufferPool::MAX_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn rms_envelope_follows_the_level() {
+        let pool = Pool::new();
+
+        // A second of quiet, then a second of loud
+        let samples: Vec<Sample> = [0.1, 0.8]
+            .iter()
+            .flat_map(|level| vec![*level; SAMPLES_PER_SEC])
+            .collect();
+
+        let length = samples.len();
+        let loader = pool.add(samples.into_sample_reader(), length);
+
+        // Only what is loaded is measured
+        pool.load(loader.id(), SAMPLES_PER_SEC / 2);
+        assert_eq!(pool.rms_envelope(loader.id(), 250).len(), 2);
+
+        pool.load(loader.id(), length);
+        let envelope = pool.rms_envelope(loader.id(), 250);
+
+        assert_eq!(envelope.len(), 8);
+        assert!(envelope[..4].iter().all(|l| (l - 0.1).abs() < 0.001));
+        assert!(envelope[4..].iter().all(|l| (l - 0.8).abs() < 0.001));
+    }
+
+    #[test]
+    fn seekable_sources_do_not_decode_the_prefix() {
+        let path = env::temp_dir().join(format!("gct-seek-{}.raw", process::id()));
+
+        let samples: Vec<Sample> = (0..SAMPLES_PER_SEC * 20).map(|i| i as Sample).collect();
+        let bytes = PcmCodec::FLOAT.encode(&samples);
+        fs::write(&path, bytes).unwrap();
+
+        let pool = Pool::new();
+        let middle = samples.len() / 2;
+
+        let file = RawFile::open(&path).unwrap();
+        let loader = pool.add(
+            file,
+            fs::metadata(&path).unwrap().len() as usize / SAMPLE_IN_BYTES,
+        );
+
+        assert!(loader.seek(middle));
+        assert_eq!(loader.loaded(), middle..middle + READY_AMOUNT);
+
+        let mut buf = [0.; 4];
+        loader.read(middle, &mut buf);
+        assert_eq!(buf, samples[middle..middle + 4]);
+
+        // Streams are decoded from the start instead, when they are loaded next
+        let stream = pool.add(samples.clone().into_sample_reader(), samples.len());
+
+        assert!(!stream.seek(middle));
+        assert_eq!(stream.available(), 0);
+
+        stream.load(middle + READY_AMOUNT);
+        assert_eq!(stream.loaded(), 0..middle + READY_AMOUNT);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn loading_starts_where_it_was_asked_to() {
+        let decoded: Arc<AtomicCell<usize>> = Default::default();
+        let length = SAMPLES_PER_SEC * 120;
+
+        let pool = Pool::new();
+        let loader = pool.add(
+            Counting {
+                offset: 0,
+                length,
+                decoded: decoded.clone(),
+            },
+            length,
+        );
+
+        loader.start_at(SAMPLES_PER_SEC * 10);
+        assert_eq!(loader.available(), 0);
+
+        loader.load(READY_AMOUNT);
+        assert_eq!(
+            loader.loaded(),
+            SAMPLES_PER_SEC * 10..SAMPLES_PER_SEC * 10 + READY_AMOUNT
+        );
+        assert_eq!(decoded.load(), READY_AMOUNT);
+
+        // What is loaded already stays where it is
+        loader.start_at(0);
+        loader.load(READY_AMOUNT);
+        assert_eq!(loader.loaded().start, SAMPLES_PER_SEC * 10);
+
+        // Streams are loaded from the beginning
+        let stream = pool.add(vec![0.; length].into_sample_reader(), length);
+
+        stream.start_at(SAMPLES_PER_SEC * 10);
+        stream.load(READY_AMOUNT);
+        assert_eq!(stream.loaded(), 0..READY_AMOUNT);
+    }
+
+    #[test]
+    fn clips_of_seekable_sources_only_decode_their_region() {
+        let decoded: Arc<AtomicCell<usize>> = Default::default();
+
+        // Two minutes, where every sample is still exact as a float
+        let source = Counting {
+            offset: 0,
+            length: SAMPLES_PER_SEC * 120,
+            decoded: decoded.clone(),
+        };
+
+        // The last three seconds
+        let length = SAMPLES_PER_SEC * 3;
+        let start = source.length - length;
+
+        let pool = Pool::new();
+        let loader = pool.add(Clip::new(source, start, length), length);
+
+        loader.load(length);
+        assert_eq!(loader.loaded(), 0..length);
+        assert_eq!(decoded.load(), length);
+
+        let mut buf = [0.; 4];
+        loader.read(length - 4, &mut buf);
+        assert_eq!(
+            buf,
+            [-4., -3., -2., -1.].map(|o| (start + length) as Sample + o)
+        );
+
+        // Seeking is relative to the region too
+        let clip = Clip::new(
+            Counting {
+                offset: 0,
+                length: SAMPLES_PER_SEC * 120,
+                decoded: decoded.clone(),
+            },
+            start,
+            length,
+        );
+
+        decoded.store(0);
+        let loader = pool.add(clip, length);
+
+        assert!(loader.seek(length / 2));
+        loader.read(length / 2, &mut buf);
+
+        assert_eq!(buf[0], (start + length / 2) as Sample);
+        // Only the rest of the region is loaded after the seek
+        assert_eq!(decoded.load(), length - length / 2);
+
+        // Streams cannot seek, so what comes before the region is decoded and discarded
+        let samples: Vec<Sample> = (0..SAMPLES_PER_SEC).map(|i| i as Sample).collect();
+        let clip = Clip::new(samples.into_sample_reader(), SAMPLES_PER_SEC / 2, 4);
+
+        let stream = pool.add(clip, 4);
+        stream.load(4);
+        stream.read(0, &mut buf);
+
+        assert_eq!(
+            buf,
+            [0., 1., 2., 3.].map(|o| (SAMPLES_PER_SEC / 2) as Sample + o)
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use crossbeam::atomic::AtomicCell;
+
+use super::{Sample, CHANNEL_COUNT};
+
+/// Measurements of the samples that were most recently played
+pub struct Metering {
+    correlation: AtomicCell<f32>,
+}
+
+impl Metering {
+    pub fn new() -> Self {
+        Self {
+            correlation: AtomicCell::new(0.),
+        }
+    }
+
+    /// Updates the measurements with a window of interleaved samples
+    pub fn measure(&self, samples: &[Sample]) {
+        self.correlation.store(phase_correlation(samples));
+    }
+
+    /// Returns the correlation between the left and right channel,
+    /// from -1 (cancels out in mono) to +1 (identical channels).
+    pub fn correlation(&self) -> f32 {
+        self.correlation.load()
+    }
+}
+
+impl Default for Metering {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Calculates the root mean square of samples, which is 0 if there are none
+pub fn rms(samples: &[Sample]) -> f32 {
+    if samples.is_empty() {
+        return 0.;
+    }
+
+    let sum: f32 = samples.iter().map(|s| s * s).sum();
+    (sum / samples.len() as f32).sqrt()
+}
+
+/// Calculates the phase correlation of interleaved stereo samples.
+/// Silence has no phase, so it is reported as 0.
+pub fn phase_correlation(samples: &[Sample]) -> f32 {
+    let (mut lr, mut ll, mut rr) = (0., 0., 0.);
+
+    for frame in samples.chunks_exact(CHANNEL_COUNT) {
+        let (left, right) = (frame[0], frame[1]);
+
+        lr += left * right;
+        ll += left * left;
+        rr += right * right;
+    }
+
+    let energy = (ll * rr).sqrt();
+
+    if energy == 0. {
+        return 0.;
+    }
+
+    (lr / energy).clamp(-1., 1.)
+}
+
+#[cfg(test)]
+mod test {
+    use super::phase_correlation;
+
+    fn stereo(left: impl Fn(f32) -> f32, right: impl Fn(f32) -> f32) -> Vec<f32> {
+        (0..4410)
+            .map(|i| (i as f32 / 44100. * 440. * std::f32::consts::TAU).sin())
+            .flat_map(|s| [left(s), right(s)])
+            .collect()
+    }
+
+    #[test]
+    fn correlation_detects_phase() {
+        let in_phase = stereo(|s| s, |s| s * 0.5);
+        let out_of_phase = stereo(|s| s, |s| -s);
+
+        assert!(phase_correlation(&in_phase) > 0.99);
+        assert!(phase_correlation(&out_of_phase) < -0.99);
+        assert_eq!(phase_correlation(&[0.; 64]), 0.);
+    }
+}
@@ -1,233 +1,4748 @@
-use log::info;
-use std::sync::Arc;
+use crossbeam::atomic::AtomicCell;
+use log::{error, info, warn};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt::Display,
+    io,
+    ops::Deref,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
 
+mod allocation;
+mod autoplay;
 mod buffering;
 mod decoding;
 mod encoding;
 mod events;
+mod health;
+mod idle;
 mod input;
 mod loading;
+mod metering;
 mod playback;
+mod preset;
 mod processing;
 mod queuing;
+mod resampling;
+mod settings;
 mod source;
+mod status;
+#[cfg(test)]
+mod testing;
+mod timers;
 mod track;
 mod util;
 
+pub use allocation::preallocate_buffers;
+pub use autoplay::{Autoplay, InputFactory};
 pub use buffering::*;
-pub use decoding::raw_samples_from_bytes;
+pub use decoding::{
+    prewarm_codec, raw_samples_from_bytes, register_decoder, Decoder, PrewarmError, ProbedFile,
+    RawFile,
+};
 pub use encoding::*;
 pub use events::*;
-pub use input::Input;
+pub use input::{FileError, Input, ParseError, ResolveError, UrlError};
 pub use loading::*;
+pub use metering::Metering;
 pub use playback::*;
-pub use queuing::Queue;
-pub use track::Track;
+pub use preset::{ImportError, InvalidPreset, Preset};
+pub use processing::chain::*;
+pub use processing::effects::{CrossfeedConfig, EqBand, InvalidBand, NoiseGateConfig};
+pub use processing::ffmpeg::{set_sample_rate_policy, SampleRatePolicy};
+pub use queuing::{Queue, RepeatMode};
+pub use settings::{GuildSettings, SettingsStore, SAVE_DELAY};
+pub use source::Cache;
+pub use track::{FailedTrackInfo, PlayedTrackInfo, Track, TrackCrossfade, TrackId, TrackInfo};
 pub use util::pipeline;
 
 #[derive(Clone)]
 pub struct AudioSystem {
+    /// The format the output is streamed in
+    config: AudioConfig,
     events: AudioEventChannel,
     queue: Arc<Queue>,
     registry: Arc<buffering::BufferRegistry>,
+    /// Receives the samples before any processing, see [AudioSystem::raw_stream]
+    raw_registry: Arc<buffering::BufferRegistry>,
+    /// What [AudioSystem::output_stream] streams, see [AudioSystem::set_output_format]
+    output_format: Arc<AtomicCell<OutputFormat>>,
+    /// Converts what is written to the registries to the format of the config
+    converter: Arc<Mutex<OutputConverter>>,
+    raw_converter: Arc<Mutex<OutputConverter>>,
     scheduler: Arc<playback::Scheduler>,
     pool: Arc<loading::Pool>,
+    chain: Arc<Mutex<ProcessingChain>>,
+    sidechain: Arc<Mutex<Option<processing::effects::Ducker>>>,
+    noise_gate: Arc<Mutex<Option<processing::effects::NoiseGate>>>,
+    crossfeed: Arc<Mutex<Option<processing::effects::Crossfeed>>>,
+    normalizer: Arc<Mutex<Option<processing::effects::LoudnessNormalizer>>>,
+    equalizer: Arc<Mutex<processing::effects::Equalizer>>,
+    metering: Arc<Metering>,
+    activity: Arc<health::Activity>,
+    power_saving: Arc<idle::PowerSaving>,
+    timers: Arc<timers::Timers>,
+    autoplay: Arc<Mutex<Option<Autoplay>>>,
+    /// A snippet playing in front of the queue, see [AudioSystem::preview]
+    preview: Arc<Mutex<Option<Arc<Loader>>>>,
+    /// The track that replaces the current one once a worker loaded its start,
+    /// see [AudioSystem::replace_current]
+    replacement: Arc<Mutex<Option<Track>>>,
+    desync_policy: Arc<AtomicCell<DesyncPolicy>>,
+    seek_policy: Arc<AtomicCell<SeekPolicy>>,
+    /// How far into a track going to the previous one restarts it instead
+    previous_threshold: Arc<AtomicCell<Duration>>,
+    /// How many tracks from the current one on are scheduled, see [AudioSystem::set_prefetch_depth]
+    prefetch_depth: Arc<AtomicCell<usize>>,
+    /// How many played tracks keep their samples, see [AudioSystem::set_retained_tracks]
+    retained_tracks: Arc<AtomicCell<usize>>,
+    /// The part of the current track that repeats, see [AudioSystem::set_ab_loop]
+    ab_loop: Arc<AtomicCell<Option<AbLoop>>>,
+    playback_state: Arc<AtomicCell<PlaybackState>>,
+    repeat: Arc<AtomicCell<RepeatMode>>,
+    /// How long playback fades in after resuming, in samples
+    resume_fade: Arc<AtomicCell<usize>>,
+    /// How far into the fade after resuming playback is, while it is fading in
+    fading_in: Arc<AtomicCell<Option<usize>>>,
+    /// The loader of the last track in the queue once it played to its end,
+    /// as it is only moved on from once more is queued after it.
+    queue_ended: Arc<AtomicCell<Option<LoaderId>>>,
+    /// The tracks that played until their end, the most recent last
+    history: Arc<Mutex<VecDeque<PlayedTrackInfo>>>,
+    /// The loudness in LUFS every track is brought to, if tracks are normalized
+    normalization: Arc<AtomicCell<Option<f32>>>,
+    volume: Arc<AtomicCell<f32>>,
+    /// Silences the output without changing the volume, so unmuting returns to it
+    muted: Arc<AtomicCell<bool>>,
+    /// The gain the last chunk ended at, which the next chunk ramps from
+    applied_volume: Arc<AtomicCell<f32>>,
+    /// How fast playback is, see [AudioSystem::set_speed]
+    speed: Arc<AtomicCell<f32>>,
+    stretcher: Arc<Mutex<processing::stretching::TimeStretcher>>,
+    /// Where the settings of the guild this is playing in are saved
+    settings: Option<(Arc<Mutex<SettingsStore>>, u64)>,
+    threads: Arc<Threads>,
 }
 
 impl AudioSystem {
     fn new() -> Self {
+        Self::with_config(AudioConfig::DEFAULT)
+    }
+
+    /// Creates a system that streams its output in the format of the config
+    pub fn with_config(config: AudioConfig) -> Self {
         let events = AudioEventChannel::new();
 
         let queue: Arc<_> = Queue::new(events.clone()).into();
 
         Self {
+            config,
             events,
-            registry: buffering::BufferRegistry::new().into(),
+            registry: buffering::BufferRegistry::with_config(config).into(),
+            raw_registry: buffering::BufferRegistry::with_config(config).into(),
+            output_format: Arc::new(OutputFormat::Pcm.into()),
+            converter: Arc::new(OutputConverter::new(config).into()),
+            raw_converter: Arc::new(OutputConverter::new(config).into()),
             scheduler: playback::Scheduler::new().into(),
             pool: loading::Pool::new().into(),
+            chain: Arc::new(
+                ProcessingChain::new()
+                    .with_chunk_size(Self::samples_in(config.chunk_duration()))
+                    .into(),
+            ),
+            sidechain: Default::default(),
+            noise_gate: Default::default(),
+            crossfeed: Default::default(),
+            normalizer: Default::default(),
+            equalizer: Arc::new(processing::effects::Equalizer::flat().into()),
+            metering: Default::default(),
+            activity: Default::default(),
+            power_saving: Default::default(),
+            timers: Default::default(),
+            autoplay: Default::default(),
+            preview: Default::default(),
+            replacement: Default::default(),
+            desync_policy: Arc::new(DesyncPolicy::Report.into()),
+            seek_policy: Arc::new(SeekPolicy::Wait.into()),
+            previous_threshold: Arc::new(DEFAULT_PREVIOUS_THRESHOLD.into()),
+            prefetch_depth: Arc::new(DEFAULT_PREFETCH_DEPTH.into()),
+            retained_tracks: Arc::new(DEFAULT_RETAINED_TRACKS.into()),
+            ab_loop: Default::default(),
+            playback_state: Arc::new(PlaybackState::Playing.into()),
+            repeat: Arc::new(RepeatMode::Off.into()),
+            resume_fade: Arc::new(Self::samples_in(DEFAULT_RESUME_FADE).into()),
+            fading_in: Default::default(),
+            queue_ended: Default::default(),
+            history: Default::default(),
+            normalization: Default::default(),
+            volume: Arc::new(1.0.into()),
+            muted: Default::default(),
+            speed: Arc::new(1.0.into()),
+            stretcher: Default::default(),
+            applied_volume: Arc::new(1.0.into()),
+            settings: None,
+            threads: Default::default(),
             queue,
         }
     }
 
+    /// Restores the settings of a guild from the store, which saves them when they change
+    pub fn with_settings(self, store: Arc<Mutex<SettingsStore>>, guild: u64) -> Self {
+        let settings = store.lock().unwrap().get(guild);
+
+        self.volume.store(settings.volume);
+        self.applied_volume.store(settings.volume);
+        self.repeat.store(settings.repeat);
+
+        if let Some(bands) = settings.eq {
+            if let Err(err) = self.equalizer.lock().unwrap().set_bands(bands) {
+                warn!("The saved equalizer is not restored: {}", err);
+            }
+        }
+
+        Self {
+            settings: Some((store, guild)),
+            ..self
+        }
+    }
+
+    /// Changes the settings of this guild, if they are persisted
+    fn update_settings<F: FnOnce(&mut GuildSettings)>(&self, update: F) {
+        if let Some((store, guild)) = &self.settings {
+            store.lock().unwrap().update(*guild, update);
+        }
+    }
+
+    pub fn config(&self) -> AudioConfig {
+        self.config
+    }
+
+    /// Returns the amount of samples mixed for every chunk
+    fn chunk_size(&self) -> usize {
+        Self::samples_in(self.config.chunk_duration())
+    }
+
+    /// Returns a channel that receives every event emitted after this,
+    /// so any amount of listeners can react to what is happening.
+    pub fn subscribe(&self) -> AudioEventChannel {
+        self.events.clone()
+    }
+
     pub fn stream(&self) -> AudioBufferConsumer {
         self.registry.get_consumer()
     }
 
+    /// Returns how many streams are still being read, such as by connected clients
+    pub fn stream_count(&self) -> usize {
+        self.registry.consumer_count()
+    }
+
+    /// Returns a stream of the decoded samples before any effects or volume are applied,
+    /// for analysis. Samples are dropped if the stream is not read in time.
+    pub fn raw_stream(&self) -> AudioBufferConsumer {
+        self.raw_registry
+            .get_consumer_with_format(SampleFormat::Float32)
+    }
+
+    /// Returns a stream that always uses the given format,
+    /// ignoring changes made with [AudioSystem::set_format].
+    pub fn stream_with_format(&self, format: SampleFormat) -> AudioBufferConsumer {
+        self.registry.get_consumer_with_format(format)
+    }
+
+    /// Returns a stream of whole Opus packets at 48 kHz, each of which decodes on its own
+    pub fn packet_stream(&self) -> PacketConsumer {
+        let stream = self.stream_with_format(SampleFormat::Float32);
+        PacketConsumer::new(OpusEncoder::new(stream))
+    }
+
+    /// Returns a stream of Opus frames at 48 kHz, for clients that cannot play raw samples
+    pub fn opus_stream(&self) -> OpusConsumer {
+        let stream = self.stream_with_format(SampleFormat::Float32);
+        OpusConsumer::new(OpusEncoder::new(stream))
+    }
+
+    /// Returns a stream of the output in the current [OutputFormat].
+    /// PCM streams use the default format, see [AudioSystem::set_format].
+    pub fn output_stream(&self) -> OutputStream {
+        match self.output_format.load() {
+            OutputFormat::Pcm => OutputStream::Pcm(self.stream()),
+            OutputFormat::Opus => {
+                let stream = self.following_stream(SampleFormat::Float32);
+                OutputStream::Opus(OpusConsumer::new(OpusEncoder::new(stream)))
+            }
+        }
+    }
+
+    /// Returns a stream in the given format that ends when the output format changes,
+    /// for clients that can only read one sample format but follow the output.
+    pub fn following_stream(&self, format: SampleFormat) -> AudioBufferConsumer {
+        self.registry.get_following_consumer(format)
+    }
+
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format.load()
+    }
+
+    /// Changes whether the output is streamed as PCM or Opus. Existing output streams
+    /// end once they have been read, and must be re-created with [AudioSystem::output_stream].
+    pub fn set_output_format(&self, format: OutputFormat) {
+        if self.output_format.swap(format) == format {
+            return;
+        }
+
+        info!("Changing output format to {:?}", format);
+        self.registry.invalidate();
+        self.events.emit(AudioEvent::OutputFormatChanged(format));
+    }
+
+    /// Returns a stream with a buffer of the given size in bytes
+    pub fn stream_sized(&self, bytes: usize) -> Result<AudioBufferConsumer, BufferTooSmall> {
+        self.registry.get_consumer_sized(bytes)
+    }
+
+    /// Changes the format of streams. Existing streams end once
+    /// they have been read, and must be re-created to use the new format.
+    pub fn set_format(&self, format: SampleFormat) {
+        if self.registry.format() == format {
+            return;
+        }
+
+        info!("Changing stream format to {:?}", format);
+        self.registry.set_format(format);
+
+        // Streams that follow the output end as well, so they are re-created
+        self.events
+            .emit(AudioEvent::OutputFormatChanged(self.output_format()));
+    }
+
+    /// Sets the peak level in dBFS that samples are reduced to
+    /// before being encoded to a format that clips.
+    pub fn set_headroom(&self, ceiling: f32) {
+        self.registry.set_headroom(Headroom::new(ceiling));
+    }
+
     pub fn start(&self) {
+        if self.threads.running.swap(true) {
+            warn!("Audio system is already started");
+            return;
+        }
+
         info!("Starting audio system");
+        self.threads.draining.store(false);
+        self.activity.record_tick(Instant::now());
 
-        playback_thread::start(self);
-        loading_thread::start(self);
+        let mut handles = self.threads.handles.lock().unwrap();
+        handles.push(playback_thread::start(self));
+        handles.push(loading_thread::start(self));
     }
 
-    pub fn add(&self, input: Input) {
-        let duration = input.duration();
-        let reader = input.into_sample_reader();
+    /// Sets how many sources can download at once, the rest wait for their turn
+    pub fn set_max_downloads(&self, max: usize) {
+        self.pool.set_max_downloads(max);
+    }
 
-        let length = (SAMPLES_PER_SEC as f32) * duration;
-        let loader = self.pool.add(reader, length.round() as usize);
+    /// Limits how much audio the loading thread requests each cycle, so loading
+    /// is spread out instead of spiking, or lets it request everything with `None`.
+    /// The budget is never less than what plays during a cycle, so playback keeps up.
+    pub fn set_load_budget(&self, budget: Option<Duration>) {
+        self.threads.load_budget.store(budget.map(Self::samples_in));
+    }
 
-        // This is temporary for now
-        let track = Track::new(loader);
-        self.queue.add_track(track, queuing::QueuePosition::Add);
-        self.notify_queue_update();
+    /// Sets how long the loading thread waits between requesting what is needed.
+    /// A shorter interval reacts sooner to slow sources, at the cost of waking up more often.
+    pub fn set_loading_interval(&self, interval: Duration) {
+        let interval = interval.max(loading_thread::MIN_INTERVAL);
+        self.threads.loading_interval.store(interval);
     }
 
-    pub fn next(&self) {
-        self.queue.next();
+    /// Sets how many tracks are scheduled to load, counting the current one, which is
+    /// always loaded. The track after it is scheduled regardless, so it starts without a gap.
+    /// Loading further ahead helps on slow networks, but every scheduled track
+    /// keeps what it loaded in the pool, so memory grows with the depth.
+    pub fn set_prefetch_depth(&self, depth: usize) {
+        self.prefetch_depth.store(depth.max(1));
         self.notify_queue_update();
     }
 
-    fn notify_queue_update(&self) {
-        self.scheduler.set_loaders(
-            self.queue
-                .peek_ahead(3)
-                .iter()
-                .map(|t| t.loader.clone())
-                .collect(),
-        );
+    pub fn prefetch_depth(&self) -> usize {
+        self.prefetch_depth.load()
     }
-}
 
-impl Default for AudioSystem {
-    fn default() -> Self {
-        Self::new()
+    /// Sets how many of the most recently played tracks keep their samples, up to [MAX_HISTORY],
+    /// so going back to them plays right away. Older tracks are evicted from the pool
+    /// to bound memory in long sessions, and have to load again if they are returned to.
+    pub fn set_retained_tracks(&self, count: usize) {
+        self.retained_tracks.store(count.min(MAX_HISTORY));
+        self.evict_played();
     }
-}
 
-mod playback_thread {
-    use std::thread;
-    use std::time::{Duration, Instant};
+    /// Returns how much audio the loading thread requested in its last cycle
+    pub fn last_load(&self) -> Duration {
+        Self::duration_of(self.threads.last_load.load())
+    }
 
-    use log::{info, warn};
+    /// Sets the amount of threads that decode sources, used when the system starts
+    pub fn set_decode_threads(&self, count: usize) {
+        self.threads.decode_threads.store(count.max(1));
+    }
 
-    use super::config::*;
-    use super::AudioSystem;
+    /// Signals the threads to stop and waits for them to finish.
+    /// The queue and settings are kept, so the system can be started again.
+    pub fn shutdown(&self) {
+        if !self.threads.running.swap(false) {
+            return;
+        }
 
-    /// Starts the thread which will process samples in real-time
-    pub fn start(sys: &AudioSystem) {
-        let system = sys.clone();
-        let read_samples = move |buf: &mut [Sample]| {
-            let advancements = system.scheduler.advance(buf.len());
-            let mut amount_read = 0;
+        info!("Stopping audio system");
+
+        let handles: Vec<_> = self.threads.handles.lock().unwrap().drain(..).collect();
+        let current = thread::current().id();
 
-            for (id, range) in advancements.iter() {
-                amount_read += system.pool.read(*id, range.start, &mut buf[amount_read..]);
+        for handle in handles {
+            // A thread cannot wait for itself to finish
+            if handle.thread().id() != current {
+                // The loading thread would otherwise finish waiting for its next cycle first
+                handle.thread().unpark();
+
+                // Panicking here could abort the process when a handle is dropped during a panic
+                if let Err(err) = handle.join() {
+                    error!("An audio thread panicked: {:?}", err);
+                }
             }
+        }
+    }
+
+    /// Stops accepting new tracks, and stops the system
+    /// once the tracks in the queue have finished playing.
+    pub fn drain(&self) {
+        if !self.threads.draining.swap(true) {
+            info!("Draining audio system");
+        }
+    }
+
+    /// Silences the output right away, for when something should stop playing at once.
+    /// Nothing fades out, the queue is cleared and consumers lose what they have not read.
+    /// Autoplay is left as it was, so it can pick something else to play.
+    pub fn panic_stop(&self) {
+        warn!("Stopping playback immediately");
+
+        // The playback thread holds this for a whole tick, so it cannot write
+        // a chunk it read before the queue was cleared after the flush
+        let converter = self.converter.lock().unwrap();
+
+        self.clear(true);
+        self.registry.flush();
+        self.registry
+            .write_samples(&vec![0.; self.config.chunk_size()]);
+
+        drop(converter);
+        self.events.emit(AudioEvent::PanicStopped);
+    }
+
+    /// Stops playback and clears the queue, letting what is already streamed play out
+    pub fn stop(&self) {
+        info!("Stopping playback");
+        self.clear_playback();
+    }
+
+    /// Clears everything that would play, so autoplay has to be enabled again too
+    fn clear_playback(&self) {
+        if let Some(autoplay) = self.autoplay.lock().unwrap().as_mut() {
+            autoplay.set_enabled(false);
+        }
+
+        self.clear(true);
+    }
 
-            for (id, _) in advancements.iter().skip(1) {
-                system.next();
+    /// Removes every track from the queue, or every track but the one playing,
+    /// releasing what was loaded for the removed tracks.
+    pub fn clear(&self, include_current: bool) {
+        let removed = self.queue.clear(include_current);
+        info!("Cleared {} tracks from the queue", removed.len());
+
+        self.notify_queue_cleared(include_current);
+        self.release_loaders(removed);
+    }
+
+    /// Resets the scheduler after the queue was cleared
+    fn notify_queue_cleared(&self, include_current: bool) {
+        if include_current {
+            self.preview.lock().unwrap().take();
+            self.cancel_replacement();
+            self.queue_ended.take();
+            self.scheduler.clear();
+        } else {
+            self.notify_queue_update();
+        }
+    }
+
+    /// Releases the loaders of tracks that are no longer queued,
+    /// unless another track in the queue or the preview uses them.
+    fn release_loaders(&self, tracks: Vec<Track>) {
+        let preview = self.preview.lock().unwrap().as_ref().map(|p| p.id());
+        let queued: Vec<_> = self
+            .queue
+            .peek_ahead(usize::MAX)
+            .iter()
+            .map(|t| t.loader.id())
+            .collect();
+
+        for track in tracks {
+            let id = track.loader.id();
+
+            if !queued.contains(&id) && preview != Some(id) {
+                self.pool.release(id);
             }
-        };
+        }
+    }
 
-        let system = sys.clone();
-        let tick = move || {
-            let mut samples = vec![0.; STREAM_CHUNK_SIZE];
-            read_samples(&mut samples);
+    pub fn is_draining(&self) -> bool {
+        self.threads.draining.load()
+    }
 
-            let samples_as_bytes: Vec<_> = samples
-                .into_iter()
-                .flat_map(|sample| sample.to_le_bytes())
-                .collect();
+    /// Returns true if draining and there is nothing left to play
+    fn is_drained(&self) -> bool {
+        self.is_draining() && self.scheduler.is_finished()
+    }
+
+    fn ensure_not_draining(&self) -> Result<(), Draining> {
+        match self.is_draining() {
+            true => Err(Draining),
+            false => Ok(()),
+        }
+    }
+
+    pub fn add(&self, input: Input) -> Result<(), Draining> {
+        self.add_input(input, None)
+    }
+
+    /// Adds an input on behalf of a user, such as a Discord user id,
+    /// which is included when the track starts.
+    pub fn add_requested(&self, input: Input, requested_by: u64) -> Result<(), Draining> {
+        self.add_input(input, Some(requested_by))
+    }
+
+    /// Adds an input with metadata that is shown instead of what the source says
+    pub fn add_with_meta(
+        &self,
+        input: Input,
+        overrides: track::TrackMetaOverrides,
+    ) -> Result<(), Draining> {
+        let length = overrides.duration.map(Self::samples_in);
+        let track = self.track_from_input(input, length)?;
+        self.add_track(track.with_overrides(overrides));
+
+        Ok(())
+    }
+
+    /// Adds an input that crossfades for its own lengths instead of the crossfade,
+    /// such as a song that needs a longer blend into the next.
+    pub fn add_with_crossfade(
+        &self,
+        input: Input,
+        crossfade: track::TrackCrossfade,
+    ) -> Result<(), Draining> {
+        let track = self.track_from_input(input, None)?;
+        self.add_track(track.with_crossfade(crossfade));
+
+        Ok(())
+    }
+
+    /// Adds an input that is normalized to its own loudness in LUFS instead of the normalization
+    pub fn add_with_loudness_target(&self, input: Input, target: f32) -> Result<(), Draining> {
+        let track = self.track_from_input(input, None)?;
+        self.add_track(track.with_loudness_target(target));
+
+        Ok(())
+    }
+
+    /// Adds several inputs in order, such as the tracks of an album
+    pub fn add_many(&self, inputs: Vec<Input>) -> Result<(), Draining> {
+        let tracks = inputs
+            .into_iter()
+            .map(|input| self.track_from_input(input, None))
+            .collect::<Result<Vec<_>, _>>()?;
 
-            system.registry.write_byte_samples(&samples_as_bytes);
+        self.add_tracks(tracks);
+        Ok(())
+    }
+
+    /// Adds the audio files in a directory sorted by their path, and in its subdirectories
+    /// if recursive. Returns the amount of files added, as unsupported files are skipped.
+    pub fn add_directory(&self, path: &Path, recursive: bool) -> Result<usize, DirectoryError> {
+        self.ensure_not_draining()?;
+
+        let inputs = Input::from_directory(path, recursive).map_err(DirectoryError::Io)?;
+        let count = inputs.len();
+
+        info!("Adding {} tracks from {}", count, path.display());
+        self.add_many(inputs)?;
+
+        Ok(count)
+    }
+
+    fn add_input(&self, input: Input, requested_by: Option<u64>) -> Result<(), Draining> {
+        let track = self.track_from_input(input, None)?;
+
+        match requested_by {
+            Some(requester) => self.add_track(track.with_requester(requester)),
+            None => self.add_track(track),
+        }
+
+        Ok(())
+    }
+
+    /// Creates a track that decodes the input, expecting the given length if known
+    fn track_from_input(&self, input: Input, length: Option<usize>) -> Result<Track, Draining> {
+        self.ensure_not_draining()?;
+
+        let length = length.unwrap_or_else(|| Self::expected_length(&input));
+        let title = input.to_string();
+        let metadata = input.metadata();
+        let download = input.is_download();
+        let reader = input.into_sample_reader();
+
+        let loader = if download {
+            self.pool.add_download(reader, length)
+        } else {
+            self.pool.add(reader, length)
         };
 
-        thread::Builder::new()
-            .name("audio_stream".to_string())
-            .spawn(move || {
-                info!(
-                    "Now processing {} samples per {}ms ({} sample/s) at {:.1} kHz",
-                    STREAM_CHUNK_SIZE,
-                    STREAM_CHUNK_DURATION.as_millis(),
-                    SAMPLES_PER_SEC,
-                    SAMPLE_RATE as f32 / 1000.
-                );
+        let track = Track::new(loader, title);
+        Ok(track.with_metadata(metadata))
+    }
 
-                loop {
-                    let now = Instant::now();
-                    tick();
+    /// Sets the cache that [AudioSystem::add_cached] reads from and writes to,
+    /// or stops caching with `None`, in which case those inputs are decoded every time.
+    pub fn set_cache(&self, cache: Option<Cache>) {
+        self.pool.set_cache(cache);
+    }
 
-                    wait_for_next(now);
-                }
-            })
-            .unwrap();
+    /// Adds an input which is cached on disk after decoding,
+    /// so adding it again does not decode it again.
+    pub fn add_cached(&self, input: Input) -> Result<(), Draining> {
+        self.ensure_not_draining()?;
+
+        let length = Self::expected_length(&input);
+        let fingerprint = input.fingerprint();
+        let title = input.to_string();
+        let metadata = input.metadata();
+
+        let loader =
+            self.pool
+                .add_fingerprinted(&fingerprint, move || input.into_sample_reader(), length);
+
+        self.add_track(Track::new(loader, title).with_metadata(metadata));
+        Ok(())
     }
 
-    fn wait_for_next(now: Instant) {
-        let elapsed = now.elapsed();
-        let elapsed_micros = elapsed.as_micros();
-        let elapsed_millis = elapsed_micros / 1000;
+    /// Adds a silent track of the given duration
+    pub fn add_silence(&self, duration: Duration) -> Result<(), Draining> {
+        self.ensure_not_draining()?;
 
-        let duration_micros = STREAM_CHUNK_DURATION.as_micros();
+        let length = Self::samples_in(duration);
+        let loader = self.pool.add(pipeline::Silence::new(length), length);
+        self.add_loader(loader, "Silence".to_string());
+        Ok(())
+    }
 
-        if elapsed_millis > SAMPLES_PER_SEC as u128 / 10000 {
-            warn!(
-                "Stream took too long ({}ms) to process samples!",
-                elapsed_millis
-            )
+    fn add_loader(&self, loader: Arc<Loader>, title: String) {
+        self.add_track(Track::new(loader, title));
+    }
+
+    fn add_track(&self, track: Track) {
+        self.add_tracks(vec![track]);
+    }
+
+    fn add_tracks(&self, tracks: Vec<Track>) {
+        if tracks.is_empty() {
+            return;
         }
 
-        let corrected = duration_micros
-            .checked_sub(elapsed_micros)
-            .unwrap_or_default();
+        let was_empty = self.current_track().is_none();
+
+        self.queue.add_tracks(tracks);
+        self.notify_queue_update();
 
-        spin_sleep::sleep(Duration::from_micros(corrected as u64));
+        if was_empty {
+            self.restart_current();
+            self.notify_track_started();
+        }
     }
-}
 
-mod loading_thread {
-    use std::{thread, time::Duration};
+    fn notify_track_started(&self) {
+        if let Some(track) = self.current_track() {
+            self.events.emit(AudioEvent::TrackStarted(track));
+        }
+    }
 
-    use log::info;
+    /// Replaces the track that is playing without changing the rest of the queue.
+    /// The replacement loads on a decode worker, and the current track keeps playing until
+    /// it has. If it cannot be loaded, [AudioEvent::ReplacementFailed] is emitted instead.
+    pub fn replace_current(&self, input: Input) -> Result<(), ReplaceError> {
+        let length = Self::expected_length(&input);
+        let title = input.to_string();
+        let reader = input.into_sample_reader();
 
-    use super::AudioSystem;
+        let loader = self.pool.add(reader, length);
+        self.replace_current_loader(loader, title)
+    }
 
-    // Starts the thread that will load sources
-    pub fn start(system: &AudioSystem) {
-        let scheduler = system.scheduler.clone();
-        let pool = system.pool.clone();
+    fn replace_current_loader(
+        &self,
+        loader: Arc<Loader>,
+        title: String,
+    ) -> Result<(), ReplaceError> {
+        if self.current_track().is_none() {
+            self.pool.release(loader.id());
+            return Err(ReplaceError::NothingPlaying);
+        }
 
-        thread::Builder::new()
-            .name("audio_loading".to_string())
-            .spawn(move || {
-                info!("Now listening for load requests",);
+        let previous = self
+            .replacement
+            .lock()
+            .unwrap()
+            .replace(Track::new(loader, title));
 
-                loop {
-                    let requests = scheduler.preload();
+        // Only the latest replacement is kept
+        if let Some(previous) = previous {
+            self.pool.release(previous.loader.id());
+        }
 
-                    for (id, amount) in requests {
-                        let new_amount = pool.load(id, amount);
-                        scheduler.notify_load(id, new_amount);
-                    }
+        Ok(())
+    }
 
-                    thread::sleep(Duration::from_millis(500));
-                }
-            })
-            .unwrap();
+    /// Returns the request that loads the start of the replacement, if it has not loaded yet
+    fn replacement_request(&self) -> Option<(LoaderId, usize)> {
+        let replacement = self.replacement.lock().unwrap();
+
+        replacement
+            .as_ref()
+            .filter(|track| track.loader.available() == 0 && track.loader.failure().is_none())
+            .map(|track| (track.loader.id(), self.chunk_size()))
     }
-}
 
-mod config {
-    use std::time::Duration;
+    /// Switches to the replacement once its start has loaded,
+    /// or drops it if it failed to load.
+    fn apply_replacement(&self) {
+        let track = {
+            let mut replacement = self.replacement.lock().unwrap();
 
-    pub type Sample = f32;
-    pub const PCM_MIME: &str = "audio/pcm;rate=44100;encoding=float;bits=32";
+            let settled = replacement
+                .as_ref()
+                .map(|track| track.loader.available() > 0 || track.loader.failure().is_some());
 
-    pub const SAMPLE_RATE: usize = 44100;
-    pub const CHANNEL_COUNT: usize = 2;
+            match settled {
+                Some(true) => replacement.take().unwrap(),
+                _ => return,
+            }
+        };
 
-    pub const SAMPLE_IN_BYTES: usize = 4;
-    pub const SAMPLES_PER_SEC: usize = SAMPLE_RATE * CHANNEL_COUNT;
-    pub const BYTES_PER_SAMPLE: usize = 4 * SAMPLE_IN_BYTES;
+        let info = track.info();
 
-    pub const STREAM_CHUNK_DURATION: Duration = Duration::from_millis(100);
-    pub const STREAM_CHUNK_SIZE: usize =
-        (((SAMPLES_PER_SEC as u128) * STREAM_CHUNK_DURATION.as_millis()) / 1000) as usize;
-}
+        if track.loader.available() == 0 {
+            warn!("Replacement {} could not be loaded", info.title);
 
-pub use config::*;
+            self.pool.release(track.loader.id());
+            self.events.emit(AudioEvent::ReplacementFailed(info));
+            return;
+        }
 
-use crate::util::model::Identified;
+        // The current track ended or was cleared while the replacement loaded
+        if self.current_track().is_none() {
+            self.pool.release(track.loader.id());
+            return;
+        }
+
+        info!("Replacing the current track with {}", info.title);
+
+        self.queue.replace_current(track);
+        self.scheduler.replace_current(self.queued_loaders());
+        self.notify_track_started();
+    }
+
+    /// Drops the replacement that has not loaded yet, if any
+    fn cancel_replacement(&self) {
+        if let Some(track) = self.replacement.lock().unwrap().take() {
+            self.pool.release(track.loader.id());
+        }
+    }
+
+    /// Returns the track that is currently playing
+    pub fn current_track(&self) -> Option<TrackInfo> {
+        self.queue.peek_ahead(1).first().map(Track::info)
+    }
+
+    /// Returns the current track with its metadata and position,
+    /// such as to show it again once the message announcing it is gone.
+    pub fn current_track_detailed(&self) -> Option<track::TrackDetails> {
+        let track = self.queue.peek_ahead(1).into_iter().next()?;
+        Some(track.details(self.position()?))
+    }
+
+    /// Returns how far into the current track playback is, or `None` if nothing is playing
+    pub fn position(&self) -> Option<Duration> {
+        self.current_track()?;
+
+        let position = self.scheduler.offset() as f64 / SAMPLES_PER_SEC as f64;
+        Some(Duration::from_secs_f64(position))
+    }
 
-use self::pipeline::IntoSampleReader;
+    /// Returns the current track with its total duration, or `None` if nothing is playing
+    pub fn now_playing(&self) -> Option<(Track, Duration)> {
+        let track = self.queue.peek_ahead(1).into_iter().next()?;
+        let duration = track.duration();
+
+        Some((track, duration))
+    }
+
+    /// Returns the track that will play after the current one with the repeat mode,
+    /// without advancing
+    pub fn next_track(&self) -> Option<TrackInfo> {
+        self.upcoming_track().as_ref().map(Track::info)
+    }
+
+    /// Returns the estimated time until the next track has loaded enough to play,
+    /// or `None` if it is already ready or there is not enough information.
+    pub fn next_ready_eta(&self) -> Option<Duration> {
+        self.upcoming_track()?.loader.ready_eta()
+    }
+
+    fn upcoming_track(&self) -> Option<Track> {
+        self.queue
+            .peek_repeating(2, self.repeat())
+            .into_iter()
+            .nth(1)
+    }
+
+    /// Moves playback of the current track to a position.
+    /// Tracks that can seek do not decode everything before the position,
+    /// and seeking to the end or past it skips to the next track.
+    /// Positions that are not loaded yet are handled according to the [SeekPolicy].
+    pub fn seek(&self, position: Duration) -> Result<(), SeekError> {
+        let offset = Self::samples_in(position);
+
+        let expected = match self.queue.peek_ahead(1).first() {
+            Some(track) => track.loader.expected(),
+            None => return Err(SeekError::NothingPlaying),
+        };
+
+        if offset >= expected {
+            self.next();
+            return Ok(());
+        }
+
+        self.seek_to(offset, self.seek_policy.load())
+    }
+
+    /// Moves playback of the current track forward by an amount, such as to skip an intro.
+    /// Skipping to the end or past it moves on to the next track, like [AudioSystem::seek].
+    pub fn skip_forward(&self, amount: Duration) -> Result<(), SeekError> {
+        let position = self.position().ok_or(SeekError::NothingPlaying)?;
+        self.seek(position + amount)
+    }
+
+    fn seek_to(&self, offset: usize, policy: SeekPolicy) -> Result<(), SeekError> {
+        let loader = match self.queue.peek_ahead(1).first() {
+            Some(track) => track.loader.clone(),
+            None => return Err(SeekError::NothingPlaying),
+        };
+
+        let offset = offset.min(loader.expected());
+        let seeked = loader.seek(offset);
+        self.queue_ended.take();
+
+        if !seeked && !loader.loaded().contains(&offset) && policy == SeekPolicy::Refuse {
+            return Err(SeekError::NotLoaded);
+        }
+
+        // Otherwise silence plays while the scheduler has it loaded up to the position
+        self.scheduler.notify_load(loader.id(), loader.available());
+        self.scheduler.seek(offset);
+
+        Ok(())
+    }
+
+    /// Sets what happens when seeking to where the current track has not loaded yet
+    pub fn set_seek_policy(&self, policy: SeekPolicy) {
+        self.seek_policy.store(policy);
+    }
+
+    /// Repeats the part of the current track between two positions until
+    /// [AudioSystem::clear_ab_loop] is called or the track changes.
+    /// Playback that is already past the end jumps back to the start.
+    pub fn set_ab_loop(&self, a: Duration, b: Duration) -> Result<(), AbLoopError> {
+        let loader = match self.queue.peek_ahead(1).first() {
+            Some(track) => track.loader.clone(),
+            None => return Err(AbLoopError::NothingPlaying),
+        };
+
+        let (start, end) = (Self::samples_in(a), Self::samples_in(b));
+
+        if start >= end || end > loader.expected() {
+            return Err(AbLoopError::InvalidRange);
+        }
+
+        self.ab_loop.store(Some(AbLoop {
+            loader: loader.id(),
+            start,
+            end,
+        }));
+
+        Ok(())
+    }
+
+    /// Stops repeating part of the current track, continuing from where it is
+    pub fn clear_ab_loop(&self) {
+        self.ab_loop.store(None);
+    }
+
+    /// Holds the current track where it is, writing silence until [AudioSystem::resume]
+    pub fn pause(&self) {
+        self.set_playback_state(PlaybackState::Paused);
+    }
+
+    /// Continues playback after a pause, fading in so it does not click
+    pub fn resume(&self) {
+        self.set_playback_state(PlaybackState::Playing);
+    }
+
+    /// Sets how long playback fades in after resuming, or disables it with [Duration::ZERO]
+    pub fn set_resume_fade(&self, duration: Duration) {
+        self.resume_fade.store(Self::samples_in(duration));
+    }
+
+    /// Returns the amount of samples in whole frames that play in a duration
+    fn samples_in(duration: Duration) -> usize {
+        let samples = (duration.as_secs_f64() * SAMPLES_PER_SEC as f64) as usize;
+        samples - samples % CHANNEL_COUNT
+    }
+
+    /// Returns how long an amount of samples plays for
+    fn duration_of(samples: usize) -> Duration {
+        Duration::from_secs_f64(samples as f64 / SAMPLES_PER_SEC as f64)
+    }
+
+    pub fn playback_state(&self) -> PlaybackState {
+        self.playback_state.load()
+    }
+
+    fn set_playback_state(&self, state: PlaybackState) {
+        if self.playback_state.swap(state) != state {
+            if state == PlaybackState::Playing {
+                self.fading_in.store(Some(0));
+            }
+
+            info!("Playback is now {:?}", state);
+            self.events.emit(AudioEvent::PlaybackState(state));
+        }
+    }
+
+    /// Sets what plays once a track has finished.
+    /// Skipping with [AudioSystem::next] still moves to the next track.
+    pub fn set_repeat(&self, mode: RepeatMode) {
+        if self.repeat.swap(mode) != mode {
+            info!("Repeat is now {:?}", mode);
+            self.update_settings(|s| s.repeat = mode);
+
+            self.notify_queue_update();
+            self.events.emit(AudioEvent::Repeat(mode));
+        }
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat.load()
+    }
+
+    /// Skips to the next track, which plays from its start
+    pub fn next(&self) {
+        self.advance_queue();
+        self.restart_current();
+        self.notify_track_started();
+    }
+
+    /// Goes back to the track that played before the current one, which plays from its start.
+    /// Past the first seconds of the current track, it restarts instead, like most players.
+    /// The last track comes before the first if the queue repeats.
+    pub fn previous(&self) {
+        let position = self.position().unwrap_or_default();
+
+        if position < self.previous_threshold.load() {
+            self.preview.lock().unwrap().take();
+            self.clear_ab_loop();
+
+            let wrap = self.repeat() == RepeatMode::Queue;
+
+            if self.queue.previous(wrap).is_some() {
+                self.notify_queue_update();
+                self.restart_current();
+                self.notify_track_started();
+                return;
+            }
+        }
+
+        self.restart_current();
+    }
+
+    /// Sets how far into a track [AudioSystem::previous] restarts it instead of going back,
+    /// where [Duration::ZERO] always restarts it
+    pub fn set_previous_threshold(&self, threshold: Duration) {
+        self.previous_threshold.store(threshold);
+    }
+
+    /// Skips to the next track, moving the current one to the end of the queue
+    /// so it comes around again instead of being left behind.
+    pub fn next_requeue(&self) {
+        self.preview.lock().unwrap().take();
+
+        if !self.queue.requeue_current() {
+            return;
+        }
+
+        self.notify_queue_update();
+        self.restart_current();
+        self.notify_track_started();
+    }
+
+    /// Removes a track from the queue, skipping to the next one if it is playing
+    pub fn remove(&self, id: TrackId) -> Option<TrackInfo> {
+        let current = self.current_track().map(|t| t.id);
+        let scheduled = self.queued_loaders();
+
+        let removed = self.queue.remove(id)?;
+        info!("Removed {} from the queue", removed.title());
+
+        if current == Some(id) {
+            self.preview.lock().unwrap().take();
+            self.notify_queue_update();
+            self.restart_current();
+            self.notify_track_started();
+        } else if scheduled.iter().any(|l| l.id() == removed.loader.id()) {
+            self.notify_queue_update();
+        }
+
+        Some(removed.info())
+    }
+
+    /// Moves a track to an index in the queue, returning false if it is not queued.
+    /// Tracks moved near the current one are loaded in time to play.
+    pub fn move_track(&self, id: TrackId, new_index: usize) -> bool {
+        if !self.queue.move_track(id, new_index) {
+            return false;
+        }
+
+        self.notify_queue_update();
+        true
+    }
+
+    /// Shuffles the tracks after the current one, with a seed to always shuffle the same way
+    pub fn shuffle(&self, seed: Option<u64>) {
+        self.queue.shuffle(seed);
+        self.notify_queue_update();
+    }
+
+    /// Plays the current track from its start, or after its intro
+    fn restart_current(&self) {
+        let intro = self
+            .queue
+            .peek_ahead(1)
+            .first()
+            .map(|track| self.scheduler.intro(track.loader.expected()))
+            .unwrap_or_default();
+
+        if intro > 0 {
+            let _ = self.seek_to(intro, SeekPolicy::Wait);
+        } else {
+            self.queue_ended.take();
+            self.scheduler.seek(0);
+        }
+    }
+
+    /// Moves the queue along without touching the playback offset,
+    /// which is already in the next track when the current one ended on its own.
+    fn advance_queue(&self) {
+        self.preview.lock().unwrap().take();
+        self.clear_ab_loop();
+
+        self.queue.next();
+        self.notify_queue_update();
+        self.evict_played();
+    }
+
+    /// Evicts the samples of played tracks that are older than the retained ones,
+    /// unless their loader is still to be played. A repeating queue plays them again,
+    /// so nothing is evicted then.
+    fn evict_played(&self) {
+        if self.repeat() == RepeatMode::Queue {
+            return;
+        }
+
+        let played = self.queue.played();
+        let retained = self.retained_tracks.load();
+        let preview = self.preview.lock().unwrap().as_ref().map(|p| p.id());
+
+        let kept: Vec<_> = self
+            .queue
+            .peek_ahead(usize::MAX)
+            .iter()
+            .chain(played.iter().rev().take(retained))
+            .map(|t| t.loader.id())
+            .chain(preview)
+            .collect();
+
+        for track in played.iter().rev().skip(retained) {
+            let id = track.loader.id();
+
+            if !kept.contains(&id) {
+                self.pool.evict(id);
+            }
+        }
+    }
+
+    /// Plays a snippet of an input, then returns to where the current track was.
+    /// The queue is left as is, so the snippet is not part of it.
+    pub fn preview(&self, input: Input, start: Duration, length: Duration) -> Result<(), Draining> {
+        self.ensure_not_draining()?;
+
+        let (start, length) = (Self::samples_in(start), Self::samples_in(length));
+        let title = input.to_string();
+        let reader = Clip::new(input.into_sample_reader(), start, length);
+
+        self.preview_loader(self.pool.add(reader, length), title);
+        Ok(())
+    }
+
+    fn preview_loader(&self, loader: Arc<Loader>, title: String) {
+        info!("Previewing {}", title);
+
+        *self.preview.lock().unwrap() = Some(loader);
+        self.scheduler.interject(self.queued_loaders());
+    }
+
+    /// Called when a loader has been played all the way through
+    fn finish(&self, id: LoaderId) {
+        let mut preview = self.preview.lock().unwrap();
+
+        if preview.as_ref().map(|p| p.id()) == Some(id) {
+            preview.take();
+            drop(preview);
+
+            self.notify_queue_update();
+        } else {
+            drop(preview);
+
+            // The last track was already reported when the queue ended on it
+            let reported = self.queue_ended.take() == Some(id);
+
+            let current = self.queue.peek_ahead(1).into_iter().next();
+
+            if let Some(track) = current.filter(|_| !reported) {
+                self.events.emit(AudioEvent::TrackEnded(track.id()));
+                self.record_played(track.info());
+            }
+
+            // Nothing comes after it, so the queue stays on it until more is added
+            if self.scheduler.is_finished() {
+                self.queue_ended.store(Some(id));
+                return;
+            }
+
+            // The scheduler has already moved on to the start of the same track
+            match self.repeat() {
+                RepeatMode::Track => self.notify_queue_update(),
+                RepeatMode::TrackOnce => self.set_repeat(RepeatMode::Off),
+                _ => self.advance_queue(),
+            }
+
+            self.notify_track_started();
+        }
+    }
+
+    /// Remembers a track that played until its end, forgetting the oldest past [MAX_HISTORY]
+    fn record_played(&self, track: TrackInfo) {
+        let mut history = self.history.lock().unwrap();
+
+        history.push_back(PlayedTrackInfo {
+            track,
+            finished_at: SystemTime::now(),
+        });
+
+        if history.len() > MAX_HISTORY {
+            history.pop_front();
+        }
+    }
+
+    /// Returns up to `amount` of the tracks that played until their end, the most recent first
+    pub fn recently_played(&self, amount: usize) -> Vec<PlayedTrackInfo> {
+        let history = self.history.lock().unwrap();
+        history.iter().rev().take(amount).cloned().collect()
+    }
+
+    /// Sets the pool that tracks are picked from when nothing is left to play
+    pub fn set_autoplay(&self, mut pool: Autoplay<InputFactory>, enabled: bool) {
+        pool.set_enabled(enabled);
+        *self.autoplay.lock().unwrap() = Some(pool);
+    }
+
+    /// Sets how many other tracks autoplay picks before picking a track again
+    pub fn set_no_repeat_window(&self, window: usize) -> Result<(), NoAutoplay> {
+        match self.autoplay.lock().unwrap().as_mut() {
+            Some(autoplay) => {
+                autoplay.set_no_repeat_window(window);
+                Ok(())
+            }
+            None => Err(NoAutoplay),
+        }
+    }
+
+    /// Adds a track from the autoplay pool if nothing plays after the current track,
+    /// so it is loaded in time to play without a gap.
+    fn autoplay_if_needed(&self) {
+        if self.is_draining() || self.next_track().is_some() {
+            return;
+        }
+
+        let input = {
+            let mut autoplay = self.autoplay.lock().unwrap();
+
+            match autoplay.as_mut().filter(|a| a.is_enabled()) {
+                Some(autoplay) => autoplay.pick().and_then(|create| create()),
+                None => return,
+            }
+        };
+
+        match input {
+            Some(input) => {
+                info!("Autoplaying {}", input);
+                let _ = self.add(input);
+            }
+            None => warn!("Failed to create an input to autoplay"),
+        }
+    }
+
+    fn expected_length(input: &Input) -> usize {
+        let length = (SAMPLES_PER_SEC as f32) * input.duration();
+        length.round() as usize
+    }
+
+    /// Sets how long tracks overlap when transitioning between them,
+    /// or disables crossfading if `None`.
+    pub fn set_crossfade(&self, duration: Option<Duration>) {
+        let length = duration.map(Self::samples_in).unwrap_or_default();
+        self.scheduler.set_crossfade(length);
+    }
+
+    /// Returns how long tracks overlap, if crossfading is enabled
+    pub fn crossfade(&self) -> Option<Duration> {
+        match self.scheduler.crossfade() {
+            0 => None,
+            length => Some(Duration::from_secs_f32(
+                length as f32 / SAMPLES_PER_SEC as f32,
+            )),
+        }
+    }
+
+    /// Skips the first part of every track when it starts, such as the intro of a podcast.
+    /// Tracks that are not longer than that are played in full.
+    pub fn set_skip_intro(&self, duration: Duration) {
+        self.scheduler.set_skip_intro(Self::samples_in(duration));
+    }
+
+    /// Skips silence where tracks join, without modifying the tracks
+    pub fn set_trim_boundaries(&self, enabled: bool) {
+        self.scheduler.set_trim_boundaries(enabled);
+    }
+
+    /// Adds an effect to the end of the processing chain
+    pub fn add_effect<E: 'static + Effect>(&self, effect: E) -> Result<(), BudgetExceeded> {
+        let mut chain = self.chain.lock().unwrap();
+        chain.add(effect)
+    }
+
+    /// Gates quiet parts of tracks before they are processed, or disables the gate with `None`
+    pub fn set_noise_gate(&self, config: Option<NoiseGateConfig>) {
+        let gate = config.map(processing::effects::NoiseGate::new);
+        *self.noise_gate.lock().unwrap() = gate;
+    }
+
+    /// Blends the channels into each other for headphone listeners, or disables it with `None`
+    pub fn set_crossfeed(&self, config: Option<CrossfeedConfig>) {
+        let crossfeed = config.map(processing::effects::Crossfeed::new);
+        *self.crossfeed.lock().unwrap() = crossfeed;
+    }
+
+    /// Replaces the whole processing chain at once, such as when switching presets.
+    /// The previous chain fades out to avoid clicks.
+    pub fn set_chain(&self, chain: ProcessingChain) {
+        self.chain.lock().unwrap().replace(chain);
+    }
+
+    /// Replaces every band of the equalizer, or none if any of them is invalid
+    pub fn set_eq(&self, bands: Vec<EqBand>) -> Result<(), InvalidBand> {
+        self.equalizer.lock().unwrap().set_bands(bands)?;
+        self.save_eq();
+
+        Ok(())
+    }
+
+    /// Adds a band to the equalizer, returning its index.
+    /// The other bands are left as they are.
+    pub fn add_eq_band(&self, band: EqBand) -> Result<usize, InvalidBand> {
+        let index = self.equalizer.lock().unwrap().add_band(band)?;
+        self.save_eq();
+
+        Ok(index)
+    }
+
+    /// Changes the band of the equalizer at an index, returning false if there is none.
+    /// The audio thread only sees the band before or after the change, never a mix of both.
+    pub fn set_eq_band(&self, index: usize, band: EqBand) -> Result<bool, InvalidBand> {
+        let changed = self.equalizer.lock().unwrap().set_band(index, band)?;
+        self.save_eq();
+
+        Ok(changed)
+    }
+
+    /// Resets the equalizer to its five flat bands
+    pub fn clear_eq(&self) {
+        self.equalizer.lock().unwrap().clear();
+        self.update_settings(|s| s.eq = None);
+    }
+
+    /// Removes a band from the equalizer, returning it if it exists
+    pub fn remove_eq_band(&self, index: usize) -> Option<EqBand> {
+        let removed = self.equalizer.lock().unwrap().remove_band(index);
+        self.save_eq();
+
+        removed
+    }
+
+    fn save_eq(&self) {
+        let bands = self.eq_bands();
+        self.update_settings(|s| s.eq = Some(bands));
+    }
+
+    pub fn eq_bands(&self) -> Vec<EqBand> {
+        self.equalizer.lock().unwrap().bands()
+    }
+
+    /// Plays every track with a gain that brings its loudness to a target in LUFS, such as -14,
+    /// or plays them as they are with `None`. Tracks play at unity until they are fully loaded,
+    /// then ramp to their gain over a chunk.
+    pub fn set_normalization(&self, target: Option<f32>) {
+        self.normalization.store(target);
+    }
+
+    /// Slowly adjusts the final mix towards a loudness in LUFS, such as -14,
+    /// or disables it with `None`. This is applied before the volume.
+    pub fn set_output_loudness(&self, target: Option<f32>) {
+        let normalizer = target.map(processing::effects::LoudnessNormalizer::new);
+        *self.normalizer.lock().unwrap() = normalizer;
+    }
+
+    /// Ducks the music while the source, such as a microphone,
+    /// is louder than the threshold in RMS.
+    pub fn set_sidechain_source(&self, source: AudioBufferConsumer, threshold: f32) {
+        let ducker = processing::effects::Ducker::new(source, threshold);
+        *self.sidechain.lock().unwrap() = Some(ducker);
+    }
+
+    /// Returns the effects of the processing chain in the order they are applied
+    pub fn list_effects(&self) -> Vec<EffectInfo> {
+        self.chain.lock().unwrap().effects()
+    }
+
+    /// Enables or bypasses the effect at the index of the processing chain
+    pub fn set_effect_enabled(&self, index: usize, enabled: bool) -> bool {
+        let mut chain = self.chain.lock().unwrap();
+        chain.set_enabled(index, enabled)
+    }
+
+    /// Limits the total cost of effects in the processing chain
+    pub fn set_processing_budget(&self, budget: Option<usize>) {
+        let mut chain = self.chain.lock().unwrap();
+        chain.set_budget(budget);
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume.load()
+    }
+
+    /// Sets the volume as a gain from 0 to [MAX_VOLUME], which is remembered for the guild.
+    /// The next chunk ramps to it, so the change does not click.
+    pub fn set_volume(&self, volume: f32) {
+        let volume = volume.max(0.).min(MAX_VOLUME);
+
+        self.volume.store(volume);
+        self.update_settings(|s| s.volume = volume);
+    }
+
+    /// Silences the output, ramping down over the next chunk like a volume change.
+    /// The volume is kept, and changing it while muted changes what unmuting returns to.
+    pub fn mute(&self) {
+        if !self.muted.swap(true) {
+            info!("Muted at a volume of {:.2}", self.volume());
+        }
+    }
+
+    /// Returns to the volume that was set before muting, ramping up over the next chunk
+    pub fn unmute(&self) {
+        if self.muted.swap(false) {
+            info!("Unmuted to a volume of {:.2}", self.volume());
+        }
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load()
+    }
+
+    /// Sets how fast playback is, from [MIN_SPEED] to [MAX_SPEED], without changing the pitch.
+    /// Tracks take longer or shorter to play, but positions are still within the track.
+    pub fn set_speed(&self, speed: f32) {
+        let speed = speed.max(MIN_SPEED).min(MAX_SPEED);
+
+        if self.speed.swap(speed) != speed {
+            info!("Playing at {:.2}x speed", speed);
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed.load()
+    }
+
+    /// Sets what happens when processing falls behind real-time
+    pub fn set_desync_policy(&self, policy: DesyncPolicy) {
+        self.desync_policy.store(policy);
+    }
+
+    /// Estimates how long it takes for a sample to reach a consumer after it is read.
+    /// A whole chunk is processed before any of it is written, and then it waits
+    /// behind what consumers have not read yet.
+    pub fn estimated_latency(&self) -> Duration {
+        let buffered = self.registry.average_fill() as f64 / self.config.samples_per_sec() as f64;
+        self.config.chunk_duration() + Duration::from_secs_f64(buffered)
+    }
+
+    /// Returns the bitrate measured from what is streamed in the current format,
+    /// or `None` if it is raw, as its bitrate is always the same.
+    pub fn measured_bitrate(&self) -> Option<u32> {
+        self.registry.measured_bitrate()
+    }
+
+    /// Returns how many samples and bytes have been written to streams since the system was created
+    pub fn total_served(&self) -> ServedStats {
+        self.registry.served()
+    }
+
+    /// Returns the queued tracks that failed to load since this was last called.
+    /// Failures are only kept for a while, and tracks that were removed are left out.
+    pub fn failed_tracks(&self) -> Vec<FailedTrackInfo> {
+        self.pool
+            .take_failures()
+            .into_iter()
+            .filter_map(|(id, reason)| {
+                let track = self.queue.find_by_loader(id)?;
+
+                Some(FailedTrackInfo {
+                    track: track.info(),
+                    reason,
+                })
+            })
+            .collect()
+    }
+
+    /// Emits [AudioEvent::DurationCorrected] for the queued tracks
+    /// that ended far from their declared duration since this was last called.
+    fn report_duration_corrections(&self) {
+        for (id, declared, actual) in self.pool.take_corrections() {
+            if let Some(track) = self.queue.find_by_loader(id) {
+                self.events.emit(AudioEvent::DurationCorrected {
+                    track_id: track.id(),
+                    declared: Self::duration_of(declared),
+                    actual: Self::duration_of(actual),
+                });
+            }
+        }
+    }
+
+    /// Returns the measurements of what is currently playing
+    pub fn metering(&self) -> &Metering {
+        &self.metering
+    }
+
+    fn notify_queue_update(&self) {
+        self.scheduler.set_loaders(self.queued_loaders());
+    }
+
+    /// Returns the loaders of the current and upcoming tracks, and gives their
+    /// crossfade overrides to the scheduler so it has them before they are scheduled.
+    /// Tracks shorter than a chunk can all end within a single tick,
+    /// so enough upcoming tracks are included to fill one.
+    fn queued_loaders(&self) -> Vec<Arc<Loader>> {
+        let preview = self.preview.lock().unwrap().clone();
+        let depth = self.prefetch_depth.load();
+        let mut upcoming_length = 0;
+
+        // Repeating tracks can be scheduled any number of times, so stop at some point
+        let queued = self
+            .queue
+            .peek_repeating(MAX_SCHEDULED, self.repeat())
+            .into_iter()
+            .enumerate()
+            .take_while(|(i, track)| {
+                let include = *i < depth || upcoming_length < self.chunk_size();
+
+                if *i > 0 {
+                    upcoming_length += track.loader.expected();
+                }
+
+                include
+            })
+            .map(|(_, track)| track)
+            .collect::<Vec<_>>();
+
+        let overrides = queued
+            .iter()
+            .map(|track| {
+                let crossfade = track.crossfade();
+                let fade = CrossfadeOverride {
+                    fade_in: crossfade.fade_in.map(Self::samples_in),
+                    fade_out: crossfade.fade_out.map(Self::samples_in),
+                };
+
+                (track.loader.id(), fade)
+            })
+            .collect();
+
+        self.scheduler.set_crossfade_overrides(overrides);
+
+        let targets = queued
+            .iter()
+            .filter_map(|track| Some((track.loader.id(), track.loudness_target()?)))
+            .collect();
+
+        self.pool.set_loudness_targets(targets);
+
+        let queued = queued.into_iter().map(|track| track.loader);
+        preview.into_iter().chain(queued).collect()
+    }
+}
+
+impl Default for AudioSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How far into a track going to the previous one restarts it, unless set otherwise
+const DEFAULT_PREVIOUS_THRESHOLD: Duration = Duration::from_secs(3);
+
+/// The most tracks that are remembered as recently played
+pub const MAX_HISTORY: usize = 100;
+
+/// How many tracks from the current one on are scheduled, unless set otherwise
+const DEFAULT_PREFETCH_DEPTH: usize = 3;
+
+/// How many played tracks keep their samples, unless set otherwise
+const DEFAULT_RETAINED_TRACKS: usize = 2;
+
+/// The most tracks that are scheduled ahead, as repeating tracks never run out
+const MAX_SCHEDULED: usize = 256;
+
+/// The loudest volume that can be set, which doubles the gain
+pub const MAX_VOLUME: f32 = 2.;
+
+/// The slowest playback can be, which takes twice as long
+pub const MIN_SPEED: f32 = 0.5;
+
+/// The fastest playback can be, which takes half as long
+pub const MAX_SPEED: f32 = 2.;
+
+/// How long playback fades in after resuming, unless set otherwise
+pub const DEFAULT_RESUME_FADE: Duration = Duration::from_millis(20);
+
+/// Whether the queue is moving forward
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackState {
+    Playing,
+    /// Silence is written in place of the current track, which stays where it was
+    Paused,
+}
+
+/// What to do when processing falls too far behind real-time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DesyncPolicy {
+    /// Only emit [AudioEvent::Desync]
+    Report,
+    /// Skip the chunks that should have played, then emit [AudioEvent::Desync]
+    DropChunks,
+}
+
+/// What to do when seeking to where the current track has not loaded yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekPolicy {
+    /// Play silence while everything up to the position is loaded
+    Wait,
+    /// Refuse to seek, unless the source can jump straight to the position
+    Refuse,
+}
+
+impl SeekPolicy {
+    pub fn parse(str: &str) -> Option<Self> {
+        match str {
+            "wait" => Some(Self::Wait),
+            "refuse" => Some(Self::Refuse),
+            _ => None,
+        }
+    }
+}
+
+/// A part of a track that repeats, in samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AbLoop {
+    loader: LoaderId,
+    start: usize,
+    end: usize,
+}
+
+/// An A-B loop could not be set
+#[derive(Debug, PartialEq, Eq)]
+pub enum AbLoopError {
+    NothingPlaying,
+    /// A is not before B, or B is past the end of the track
+    InvalidRange,
+}
+
+impl Display for AbLoopError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AbLoopError::NothingPlaying => write!(f, "Nothing is playing"),
+            AbLoopError::InvalidRange => {
+                write!(f, "The loop must start before it ends, within the track")
+            }
+        }
+    }
+}
+
+impl Error for AbLoopError {}
+
+/// The current track could not be seeked
+#[derive(Debug, PartialEq, Eq)]
+pub enum SeekError {
+    NothingPlaying,
+    /// The position is not loaded, and the source cannot seek to it
+    NotLoaded,
+}
+
+impl Display for SeekError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SeekError::NothingPlaying => write!(f, "Nothing is playing"),
+            SeekError::NotLoaded => write!(f, "That part of the track has not loaded yet"),
+        }
+    }
+}
+
+impl Error for SeekError {}
+
+/// The current track could not be replaced
+#[derive(Debug)]
+pub enum ReplaceError {
+    NothingPlaying,
+}
+
+impl Display for ReplaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplaceError::NothingPlaying => write!(f, "Nothing is playing"),
+        }
+    }
+}
+
+impl Error for ReplaceError {}
+
+/// A track was added while the system is draining
+#[derive(Debug)]
+pub struct Draining;
+
+impl Display for Draining {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The audio system is draining and does not accept tracks")
+    }
+}
+
+impl Error for Draining {}
+
+/// Autoplay was configured before a pool was set with [AudioSystem::set_autoplay]
+#[derive(Debug)]
+pub struct NoAutoplay;
+
+impl Display for NoAutoplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "There is no autoplay pool to configure")
+    }
+}
+
+impl Error for NoAutoplay {}
+
+/// The tracks of a directory could not be added
+#[derive(Debug)]
+pub enum DirectoryError {
+    Draining,
+    Io(io::Error),
+}
+
+impl From<Draining> for DirectoryError {
+    fn from(_: Draining) -> Self {
+        DirectoryError::Draining
+    }
+}
+
+impl Display for DirectoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DirectoryError::Draining => write!(f, "{}", Draining),
+            DirectoryError::Io(err) => write!(f, "The directory could not be read: {}", err),
+        }
+    }
+}
+
+impl Error for DirectoryError {}
+
+/// The background threads of an [AudioSystem]
+struct Threads {
+    running: AtomicCell<bool>,
+    /// The threads stop once the queue has finished playing
+    draining: AtomicCell<bool>,
+    /// The amount of threads that decode sources
+    decode_threads: AtomicCell<usize>,
+    /// The most samples the loading thread requests each cycle, if limited
+    load_budget: AtomicCell<Option<usize>>,
+    /// How many samples the loading thread requested in its last cycle
+    last_load: AtomicCell<usize>,
+    /// How long the loading thread waits between cycles
+    loading_interval: AtomicCell<Duration>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Default for Threads {
+    fn default() -> Self {
+        Self {
+            running: Default::default(),
+            draining: Default::default(),
+            decode_threads: AtomicCell::new(2),
+            load_budget: Default::default(),
+            last_load: Default::default(),
+            loading_interval: AtomicCell::new(loading_thread::DEFAULT_INTERVAL),
+            handles: Default::default(),
+        }
+    }
+}
+
+impl Threads {
+    fn is_running(&self) -> bool {
+        self.running.load()
+    }
+
+    /// Returns true if a thread stopped, such as from a panic
+    fn any_finished(&self) -> bool {
+        let handles = self.handles.lock().unwrap();
+        handles.iter().any(|h| h.is_finished())
+    }
+}
+
+/// Owns an [AudioSystem] and stops its threads when dropped.
+///
+/// The system itself is cheap to clone and shared with its threads,
+/// so the owner is used to tell when it is no longer needed.
+pub struct AudioSystemHandle {
+    system: AudioSystem,
+}
+
+impl AudioSystemHandle {
+    pub fn new() -> Self {
+        Self {
+            system: AudioSystem::new(),
+        }
+    }
+}
+
+impl AudioSystemHandle {
+    /// Creates a system for a guild, which restores and saves its settings
+    pub fn with_settings(store: Arc<Mutex<SettingsStore>>, guild: u64) -> Self {
+        Self {
+            system: AudioSystem::new().with_settings(store, guild),
+        }
+    }
+}
+
+impl Default for AudioSystemHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for AudioSystemHandle {
+    type Target = AudioSystem;
+
+    fn deref(&self) -> &Self::Target {
+        &self.system
+    }
+}
+
+impl Drop for AudioSystemHandle {
+    fn drop(&mut self) {
+        self.system.shutdown();
+    }
+}
+
+mod playback_thread {
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+
+    use log::{info, warn};
+
+    use std::{f32::consts::FRAC_PI_2, mem};
+
+    use super::allocation::PLAYBACK_BUFFERS;
+    use super::config::*;
+    use super::{AudioEvent, AudioSystem, DesyncPolicy, Effect, Fade, LoaderId, PlaybackState};
+
+    /// Starts the thread which will process samples in real-time
+    pub fn start(sys: &AudioSystem) -> JoinHandle<()> {
+        let system = sys.clone();
+
+        thread::Builder::new()
+            .name("audio_stream".to_string())
+            .spawn(move || {
+                let config = system.config();
+
+                info!(
+                    "Now processing {} samples per {}ms ({} sample/s) at {:.1} kHz",
+                    system.chunk_size(),
+                    config.chunk_duration().as_millis(),
+                    SAMPLES_PER_SEC,
+                    SAMPLE_RATE as f32 / 1000.
+                );
+
+                if !config.is_native() {
+                    info!(
+                        "Streaming {} channels at {:.1} kHz",
+                        config.channel_count(),
+                        config.sample_rate() as f32 / 1000.
+                    );
+                }
+
+                let mut drift = Drift::default();
+
+                while system.threads.is_running() {
+                    step(&system, &RealClock, &mut drift);
+                }
+            })
+            .unwrap()
+    }
+
+    /// A source of time, so the timing of playback can be controlled in tests
+    pub trait Clock {
+        fn now(&self) -> Instant;
+        fn sleep(&self, duration: Duration);
+    }
+
+    /// The system clock, used outside of tests
+    pub struct RealClock;
+
+    impl Clock for RealClock {
+        fn now(&self) -> Instant {
+            Instant::now()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            spin_sleep::sleep(duration)
+        }
+    }
+
+    /// A clock that only moves when it is advanced or slept on
+    #[cfg(test)]
+    pub struct MockClock {
+        now: std::sync::Mutex<Instant>,
+    }
+
+    #[cfg(test)]
+    impl MockClock {
+        pub fn new() -> Self {
+            Self {
+                now: Instant::now().into(),
+            }
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    #[cfg(test)]
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.advance(duration)
+        }
+    }
+
+    /// Processes a single chunk, then waits until the next one is due
+    pub fn step<C: Clock>(system: &AudioSystem, clock: &C, drift: &mut Drift) {
+        let now = clock.now();
+        system.run_timers(now);
+
+        // The queue stays where it was until someone listens again
+        if system.update_idle(now) {
+            system.activity.record_tick(now);
+            wait_for_next(system, clock, now);
+            return;
+        }
+
+        tick(system);
+        report_position(system, now);
+
+        system.activity.record_tick(clock.now());
+
+        check_drift(system, drift, clock.now().duration_since(now));
+        wait_for_next(system, clock, now);
+    }
+
+    /// Processes a single chunk of samples and writes it to the streams
+    pub fn tick(system: &AudioSystem) {
+        // Held for the whole tick, so a panic stop happens entirely before or after it
+        let mut converter = system.converter.lock().unwrap();
+
+        let mut samples = PLAYBACK_BUFFERS.borrow(system.chunk_size());
+        read_samples(system, &mut samples);
+
+        let mut raw_converter = system.raw_converter.lock().unwrap();
+        system
+            .raw_registry
+            .write_samples(raw_converter.convert(&samples));
+        drop(raw_converter);
+
+        if let Some(gate) = system.noise_gate.lock().unwrap().as_mut() {
+            gate.process(&mut samples);
+        }
+
+        system.equalizer.lock().unwrap().process(&mut samples);
+
+        system.chain.lock().unwrap().process(&mut samples);
+
+        if let Some(ducker) = system.sidechain.lock().unwrap().as_mut() {
+            ducker.process(&mut samples);
+        }
+
+        if let Some(crossfeed) = system.crossfeed.lock().unwrap().as_mut() {
+            crossfeed.process(&mut samples);
+        }
+
+        if let Some(normalizer) = system.normalizer.lock().unwrap().as_mut() {
+            normalizer.process(&mut samples);
+        }
+
+        apply_volume(system, &mut samples);
+        system.metering.measure(&samples);
+        system.registry.write_samples(converter.convert(&samples));
+        drop(converter);
+
+        if system.is_drained() {
+            info!("Finished draining");
+
+            system.shutdown();
+            system.events.emit(AudioEvent::Drained);
+        }
+    }
+
+    /// Emits the position of the current track, unless it was reported less than a second ago
+    fn report_position(system: &AudioSystem, now: Instant) {
+        if system.playback_state() == PlaybackState::Paused {
+            return;
+        }
+
+        let position = match system.position() {
+            Some(position) => position,
+            None => return,
+        };
+
+        if system.activity.should_report_position(now) {
+            system.events.emit(AudioEvent::PositionUpdated(position));
+        }
+    }
+
+    /// Multiplies the samples by the volume, ramping from the volume of the last chunk
+    fn apply_volume(system: &AudioSystem, samples: &mut [Sample]) {
+        let target = match system.muted.load() {
+            true => 0.,
+            false => system.volume.load(),
+        };
+
+        let from = system.applied_volume.swap(target);
+        let frames = samples.len() / CHANNEL_COUNT;
+
+        for (i, frame) in samples.chunks_mut(CHANNEL_COUNT).enumerate() {
+            let gain = from + (target - from) * (i + 1) as f32 / frames as f32;
+
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+
+    /// Reads the samples to play next, advancing the queue when tracks end.
+    /// Playing at a different speed reads more or less of the tracks to stretch into `buf`.
+    pub fn read_samples(system: &AudioSystem, buf: &mut [Sample]) {
+        if system.playback_state() == PlaybackState::Paused {
+            buf.fill(0.);
+            return;
+        }
+
+        let speed = system.speed.load();
+        let mut stretcher = system.stretcher.lock().unwrap();
+
+        if speed == 1. {
+            // What the stretcher read ahead plays first, so going back to normal skips nothing
+            let drained = stretcher.drain(buf);
+            read_tracks(system, &mut buf[drained..]);
+        } else {
+            let mut input = PLAYBACK_BUFFERS.borrow(stretcher.needed_input(buf.len(), speed));
+            read_tracks(system, &mut input);
+            stretcher.process(&input, buf, speed);
+        }
+
+        fade_in(system, buf);
+    }
+
+    /// Reads the samples of the tracks as they are
+    fn read_tracks(system: &AudioSystem, buf: &mut [Sample]) {
+        let mut amount_read = 0;
+        let mut finished = vec![];
+        let mut last_read = None;
+
+        // An A-B loop splits the read where it jumps back
+        while amount_read < buf.len() {
+            let amount = until_loop_end(system, buf.len() - amount_read);
+            let advancements = system.scheduler.advance(amount);
+            let start = amount_read;
+
+            for (i, (id, range)) in advancements.iter().enumerate() {
+                let end = amount_read + range.len();
+                let slice = &mut buf[amount_read..end];
+
+                let read = system.pool.read(*id, range.start, slice);
+                apply_track_gain(system, *id, &mut slice[..read]);
+                amount_read += read;
+                last_read = Some(*id);
+
+                // The loader has less than it was scheduled with, so continue from what it has
+                if read < range.len() && i + 1 == advancements.len() {
+                    system.scheduler.rewind(range.start + read);
+                }
+
+                if let Some(fade) = system.scheduler.fade().filter(|f| f.into == *id) {
+                    mix_fade(system, &fade, range.start, slice);
+                }
+            }
+
+            // Every loader but the last was played through
+            let played = advancements.len().saturating_sub(1);
+            finished.extend(advancements.iter().take(played).map(|(id, _)| *id));
+
+            if amount_read - start < amount {
+                break;
+            }
+        }
+
+        // The last loader of the queue has nothing to move on to, so it ends on its own
+        let last = system.scheduler.current();
+
+        if system.scheduler.is_finished() && last != system.queue_ended.load() {
+            finished.extend(last);
+        }
+
+        // Whatever is left over is silent instead of what the buffer had in it
+        buf[amount_read..].fill(0.);
+
+        // Running out of samples while there is more to play means loading is too slow
+        let underran = amount_read < buf.len() && !system.scheduler.is_finished();
+
+        if system.activity.record_read(underran) {
+            let missing = buf.len() - amount_read;
+            let track = last_read.and_then(|id| system.queue.find_by_loader(id));
+
+            warn!("Ran out of loaded samples to play, {} were silent", missing);
+            system.events.emit(AudioEvent::BufferUnderrun {
+                track_id: track.map(|t| t.id()),
+                missing,
+            });
+        }
+
+        for id in finished {
+            system.finish(id);
+        }
+    }
+
+    /// Returns how much can be read before the A-B loop jumps back,
+    /// jumping back first if playback is at the end of it.
+    fn until_loop_end(system: &AudioSystem, amount: usize) -> usize {
+        let ab_loop = match system.ab_loop.load() {
+            Some(ab_loop) => ab_loop,
+            None => return amount,
+        };
+
+        // The track changed without going through the queue, such as for a preview
+        if system.scheduler.current() != Some(ab_loop.loader) {
+            system.clear_ab_loop();
+            return amount;
+        }
+
+        if system.scheduler.offset() >= ab_loop.end {
+            system.scheduler.seek(ab_loop.start);
+        }
+
+        amount.min(ab_loop.end - system.scheduler.offset())
+    }
+
+    /// Returns the gain that normalizes a loader, or unity if it is not measured yet
+    fn track_gain(system: &AudioSystem, id: LoaderId) -> f32 {
+        system.pool.gain(id, system.normalization.load())
+    }
+
+    /// Multiplies the samples by the gain that normalizes a loader, ramping from the gain
+    /// it was last played with, as its loudness may only become known while it plays.
+    fn apply_track_gain(system: &AudioSystem, id: LoaderId, samples: &mut [Sample]) {
+        if samples.is_empty() {
+            return;
+        }
+
+        let target = track_gain(system, id);
+        let from = system.pool.swap_applied_gain(id, target).unwrap_or(target);
+
+        if from == target {
+            return apply_gain(samples, target);
+        }
+
+        let frames = (samples.len() / CHANNEL_COUNT).max(1);
+
+        for (i, frame) in samples.chunks_mut(CHANNEL_COUNT).enumerate() {
+            let gain = from + (target - from) * (i + 1) as f32 / frames as f32;
+
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+
+    fn apply_gain(samples: &mut [Sample], gain: f32) {
+        if gain == 1. {
+            return;
+        }
+
+        for sample in samples {
+            *sample *= gain;
+        }
+    }
+
+    /// Ramps up the samples after resuming from a pause,
+    /// as jumping straight to where the track was clicks.
+    fn fade_in(system: &AudioSystem, samples: &mut [Sample]) {
+        let position = match system.fading_in.load() {
+            Some(position) => position,
+            None => return,
+        };
+
+        let length = system.resume_fade.load();
+        let frames = length / CHANNEL_COUNT;
+
+        for (i, frame) in samples.chunks_mut(CHANNEL_COUNT).enumerate() {
+            let played = position / CHANNEL_COUNT + i;
+
+            if played >= frames {
+                break;
+            }
+
+            let gain = (played + 1) as f32 / frames as f32;
+
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+
+        let position = position + samples.len();
+        system
+            .fading_in
+            .store((position < length).then_some(position));
+    }
+
+    /// How far processing has fallen behind, as ticks that take longer
+    /// than a chunk cannot be made up for by sleeping less.
+    #[derive(Default)]
+    pub struct Drift {
+        behind: Duration,
+    }
+
+    /// Drift beyond this is handled according to the [DesyncPolicy]
+    const DESYNC_THRESHOLD: Duration = Duration::from_millis(500);
+
+    /// Keeps track of ticks that took longer than a chunk,
+    /// catching up if configured to once the drift is too large.
+    pub fn check_drift(system: &AudioSystem, drift: &mut Drift, elapsed: Duration) {
+        let chunk_duration = system.config.chunk_duration();
+        drift.behind += elapsed.saturating_sub(chunk_duration);
+
+        if drift.behind < DESYNC_THRESHOLD {
+            return;
+        }
+
+        let behind = mem::take(&mut drift.behind);
+        warn!("Audio processing is {}ms behind", behind.as_millis());
+
+        if system.desync_policy.load() == DesyncPolicy::DropChunks {
+            let chunks = behind.as_micros() / chunk_duration.as_micros();
+            let mut samples = PLAYBACK_BUFFERS.borrow(system.chunk_size());
+
+            for _ in 0..chunks {
+                read_samples(system, &mut samples);
+            }
+
+            info!("Dropped {} chunks to catch up", chunks);
+        }
+
+        system.events.emit(AudioEvent::Desync { behind });
+    }
+
+    /// Mixes the end of the previous loader into samples read at `offset`
+    /// of the loader that is faded into, using an equal power curve.
+    /// Each side fades over its own length, the previous one ending and the next one starting
+    /// where they overlap, so they are at equal power halfway if the lengths are the same.
+    fn mix_fade(system: &AudioSystem, fade: &Fade, offset: usize, buf: &mut [Sample]) {
+        let position = match offset.checked_sub(fade.into_start) {
+            Some(position) if position < fade.length => position,
+            _ => return,
+        };
+
+        let amount = buf.len().min(fade.length - position);
+        let mut tail = PLAYBACK_BUFFERS.borrow(amount);
+        let read = system
+            .pool
+            .read(fade.from, fade.start + position, &mut tail);
+        apply_track_gain(system, fade.from, &mut tail[..read]);
+
+        let fade_out_start = fade.length - fade.fade_out;
+
+        for (i, (sample, previous)) in buf.iter_mut().zip(tail.iter()).enumerate() {
+            // Both channels of a frame use the same gain
+            let frame = (position + i) / CHANNEL_COUNT * CHANNEL_COUNT;
+
+            let fade_in = match fade.fade_in {
+                0 => 1.,
+                length => (frame.min(length) as f32 / length as f32 * FRAC_PI_2).sin(),
+            };
+
+            let fade_out = match frame.checked_sub(fade_out_start) {
+                Some(frame) => (frame as f32 / fade.fade_out as f32 * FRAC_PI_2).cos(),
+                None => 1.,
+            };
+
+            *sample = *sample * fade_in + previous * fade_out;
+        }
+    }
+
+    fn wait_for_next<C: Clock>(system: &AudioSystem, clock: &C, now: Instant) {
+        let elapsed = clock.now().duration_since(now);
+        let elapsed_micros = elapsed.as_micros();
+        let elapsed_millis = elapsed_micros / 1000;
+
+        let duration_micros = system.config.chunk_duration().as_micros();
+
+        // Taking longer than the chunk plays for means the output falls behind
+        if elapsed_micros > duration_micros {
+            warn!(
+                "Stream took too long ({}ms) to process samples!",
+                elapsed_millis
+            )
+        }
+
+        let corrected = duration_micros
+            .checked_sub(elapsed_micros)
+            .unwrap_or_default();
+
+        clock.sleep(Duration::from_micros(corrected as u64));
+    }
+}
+
+mod loading_thread {
+    use std::{
+        sync::{Arc, Condvar, Mutex},
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use crossbeam::channel::{unbounded, Sender};
+    use log::{error, info};
+
+    use super::{AudioSystem, LoaderId, Pool, Scheduler};
+
+    /// How long the loading thread waits between requesting what is needed, unless set otherwise
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(500);
+
+    /// The shortest wait between requests, so the thread does not spin
+    pub const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+    // Starts the thread that will poll for load requests
+    pub fn start(sys: &AudioSystem) -> JoinHandle<()> {
+        let system = sys.clone();
+
+        thread::Builder::new()
+            .name("audio_loading".to_string())
+            .spawn(move || {
+                info!("Now listening for load requests",);
+
+                let workers = Workers::new(
+                    system.threads.decode_threads.load(),
+                    system.scheduler.clone(),
+                    system.pool.clone(),
+                );
+
+                while system.threads.is_running() {
+                    cycle(&system, &workers);
+
+                    // Unparked early when the system shuts down
+                    thread::park_timeout(system.threads.loading_interval.load());
+                }
+            })
+            .unwrap()
+    }
+
+    /// Requests what is needed next from the workers, which the loading thread does every interval
+    pub fn cycle(system: &AudioSystem, workers: &Workers) {
+        if system.is_idle() {
+            return;
+        }
+
+        system.apply_replacement();
+        system.autoplay_if_needed();
+
+        // Loading less than plays during a cycle would fall behind
+        let minimum = AudioSystem::samples_in(system.threads.loading_interval.load());
+        let budget = system.threads.load_budget.load().map(|b| b.max(minimum));
+        let mut requests = within_budget(system.scheduler.preload(), budget);
+
+        system.threads.last_load.store(total(&requests));
+        requests.extend(system.replacement_request());
+
+        workers.dispatch(requests);
+        system.report_duration_corrections();
+    }
+
+    /// Loads the samples the scheduler needs next on the calling thread,
+    /// for tests that only care about what plays
+    #[cfg(test)]
+    pub fn load_requested(scheduler: &Scheduler, pool: &Pool) {
+        for (id, amount) in scheduler.preload() {
+            let new_amount = pool.load(id, amount);
+            scheduler.notify_load(id, new_amount);
+        }
+    }
+
+    /// Cuts the requests off where they exceed the budget.
+    /// Requests come in order of need, so the current track is loaded first.
+    fn within_budget(
+        requests: Vec<(LoaderId, usize)>,
+        budget: Option<usize>,
+    ) -> Vec<(LoaderId, usize)> {
+        let mut remaining = match budget {
+            Some(budget) => budget,
+            None => return requests,
+        };
+
+        requests
+            .into_iter()
+            .map_while(|(id, amount)| {
+                if remaining == 0 {
+                    return None;
+                }
+
+                let amount = amount.min(remaining);
+                remaining -= amount;
+
+                Some((id, amount))
+            })
+            .collect()
+    }
+
+    fn total(requests: &[(LoaderId, usize)]) -> usize {
+        requests.iter().map(|(_, amount)| amount).sum()
+    }
+
+    /// A fixed amount of threads that decode, so a slow
+    /// decode does not hold up loading other sources.
+    pub struct Workers {
+        sender: Option<Sender<(LoaderId, usize)>>,
+        /// Loaders that are waiting for or being loaded by a worker,
+        /// and a condition that is notified whenever one is finished
+        in_flight: Arc<(Mutex<Vec<LoaderId>>, Condvar)>,
+        handles: Vec<JoinHandle<()>>,
+    }
+
+    impl Workers {
+        pub fn new(count: usize, scheduler: Arc<Scheduler>, pool: Arc<Pool>) -> Self {
+            let (sender, receiver) = unbounded::<(LoaderId, usize)>();
+            let in_flight: Arc<(Mutex<Vec<LoaderId>>, Condvar)> = Default::default();
+
+            let handles = (0..count.max(1))
+                .map(|i| {
+                    let receiver = receiver.clone();
+                    let in_flight = in_flight.clone();
+                    let scheduler = scheduler.clone();
+                    let pool = pool.clone();
+
+                    thread::Builder::new()
+                        .name(format!("audio_decode_{}", i))
+                        .spawn(move || {
+                            // This ends once the sender is dropped
+                            for (id, amount) in receiver.iter() {
+                                let new_amount = pool.load(id, amount);
+                                scheduler.notify_load(id, new_amount);
+
+                                let (loaders, finished) = &*in_flight;
+                                loaders.lock().unwrap().retain(|i| *i != id);
+                                finished.notify_all();
+                            }
+                        })
+                        .unwrap()
+                })
+                .collect();
+
+            Self {
+                sender: Some(sender),
+                in_flight,
+                handles,
+            }
+        }
+
+        /// Sends requests to the workers, unless the loader is already being loaded
+        pub fn dispatch(&self, requests: Vec<(LoaderId, usize)>) {
+            let mut in_flight = self.in_flight.0.lock().unwrap();
+
+            for (id, amount) in requests {
+                if amount == 0 || in_flight.contains(&id) {
+                    continue;
+                }
+
+                in_flight.push(id);
+
+                if let Some(sender) = &self.sender {
+                    sender.send((id, amount)).expect("Workers are running");
+                }
+            }
+        }
+
+        /// Returns the amount of loaders waiting for or being loaded by a worker
+        pub fn in_flight(&self) -> usize {
+            self.in_flight.0.lock().unwrap().len()
+        }
+
+        /// Waits until at most `amount` loaders are waiting for or being loaded by a worker
+        pub fn wait_until_in_flight(&self, amount: usize) {
+            let (loaders, finished) = &*self.in_flight;
+            let _loaders = finished
+                .wait_while(loaders.lock().unwrap(), |loaders| loaders.len() > amount)
+                .unwrap();
+        }
+
+        /// Waits until the workers finished every request that was dispatched
+        pub fn wait_until_idle(&self) {
+            self.wait_until_in_flight(0);
+        }
+    }
+
+    impl Drop for Workers {
+        fn drop(&mut self) {
+            self.sender.take();
+
+            for handle in self.handles.drain(..) {
+                if let Err(err) = handle.join() {
+                    error!("A decode thread panicked: {:?}", err);
+                }
+            }
+        }
+    }
+}
+
+mod config {
+    use std::{error::Error, fmt::Display, time::Duration};
+
+    use super::processing::ffmpeg::MAX_INPUT_SAMPLE_RATE;
+
+    pub type Sample = f32;
+
+    pub const SAMPLE_RATE: usize = 44100;
+    pub const CHANNEL_COUNT: usize = 2;
+
+    pub const SAMPLE_IN_BYTES: usize = 4;
+    pub const SAMPLES_PER_SEC: usize = SAMPLE_RATE * CHANNEL_COUNT;
+    pub const BYTES_PER_SAMPLE: usize = 4 * SAMPLE_IN_BYTES;
+
+    pub const STREAM_CHUNK_DURATION: Duration = Duration::from_millis(100);
+    pub const STREAM_CHUNK_SIZE: usize =
+        (((SAMPLES_PER_SEC as u128) * STREAM_CHUNK_DURATION.as_millis()) / 1000) as usize;
+
+    /// The most channels the output can have
+    pub const MAX_CHANNEL_COUNT: usize = 8;
+
+    /// The format the output is streamed in, see [super::AudioSystem::with_config].
+    /// Tracks are always decoded and mixed at [SAMPLE_RATE] with [CHANNEL_COUNT] channels,
+    /// and converted to this as they are written to streams.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct AudioConfig {
+        sample_rate: usize,
+        channel_count: usize,
+        chunk_duration: Duration,
+    }
+
+    impl AudioConfig {
+        /// Streams what is mixed as it is, in chunks of [STREAM_CHUNK_DURATION]
+        pub const DEFAULT: Self = Self {
+            sample_rate: SAMPLE_RATE,
+            channel_count: CHANNEL_COUNT,
+            chunk_duration: STREAM_CHUNK_DURATION,
+        };
+
+        /// The shortest and longest a chunk can be
+        pub const CHUNK_DURATIONS: (Duration, Duration) =
+            (Duration::from_millis(10), Duration::from_secs(1));
+
+        pub fn new(
+            sample_rate: usize,
+            channel_count: usize,
+            chunk_duration: Duration,
+        ) -> Result<Self, InvalidConfig> {
+            let (shortest, longest) = Self::CHUNK_DURATIONS;
+
+            if !(1..=MAX_INPUT_SAMPLE_RATE).contains(&sample_rate) {
+                return Err(InvalidConfig::SampleRate(sample_rate));
+            }
+
+            if !(1..=MAX_CHANNEL_COUNT).contains(&channel_count) {
+                return Err(InvalidConfig::ChannelCount(channel_count));
+            }
+
+            if chunk_duration < shortest || chunk_duration > longest {
+                return Err(InvalidConfig::ChunkDuration(chunk_duration));
+            }
+
+            Ok(Self {
+                sample_rate,
+                channel_count,
+                chunk_duration,
+            })
+        }
+
+        pub fn sample_rate(&self) -> usize {
+            self.sample_rate
+        }
+
+        pub fn channel_count(&self) -> usize {
+            self.channel_count
+        }
+
+        /// Returns how long each chunk that is processed and streamed at once plays for
+        pub fn chunk_duration(&self) -> Duration {
+            self.chunk_duration
+        }
+
+        pub fn samples_per_sec(&self) -> usize {
+            self.sample_rate * self.channel_count
+        }
+
+        /// Returns the amount of samples streamed for every chunk, in whole frames
+        pub fn chunk_size(&self) -> usize {
+            let frames = self.sample_rate as u128 * self.chunk_duration.as_micros() / 1_000_000;
+            frames as usize * self.channel_count
+        }
+
+        /// Returns the mime type of raw streams in this format
+        pub fn pcm_mime(&self) -> String {
+            format!("audio/pcm;rate={};encoding=float;bits=32", self.sample_rate)
+        }
+
+        /// Returns true if what is mixed is streamed without converting it
+        pub fn is_native(&self) -> bool {
+            self.sample_rate == SAMPLE_RATE && self.channel_count == CHANNEL_COUNT
+        }
+    }
+
+    impl Default for AudioConfig {
+        fn default() -> Self {
+            Self::DEFAULT
+        }
+    }
+
+    /// Part of an [AudioConfig] is out of the range that can be streamed
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum InvalidConfig {
+        SampleRate(usize),
+        ChannelCount(usize),
+        ChunkDuration(Duration),
+    }
+
+    impl Display for InvalidConfig {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::SampleRate(rate) => write!(
+                    f,
+                    "A sample rate of {} Hz is not supported, the supported range is 1 to {} Hz",
+                    rate, MAX_INPUT_SAMPLE_RATE
+                ),
+                Self::ChannelCount(count) => write!(
+                    f,
+                    "{} channels are not supported, the supported range is 1 to {}",
+                    count, MAX_CHANNEL_COUNT
+                ),
+                Self::ChunkDuration(duration) => {
+                    let (shortest, longest) = AudioConfig::CHUNK_DURATIONS;
+
+                    write!(
+                        f,
+                        "Chunks of {}ms are not supported, the supported range is {}ms to {}ms",
+                        duration.as_millis(),
+                        shortest.as_millis(),
+                        longest.as_millis()
+                    )
+                }
+            }
+        }
+    }
+
+    impl Error for InvalidConfig {}
+}
+
+pub use config::*;
+
+use crate::util::model::Identified;
+
+use self::pipeline::{Clip, IntoSampleReader};
+use self::resampling::OutputConverter;
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs, process,
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crossbeam::channel::{bounded, Receiver};
+
+    use super::{
+        loading_thread,
+        pipeline::{Clip, IntoSampleReader, SampleReader, SamplesRead},
+        playback_thread::{self, Clock, MockClock},
+        processing,
+        queuing::QueueEvent,
+        testing,
+        track::{Metadata, TrackMetaOverrides},
+        AbLoopError, AudioConfig, AudioEvent, AudioSystem, AudioSystemHandle, Autoplay,
+        DesyncPolicy, Effect, EqBand, Input, InputFactory, InvalidConfig, LoadFailure, Loader,
+        OutputFormat, PlaybackState, Pool, RepeatMode, SampleFormat, Scheduler, SeekError,
+        SeekPolicy, ServedStats, SettingsStore, Track, TrackCrossfade, TrackId, MAX_SPEED,
+        MAX_VOLUME, SAMPLES_PER_SEC, SAMPLE_RATE, SAVE_DELAY, STREAM_CHUNK_DURATION,
+        STREAM_CHUNK_SIZE,
+    };
+    use crate::util::model::Identified;
+
+    /// Adds a track which plays the given samples
+    fn add_samples(system: &AudioSystem, title: &str, samples: Vec<f32>) {
+        let length = samples.len();
+        let loader = system.pool.add(samples.into_sample_reader(), length);
+
+        system.add_loader(loader, title.to_string());
+    }
+
+    /// Loads everything that is requested, then returns the next chunks of samples
+    fn play(system: &AudioSystem, chunks: usize) -> Vec<f32> {
+        let mut samples = vec![];
+
+        for _ in 0..chunks {
+            loading_thread::load_requested(&system.scheduler, &system.pool);
+
+            let mut buf = vec![0.; STREAM_CHUNK_SIZE];
+            playback_thread::read_samples(system, &mut buf);
+
+            samples.extend(buf);
+        }
+
+        samples
+    }
+
+    #[test]
+    fn silence_plays_for_its_duration() {
+        let system = AudioSystem::new();
+
+        system.add_silence(Duration::from_secs(1)).unwrap();
+        add_samples(&system, "Ones", vec![1.; 10]);
+
+        let samples = play(&system, 11);
+        let silent = samples.iter().position(|s| *s != 0.).unwrap();
+
+        assert_eq!(silent, SAMPLES_PER_SEC);
+        assert_eq!(&samples[silent..silent + 10], &[1.; 10]);
+    }
+
+    #[test]
+    fn trimmed_silence_is_not_played() {
+        let system = AudioSystem::new();
+        system.set_trim_boundaries(true);
+
+        add_samples(&system, "First", vec![1., 1., 0., 0.]);
+        add_samples(&system, "Second", vec![0., 0., 2., 2.]);
+
+        let samples = play(&system, 1);
+        assert_eq!(&samples[..4], &[1., 1., 2., 2.]);
+    }
+
+    #[test]
+    fn crossfaded_tracks_are_mixed() {
+        let system = AudioSystem::new();
+        system.scheduler.set_crossfade(8);
+
+        add_samples(&system, "First", vec![1.; 16]);
+        add_samples(&system, "Second", vec![1.; 16]);
+
+        let samples = play(&system, 1);
+        let audible = samples.iter().filter(|s| **s != 0.).count();
+
+        // The tracks overlap, so they play for a shorter time
+        assert_eq!(audible, 24);
+
+        // Halfway through the fade, both tracks are at equal power
+        let halfway = samples[12];
+        assert!((halfway - 2_f32.sqrt()).abs() < 0.001, "{}", halfway);
+    }
+
+    #[test]
+    fn tracks_crossfade_for_their_own_lengths() {
+        let system = AudioSystem::new();
+
+        let add = |title: &str, sample: f32, crossfade| {
+            let loader = system.pool.add(
+                vec![sample; SAMPLES_PER_SEC].into_sample_reader(),
+                SAMPLES_PER_SEC,
+            );
+            let track = Track::new(loader, title.to_string()).with_crossfade(crossfade);
+
+            system.add_track(track);
+        };
+
+        // Crossfading is disabled, but these tracks fade anyway
+        add(
+            "Long fade out",
+            1.,
+            TrackCrossfade {
+                fade_out: Some(Duration::from_millis(500)),
+                ..Default::default()
+            },
+        );
+        add(
+            "Short fade in",
+            2.,
+            TrackCrossfade {
+                fade_in: Some(Duration::from_millis(250)),
+                ..Default::default()
+            },
+        );
+
+        let samples = play(&system, 16);
+        let (fade_out, fade_in) = (SAMPLES_PER_SEC / 2, SAMPLES_PER_SEC / 4);
+
+        let fade = system.scheduler.fade().unwrap();
+        assert_eq!(
+            (fade.length, fade.fade_out, fade.fade_in),
+            (fade_out, fade_out, fade_in)
+        );
+
+        // They overlap for the longer of the two fades
+        let audible = samples.iter().filter(|s| **s != 0.).count();
+        assert_eq!(audible, SAMPLES_PER_SEC * 2 - fade_out);
+
+        // The second track has faded in while the first is halfway through fading out
+        let overlap = SAMPLES_PER_SEC - fade_out;
+        let faded_in = samples[overlap + fade_in];
+        assert!(
+            (faded_in - (2. + 0.5_f32.sqrt())).abs() < 0.001,
+            "{}",
+            faded_in
+        );
+    }
+
+    #[test]
+    fn slow_ticks_are_caught_up_with() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        system.set_desync_policy(DesyncPolicy::DropChunks);
+
+        let samples: Vec<_> = (0..STREAM_CHUNK_SIZE * 10).map(|i| i as f32).collect();
+        add_samples(&system, "Ramp", samples);
+        play(&system, 1);
+
+        // Ignore the events from adding the track
+        while events.try_wait().is_some() {}
+
+        let mut drift = playback_thread::Drift::default();
+        let slow = STREAM_CHUNK_DURATION * 3 / 2;
+
+        // Each tick is half a chunk late, so it takes 10 ticks to be 500ms behind
+        for _ in 0..9 {
+            playback_thread::check_drift(&system, &mut drift, slow);
+        }
+
+        assert!(events.try_wait().is_none());
+        playback_thread::check_drift(&system, &mut drift, slow);
+
+        let behind = std::iter::from_fn(|| events.try_wait())
+            .find_map(|e| match e {
+                AudioEvent::Desync { behind } => Some(behind),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(behind, STREAM_CHUNK_DURATION * 5);
+
+        // The chunks that should have played were skipped
+        let next = play(&system, 1);
+        assert_eq!(next[0], (STREAM_CHUNK_SIZE * 6) as f32);
+    }
+
+    #[test]
+    fn slow_ticks_are_detected_with_a_mock_clock() {
+        /// Takes a fixed amount of time on the mock clock to process
+        struct Slow(Arc<MockClock>, Duration);
+
+        impl Effect for Slow {
+            fn name(&self) -> &'static str {
+                "Slow"
+            }
+
+            fn cost(&self) -> usize {
+                0
+            }
+
+            fn process(&mut self, _: &mut [f32]) {
+                self.0.advance(self.1);
+            }
+        }
+
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        let clock = Arc::new(MockClock::new());
+        let mut drift = playback_thread::Drift::default();
+
+        // A fast tick sleeps for the rest of the chunk
+        let start = clock.now();
+        playback_thread::step(&system, &*clock, &mut drift);
+        assert_eq!(clock.now() - start, STREAM_CHUNK_DURATION);
+
+        let slow = Slow(clock.clone(), STREAM_CHUNK_DURATION * 3 / 2);
+        system.add_effect(slow).unwrap();
+
+        // Each tick is half a chunk late, so it takes 10 ticks to be 500ms behind
+        for _ in 0..9 {
+            playback_thread::step(&system, &*clock, &mut drift);
+        }
+
+        assert!(events.try_wait().is_none());
+        playback_thread::step(&system, &*clock, &mut drift);
+
+        match events.try_wait() {
+            Some(AudioEvent::Desync { behind }) => assert_eq!(behind, STREAM_CHUNK_DURATION * 5),
+            _ => panic!("Expected a desync event"),
+        }
+
+        // Slow ticks do not sleep at all
+        assert_eq!(clock.now() - start, STREAM_CHUNK_DURATION * 16);
+    }
+
+    #[test]
+    fn previews_return_to_where_the_track_was() {
+        let system = AudioSystem::new();
+
+        let music: Vec<_> = (0..STREAM_CHUNK_SIZE * 4).map(|i| i as f32).collect();
+        add_samples(&system, "Music", music);
+        play(&system, 1);
+
+        // A chunk from the middle of another track
+        let other: Vec<_> = (0..STREAM_CHUNK_SIZE * 4).map(|i| -(i as f32)).collect();
+        let clip = Clip::new(
+            other.into_sample_reader(),
+            STREAM_CHUNK_SIZE * 2,
+            STREAM_CHUNK_SIZE,
+        );
+
+        let loader = system.pool.add(clip, STREAM_CHUNK_SIZE);
+        system.preview_loader(loader, "Preview".to_string());
+
+        let snippet: Vec<_> = (STREAM_CHUNK_SIZE * 2..STREAM_CHUNK_SIZE * 3)
+            .map(|i| -(i as f32))
+            .collect();
+        let resumed: Vec<_> = (STREAM_CHUNK_SIZE..STREAM_CHUNK_SIZE * 2)
+            .map(|i| i as f32)
+            .collect();
+
+        // Only the snippet plays, then the music continues where it was
+        assert_eq!(play(&system, 2), [snippet, resumed].concat());
+
+        assert!(system.preview.lock().unwrap().is_none());
+        assert_eq!(system.current_track().unwrap().title, "Music");
+    }
+
+    #[test]
+    fn started_tracks_include_the_requester() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        let loader = system.pool.add(vec![1.; 4].into_sample_reader(), 4);
+        system.add_track(Track::new(loader, "Requested".to_string()).with_requester(42));
+
+        let started = std::iter::from_fn(|| events.try_wait())
+            .find_map(|e| match e {
+                AudioEvent::TrackStarted(track) => Some(track),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(started.title, "Requested");
+        assert_eq!(started.requested_by, Some(42));
+        assert_eq!(system.current_track().unwrap().requested_by, Some(42));
+    }
+
+    #[test]
+    fn position_restarts_with_every_track() {
+        let system = AudioSystem::new();
+        assert!(system.position().is_none());
+        assert!(system.now_playing().is_none());
+
+        add_samples(&system, "First", vec![0.; SAMPLES_PER_SEC * 2]);
+        add_samples(&system, "Second", vec![0.; SAMPLES_PER_SEC * 3]);
+
+        play(&system, 4);
+        assert_eq!(system.position(), Some(STREAM_CHUNK_DURATION * 4));
+
+        let (track, duration) = system.now_playing().unwrap();
+        assert_eq!(track.title(), "First");
+        assert_eq!(duration, Duration::from_secs(2));
+
+        system.next();
+        assert_eq!(system.position(), Some(Duration::ZERO));
+
+        let (track, duration) = system.now_playing().unwrap();
+        assert_eq!(track.title(), "Second");
+        assert_eq!(duration, Duration::from_secs(3));
+
+        play(&system, 2);
+        assert_eq!(system.position(), Some(STREAM_CHUNK_DURATION * 2));
+
+        // The queue wraps around to the first track
+        system.next();
+        assert_eq!(system.position(), Some(Duration::ZERO));
+        assert_eq!(system.now_playing().unwrap().0.title(), "First");
+
+        system.panic_stop();
+        assert!(system.position().is_none());
+        assert!(system.now_playing().is_none());
+    }
+
+    #[test]
+    fn tracks_play_for_as_long_as_they_actually_are() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        // Declares a second, but goes on for three
+        let longer = vec![1.; SAMPLES_PER_SEC * 3].into_sample_reader();
+        let longer = system.pool.add(longer, SAMPLES_PER_SEC);
+        system.add_loader(longer.clone(), "Longer".to_string());
+
+        // Declares three seconds, but ends after one
+        let shorter = vec![0.5; SAMPLES_PER_SEC].into_sample_reader();
+        let shorter = system.pool.add(shorter, SAMPLES_PER_SEC * 3);
+        system.add_loader(shorter.clone(), "Shorter".to_string());
+
+        assert!(play(&system, 30).iter().all(|s| *s == 1.));
+        assert!(play(&system, 10).iter().all(|s| *s == 0.5));
+        assert_eq!(longer.expected(), SAMPLES_PER_SEC * 3);
+        assert_eq!(shorter.expected(), SAMPLES_PER_SEC);
+
+        system.report_duration_corrections();
+
+        let corrected: Vec<_> = std::iter::from_fn(|| events.try_wait())
+            .filter_map(|e| match e {
+                AudioEvent::DurationCorrected {
+                    track_id,
+                    declared,
+                    actual,
+                } => Some((track_id, declared.as_secs(), actual.as_secs())),
+                _ => None,
+            })
+            .collect();
+
+        let track_of = |loader: &Arc<Loader>| system.queue.find_by_loader(loader.id()).unwrap();
+        assert_eq!(
+            corrected,
+            vec![
+                (track_of(&longer).id(), 1, 3),
+                (track_of(&shorter).id(), 3, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn faster_playback_reaches_the_next_track_sooner() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        add_samples(&system, "First", vec![1.; SAMPLES_PER_SEC * 2]);
+        add_samples(&system, "Second", vec![0.5; SAMPLES_PER_SEC * 2]);
+
+        system.set_speed(4.);
+        assert_eq!(system.speed(), MAX_SPEED);
+
+        // Half a second plays a second of the track
+        play(&system, 5);
+        let position = system.position().unwrap();
+        assert!(position >= Duration::from_secs(1) && position < Duration::from_millis(1050));
+
+        // The tracks follow each other without a gap
+        let played = play(&system, 10);
+        assert!(played.iter().all(|s| (0.49..=1.01).contains(s)));
+        assert_eq!(system.current_track().unwrap().title, "Second");
+        assert!(std::iter::from_fn(|| events.try_wait())
+            .any(|e| matches!(e, AudioEvent::TrackEnded(_))));
+
+        // Going back to normal plays what was read ahead first, then goes on from there
+        system.set_speed(1.);
+        play(&system, 5);
+
+        let elapsed = system.position().unwrap() - position - Duration::from_secs(2);
+        assert!(elapsed > Duration::from_millis(450) && elapsed <= Duration::from_millis(500));
+        assert!(play(&system, 1).iter().all(|s| *s == 0.5));
+    }
+
+    #[test]
+    fn previous_goes_back_early_in_a_track_and_restarts_it_later() {
+        let system = AudioSystem::new();
+
+        add_samples(&system, "First", vec![0.; SAMPLES_PER_SEC * 2]);
+        add_samples(&system, "Second", vec![0.; SAMPLES_PER_SEC * 2]);
+
+        // Nothing comes before the first track, so it restarts
+        play(&system, 2);
+        system.previous();
+        assert_eq!(system.current_track().unwrap().title, "First");
+        assert_eq!(system.position(), Some(Duration::ZERO));
+
+        system.next();
+        play(&system, 2);
+        system.previous();
+        assert_eq!(system.current_track().unwrap().title, "First");
+        assert_eq!(system.position(), Some(Duration::ZERO));
+
+        // Past the threshold, the current track restarts
+        system.next();
+        system.set_previous_threshold(STREAM_CHUNK_DURATION);
+        play(&system, 2);
+        system.previous();
+        assert_eq!(system.current_track().unwrap().title, "Second");
+        assert_eq!(system.position(), Some(Duration::ZERO));
+
+        // The last track comes before the first when the queue repeats
+        system.set_repeat(RepeatMode::Queue);
+        system.next();
+        system.previous();
+        assert_eq!(system.current_track().unwrap().title, "Second");
+    }
+
+    #[test]
+    fn seeking_moves_the_position() {
+        let system = AudioSystem::new();
+        assert_eq!(
+            system.seek(Duration::from_secs(1)),
+            Err(SeekError::NothingPlaying)
+        );
+
+        let ramp = (0..SAMPLES_PER_SEC * 2).map(|i| i as f32).collect();
+        add_samples(&system, "First", ramp);
+        add_samples(&system, "Second", vec![0.; SAMPLES_PER_SEC]);
+
+        play(&system, 1);
+
+        assert!(system.seek(Duration::from_secs(1)).is_ok());
+        assert_eq!(system.position(), Some(Duration::from_secs(1)));
+
+        let played = play(&system, 1);
+        assert_eq!(played[0], SAMPLES_PER_SEC as f32);
+        assert_eq!(
+            system.position(),
+            Some(Duration::from_secs(1) + STREAM_CHUNK_DURATION)
+        );
+
+        // Seeking past the end moves on to the next track
+        assert!(system.seek(Duration::from_secs(5)).is_ok());
+        assert_eq!(system.current_track().unwrap().title, "Second");
+        assert_eq!(system.position(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn skipping_forward_moves_from_the_position() {
+        let system = AudioSystem::new();
+        assert_eq!(
+            system.skip_forward(Duration::from_secs(1)),
+            Err(SeekError::NothingPlaying)
+        );
+
+        let ramp = (0..SAMPLES_PER_SEC * 2).map(|i| i as f32).collect();
+        add_samples(&system, "First", ramp);
+        add_samples(&system, "Second", vec![0.; SAMPLES_PER_SEC]);
+
+        play(&system, 1);
+
+        assert!(system.skip_forward(Duration::from_secs(1)).is_ok());
+        assert_eq!(
+            system.position(),
+            Some(Duration::from_secs(1) + STREAM_CHUNK_DURATION)
+        );
+
+        let played = play(&system, 1);
+        assert_eq!(played[0], (SAMPLES_PER_SEC + STREAM_CHUNK_SIZE) as f32);
+
+        // Skipping past the end moves on to the next track
+        assert!(system.skip_forward(Duration::from_secs(1)).is_ok());
+        assert_eq!(system.current_track().unwrap().title, "Second");
+        assert_eq!(system.position(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn seeking_past_what_is_loaded_waits_for_it() {
+        /// A stream that decodes a ramp, a few seconds at a time
+        struct Trickle(usize);
+
+        impl SampleReader for Trickle {
+            fn read_samples(&mut self, buf: &mut [f32]) -> SamplesRead {
+                let amount = buf.len().min(SAMPLES_PER_SEC * 5);
+
+                for (i, sample) in buf[..amount].iter_mut().enumerate() {
+                    *sample = (self.0 + i) as f32;
+                }
+
+                self.0 += amount;
+                SamplesRead::More(amount)
+            }
+        }
+
+        let system = AudioSystem::new();
+        let loader = system.pool.add(Trickle(0), SAMPLES_PER_SEC * 60);
+        system.add_loader(loader.clone(), "Stream".to_string());
+
+        play(&system, 1);
+        assert!(loader.available() < SAMPLES_PER_SEC * 40);
+
+        // Streams cannot jump ahead, so this is refused
+        system.set_seek_policy(SeekPolicy::Refuse);
+        assert_eq!(
+            system.seek(Duration::from_secs(40)),
+            Err(SeekError::NotLoaded)
+        );
+        assert!(system.seek(Duration::from_secs(1)).is_ok());
+
+        // Silence plays until the stream has been decoded up to the position
+        system.set_seek_policy(SeekPolicy::Wait);
+        assert!(system.seek(Duration::from_secs(40)).is_ok());
+        assert!(play(&system, 1).iter().all(|s| *s == 0.));
+        assert_eq!(system.position(), Some(Duration::from_secs(40)));
+
+        let resumed = (0..10)
+            .map(|_| play(&system, 1))
+            .find(|chunk| chunk[0] != 0.)
+            .expect("Playback resumes");
+
+        assert_eq!(resumed[0], (SAMPLES_PER_SEC * 40) as f32);
+    }
+
+    #[test]
+    fn ab_loops_repeat_part_of_the_track() {
+        let system = AudioSystem::new();
+        let (a, b) = (Duration::from_millis(100), Duration::from_millis(250));
+
+        assert_eq!(system.set_ab_loop(a, b), Err(AbLoopError::NothingPlaying));
+
+        let ramp = (0..SAMPLES_PER_SEC * 2).map(|i| i as f32).collect();
+        add_samples(&system, "Practice", ramp);
+
+        assert_eq!(system.set_ab_loop(b, a), Err(AbLoopError::InvalidRange));
+        assert_eq!(
+            system.set_ab_loop(a, Duration::from_secs(3)),
+            Err(AbLoopError::InvalidRange)
+        );
+        assert!(system.set_ab_loop(a, b).is_ok());
+
+        let (start, end) = (AudioSystem::samples_in(a), AudioSystem::samples_in(b));
+        let played = play(&system, 6);
+
+        // Playing up to B, then twice through the loop and a bit more
+        let wraps = played
+            .windows(2)
+            .filter(|w| w[0] == (end - 1) as f32 && w[1] == start as f32)
+            .count();
+
+        assert_eq!(wraps, 3);
+        assert!(played.iter().all(|s| *s < end as f32));
+
+        system.clear_ab_loop();
+
+        let played = play(&system, 2);
+        assert!(played.iter().any(|s| *s >= end as f32));
+    }
+
+    #[test]
+    fn scheduled_stops_happen_on_time() {
+        let system = AudioSystem::new();
+        let clock = MockClock::new();
+        let mut drift = playback_thread::Drift::default();
+
+        add_samples(&system, "Show", vec![0.; SAMPLES_PER_SEC * 10]);
+        loading_thread::load_requested(&system.scheduler, &system.pool);
+
+        let pause = system.schedule_pause(Duration::from_millis(200));
+        system.schedule_stop(Duration::from_millis(500));
+        assert!(system.cancel_timer(pause));
+        assert!(!system.cancel_timer(pause));
+
+        let mut steps = 0;
+
+        while system.current_track().is_some() && steps < 20 {
+            playback_thread::step(&system, &clock, &mut drift);
+            steps += 1;
+        }
+
+        // Every step moves the clock by a chunk
+        assert!((6..=7).contains(&steps), "Stopped after {} steps", steps);
+        assert_eq!(system.playback_state(), PlaybackState::Playing);
+    }
+
+    #[test]
+    fn removed_tracks_are_not_played() {
+        let system = AudioSystem::new();
+
+        add_samples(&system, "First", vec![0.1; STREAM_CHUNK_SIZE * 2]);
+        add_samples(&system, "Second", vec![0.2; STREAM_CHUNK_SIZE]);
+        add_samples(&system, "Third", vec![0.3; STREAM_CHUNK_SIZE]);
+
+        let ids: Vec<_> = system.queue.peek_ahead(3).iter().map(|t| t.id()).collect();
+
+        // Removing an upcoming track reschedules what plays after the current one
+        let removed = system.remove(ids[1]).unwrap();
+        assert_eq!(removed.title, "Second");
+        assert!(system.remove(ids[1]).is_none());
+
+        let samples = play(&system, 3);
+        assert_eq!(samples[STREAM_CHUNK_SIZE * 2], 0.3);
+
+        // Removing the current track skips to the next one from its start
+        system.remove(ids[2]);
+        assert_eq!(system.current_track().unwrap().title, "First");
+        assert_eq!(system.position(), Some(Duration::ZERO));
+        assert_eq!(play(&system, 1)[0], 0.1);
+    }
+
+    #[test]
+    fn clearing_the_queue_releases_the_loaders() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        let loaders: Vec<_> = (0..4)
+            .map(|i| {
+                let samples = vec![1.; STREAM_CHUNK_SIZE * 2];
+                let loader = system
+                    .pool
+                    .add(samples.into_sample_reader(), STREAM_CHUNK_SIZE * 2);
+                let weak = Arc::downgrade(&loader);
+
+                system.add_loader(loader, format!("Track {}", i));
+                weak
+            })
+            .collect();
+
+        play(&system, 1);
+        while events.try_wait().is_some() {}
+
+        // Only the current track is kept
+        system.clear(false);
+        assert_eq!(system.queue.peek_ahead(3).len(), 1);
+        assert_eq!(system.current_track().unwrap().title, "Track 0");
+
+        let updates = std::iter::from_fn(|| events.try_wait())
+            .filter(|e| matches!(e, AudioEvent::Queue(QueueEvent::Update)))
+            .count();
+        assert_eq!(updates, 1);
+
+        assert!(loaders[0].upgrade().is_some());
+        assert!(loaders[1..].iter().all(|l| l.upgrade().is_none()));
+
+        system.clear(true);
+        assert!(system.queue.peek_ahead(3).is_empty());
+        assert!(loaders[0].upgrade().is_none());
+        assert_eq!(play(&system, 1), vec![0.; STREAM_CHUNK_SIZE]);
+    }
+
+    #[test]
+    fn moved_tracks_are_scheduled_in_their_new_place() {
+        let system = AudioSystem::new();
+
+        for i in 0..5 {
+            add_samples(&system, &i.to_string(), vec![i as f32; STREAM_CHUNK_SIZE]);
+        }
+
+        let tracks = system.queue.peek_ahead(5);
+        let titles = |system: &AudioSystem| -> Vec<_> {
+            system
+                .queue
+                .peek_ahead(5)
+                .iter()
+                .map(|t| t.title().to_string())
+                .collect()
+        };
+
+        // The last track is moved into the preloaded tracks
+        assert!(system.move_track(tracks[4].id(), 1));
+        assert_eq!(titles(&system), ["0", "4", "1", "2", "3"]);
+
+        let preloading: Vec<_> = system.scheduler.preload().iter().map(|r| r.0).collect();
+        assert!(preloading.contains(&tracks[4].loader.id()));
+        assert!(!preloading.contains(&tracks[3].loader.id()));
+
+        // Indices past the end move the track to the end
+        assert!(system.move_track(tracks[1].id(), 10));
+        assert_eq!(titles(&system), ["0", "4", "2", "3", "1"]);
+
+        // The current track stays current when moved
+        assert!(system.move_track(tracks[0].id(), 2));
+        assert_eq!(system.current_track().unwrap().title, "0");
+        assert_eq!(titles(&system), ["0", "3", "1"]);
+
+        assert!(system.move_track(tracks[0].id(), 0));
+        assert_eq!(titles(&system), ["0", "4", "2", "3", "1"]);
+
+        let samples = play(&system, 2);
+        assert_eq!(samples[STREAM_CHUNK_SIZE..], [4.; STREAM_CHUNK_SIZE]);
+        assert_eq!(system.current_track().unwrap().title, "4");
+
+        assert!(!system.move_track(TrackId::new(), 0));
+    }
+
+    #[test]
+    fn requeued_tracks_come_around_again() {
+        let system = AudioSystem::new();
+
+        for title in ["First", "Second", "Third"] {
+            add_samples(&system, title, vec![0.; STREAM_CHUNK_SIZE * 2]);
+        }
+
+        let titles = |system: &AudioSystem| -> Vec<_> {
+            system
+                .queue
+                .peek_ahead(3)
+                .iter()
+                .map(|t| t.title().to_string())
+                .collect()
+        };
+
+        play(&system, 1);
+        system.next_requeue();
+
+        assert_eq!(titles(&system), ["Second", "Third", "First"]);
+        assert_eq!(system.position(), Some(Duration::ZERO));
+
+        // The skipped track plays again after the others
+        play(&system, 5);
+        assert_eq!(system.current_track().unwrap().title, "First");
+    }
+
+    #[test]
+    fn repeated_tracks_roll_over_to_their_start() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        let counting: Vec<_> = (0..STREAM_CHUNK_SIZE * 3 / 2).map(|i| i as f32).collect();
+        add_samples(&system, "Counting", counting.clone());
+        add_samples(&system, "Next", vec![-1.; STREAM_CHUNK_SIZE]);
+
+        system.set_repeat(RepeatMode::Track);
+        system.set_repeat(RepeatMode::Track);
+
+        let samples = play(&system, 2);
+        let half = STREAM_CHUNK_SIZE / 2;
+
+        // The track starts over in the middle of a chunk instead of moving on
+        assert_eq!(&samples[..STREAM_CHUNK_SIZE * 3 / 2], &counting[..]);
+        assert_eq!(&samples[STREAM_CHUNK_SIZE * 3 / 2..], &counting[..half]);
+        assert_eq!(system.current_track().unwrap().title, "Counting");
+        assert_eq!(system.scheduler.offset(), half);
+
+        // Skipping still moves on
+        system.next();
+        assert_eq!(system.current_track().unwrap().title, "Next");
+
+        let changes = std::iter::from_fn(|| events.try_wait())
+            .filter(|e| matches!(e, AudioEvent::Repeat(RepeatMode::Track)))
+            .count();
+        assert_eq!(changes, 1);
+    }
+
+    #[test]
+    fn tracks_repeated_once_play_twice() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        add_samples(&system, "Again", vec![1.; STREAM_CHUNK_SIZE]);
+        add_samples(&system, "Next", vec![2.; STREAM_CHUNK_SIZE]);
+        system.set_repeat(RepeatMode::TrackOnce);
+
+        let samples = play(&system, 3);
+        let chunks: Vec<_> = samples.chunks(STREAM_CHUNK_SIZE).collect();
+
+        assert_eq!(chunks[0], &[1.; STREAM_CHUNK_SIZE]);
+        assert_eq!(chunks[1], &[1.; STREAM_CHUNK_SIZE]);
+        assert_eq!(chunks[2], &[2.; STREAM_CHUNK_SIZE]);
+
+        assert_eq!(system.current_track().unwrap().title, "Next");
+        assert_eq!(system.repeat(), RepeatMode::Off);
+
+        let changes: Vec<_> = std::iter::from_fn(|| events.try_wait())
+            .filter_map(|e| match e {
+                AudioEvent::Repeat(mode) => Some(mode),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(changes, [RepeatMode::TrackOnce, RepeatMode::Off]);
+    }
+
+    #[test]
+    fn repeated_queues_start_over_after_the_last_track() {
+        let system = AudioSystem::new();
+        system.set_repeat(RepeatMode::Queue);
+
+        add_samples(&system, "First", vec![1.; STREAM_CHUNK_SIZE]);
+        add_samples(&system, "Second", vec![2.; STREAM_CHUNK_SIZE]);
+
+        let samples = play(&system, 3);
+        let chunks: Vec<_> = samples.chunks(STREAM_CHUNK_SIZE).collect();
+
+        assert_eq!(chunks[1], &[2.; STREAM_CHUNK_SIZE]);
+        assert_eq!(chunks[2], &[1.; STREAM_CHUNK_SIZE]);
+        assert_eq!(system.current_track().unwrap().title, "First");
+        assert_eq!(system.queue.peek_ahead(3).len(), 2);
+
+        // Without repeating, the last track is followed by silence
+        system.set_repeat(RepeatMode::Off);
+
+        let samples = play(&system, 2);
+        assert_eq!(&samples[..STREAM_CHUNK_SIZE], &[2.; STREAM_CHUNK_SIZE]);
+        assert_eq!(&samples[STREAM_CHUNK_SIZE..], &[0.; STREAM_CHUNK_SIZE]);
+        assert_eq!(system.current_track().unwrap().title, "Second");
+    }
+
+    #[test]
+    fn shuffling_only_moves_pending_tracks() {
+        let shuffled = |seed| {
+            let system = AudioSystem::new();
+            let events = system.events.clone();
+
+            for i in 0..8 {
+                add_samples(&system, &i.to_string(), vec![i as f32; STREAM_CHUNK_SIZE]);
+            }
+
+            system.next();
+            while events.try_wait().is_some() {}
+
+            system.shuffle(Some(seed));
+
+            let updates = std::iter::from_fn(|| events.try_wait())
+                .filter(|e| matches!(e, AudioEvent::Queue(QueueEvent::Update)))
+                .count();
+            assert_eq!(updates, 1);
+
+            let upcoming = system.queue.peek_ahead(3);
+            let preloading: Vec<_> = system.scheduler.preload().iter().map(|r| r.0).collect();
+            assert!(upcoming.iter().all(|t| preloading.contains(&t.loader.id())));
+
+            // Going around the queue shows the tracks before the current one
+            system.set_repeat(RepeatMode::Queue);
+            system
+                .queue
+                .peek_repeating(8, RepeatMode::Queue)
+                .iter()
+                .map(|t| t.title().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let order = shuffled(42);
+
+        // The current track and the one that played before it are in place
+        assert_eq!(order[0], "1");
+        assert_eq!(order[7], "0");
+
+        assert_eq!(order[1..7], ["4", "7", "6", "5", "2", "3"]);
+
+        assert_eq!(shuffled(42), order);
+        assert_ne!(shuffled(7), order);
+    }
+
+    #[test]
+    fn detailed_track_includes_everything_known() {
+        let system = AudioSystem::new();
+        assert!(system.current_track_detailed().is_none());
+
+        let metadata = Metadata {
+            artist: Some("Artist".to_string()),
+            album: Some("Album".to_string()),
+            artwork: Some("https://example.com/cover.png".to_string()),
+            codec: Some("mp3".to_string()),
+        };
+
+        let loader = system.pool.add(
+            vec![0.; SAMPLES_PER_SEC * 2].into_sample_reader(),
+            SAMPLES_PER_SEC * 2,
+        );
+        let track = Track::new(loader, "Tagged".to_string())
+            .with_requester(42)
+            .with_metadata(metadata.clone());
+
+        let events = system.events.clone();
+        system.add_track(track);
+        play(&system, 5);
+
+        let started = std::iter::from_fn(|| events.try_wait()).find_map(|e| match e {
+            AudioEvent::TrackStarted(track) => Some(track),
+            _ => None,
+        });
+        assert_eq!(started.unwrap().metadata, metadata);
+
+        let details = system.current_track_detailed().unwrap();
+
+        assert_eq!(details.title, "Tagged");
+        assert_eq!(details.metadata, metadata);
+        assert_eq!(details.position, STREAM_CHUNK_DURATION * 5);
+        assert_eq!(details.duration, Duration::from_secs(2));
+        assert_eq!(details.requested_by, Some(42));
+    }
+
+    #[test]
+    fn skip_intro_starts_tracks_after_the_intro() {
+        let system = AudioSystem::new();
+        system.set_skip_intro(Duration::from_secs(10));
+
+        let with_intro = |level: f32| {
+            let mut samples = vec![0.; SAMPLES_PER_SEC * 10];
+            samples.extend(vec![level; STREAM_CHUNK_SIZE * 2]);
+            samples
+        };
+
+        add_samples(&system, "First", with_intro(1.));
+        add_samples(&system, "Short", vec![0.5; STREAM_CHUNK_SIZE]);
+        add_samples(&system, "Third", with_intro(0.25));
+
+        assert_eq!(system.scheduler.offset(), SAMPLES_PER_SEC * 10);
+
+        let samples = play(&system, 4);
+        let chunks: Vec<_> = samples.chunks(STREAM_CHUNK_SIZE).collect();
+
+        assert_eq!(chunks[0], &[1.; STREAM_CHUNK_SIZE]);
+        assert_eq!(chunks[1], &[1.; STREAM_CHUNK_SIZE]);
+        // Shorter than the intro, so it plays in full
+        assert_eq!(chunks[2], &[0.5; STREAM_CHUNK_SIZE]);
+        assert_eq!(chunks[3], &[0.25; STREAM_CHUNK_SIZE]);
+    }
+
+    #[test]
+    fn playback_emits_granular_events() {
+        let system = AudioSystem::new();
+        let events = system.subscribe();
+
+        let clock = MockClock::new();
+        let mut drift = playback_thread::Drift::default();
+
+        add_samples(&system, "First", vec![0.; SAMPLES_PER_SEC * 3]);
+        add_samples(&system, "Second", vec![0.; SAMPLES_PER_SEC * 3]);
+        let first = system.current_track().unwrap().id;
+
+        for _ in 0..35 {
+            loading_thread::load_requested(&system.scheduler, &system.pool);
+            playback_thread::step(&system, &clock, &mut drift);
+        }
+
+        let events: Vec<_> = std::iter::from_fn(|| events.try_wait()).collect();
+
+        let positions: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                AudioEvent::PositionUpdated(position) => Some(*position),
+                _ => None,
+            })
+            .collect();
+
+        // Reported once a second, restarting with the next track
+        assert_eq!(
+            positions,
+            [
+                STREAM_CHUNK_DURATION,
+                STREAM_CHUNK_DURATION * 11,
+                STREAM_CHUNK_DURATION * 21,
+                STREAM_CHUNK_DURATION
+            ]
+        );
+
+        let ended = events
+            .iter()
+            .position(|e| matches!(e, AudioEvent::TrackEnded(id) if *id == first))
+            .unwrap();
+        let started = events
+            .iter()
+            .position(|e| matches!(e, AudioEvent::TrackStarted(t) if t.title == "Second"))
+            .unwrap();
+
+        assert!(ended < started);
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, AudioEvent::BufferUnderrun { .. })));
+    }
+
+    #[test]
+    fn the_last_track_of_the_queue_ends_once() {
+        let system = AudioSystem::new();
+        let events = system.subscribe();
+
+        let ended = || {
+            std::iter::from_fn(|| events.try_wait())
+                .filter(|e| matches!(e, AudioEvent::TrackEnded(_)))
+                .count()
+        };
+
+        add_samples(&system, "Only", vec![1.; STREAM_CHUNK_SIZE * 2]);
+
+        // Silence plays after it, without ending it again
+        play(&system, 5);
+        assert_eq!(ended(), 1);
+
+        // Queueing more moves on from it without reporting it twice
+        add_samples(&system, "Next", vec![0.5; STREAM_CHUNK_SIZE]);
+        assert_eq!(play(&system, 1), vec![0.5; STREAM_CHUNK_SIZE]);
+
+        assert_eq!(system.current_track().unwrap().title, "Next");
+        assert_eq!(ended(), 0);
+    }
+
+    #[test]
+    fn recently_played_tracks_are_the_most_recent_first() {
+        let system = AudioSystem::new();
+
+        let tracks = ["First", "Second", "Third", "Fourth"].map(|title| {
+            let loader = system.pool.add(
+                vec![0.; STREAM_CHUNK_SIZE].into_sample_reader(),
+                STREAM_CHUNK_SIZE,
+            );
+
+            Track::new(loader, title.to_string()).with_requester(7)
+        });
+
+        system.add_tracks(tracks.to_vec());
+        play(&system, 3);
+
+        // The third track is still playing
+        let played = system.recently_played(10);
+        let titles: Vec<_> = played.iter().map(|p| p.track.title.as_str()).collect();
+
+        assert_eq!(titles, ["Second", "First"]);
+        assert!(played.iter().all(|p| p.track.requested_by == Some(7)));
+        assert!(played[0].finished_at >= played[1].finished_at);
+
+        assert_eq!(system.recently_played(1).len(), 1);
+    }
+
+    #[test]
+    fn the_last_track_played_is_recently_played() {
+        let system = AudioSystem::new();
+        add_samples(&system, "Only", vec![0.; STREAM_CHUNK_SIZE]);
+
+        assert!(system.recently_played(10).is_empty());
+        play(&system, 3);
+
+        let played = system.recently_played(10);
+        let titles: Vec<_> = played.iter().map(|p| p.track.title.as_str()).collect();
+
+        // Recorded once, even though the queue stays on it
+        assert_eq!(titles, ["Only"]);
+    }
+
+    #[test]
+    fn underruns_are_reported_when_they_start() {
+        let system = AudioSystem::new();
+        let events = system.subscribe();
+
+        add_samples(&system, "Slow", vec![0.; SAMPLES_PER_SEC]);
+
+        // Nothing is loaded yet
+        playback_thread::tick(&system);
+        playback_thread::tick(&system);
+
+        play(&system, 1);
+        playback_thread::tick(&system);
+
+        let underruns = std::iter::from_fn(|| events.try_wait())
+            .filter(|e| matches!(e, AudioEvent::BufferUnderrun { .. }))
+            .count();
+
+        assert_eq!(underruns, 1);
+    }
+
+    #[test]
+    fn playback_continues_where_it_ran_out_of_samples() {
+        let system = AudioSystem::new();
+        let events = system.subscribe();
+
+        let samples: Vec<_> = (0..STREAM_CHUNK_SIZE * 3).map(|i| i as f32).collect();
+        let loader = system
+            .pool
+            .add(samples.into_sample_reader(), STREAM_CHUNK_SIZE * 3);
+        let id = loader.id();
+
+        system.add_loader(loader, "Slow".to_string());
+        let track = system.queue.find_by_loader(id).unwrap();
+
+        let load = |amount| {
+            let available = system.pool.load(id, amount);
+            system.scheduler.notify_load(id, available);
+        };
+
+        let read = || {
+            let mut buf = vec![f32::NAN; STREAM_CHUNK_SIZE];
+            playback_thread::read_samples(&system, &mut buf);
+            buf
+        };
+
+        load(STREAM_CHUNK_SIZE * 3 / 2);
+        read();
+
+        // Half of the chunk is loaded, and the rest is silent
+        let chunk = read();
+        let half = STREAM_CHUNK_SIZE / 2;
+
+        assert_eq!(chunk[half - 1], (STREAM_CHUNK_SIZE + half - 1) as f32);
+        assert!(chunk[half..].iter().all(|s| *s == 0.));
+
+        let underrun = std::iter::from_fn(|| events.try_wait()).find_map(|e| match e {
+            AudioEvent::BufferUnderrun { track_id, missing } => Some((track_id, missing)),
+            _ => None,
+        });
+
+        assert_eq!(underrun, Some((Some(track.id()), half)));
+
+        // Nothing was skipped while waiting for the loader
+        load(STREAM_CHUNK_SIZE * 3 / 2);
+        assert_eq!(read()[0], (STREAM_CHUNK_SIZE + half) as f32);
+    }
+
+    #[test]
+    fn processing_pauses_while_nobody_is_listening() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        let clock = MockClock::new();
+        let mut drift = playback_thread::Drift::default();
+
+        system.set_power_saving(Some(Duration::from_secs(1)));
+        add_samples(&system, "Long", vec![0.; SAMPLES_PER_SEC * 10]);
+        loading_thread::load_requested(&system.scheduler, &system.pool);
+
+        let consumer = system.stream();
+        playback_thread::step(&system, &clock, &mut drift);
+        assert_eq!(system.scheduler.offset(), STREAM_CHUNK_SIZE);
+
+        drop(consumer);
+
+        // Processing continues for the grace period
+        for _ in 0..15 {
+            playback_thread::step(&system, &clock, &mut drift);
+        }
+
+        assert!(system.is_idle());
+        let paused_at = system.scheduler.offset();
+        assert_eq!(paused_at, STREAM_CHUNK_SIZE * 11);
+
+        playback_thread::step(&system, &clock, &mut drift);
+        assert_eq!(system.scheduler.offset(), paused_at);
+
+        let _consumer = system.stream();
+        playback_thread::step(&system, &clock, &mut drift);
+
+        assert!(!system.is_idle());
+        assert_eq!(system.scheduler.offset(), paused_at + STREAM_CHUNK_SIZE);
+
+        let events: Vec<_> = std::iter::from_fn(|| events.try_wait()).collect();
+        let paused = events
+            .iter()
+            .position(|e| matches!(e, AudioEvent::NoConsumers))
+            .unwrap();
+        let resumed = events
+            .iter()
+            .position(|e| matches!(e, AudioEvent::ConsumersPresent))
+            .unwrap();
+
+        assert!(paused < resumed);
+    }
+
+    #[test]
+    fn paused_playback_holds_its_position() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        // Fading in is covered separately
+        system.set_resume_fade(Duration::ZERO);
+
+        let samples: Vec<_> = (0..STREAM_CHUNK_SIZE * 4).map(|i| i as f32).collect();
+        add_samples(&system, "Counting", samples.clone());
+        play(&system, 1);
+
+        system.pause();
+        system.pause();
+
+        assert_eq!(play(&system, 2), vec![0.; STREAM_CHUNK_SIZE * 2]);
+        assert_eq!(system.scheduler.offset(), STREAM_CHUNK_SIZE);
+
+        system.resume();
+        assert_eq!(
+            play(&system, 1),
+            &samples[STREAM_CHUNK_SIZE..STREAM_CHUNK_SIZE * 2]
+        );
+
+        let changes: Vec<_> = std::iter::from_fn(|| events.try_wait())
+            .filter_map(|e| match e {
+                AudioEvent::PlaybackState(state) => Some(state),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(changes, [PlaybackState::Paused, PlaybackState::Playing]);
+    }
+
+    #[test]
+    fn resuming_fades_in() {
+        let system = AudioSystem::new();
+
+        add_samples(&system, "Tone", vec![1.; STREAM_CHUNK_SIZE * 4]);
+        play(&system, 1);
+
+        system.pause();
+        play(&system, 1);
+        system.resume();
+
+        let samples = play(&system, 1);
+        let fade = SAMPLES_PER_SEC / 50;
+
+        // Both channels of a frame share a gain, which only goes up
+        assert!(samples.chunks(2).all(|frame| frame[0] == frame[1]));
+        assert!(samples.windows(2).all(|pair| pair[1] >= pair[0]));
+        assert!(samples[0] < 0.01);
+        assert!(samples[fade - 1] > 0.99);
+        assert_eq!(&samples[fade..], &vec![1.; STREAM_CHUNK_SIZE - fade][..]);
+
+        // Playing for the first time does not fade in
+        let system = AudioSystem::new();
+        add_samples(&system, "Tone", vec![1.; STREAM_CHUNK_SIZE]);
+        assert_eq!(play(&system, 1), vec![1.; STREAM_CHUNK_SIZE]);
+    }
+
+    #[test]
+    fn overrides_are_shown_over_tags() {
+        let system = AudioSystem::new();
+
+        let tags = Metadata {
+            artist: Some("Tagged artist".to_string()),
+            album: Some("Tagged album".to_string()),
+            ..Default::default()
+        };
+
+        let overrides = TrackMetaOverrides {
+            title: Some("Known title".to_string()),
+            artwork: Some("https://example.com/cover.png".to_string()),
+            ..Default::default()
+        };
+
+        let loader = system.pool.add(vec![0.; 4].into_sample_reader(), 4);
+        let track = Track::new(loader, "Tagged title".to_string())
+            .with_metadata(tags)
+            .with_overrides(overrides);
+
+        system.add_track(track);
+
+        let details = system.current_track_detailed().unwrap();
+
+        assert_eq!(system.current_track().unwrap().title, "Known title");
+        assert_eq!(details.title, "Known title");
+        assert_eq!(details.metadata.artist.as_deref(), Some("Tagged artist"));
+        assert_eq!(details.metadata.album.as_deref(), Some("Tagged album"));
+        assert_eq!(
+            details.metadata.artwork.as_deref(),
+            Some("https://example.com/cover.png")
+        );
+    }
+
+    #[test]
+    fn tracks_shorter_than_a_chunk_play_in_one_tick() {
+        let system = AudioSystem::new();
+        let tone = SAMPLES_PER_SEC / 100;
+
+        for (i, title) in ["First", "Second", "Third"].iter().enumerate() {
+            add_samples(&system, title, vec![i as f32 + 1.; tone]);
+        }
+
+        add_samples(&system, "Long", vec![4.; STREAM_CHUNK_SIZE * 2]);
+
+        let samples = play(&system, 1);
+        let expected = [
+            vec![1.; tone],
+            vec![2.; tone],
+            vec![3.; tone],
+            vec![4.; STREAM_CHUNK_SIZE - tone * 3],
+        ]
+        .concat();
+
+        assert_eq!(samples, expected);
+        assert_eq!(system.current_track().unwrap().title, "Long");
+    }
+
+    #[test]
+    fn tracks_join_without_a_gap() {
+        let system = AudioSystem::new();
+
+        // The tracks join in the middle of the second chunk
+        let length = STREAM_CHUNK_SIZE + STREAM_CHUNK_SIZE / 2;
+        let ramp: Vec<f32> = (0..length * 2).map(|i| i as f32 + 1.).collect();
+
+        add_samples(&system, "First", ramp[..length].to_vec());
+        add_samples(&system, "Second", ramp[length..].to_vec());
+
+        assert_eq!(play(&system, 3), ramp);
+        assert_eq!(system.current_track().unwrap().title, "Second");
+    }
+
+    #[test]
+    fn latency_includes_what_consumers_have_not_read() {
+        let system = AudioSystem::new();
+        assert_eq!(system.estimated_latency(), STREAM_CHUNK_DURATION);
+
+        let _consumer = system.stream();
+        system.registry.write_samples(&[0.; SAMPLES_PER_SEC / 5]);
+
+        let latency = system.estimated_latency();
+        let expected = STREAM_CHUNK_DURATION + Duration::from_millis(200);
+
+        let difference = (latency.as_secs_f64() - expected.as_secs_f64()).abs();
+        assert!(difference < 0.001, "{:?}", latency);
+    }
+
+    #[test]
+    fn raw_stream_is_not_processed() {
+        let system = AudioSystem::new();
+        let mut raw = system.raw_stream();
+        let mut output = system.stream_with_format(SampleFormat::Float32);
+
+        add_samples(&system, "Tone", vec![0.8; STREAM_CHUNK_SIZE * 2]);
+        loading_thread::load_requested(&system.scheduler, &system.pool);
+
+        // Skip the chunk that ramps to the volume
+        system.set_volume(0.5);
+        playback_thread::tick(&system);
+        raw.read_available();
+        output.read_available();
+
+        playback_thread::tick(&system);
+
+        let raw = SampleFormat::Float32.decode(&raw.read_available());
+        let output = SampleFormat::Float32.decode(&output.read_available());
+
+        assert_eq!(raw, vec![0.8; STREAM_CHUNK_SIZE]);
+        assert_eq!(output, vec![0.4; STREAM_CHUNK_SIZE]);
+    }
+
+    #[test]
+    fn tracks_are_normalized_once_loaded() {
+        let system = AudioSystem::new();
+        system.set_normalization(Some(-20.));
+
+        let sine = |amplitude: f32, seconds: usize| -> Vec<f32> {
+            (0..SAMPLES_PER_SEC * seconds)
+                .map(|i| (i / 2) as f32 * std::f32::consts::TAU * 1000. / 44100.)
+                .map(|phase| phase.sin() * amplitude)
+                .collect()
+        };
+        let peak = |samples: &[f32]| samples.iter().fold(0_f32, |p, s| p.max(s.abs()));
+
+        // Nothing is known until every sample has been measured
+        let loader = system
+            .pool
+            .add(sine(0.05, 2).into_sample_reader(), SAMPLES_PER_SEC * 2);
+
+        loader.load(SAMPLES_PER_SEC);
+        assert_eq!(system.pool.loudness(loader.id()), None);
+
+        loader.load(SAMPLES_PER_SEC);
+        assert!(system.pool.loudness(loader.id()).is_some());
+
+        add_samples(&system, "Quiet", sine(0.05, 1));
+        add_samples(&system, "Loud", sine(0.2, 1));
+
+        // Both are brought to about the same level, the quiet one 6 dB up
+        let quiet = play(&system, 10);
+        let loud = play(&system, 10);
+
+        assert!((peak(&quiet) - peak(&loud)).abs() < 0.001);
+        assert!((peak(&quiet) / 0.05 - 2.).abs() < 0.2);
+
+        system.set_normalization(None);
+        add_samples(&system, "As is", sine(0.05, 1));
+
+        system.next();
+        assert!((peak(&play(&system, 10)) - 0.05).abs() < 0.001);
+    }
+
+    #[test]
+    fn normalization_ramps_when_it_changes_mid_track() {
+        let system = AudioSystem::new();
+
+        let sine: Vec<f32> = (0..SAMPLES_PER_SEC * 2)
+            .map(|i| (i / 2) as f32 * std::f32::consts::TAU * 1000. / 44100.)
+            .map(|phase| phase.sin() * 0.05)
+            .collect();
+        let peak = |samples: &[f32]| samples.iter().fold(0_f32, |p, s| p.max(s.abs()));
+
+        let loader = system
+            .pool
+            .add(sine.into_sample_reader(), SAMPLES_PER_SEC * 2);
+        let id = loader.id();
+
+        loader.load(SAMPLES_PER_SEC * 2);
+        assert!(system.pool.loudness(id).is_some());
+
+        system.add_loader(loader, "Quiet".to_string());
+        assert!((peak(&play(&system, 5)) - 0.05).abs() < 0.001);
+
+        // The gain is raised over the next chunk, instead of jumping up at its start
+        system.set_normalization(Some(-20.));
+        let target = system.pool.gain(id, Some(-20.));
+
+        let ramp = play(&system, 1);
+        let tenth = STREAM_CHUNK_SIZE / 10;
+
+        assert!(peak(&ramp[..tenth]) < 0.05 * (1. + (target - 1.) * 0.1) + 0.001);
+        assert!((peak(&ramp[STREAM_CHUNK_SIZE - tenth..]) / 0.05 - target).abs() < 0.1);
+        assert!((peak(&play(&system, 1)) / 0.05 - target).abs() < 0.01);
+    }
+
+    #[test]
+    fn tracks_are_normalized_to_their_own_target() {
+        let system = AudioSystem::new();
+
+        let sine: Vec<f32> = (0..SAMPLES_PER_SEC)
+            .map(|i| (i / 2) as f32 * std::f32::consts::TAU * 1000. / 44100.)
+            .map(|phase| phase.sin() * 0.1)
+            .collect();
+        let peak = |samples: &[f32]| samples.iter().fold(0_f32, |p, s| p.max(s.abs()));
+
+        let speech = system
+            .pool
+            .add(sine.clone().into_sample_reader(), sine.len());
+        let song = system
+            .pool
+            .add(sine.clone().into_sample_reader(), sine.len());
+        let ids = (speech.id(), song.id());
+
+        // The system does not normalize, but the tracks still have their targets
+        system.add_tracks(vec![
+            Track::new(speech, "Speech".to_string()).with_loudness_target(-30.),
+            Track::new(song, "Song".to_string()).with_loudness_target(-14.),
+        ]);
+
+        let speech = play(&system, 10);
+        let song = play(&system, 10);
+
+        let measured = system.pool.loudness(ids.0).unwrap();
+        assert_eq!(system.pool.loudness(ids.1), Some(measured));
+
+        // The same source is brought down towards one target and up towards the other
+        let expected = |target| processing::loudness::track_gain(measured, target);
+
+        assert!(measured > -30. && measured < -14.);
+        assert!((peak(&speech) / 0.1 - expected(-30.)).abs() < 0.01);
+        assert!((peak(&song) / 0.1 - expected(-14.)).abs() < 0.01);
+        assert!(peak(&speech) < 0.1 && peak(&song) > 0.1);
+    }
+
+    #[test]
+    fn served_samples_and_bytes_are_counted() {
+        let system = AudioSystem::new();
+        let _float = system.stream_with_format(SampleFormat::Float32);
+        let _int = system.stream_with_format(SampleFormat::Int16);
+
+        assert_eq!(system.total_served(), ServedStats::default());
+
+        for _ in 0..2 {
+            playback_thread::tick(&system);
+        }
+
+        let served = system.total_served();
+        assert_eq!(served.samples, STREAM_CHUNK_SIZE * 2);
+        assert_eq!(served.bytes, STREAM_CHUNK_SIZE * 2 * (4 + 2));
+        assert_eq!(served.duration, STREAM_CHUNK_DURATION * 2);
+    }
+
+    #[test]
+    fn output_is_streamed_in_the_configured_format() {
+        assert_eq!(AudioConfig::default().chunk_size(), STREAM_CHUNK_SIZE);
+        assert_eq!(
+            AudioConfig::default().pcm_mime(),
+            "audio/pcm;rate=44100;encoding=float;bits=32"
+        );
+
+        assert_eq!(
+            AudioConfig::new(48000, 0, STREAM_CHUNK_DURATION),
+            Err(InvalidConfig::ChannelCount(0))
+        );
+
+        let config = AudioConfig::new(44100, 1, Duration::from_millis(20)).unwrap();
+        let system = AudioSystem::with_config(config);
+        let mut output = system.stream_with_format(SampleFormat::Float32);
+
+        let stereo = [0.5, 0.25].repeat(STREAM_CHUNK_SIZE);
+        add_samples(&system, "Stereo", stereo);
+        loading_thread::load_requested(&system.scheduler, &system.pool);
+
+        // Chunks are shorter, and each frame is mixed down to a single sample
+        playback_thread::tick(&system);
+        let samples = SampleFormat::Float32.decode(&output.read_available());
+
+        assert_eq!(samples.len(), 882);
+        assert!(samples.iter().all(|s| (s - 0.375).abs() < 1e-6));
+        assert_eq!(system.position(), Some(Duration::from_millis(20)));
+        assert_eq!(system.total_served().duration, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn unmuting_restores_the_volume() {
+        let system = AudioSystem::new();
+        let mut output = system.stream_with_format(SampleFormat::Float32);
+
+        add_samples(&system, "Tone", vec![1.; STREAM_CHUNK_SIZE * 8]);
+        loading_thread::load_requested(&system.scheduler, &system.pool);
+
+        let mut tick = || {
+            playback_thread::tick(&system);
+            SampleFormat::Float32.decode(&output.read_available())
+        };
+
+        system.set_volume(0.7);
+        tick();
+
+        // Muting ramps down over a chunk, then nothing is heard
+        system.mute();
+        assert!(system.is_muted());
+
+        let ramp = tick();
+        assert!(ramp[0] > 0.69 && ramp[STREAM_CHUNK_SIZE - 1] == 0.);
+        assert!(tick().iter().all(|s| *s == 0.));
+
+        // Unlike setting it to 0, the volume is remembered
+        assert_eq!(system.volume(), 0.7);
+
+        system.unmute();
+        assert!(!system.is_muted());
+
+        tick();
+        assert!(tick().iter().all(|s| (s - 0.7).abs() < 0.0001));
+        assert_eq!(system.volume(), 0.7);
+    }
+
+    #[test]
+    fn volume_changes_ramp_over_a_chunk() {
+        let system = AudioSystem::new();
+        let mut output = system.stream_with_format(SampleFormat::Float32);
+
+        add_samples(&system, "Tone", vec![1.; STREAM_CHUNK_SIZE * 4]);
+        loading_thread::load_requested(&system.scheduler, &system.pool);
+
+        playback_thread::tick(&system);
+        output.read_available();
+
+        system.set_volume(0.);
+        playback_thread::tick(&system);
+
+        let ramp = SampleFormat::Float32.decode(&output.read_available());
+        assert_eq!(ramp.len(), STREAM_CHUNK_SIZE);
+
+        // Both channels of a frame share a gain, which only goes down
+        assert!(ramp.chunks(2).all(|frame| frame[0] == frame[1]));
+        assert!(ramp.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(ramp[0] > 0.99);
+        assert_eq!(ramp[STREAM_CHUNK_SIZE - 1], 0.);
+
+        system.set_volume(-1.);
+        assert_eq!(system.volume(), 0.);
+
+        system.set_volume(5.);
+        assert_eq!(system.volume(), MAX_VOLUME);
+    }
+
+    #[test]
+    fn panic_stop_silences_everything_at_once() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+        let mut consumer = system.stream_with_format(SampleFormat::Float32);
+
+        let factory: InputFactory = Box::new(|| None);
+        system.set_autoplay(Autoplay::new(vec![(factory, 1)]), true);
+
+        add_samples(&system, "Loud", vec![1.; STREAM_CHUNK_SIZE * 8]);
+        add_samples(&system, "Next", vec![1.; STREAM_CHUNK_SIZE * 8]);
+
+        loading_thread::load_requested(&system.scheduler, &system.pool);
+        playback_thread::tick(&system);
+
+        system.panic_stop();
+
+        // Only the silence written by stopping is left to read
+        let remaining = SampleFormat::Float32.decode(&consumer.read_available());
+        assert_eq!(remaining, vec![0.; STREAM_CHUNK_SIZE]);
+
+        playback_thread::tick(&system);
+        let samples = SampleFormat::Float32.decode(&consumer.read_available());
+
+        assert_eq!(samples, vec![0.; STREAM_CHUNK_SIZE]);
+        assert!(system.current_track().is_none());
+        assert!(
+            std::iter::from_fn(|| events.try_wait()).any(|e| matches!(e, AudioEvent::PanicStopped))
+        );
+
+        // Stopping at once does not turn autoplay off, unlike stopping
+        let autoplay = |system: &AudioSystem| {
+            let autoplay = system.autoplay.lock().unwrap();
+            autoplay.as_ref().unwrap().is_enabled()
+        };
+
+        assert!(autoplay(&system));
+
+        system.stop();
+        assert!(!autoplay(&system));
+    }
+
+    #[test]
+    fn replacing_the_current_track_keeps_the_queue() {
+        let system = AudioSystem::new();
+        let events = system.subscribe();
+        let workers =
+            loading_thread::Workers::new(1, system.scheduler.clone(), system.pool.clone());
+
+        add_samples(&system, "Wrong", vec![1.; STREAM_CHUNK_SIZE * 4]);
+        add_samples(&system, "Next", vec![1.; 10]);
+        play(&system, 1);
+
+        // A replacement is loaded by a worker, then switched to on the next cycle
+        let replace = |loader: Arc<Loader>, title: &str| {
+            system
+                .replace_current_loader(loader, title.to_string())
+                .unwrap();
+
+            loading_thread::cycle(&system, &workers);
+            workers.wait_until_idle();
+            loading_thread::cycle(&system, &workers);
+        };
+
+        // A replacement without any samples fails, and the current track keeps playing
+        let broken = system.pool.add(Vec::<f32>::new().into_sample_reader(), 10);
+        replace(broken.clone(), "Broken");
+
+        assert_eq!(system.current_track().unwrap().title, "Wrong");
+        assert!(std::iter::from_fn(|| events.try_wait())
+            .any(|e| matches!(e, AudioEvent::ReplacementFailed(t) if t.title == "Broken")));
+
+        // The failed replacement was removed from the pool
+        assert_eq!(Arc::strong_count(&broken), 1);
+
+        let tone = system.pool.add(vec![0.5; 20].into_sample_reader(), 20);
+        replace(tone, "Tone");
+
+        assert_eq!(system.current_track().unwrap().title, "Tone");
+        assert_eq!(system.next_track().unwrap().title, "Next");
+
+        let samples = play(&system, 1);
+        assert_eq!(&samples[..20], &[0.5; 20]);
+        assert_eq!(&samples[20..30], &[1.; 10]);
+    }
+
+    #[test]
+    fn prefetch_depth_limits_the_tracks_that_load() {
+        let system = AudioSystem::new();
+
+        for i in 0..6 {
+            add_samples(&system, &i.to_string(), vec![0.; SAMPLES_PER_SEC]);
+        }
+
+        assert_eq!(system.scheduler.preload().len(), 3);
+
+        system.set_prefetch_depth(5);
+        assert_eq!(system.scheduler.preload().len(), 5);
+
+        // The current track always loads, and the next one so it follows without a gap
+        system.set_prefetch_depth(0);
+        assert_eq!(system.prefetch_depth(), 1);
+        assert_eq!(system.scheduler.preload().len(), 2);
+    }
+
+    #[test]
+    fn played_tracks_are_evicted_once_they_are_old_enough() {
+        let system = AudioSystem::new();
+        system.set_prefetch_depth(6);
+
+        for i in 1..=6 {
+            add_samples(&system, &i.to_string(), vec![i as f32; SAMPLES_PER_SEC]);
+        }
+
+        loading_thread::load_requested(&system.scheduler, &system.pool);
+        let loaded = system.pool.buffered_bytes();
+
+        for _ in 0..4 {
+            system.next();
+        }
+
+        // The two most recently played tracks are kept
+        assert_eq!(system.pool.buffered_bytes(), loaded / 6 * 4);
+
+        // Going back to a kept track plays it without loading it again
+        system.previous();
+        assert_eq!(play(&system, 1)[0], 4.);
+    }
+
+    #[test]
+    fn load_budgets_spread_loading_across_cycles() {
+        let cycles = |budget: Option<Duration>| {
+            let system = AudioSystem::new();
+            let workers =
+                loading_thread::Workers::new(1, system.scheduler.clone(), system.pool.clone());
+
+            system.set_load_budget(budget);
+            add_samples(&system, "Long", vec![0.; SAMPLES_PER_SEC * 20]);
+
+            std::iter::from_fn(|| {
+                loading_thread::cycle(&system, &workers);
+                workers.wait_until_idle();
+
+                let requested = system.threads.last_load.load();
+                (requested > 0).then_some(requested)
+            })
+            .collect::<Vec<_>>()
+        };
+
+        let budget = SAMPLES_PER_SEC * 5;
+        assert_eq!(cycles(Some(Duration::from_secs(5))), vec![budget; 4]);
+
+        // Without a budget, it is all requested at once
+        assert_eq!(cycles(None), vec![SAMPLES_PER_SEC * 20]);
+    }
+
+    #[test]
+    fn slow_decodes_do_not_delay_other_loaders() {
+        /// Decodes nothing until it is allowed to
+        struct SlowReader(Receiver<()>);
+
+        impl SampleReader for SlowReader {
+            fn read_samples(&mut self, buf: &mut [f32]) -> SamplesRead {
+                match self.0.recv() {
+                    Ok(()) => SamplesRead::More(buf.len()),
+                    Err(_) => SamplesRead::Empty(0),
+                }
+            }
+        }
+
+        let pool = Arc::new(Pool::new());
+        let scheduler = Arc::new(Scheduler::new());
+        let (allow, allowed) = bounded(1);
+
+        let slow = pool.add(SlowReader(allowed), 100);
+        let fast = pool.add(vec![1.; 100].into_sample_reader(), 100);
+        scheduler.set_loaders(vec![slow.clone(), fast.clone()]);
+
+        let workers = loading_thread::Workers::new(2, scheduler.clone(), pool.clone());
+        workers.dispatch(scheduler.preload());
+
+        // The fast loader finishes while the slow one is still decoding
+        workers.wait_until_in_flight(1);
+
+        assert_eq!(fast.available(), 100);
+        assert_eq!(slow.available(), 0);
+
+        // The slow loader is still loading, so it is not requested again
+        workers.dispatch(scheduler.preload());
+        assert_eq!(workers.in_flight(), 1);
+
+        // It ends once it has decoded
+        allow.send(()).unwrap();
+        drop(allow);
+        workers.wait_until_idle();
+
+        assert_eq!(slow.available(), 100);
+    }
+
+    #[test]
+    fn downloads_beyond_the_limit_wait() {
+        /// Tracks how many readers are reading at the same time
+        struct Download(Arc<Mutex<(usize, usize)>>);
+
+        impl SampleReader for Download {
+            fn read_samples(&mut self, buf: &mut [f32]) -> SamplesRead {
+                {
+                    let mut counts = self.0.lock().unwrap();
+                    counts.0 += 1;
+                    counts.1 = counts.1.max(counts.0);
+                }
+
+                thread::sleep(Duration::from_millis(50));
+                self.0.lock().unwrap().0 -= 1;
+
+                SamplesRead::Empty(buf.len())
+            }
+        }
+
+        let pool = Arc::new(Pool::new());
+        let scheduler = Arc::new(Scheduler::new());
+        let counts: Arc<Mutex<(usize, usize)>> = Default::default();
+
+        pool.set_max_downloads(2);
+
+        let loaders: Vec<_> = (0..5)
+            .map(|_| pool.add_download(Download(counts.clone()), 100))
+            .collect();
+        scheduler.set_loaders(loaders.clone());
+
+        let workers = loading_thread::Workers::new(5, scheduler.clone(), pool.clone());
+        workers.dispatch(loaders.iter().map(|l| (l.id(), 100)).collect());
+
+        workers.wait_until_idle();
+
+        assert!(loaders.iter().all(|l| l.available() == 100));
+        assert_eq!(counts.lock().unwrap().1, 2);
+    }
+
+    #[test]
+    fn guild_settings_are_restored() {
+        let path = env::temp_dir().join(format!("gct-settings-{}.ron", process::id()));
+        let store = Arc::new(Mutex::new(SettingsStore::restore_from(&path).unwrap()));
+
+        let system = AudioSystemHandle::with_settings(store.clone(), 1);
+        assert_eq!(system.volume(), 1.);
+
+        let band = EqBand {
+            frequency: 1000.,
+            gain: 3.,
+            q: 1.,
+        };
+
+        system.set_volume(0.5);
+        system.set_repeat(RepeatMode::Queue);
+        system.set_eq(vec![band]).unwrap();
+        drop(system);
+
+        // Nothing is written until the store is saved
+        assert!(!path.exists());
+        store.lock().unwrap().save().unwrap();
+
+        let store = Arc::new(Mutex::new(SettingsStore::restore_from(&path).unwrap()));
+        let restored = AudioSystemHandle::with_settings(store.clone(), 1);
+
+        assert_eq!(restored.volume(), 0.5);
+        assert_eq!(restored.repeat(), RepeatMode::Queue);
+        assert_eq!(restored.eq_bands(), vec![band]);
+
+        // Other guilds are unaffected
+        let other = AudioSystemHandle::with_settings(store, 2);
+        assert_eq!(other.volume(), 1.);
+        assert_eq!(other.repeat(), RepeatMode::Off);
+
+        fs::remove_file(&path).unwrap();
+
+        // A corrupt file is an error instead of being replaced by defaults
+        fs::write(&path, "Not settings").unwrap();
+        assert!(SettingsStore::restore_from(&path).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn settings_are_saved_in_the_background() {
+        let path = env::temp_dir().join(format!("gct-background-{}.ron", process::id()));
+        let store = Arc::new(Mutex::new(SettingsStore::restore_from(&path).unwrap()));
+        SettingsStore::save_in_background(&store);
+
+        let system = AudioSystemHandle::with_settings(store, 1);
+
+        // Changing settings does not write to the disk
+        for volume in [0.2, 0.4, 0.6] {
+            system.set_volume(volume);
+            assert!(!path.exists());
+        }
+
+        let started = Instant::now();
+
+        while !path.exists() && started.elapsed() < SAVE_DELAY * 4 {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let restored = SettingsStore::restore_from(&path).unwrap();
+        assert_eq!(restored.get(1).volume, 0.6);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn dropping_the_owner_stops_threads() {
+        let handle = AudioSystemHandle::new();
+        handle.start();
+
+        let clone = (*handle).clone();
+        let scheduler = Arc::downgrade(&handle.scheduler);
+        drop(clone);
+
+        assert!(handle.threads.is_running());
+        drop(handle);
+
+        // The threads have exited and released their clones of the system
+        assert!(scheduler.upgrade().is_none());
+    }
+
+    #[test]
+    fn shutdown_stops_threads_and_allows_starting_again() {
+        let system = AudioSystem::new();
+        system.set_loading_interval(Duration::from_secs(60));
+
+        system.start();
+        assert!(system.threads.is_running());
+
+        // Long enough for the loading thread to start waiting for its next cycle
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        system.shutdown();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!system.threads.is_running());
+        assert!(system.threads.handles.lock().unwrap().is_empty());
+
+        system.start();
+        assert!(system.threads.is_running());
+        assert_eq!(system.threads.handles.lock().unwrap().len(), 2);
+
+        system.shutdown();
+        assert!(!system.threads.is_running());
+    }
+
+    #[test]
+    fn next_track_is_upcoming_without_advancing() {
+        let system = AudioSystem::new();
+        assert_eq!(system.next_track(), None);
+
+        add_samples(&system, "First", vec![1.; 10]);
+        assert_eq!(system.next_track(), None);
+
+        add_samples(&system, "Second", vec![1.; 10]);
+        assert_eq!(system.current_track().unwrap().title, "First");
+        assert_eq!(system.next_track().unwrap().title, "Second");
+
+        system.next();
+        assert_eq!(system.current_track().unwrap().title, "Second");
+        assert_eq!(system.next_track(), None);
+    }
+
+    #[test]
+    fn next_track_follows_the_repeat_mode() {
+        let system = AudioSystem::new();
+
+        add_samples(&system, "First", vec![1.; 10]);
+        add_samples(&system, "Second", vec![1.; 10]);
+
+        system.set_repeat(RepeatMode::Track);
+        assert_eq!(system.next_track().unwrap().title, "First");
+
+        system.set_repeat(RepeatMode::Off);
+        system.next();
+
+        system.set_repeat(RepeatMode::Queue);
+        assert_eq!(system.next_track().unwrap().title, "First");
+
+        system.set_repeat(RepeatMode::Off);
+        assert_eq!(system.next_track(), None);
+    }
+
+    #[test]
+    fn autoplay_continues_once_the_queue_plays_to_its_end() {
+        let dir = env::temp_dir().join(format!("gct-autoplay-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // A second at the engine's sample rate, so nothing is resampled
+        let wave = testing::wave(SAMPLE_RATE as u32, &[i16::MAX / 2; SAMPLE_RATE], &[]);
+
+        let path = dir.join("Autoplayed.wav");
+        fs::write(&path, wave).unwrap();
+
+        let system = AudioSystem::new();
+        assert!(system.set_no_repeat_window(2).is_err());
+
+        let factory: InputFactory = Box::new(move || Input::from_path(&path).ok());
+        system.set_autoplay(Autoplay::new(vec![(factory, 1)]), true);
+        assert!(system.set_no_repeat_window(2).is_ok());
+
+        add_samples(&system, "Queued", vec![0.25; STREAM_CHUNK_SIZE]);
+
+        // The loading thread autoplays before each cycle
+        let mut samples = vec![];
+
+        for _ in 0..2 {
+            system.autoplay_if_needed();
+            samples.extend(play(&system, 1));
+        }
+
+        assert_eq!(system.current_track().unwrap().title, "Autoplayed");
+        assert!(samples[..STREAM_CHUNK_SIZE].iter().all(|s| *s == 0.25));
+
+        let autoplayed = &samples[STREAM_CHUNK_SIZE..];
+        assert!(autoplayed.iter().all(|s| (s - 0.5).abs() < 0.01));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn draining_stops_after_the_queue_finishes() {
+        let system = AudioSystem::new();
+        let events = system.events.clone();
+
+        add_samples(&system, "First", vec![1.; STREAM_CHUNK_SIZE]);
+        add_samples(&system, "Second", vec![1.; STREAM_CHUNK_SIZE + 10]);
+
+        // Pretend the threads are running, since ticks are done manually here
+        system.threads.running.store(true);
+        system.drain();
+
+        for _ in 0..2 {
+            loading_thread::load_requested(&system.scheduler, &system.pool);
+            playback_thread::tick(&system);
+
+            assert!(system.threads.is_running());
+            assert!(system.add_silence(Duration::from_secs(1)).is_err());
+        }
+
+        // The remainder of the second track
+        loading_thread::load_requested(&system.scheduler, &system.pool);
+        playback_thread::tick(&system);
+
+        assert!(!system.threads.is_running());
+
+        let drained = std::iter::from_fn(|| events.try_wait())
+            .filter(|e| matches!(e, AudioEvent::Drained))
+            .count();
+
+        assert_eq!(drained, 1);
+    }
+
+    #[test]
+    fn corrupt_tracks_are_reported_as_failed() {
+        let system = AudioSystem::new();
+
+        add_samples(&system, "Fine", vec![0.5; STREAM_CHUNK_SIZE * 2]);
+
+        // Decoding a corrupt file produces no samples at all
+        let corrupt = system
+            .pool
+            .add(Vec::<f32>::new().into_sample_reader(), SAMPLES_PER_SEC);
+        system.add_loader(corrupt, "Corrupt".to_string());
+
+        assert!(system.failed_tracks().is_empty());
+        play(&system, 4);
+
+        let failed = system.failed_tracks();
+
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].track.title, "Corrupt");
+        assert_eq!(failed[0].reason, LoadFailure::Decode);
+
+        // Failures are only reported once
+        assert!(system.failed_tracks().is_empty());
+    }
+
+    #[test]
+    fn changing_the_output_format_ends_output_streams() {
+        use audiopus::{coder::Decoder, Channels, SampleRate};
+        use std::io::Read;
+
+        let system = AudioSystem::new();
+        let events = system.subscribe();
+
+        let mut pcm = system.output_stream();
+        assert_eq!(pcm.format(), OutputFormat::Pcm);
+
+        playback_thread::tick(&system);
+        system.set_output_format(OutputFormat::Opus);
+
+        // What was written before the change is still read, then the stream ends
+        let mut buf = vec![0; STREAM_CHUNK_SIZE * system.registry.format().sample_size()];
+        assert_eq!(pcm.read(&mut buf).unwrap(), buf.len());
+        assert_eq!(pcm.read(&mut buf).unwrap(), 0);
+
+        let changed = std::iter::from_fn(|| events.try_wait())
+            .any(|e| matches!(e, AudioEvent::OutputFormatChanged(OutputFormat::Opus)));
+        assert!(changed);
+
+        let mut opus = system.output_stream();
+        assert_eq!(opus.format(), OutputFormat::Opus);
+
+        playback_thread::tick(&system);
+
+        let mut size = [0; 2];
+        opus.read_exact(&mut size).unwrap();
+        let size = u16::from_le_bytes(size) as usize;
+
+        let mut frame = vec![0; size];
+        opus.read_exact(&mut frame).unwrap();
+
+        let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+        let mut output = vec![0.; 5760 * 2];
+
+        let decoded = decoder
+            .decode_float(
+                Some((&frame[..]).try_into().unwrap()),
+                (&mut output[..]).try_into().unwrap(),
+                false,
+            )
+            .unwrap();
+
+        assert!(decoded > 0);
+    }
+}
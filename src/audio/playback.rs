@@ -1,4 +1,4 @@
-use super::{Loader, LoaderId, PRELOAD_AMOUNT, PRELOAD_THRESHOLD};
+use super::{Loader, LoaderId, CHANNEL_COUNT, PRELOAD_AMOUNT, PRELOAD_THRESHOLD};
 use crate::util::model::Identified;
 use crossbeam::atomic::AtomicCell;
 use std::{
@@ -16,6 +16,54 @@ pub struct Scheduler {
     total_offset: AtomicCell<usize>,
     /// Amount of contiguous loaded samples
     total_available: AtomicCell<usize>,
+    /// Skip silence at the end and start of tracks when transitioning between them
+    trim_boundaries: AtomicCell<bool>,
+    /// Amount of samples tracks overlap when transitioning between them
+    crossfade: AtomicCell<usize>,
+    /// Crossfade lengths of loaders that are used over the crossfade
+    crossfade_overrides: Mutex<Vec<(LoaderId, CrossfadeOverride)>>,
+    /// Amount of samples skipped at the start of every track
+    skip_intro: AtomicCell<usize>,
+    /// The end of the previous loader, which is mixed into the current one
+    fade: Mutex<Option<Fade>>,
+    /// Where to continue a loader from when it is transitioned to after an interjection
+    resume: Mutex<Option<(LoaderId, usize)>>,
+}
+
+/// Describes the end of a loader which is mixed into the start of the next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fade {
+    pub from: LoaderId,
+    /// The offset in the previous loader where the fade starts
+    pub start: usize,
+    /// How long both loaders overlap, which is the longer of the two fades
+    pub length: usize,
+    /// How long the previous loader fades out over, ending with the overlap
+    pub fade_out: usize,
+    pub into: LoaderId,
+    /// The offset in the next loader where the fade starts
+    pub into_start: usize,
+    /// How long the next loader fades in over, starting with the overlap
+    pub fade_in: usize,
+}
+
+/// How many samples a loader fades in and out over when crossfading,
+/// instead of the crossfade of the scheduler. Zero disables that side of the fade.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CrossfadeOverride {
+    pub fade_in: Option<usize>,
+    pub fade_out: Option<usize>,
+}
+
+/// What an advance reads, and the state it leaves the scheduler in
+struct AdvancePlan {
+    reads: Vec<(LoaderId, Range<usize>, bool)>,
+    /// The offset in the last loader read from, if anything was read
+    offset: Option<usize>,
+    /// Whether a loader was transitioned to, which uses up the resume
+    transitioned: bool,
+    /// The fade into the last loader transitioned to
+    fade: Option<Fade>,
 }
 
 struct ScheduledItem {
@@ -23,6 +71,53 @@ struct ScheduledItem {
     // TODO: This data is duplicated, perhaps find a way to deal with that
     expected: AtomicCell<usize>,
     available: AtomicCell<usize>,
+    silence: AtomicCell<Silence>,
+}
+
+/// The silence in the samples of a loader that were scanned so far
+#[derive(Debug, Clone, Copy, Default)]
+struct Silence {
+    /// The offset the scanned samples start at
+    start: usize,
+    /// The offset scanning continues from
+    end: usize,
+    leading: usize,
+    trailing: usize,
+}
+
+impl Silence {
+    fn new(start: usize) -> Self {
+        Self {
+            start,
+            end: start,
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if all of the scanned samples are silent
+    fn is_complete(&self) -> bool {
+        self.leading == self.end - self.start
+    }
+
+    /// Adds the silence at the start and end of the samples scanned after the previous ones
+    fn extend(self, end: usize, (leading, trailing): (usize, usize)) -> Self {
+        let length = end - self.end;
+
+        Self {
+            leading: if self.is_complete() {
+                self.leading + leading
+            } else {
+                self.leading
+            },
+            trailing: if trailing == length {
+                self.trailing + length
+            } else {
+                trailing
+            },
+            end,
+            ..self
+        }
+    }
 }
 
 impl Scheduler {
@@ -32,54 +127,231 @@ impl Scheduler {
             offset: Default::default(),
             total_offset: Default::default(),
             total_available: Default::default(),
+            trim_boundaries: Default::default(),
+            crossfade: Default::default(),
+            crossfade_overrides: Default::default(),
+            skip_intro: Default::default(),
+            fade: Default::default(),
+            resume: Default::default(),
         }
     }
 
     /// Returns a list of advancements describing loaders to read from,
     /// If this returns more than 1 item, it signifies that one or more
     /// loaders have been played all the way through.
+    /// Once loaded, the advancements tile the amount exactly, so where
+    /// loaders join, the first sample of one directly follows the last of the other.
     pub fn advance(&self, amount: usize) -> Vec<(LoaderId, Range<usize>)> {
         let queue = self.queue.lock().unwrap();
+        let plan = self.plan(&queue, amount);
+
+        if plan.transitioned {
+            self.resume.lock().unwrap().take();
+            *self.fade.lock().unwrap() = plan.fade;
+        }
+
+        if let Some(offset) = plan.offset {
+            self.offset.store(offset);
+        }
+
+        let total_read = plan.reads.iter().map(|(_, r, _)| r.len()).sum();
+        self.total_offset.fetch_add(total_read);
+
+        plan.reads.into_iter().map(|(id, r, _)| (id, r)).collect()
+    }
+
+    /// Returns what [Scheduler::advance] would read without advancing,
+    /// with whether each read reaches the end of its loader.
+    pub fn explain_advance(&self, amount: usize) -> Vec<(LoaderId, Range<usize>, bool)> {
+        let queue = self.queue.lock().unwrap();
+        self.plan(&queue, amount).reads
+    }
+
+    fn plan(&self, queue: &[ScheduledItem], amount: usize) -> AdvancePlan {
+        let trim = self.trim_boundaries.load();
+        let resume = *self.resume.lock().unwrap();
+
+        let mut plan = AdvancePlan {
+            reads: vec![],
+            offset: None,
+            transitioned: false,
+            fade: None,
+        };
+
+        let mut remaining = amount;
+        let mut offset = self.offset.load();
+        let mut fade = None;
+
+        for (i, item) in queue.iter().enumerate() {
+            if remaining == 0 {
+                break;
+            }
+
+            // This item is transitioned to from the previous one
+            if i > 0 {
+                offset = match resume.filter(|_| !plan.transitioned) {
+                    Some((id, offset)) if id == item.loader.id() => offset,
+                    _ => item.start(trim).max(self.intro(item.expected.load())),
+                };
+
+                plan.transitioned = true;
+                plan.fade = fade.take().map(|fade| Fade {
+                    into_start: offset,
+                    ..fade
+                });
+            }
+
+            // The end overlaps with the start of the next item
+            let next_fade = self.fade_between(item, queue.get(i + 1), trim);
+            let fade_length = next_fade.as_ref().map(|f| f.length).unwrap_or_default();
+            let available = item.end(trim) - fade_length;
+
+            fade = next_fade;
+
+            let amount_ahead = available.checked_sub(offset).unwrap_or_default();
+
+            // Don't read more than requested
+            let amount_to_read = amount_ahead.min(remaining);
+            let read_range = offset..(offset + amount_to_read);
+            let ended = item.complete() && read_range.end >= available;
+
+            remaining -= amount_to_read;
+            plan.reads.push((item.loader.id(), read_range, ended));
+
+            // This item is not finished loading, or the next one cannot be faded into yet,
+            // so stop here instead of cutting off the end of this one with silence
+            if !item.complete() || !self.is_ready_to_fade(queue.get(i + 1), fade.as_ref(), trim) {
+                remaining = 0;
+            }
+
+            plan.offset = Some(offset + amount_to_read);
+            offset = 0;
+        }
 
-        let result: Vec<_> = queue
+        plan
+    }
+
+    /// Returns the fade into the current loader, if any
+    pub fn fade(&self) -> Option<Fade> {
+        self.fade.lock().unwrap().clone()
+    }
+
+    /// Sets the amount of samples tracks overlap, or 0 to disable crossfading
+    pub fn set_crossfade(&self, length: usize) {
+        self.crossfade.store(length - length % CHANNEL_COUNT);
+    }
+
+    pub fn crossfade(&self) -> usize {
+        self.crossfade.load()
+    }
+
+    /// Sets the crossfade lengths used for transitions into and out of these loaders,
+    /// replacing the previous ones. Other loaders use the crossfade.
+    pub fn set_crossfade_overrides(&self, overrides: Vec<(LoaderId, CrossfadeOverride)>) {
+        *self.crossfade_overrides.lock().unwrap() = overrides;
+    }
+
+    /// Returns how long a loader fades in and out over, in that order
+    fn crossfades_of(&self, id: LoaderId) -> (usize, usize) {
+        let crossfade = self.crossfade.load();
+        let overrides = self.crossfade_overrides.lock().unwrap();
+
+        let fade = overrides
             .iter()
-            .scan((amount, self.offset.load()), |(remaining, offset), item| {
-                if *remaining == 0 {
-                    return None;
-                }
+            .find(|(loader, _)| *loader == id)
+            .map(|(_, fade)| *fade)
+            .unwrap_or_default();
 
-                let available = item.available.load();
-                let amount_ahead = available.checked_sub(*offset).unwrap_or_default();
+        let align = |length: usize| length - length % CHANNEL_COUNT;
 
-                // Don't read more than requested
-                let amount_to_read = amount_ahead.min(*remaining);
-                let read_range = *offset..(*offset + amount_to_read);
+        (
+            align(fade.fade_in.unwrap_or(crossfade)),
+            align(fade.fade_out.unwrap_or(crossfade)),
+        )
+    }
 
-                *remaining -= amount_to_read;
-                let result = (item.loader.id(), read_range);
+    /// Sets the amount of samples skipped when a track starts, or 0 to play tracks from the start
+    pub fn set_skip_intro(&self, length: usize) {
+        self.skip_intro.store(length - length % CHANNEL_COUNT);
+    }
 
-                // This item is not finished loading, so stop here
-                if !item.complete() {
-                    *remaining = 0;
-                }
+    /// Returns the offset a track of the expected length starts at.
+    /// Tracks that are not longer than the intro are played in full.
+    pub fn intro(&self, expected: usize) -> usize {
+        let skip = self.skip_intro.load();
 
-                self.offset.store(*offset + amount_to_read);
-                *offset = 0;
+        if expected > skip {
+            skip
+        } else {
+            0
+        }
+    }
 
-                Some(result)
-            })
-            .collect();
+    /// Returns how the end of an item fades into the next, using the fade out of the item
+    /// and the fade in of the next. Neither item overlaps for more than half of its length.
+    /// The offset the next item starts at is only known once it is transitioned to.
+    fn fade_between(
+        &self,
+        item: &ScheduledItem,
+        next: Option<&ScheduledItem>,
+        trim: bool,
+    ) -> Option<Fade> {
+        let next = next.filter(|_| item.complete())?;
+        let align = |length: usize| length - length % CHANNEL_COUNT;
 
-        let total_read = result.iter().map(|(_, r)| r.len()).sum();
-        self.total_offset.fetch_add(total_read);
+        let (_, fade_out) = self.crossfades_of(item.loader.id());
+        let (fade_in, _) = self.crossfades_of(next.loader.id());
+
+        // Both play during either fade, so each is limited by both lengths
+        let limit = (item.end(trim) / 2).min(next.expected.load() / 2);
+        let fade_out = align(fade_out.min(limit));
+        let fade_in = align(fade_in.min(limit));
+        let length = fade_out.max(fade_in);
+
+        (length > 0).then(|| Fade {
+            from: item.loader.id(),
+            start: item.end(trim) - length,
+            length,
+            fade_out,
+            into: next.loader.id(),
+            into_start: 0,
+            fade_in,
+        })
+    }
+
+    /// Returns true if the next item has loaded enough that fading into it does not run out
+    fn is_ready_to_fade(
+        &self,
+        next: Option<&ScheduledItem>,
+        fade: Option<&Fade>,
+        trim: bool,
+    ) -> bool {
+        match (next, fade) {
+            (Some(next), Some(fade)) => {
+                let start = next.start(trim).max(self.intro(next.expected.load()));
+                next.complete() || next.available.load() >= start + fade.length
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns true if the last loader has been played all the way through
+    pub fn is_finished(&self) -> bool {
+        let queue = self.queue.lock().unwrap();
+        let trim = self.trim_boundaries.load();
 
-        result
+        match queue.as_slice() {
+            [] => true,
+            [last] => last.complete() && self.offset.load() >= last.end(trim),
+            _ => false,
+        }
     }
 
     /// Returns the a vec containing loaders to load data for
     /// If there is no need to load, it returns no items
     pub fn preload(&self) -> Vec<(LoaderId, usize)> {
-        let available = self.total_available.load() - self.offset.load();
+        let offset = self.offset.load();
+        let available = self.total_available.load().saturating_sub(offset);
 
         if available > PRELOAD_THRESHOLD {
             return vec![];
@@ -87,10 +359,28 @@ impl Scheduler {
 
         let queue = self.queue.lock().unwrap();
 
+        // After seeking past what is loaded, everything up to the offset is needed first
+        let behind = queue
+            .first()
+            .filter(|i| !i.complete())
+            .map(|i| offset.saturating_sub(i.available.load()))
+            .unwrap_or_default();
+
+        // The same loader can appear more than once when a track is repeated,
+        // so make sure it is only requested to load once.
+        let mut requested = vec![];
+
         queue
             .iter()
             .skip_while(|i| i.complete())
-            .scan(PRELOAD_AMOUNT, |remaining, item| {
+            .filter(|i| {
+                let id = i.loader.id();
+                let is_duplicate = requested.contains(&id);
+
+                requested.push(id);
+                !is_duplicate
+            })
+            .scan(PRELOAD_AMOUNT + behind, |remaining, item| {
                 let unloaded = item.expected.load() - item.available.load();
                 let amount_to_load = unloaded.min(*remaining);
 
@@ -104,7 +394,60 @@ impl Scheduler {
             .collect()
     }
 
+    /// Sets whether silence should be skipped where tracks join.
+    /// This does not modify the loaded samples, and silent tracks are left as is.
+    pub fn set_trim_boundaries(&self, enabled: bool) {
+        self.trim_boundaries.store(enabled);
+    }
+
+    pub fn trim_boundaries(&self) -> bool {
+        self.trim_boundaries.load()
+    }
+
+    /// Returns the loader that is currently playing, if any
+    pub fn current(&self) -> Option<LoaderId> {
+        self.queue.lock().unwrap().first().map(|i| i.loader.id())
+    }
+
+    /// Returns the playback offset of the current loader
+    pub fn offset(&self) -> usize {
+        self.offset.load()
+    }
+
+    /// Moves the playback offset of the current loader, cancelling any fade into it
+    pub fn seek(&self, offset: usize) {
+        *self.fade.lock().unwrap() = None;
+        self.offset.store(offset);
+    }
+
+    /// Moves the offset back to where the loader advanced to last ran out of samples,
+    /// so playback continues from there once it has loaded more instead of skipping ahead.
+    pub fn rewind(&self, offset: usize) {
+        let skipped = self.offset.load().saturating_sub(offset);
+
+        self.offset.store(offset.min(self.offset.load()));
+        self.total_offset.fetch_sub(skipped);
+    }
+
+    /// Stops scheduling anything, dropping any fade and interjection
+    pub fn clear(&self) {
+        *self.fade.lock().unwrap() = None;
+        *self.resume.lock().unwrap() = None;
+
+        self.offset.store(0);
+        self.set_loaders(vec![]);
+    }
+
     pub fn set_loaders(&self, new_loaders: Vec<Arc<Loader>>) {
+        // Tracks that start after their intro are loaded from there, if they can seek
+        for loader in new_loaders.iter() {
+            let intro = self.intro(loader.expected());
+
+            if intro > 0 {
+                loader.start_at(intro);
+            }
+        }
+
         {
             let mut queue = self.queue.lock().unwrap();
             *queue = new_loaders.into_iter().map(ScheduledItem::new).collect();
@@ -113,6 +456,58 @@ impl Scheduler {
         self.calculate_total_available()
     }
 
+    /// Replaces the loaders, starting the new current loader from the beginning or after its intro.
+    /// The previous loader fades out from where it was if crossfading is enabled.
+    pub fn replace_current(&self, new_loaders: Vec<Arc<Loader>>) {
+        let previous = self.queue.lock().unwrap().first().map(|i| i.loader.clone());
+        let start = new_loaders
+            .first()
+            .map(|l| self.intro(l.expected()))
+            .unwrap_or_default();
+        let offset = self.offset.swap(start);
+
+        let fade = previous
+            .zip(new_loaders.first())
+            .map(|(from, into)| {
+                let remaining = from.available().saturating_sub(offset);
+                let align = |length: usize| length - length % CHANNEL_COUNT;
+
+                let (_, fade_out) = self.crossfades_of(from.id());
+                let (fade_in, _) = self.crossfades_of(into.id());
+                let fade_out = align(fade_out.min(remaining));
+                let fade_in = align(fade_in.min(remaining));
+
+                Fade {
+                    from: from.id(),
+                    start: offset,
+                    length: fade_out.max(fade_in),
+                    fade_out,
+                    into: into.id(),
+                    into_start: start,
+                    fade_in,
+                }
+            })
+            .filter(|fade| fade.length > 0);
+
+        *self.fade.lock().unwrap() = fade;
+        self.set_loaders(new_loaders);
+    }
+
+    /// Plays the new current loader in front of the one that was playing,
+    /// which continues where it was once the interjection has finished.
+    pub fn interject(&self, new_loaders: Vec<Arc<Loader>>) {
+        let current = self.queue.lock().unwrap().first().map(|i| i.loader.id());
+        let offset = self.offset.swap(0);
+
+        // Interjecting an interjection still returns to what played before it
+        let mut resume = self.resume.lock().unwrap();
+        *resume = resume.or(current.map(|id| (id, offset)));
+        drop(resume);
+
+        *self.fade.lock().unwrap() = None;
+        self.set_loaders(new_loaders);
+    }
+
     /// Called when a loader has more content
     pub fn notify_load(&self, id: LoaderId, new_amount: usize) {
         {
@@ -150,6 +545,7 @@ impl ScheduledItem {
             loader,
             expected: 0.into(),
             available: 0.into(),
+            silence: Default::default(),
         };
 
         me.update();
@@ -160,8 +556,251 @@ impl ScheduledItem {
         self.available.load() == self.expected.load()
     }
 
+    /// Returns true if everything loaded so far is silent
+    fn is_silent(&self) -> bool {
+        let silence = self.silence.load();
+        silence.is_complete() && silence.end == self.available.load()
+    }
+
+    /// Returns the offset to start at when transitioning to this item
+    fn start(&self, trim: bool) -> usize {
+        let silence = self.silence.load();
+
+        // The start of the source is not known after seeking
+        if trim && !self.is_silent() && silence.start == 0 {
+            silence.leading - silence.leading % CHANNEL_COUNT
+        } else {
+            0
+        }
+    }
+
+    /// Returns the offset at which there is nothing more to read
+    fn end(&self, trim: bool) -> usize {
+        let available = self.available.load();
+        let trailing = self.silence.load().trailing;
+
+        // The silence at the end is only known once everything is loaded
+        if trim && self.complete() && !self.is_silent() {
+            available - (trailing - trailing % CHANNEL_COUNT)
+        } else {
+            available
+        }
+    }
+
     fn update(&self) {
-        self.available.store(self.loader.available());
+        let loaded = self.loader.loaded();
+        let mut silence = self.silence.load();
+
+        // Only what loaded since the last update is scanned, unless
+        // the loaded samples were replaced, such as after seeking.
+        if silence.start != loaded.start || silence.end > loaded.end {
+            silence = Silence::new(loaded.start);
+        }
+
+        let scanned = self.loader.silence_in(silence.end..loaded.end);
+
+        self.silence.store(silence.extend(loaded.end, scanned));
+        self.available.store(loaded.end);
         self.expected.store(self.loader.expected());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Scheduler;
+    use crate::{
+        audio::{pipeline::IntoSampleReader, Pool},
+        util::model::Identified,
+    };
+
+    #[test]
+    fn repeated_loaders_are_scheduled_in_a_loop() {
+        let pool = Pool::new();
+        let loader = pool.add(vec![1.; 10].into_sample_reader(), 10);
+        let id = loader.id();
+
+        let scheduler = Scheduler::new();
+        scheduler.set_loaders(vec![loader.clone(), loader.clone(), loader]);
+
+        // Duplicates are only requested to load once
+        assert_eq!(scheduler.preload(), vec![(id, 10)]);
+
+        let new_amount = pool.load(id, 10);
+        scheduler.notify_load(id, new_amount);
+        assert_eq!(scheduler.preload(), vec![]);
+
+        // Reading past the end continues from the start of the same loader
+        assert_eq!(scheduler.advance(15), vec![(id, 0..10), (id, 0..5)]);
+        assert_eq!(scheduler.advance(5), vec![(id, 5..10)]);
+    }
+
+    #[test]
+    fn advancements_tile_the_amount_across_loaders() {
+        let pool = Pool::new();
+
+        let first = pool.add(vec![1.; 7].into_sample_reader(), 7);
+        let second = pool.add(vec![2.; 3].into_sample_reader(), 3);
+        let third = pool.add(vec![3.; 10].into_sample_reader(), 10);
+
+        let scheduler = Scheduler::new();
+        scheduler.set_loaders(vec![first.clone(), second.clone(), third.clone()]);
+
+        for (id, amount) in scheduler.preload() {
+            let new_amount = pool.load(id, amount);
+            scheduler.notify_load(id, new_amount);
+        }
+
+        scheduler.advance(4);
+
+        // The rest of the first, all of the second and the start of the third
+        let advancements = scheduler.advance(8);
+        let total: usize = advancements.iter().map(|(_, r)| r.len()).sum();
+
+        assert_eq!(total, 8);
+        assert_eq!(
+            advancements,
+            vec![(first.id(), 4..7), (second.id(), 0..3), (third.id(), 0..2)]
+        );
+    }
+
+    #[test]
+    fn silence_is_trimmed_where_tracks_join() {
+        let pool = Pool::new();
+
+        let first = pool.add(vec![1., 1., 0., 0., 0., 0.].into_sample_reader(), 6);
+        let second = pool.add(vec![0., 0., 0., 0., 1., 1.].into_sample_reader(), 6);
+
+        let scheduler = Scheduler::new();
+        scheduler.set_trim_boundaries(true);
+        scheduler.set_loaders(vec![first.clone(), second.clone()]);
+
+        for (id, amount) in scheduler.preload() {
+            let new_amount = pool.load(id, amount);
+            scheduler.notify_load(id, new_amount);
+        }
+
+        assert_eq!(
+            scheduler.advance(4),
+            vec![(first.id(), 0..2), (second.id(), 4..6)]
+        );
+
+        // The loaded samples are preserved
+        assert_eq!(first.available(), 6);
+        assert_eq!(second.available(), 6);
+    }
+
+    #[test]
+    fn quiet_samples_are_trimmed_as_they_load() {
+        let pool = Pool::new();
+        let noise = 0.0001;
+
+        let first = pool.add(vec![1., 1., noise, -noise, 0., 0.].into_sample_reader(), 6);
+        let second = pool.add(vec![noise, 0., 0., 0., 1., 1.].into_sample_reader(), 6);
+
+        let scheduler = Scheduler::new();
+        scheduler.set_trim_boundaries(true);
+        scheduler.set_loaders(vec![first.clone(), second.clone()]);
+
+        // The silence spans several loads, so it is scanned in parts
+        for _ in 0..3 {
+            for id in [first.id(), second.id()] {
+                let new_amount = pool.load(id, 2);
+                scheduler.notify_load(id, new_amount);
+            }
+        }
+
+        assert_eq!(
+            scheduler.advance(4),
+            vec![(first.id(), 0..2), (second.id(), 4..6)]
+        );
+    }
+
+    #[test]
+    fn crossfades_wait_for_the_next_item_to_load() {
+        let pool = Pool::new();
+
+        let first = pool.add(vec![1.; 10].into_sample_reader(), 10);
+        let second = pool.add(vec![2.; 10].into_sample_reader(), 10);
+
+        let scheduler = Scheduler::new();
+        scheduler.set_crossfade(4);
+        scheduler.set_loaders(vec![first.clone(), second.clone()]);
+
+        let new_amount = pool.load(first.id(), 10);
+        scheduler.notify_load(first.id(), new_amount);
+
+        // The end of the first item is held back until it can be faded
+        assert_eq!(scheduler.advance(10), vec![(first.id(), 0..6)]);
+        assert_eq!(scheduler.fade(), None);
+
+        let new_amount = pool.load(second.id(), 10);
+        scheduler.notify_load(second.id(), new_amount);
+
+        assert_eq!(
+            scheduler.advance(4),
+            vec![(first.id(), 6..6), (second.id(), 0..4)]
+        );
+        assert_eq!(scheduler.fade().unwrap().start, 6);
+    }
+
+    #[test]
+    fn crossfaded_items_overlap() {
+        let pool = Pool::new();
+
+        let first = pool.add(vec![1.; 10].into_sample_reader(), 10);
+        let second = pool.add(vec![2.; 10].into_sample_reader(), 10);
+
+        let scheduler = Scheduler::new();
+        scheduler.set_crossfade(4);
+        scheduler.set_loaders(vec![first.clone(), second.clone()]);
+
+        for (id, amount) in scheduler.preload() {
+            let new_amount = pool.load(id, amount);
+            scheduler.notify_load(id, new_amount);
+        }
+
+        // The second item starts 4 samples before the first ends
+        assert_eq!(
+            scheduler.advance(10),
+            vec![(first.id(), 0..6), (second.id(), 0..4)]
+        );
+
+        let fade = scheduler.fade().unwrap();
+        assert_eq!((fade.from, fade.start, fade.length), (first.id(), 6, 4));
+        assert_eq!((fade.into, fade.into_start), (second.id(), 0));
+    }
+
+    #[test]
+    fn explained_advance_matches_the_advance() {
+        let pool = Pool::new();
+
+        let first = pool.add(vec![1.; 10].into_sample_reader(), 10);
+        let second = pool.add(vec![1.; 10].into_sample_reader(), 10);
+
+        let scheduler = Scheduler::new();
+        scheduler.set_loaders(vec![first.clone(), second.clone()]);
+
+        for (id, amount) in scheduler.preload() {
+            let new_amount = pool.load(id, amount);
+            scheduler.notify_load(id, new_amount);
+        }
+
+        scheduler.advance(6);
+
+        let plan = scheduler.explain_advance(8);
+        assert_eq!(
+            plan,
+            vec![(first.id(), 6..10, true), (second.id(), 0..4, false)]
+        );
+
+        // Explaining does not move anything
+        assert_eq!(scheduler.explain_advance(8), plan);
+        assert_eq!(scheduler.offset(), 6);
+
+        let advanced = scheduler.advance(8);
+        let explained: Vec<_> = plan.into_iter().map(|(id, r, _)| (id, r)).collect();
+
+        assert_eq!(advanced, explained);
+        assert_eq!(scheduler.offset(), 4);
+    }
+}
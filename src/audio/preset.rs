@@ -0,0 +1,297 @@
+use std::{error::Error, fmt::Display, time::Duration};
+
+use json::{object, JsonValue};
+
+use super::{
+    AudioSystem, BudgetExceeded, CrossfeedConfig, EffectPreset, EqBand, InvalidBand,
+    NoiseGateConfig, ProcessingChain,
+};
+
+/// Everything that shapes how the output sounds, which can be shared as JSON
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    pub volume: f32,
+    pub speed: f32,
+    pub eq: Vec<EqBand>,
+    pub noise_gate: Option<NoiseGateConfig>,
+    pub crossfeed: Option<CrossfeedConfig>,
+    /// The loudness in LUFS the final mix is adjusted towards
+    pub output_loudness: Option<f32>,
+    /// The loudness in LUFS every track is brought to
+    pub normalization: Option<f32>,
+    /// The effects of the processing chain with whether they are enabled
+    pub effects: Vec<(EffectPreset, bool)>,
+}
+
+impl Preset {
+    pub fn to_json(&self) -> JsonValue {
+        let eq: Vec<_> = self
+            .eq
+            .iter()
+            .map(|band| {
+                object! {
+                    "frequency" => band.frequency,
+                    "gain" => band.gain,
+                    "q" => band.q,
+                }
+            })
+            .collect();
+
+        let effects: Vec<_> = self
+            .effects
+            .iter()
+            .map(|(effect, enabled)| match effect {
+                EffectPreset::Gain { gain } => object! {
+                    "effect" => "gain",
+                    "gain" => *gain,
+                    "enabled" => *enabled,
+                },
+            })
+            .collect();
+
+        object! {
+            "volume" => self.volume,
+            "speed" => self.speed,
+            "eq" => eq,
+            "noise_gate" => self.noise_gate.map(|gate| object! {
+                "threshold" => gate.threshold,
+                "attack_us" => gate.attack.as_micros() as u64,
+                "hold_us" => gate.hold.as_micros() as u64,
+                "release_us" => gate.release.as_micros() as u64,
+            }),
+            "crossfeed" => self.crossfeed.map(|crossfeed| object! {
+                "amount" => crossfeed.amount,
+                "cutoff" => crossfeed.cutoff,
+            }),
+            "output_loudness" => self.output_loudness,
+            "normalization" => self.normalization,
+            "effects" => effects,
+        }
+    }
+
+    /// Reads a preset written by [Preset::to_json]
+    pub fn from_json(json: &str) -> Result<Self, InvalidPreset> {
+        let preset = json::parse(json).map_err(|_| InvalidPreset("it is not json"))?;
+
+        let number = |value: &JsonValue, what| value.as_f32().ok_or(InvalidPreset(what));
+        let micros = |value: &JsonValue, what| {
+            value
+                .as_u64()
+                .map(Duration::from_micros)
+                .ok_or(InvalidPreset(what))
+        };
+
+        let eq = preset["eq"]
+            .members()
+            .map(|band| {
+                Ok(EqBand {
+                    frequency: number(&band["frequency"], "a band has no frequency")?,
+                    gain: number(&band["gain"], "a band has no gain")?,
+                    q: number(&band["q"], "a band has no q")?,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let gate = &preset["noise_gate"];
+        let noise_gate = match gate.is_null() {
+            true => None,
+            false => Some(NoiseGateConfig {
+                threshold: number(&gate["threshold"], "the noise gate has no threshold")?,
+                attack: micros(&gate["attack_us"], "the noise gate has no attack")?,
+                hold: micros(&gate["hold_us"], "the noise gate has no hold")?,
+                release: micros(&gate["release_us"], "the noise gate has no release")?,
+            }),
+        };
+
+        let crossfeed = &preset["crossfeed"];
+        let crossfeed = match crossfeed.is_null() {
+            true => None,
+            false => Some(CrossfeedConfig {
+                amount: number(&crossfeed["amount"], "the crossfeed has no amount")?,
+                cutoff: number(&crossfeed["cutoff"], "the crossfeed has no cutoff")?,
+            }),
+        };
+
+        let effects = preset["effects"]
+            .members()
+            .map(|stage| {
+                let effect = match stage["effect"].as_str() {
+                    Some("gain") => EffectPreset::Gain {
+                        gain: number(&stage["gain"], "a gain has no gain")?,
+                    },
+                    _ => return Err(InvalidPreset("an effect is not known")),
+                };
+
+                Ok((effect, stage["enabled"].as_bool().unwrap_or(true)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            volume: number(&preset["volume"], "there is no volume")?,
+            speed: number(&preset["speed"], "there is no speed")?,
+            eq,
+            noise_gate,
+            crossfeed,
+            output_loudness: preset["output_loudness"].as_f32(),
+            normalization: preset["normalization"].as_f32(),
+            effects,
+        })
+    }
+}
+
+/// A preset could not be read, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidPreset(pub &'static str);
+
+impl Display for InvalidPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The preset is invalid, as {}", self.0)
+    }
+}
+
+impl Error for InvalidPreset {}
+
+/// A preset could not be applied, so nothing was changed
+#[derive(Debug)]
+pub enum ImportError {
+    /// Its effects exceed the processing budget
+    Budget(BudgetExceeded),
+    /// A band of its equalizer cannot be filtered
+    Band(InvalidBand),
+}
+
+impl From<BudgetExceeded> for ImportError {
+    fn from(err: BudgetExceeded) -> Self {
+        ImportError::Budget(err)
+    }
+}
+
+impl From<InvalidBand> for ImportError {
+    fn from(err: InvalidBand) -> Self {
+        ImportError::Band(err)
+    }
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Budget(err) => write!(f, "{}", err),
+            ImportError::Band(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ImportError {}
+
+impl AudioSystem {
+    /// Captures the processing of the output, so it can be shared or restored later.
+    /// Effects that cannot be created again, such as custom ones, are left out.
+    pub fn export_preset(&self) -> Preset {
+        Preset {
+            volume: self.volume(),
+            speed: self.speed(),
+            eq: self.eq_bands(),
+            noise_gate: self.noise_gate.lock().unwrap().as_ref().map(|g| g.config()),
+            crossfeed: self.crossfeed.lock().unwrap().as_ref().map(|c| c.config()),
+            output_loudness: self.normalizer.lock().unwrap().as_ref().map(|n| n.target()),
+            normalization: self.normalization.load(),
+            effects: self.chain.lock().unwrap().presets(),
+        }
+    }
+
+    /// Applies a preset, fading to its processing chain and ramping to its volume so it
+    /// does not click. Nothing is changed if its effects exceed the processing budget,
+    /// or a band of its equalizer is invalid.
+    pub fn import_preset(&self, preset: Preset) -> Result<(), ImportError> {
+        preset.eq.iter().try_for_each(EqBand::validate)?;
+
+        let budget = self.chain.lock().unwrap().budget();
+        let chain = ProcessingChain::from_presets(&preset.effects, budget)?;
+
+        self.set_chain(chain);
+        self.set_eq(preset.eq)?;
+        self.set_noise_gate(preset.noise_gate);
+        self.set_crossfeed(preset.crossfeed);
+        self.set_output_loudness(preset.output_loudness);
+        self.set_normalization(preset.normalization);
+        self.set_speed(preset.speed);
+        self.set_volume(preset.volume);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{ImportError, Preset};
+    use crate::audio::{
+        processing::effects::Gain, AudioSystem, CrossfeedConfig, EffectPreset, EqBand, InvalidBand,
+        NoiseGateConfig,
+    };
+
+    #[test]
+    fn presets_restore_every_parameter() {
+        let system = AudioSystem::new();
+
+        system.set_volume(0.5);
+        system.set_speed(1.25);
+        system
+            .set_eq(vec![EqBand {
+                frequency: 120.,
+                gain: 3.,
+                q: 0.7,
+            }])
+            .unwrap();
+        system.set_noise_gate(Some(NoiseGateConfig {
+            threshold: 0.02,
+            hold: Duration::from_millis(80),
+            ..Default::default()
+        }));
+        system.set_crossfeed(Some(CrossfeedConfig::default()));
+        system.set_output_loudness(Some(-14.));
+        system.set_normalization(Some(-18.));
+        system.add_effect(Gain::new(0.8)).unwrap();
+        system.add_effect(Gain::new(0.5)).unwrap();
+        system.set_effect_enabled(1, false);
+
+        let preset = system.export_preset();
+        assert_eq!(
+            preset.effects,
+            vec![
+                (EffectPreset::Gain { gain: 0.8 }, true),
+                (EffectPreset::Gain { gain: 0.5 }, false)
+            ]
+        );
+
+        // Shared as a file, then loaded somewhere that has not changed anything
+        let shared = Preset::from_json(&preset.to_json().dump()).unwrap();
+        assert_eq!(shared, preset);
+
+        let other = AudioSystem::new();
+        assert_ne!(other.export_preset(), preset);
+
+        other.import_preset(shared).unwrap();
+        assert_eq!(other.export_preset(), preset);
+    }
+
+    #[test]
+    fn presets_with_invalid_bands_change_nothing() {
+        let system = AudioSystem::new();
+        let mut preset = system.export_preset();
+
+        preset.volume = 0.5;
+        preset.eq = vec![EqBand {
+            frequency: 1000.,
+            gain: 3.,
+            q: 0.,
+        }];
+
+        assert!(matches!(
+            system.import_preset(preset),
+            Err(ImportError::Band(InvalidBand::Q(_)))
+        ));
+        assert_eq!(system.volume(), 1.);
+    }
+}
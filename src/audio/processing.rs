@@ -1,8 +1,12 @@
 /// Processing implementations for ffmpeg
 pub mod ffmpeg {
     use anyhow::{Context, Result};
+    use crossbeam::atomic::AtomicCell;
+    use log::{info, warn};
 
     use std::{
+        error::Error,
+        fmt::Display,
         io::Read,
         process::{Child, ChildStdout, Command, Stdio},
     };
@@ -12,25 +16,124 @@ pub mod ffmpeg {
         SAMPLE_IN_BYTES,
     };
 
+    /// The highest input sample rate that is resampled directly
+    pub const MAX_INPUT_SAMPLE_RATE: usize = 384_000;
+
+    static SAMPLE_RATE_POLICY: AtomicCell<SampleRatePolicy> =
+        AtomicCell::new(SampleRatePolicy::Reject);
+
+    /// What to do with inputs that have a sample rate the resampler cannot handle
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SampleRatePolicy {
+        /// The input cannot be played
+        Reject,
+        /// The input is first resampled to [MAX_INPUT_SAMPLE_RATE]
+        Fallback,
+    }
+
+    impl SampleRatePolicy {
+        pub fn parse(str: &str) -> Option<Self> {
+            match str {
+                "reject" => Some(Self::Reject),
+                "fallback" => Some(Self::Fallback),
+                _ => None,
+            }
+        }
+    }
+
+    /// Sets the policy used for inputs created from now on
+    pub fn set_sample_rate_policy(policy: SampleRatePolicy) {
+        SAMPLE_RATE_POLICY.store(policy);
+    }
+
+    pub fn sample_rate_policy() -> SampleRatePolicy {
+        SAMPLE_RATE_POLICY.load()
+    }
+
+    /// How an input is resampled to [SAMPLE_RATE]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Resampling {
+        Direct,
+        /// The input is brought down to [MAX_INPUT_SAMPLE_RATE] in a separate step
+        Clamped,
+    }
+
+    /// An input has a sample rate that is rejected by the [SampleRatePolicy]
+    #[derive(Debug)]
+    pub struct UnsupportedSampleRate {
+        pub rate: usize,
+    }
+
+    impl Display for UnsupportedSampleRate {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "A sample rate of {} Hz is not supported, the supported range is 1 to {} Hz",
+                self.rate, MAX_INPUT_SAMPLE_RATE
+            )
+        }
+    }
+
+    impl Error for UnsupportedSampleRate {}
+
+    /// Decides how an input with the given sample rate is resampled
+    pub fn check_sample_rate(
+        rate: usize,
+        policy: SampleRatePolicy,
+    ) -> Result<Resampling, UnsupportedSampleRate> {
+        if (1..=MAX_INPUT_SAMPLE_RATE).contains(&rate) {
+            return Ok(Resampling::Direct);
+        }
+
+        match policy {
+            SampleRatePolicy::Reject => {
+                warn!("Rejecting input with a sample rate of {} Hz", rate);
+                Err(UnsupportedSampleRate { rate })
+            }
+            SampleRatePolicy::Fallback => {
+                info!(
+                    "Resampling input with a sample rate of {} Hz to {} Hz first",
+                    rate, MAX_INPUT_SAMPLE_RATE
+                );
+                Ok(Resampling::Clamped)
+            }
+        }
+    }
+
     /// What should the ffmpeg process do
     pub enum Operation {
         /// Convert to [Sample]
         ToRaw(String),
+        /// Convert to [Sample], resampling the input in two steps
+        ToRawClamped(String),
     }
 
     impl Operation {
+        /// Converts to [Sample] with the given resampling
+        pub fn to_raw(input: String, resampling: Resampling) -> Self {
+            match resampling {
+                Resampling::Direct => Operation::ToRaw(input),
+                Resampling::Clamped => Operation::ToRawClamped(input),
+            }
+        }
+
         fn apply(&self, command: &mut Command) {
             match self {
-                Operation::ToRaw(input) => self.convert_to_raw(input, command),
+                Operation::ToRaw(input) => self.convert_to_raw(input, &[], command),
+                Operation::ToRawClamped(input) => {
+                    let filter = format!("aresample={}", MAX_INPUT_SAMPLE_RATE);
+                    self.convert_to_raw(input, &["-af", &filter], command)
+                }
             }
         }
 
-        fn convert_to_raw(&self, input: &str, command: &mut Command) {
+        fn convert_to_raw(&self, input: &str, filters: &[&str], command: &mut Command) {
             command
                 .args(["-i", input])
                 .args(["-c:a", "pcm_f32le"])
                 .args(["-f", "f32le"])
                 .args(["-fflags", "+discardcorrupt"])
+                .args(filters)
                 .args(["-ar", &SAMPLE_RATE.to_string()])
                 .args(["-ac", &CHANNEL_COUNT.to_string()])
                 .args(["pipe:"])
@@ -93,26 +196,94 @@ pub mod ffmpeg {
 
     pub struct Probe {
         pub duration: f32,
+        /// The sample rate of the first audio stream, if it has one
+        pub sample_rate: Option<usize>,
+        /// The codec of the first audio stream, if it has one
+        pub codec: Option<String>,
+        /// The tags of the input, with lowercase keys as the case differs between formats
+        pub tags: Vec<(String, String)>,
+    }
+
+    impl Probe {
+        /// Returns the value of a tag, if the input has it
+        pub fn tag(&self, key: &str) -> Option<String> {
+            self.tags
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.to_owned())
+        }
     }
 
-    pub fn probe(input: &str) -> Probe {
+    /// Probes an input, returning None if it could not be probed, such as if it is not audio
+    pub fn probe(input: &str) -> Option<Probe> {
         let output = Command::new("ffprobe")
-            .args([input])
+            .args(["-i", input])
             .args(["-print_format", "json"])
             .args(["-show_format"])
+            .args(["-show_streams"])
             .output()
-            .unwrap();
+            .ok()?;
 
-        let raw = String::from_utf8(output.stdout).unwrap();
-        let parsed = json::parse(&raw).unwrap();
+        let raw = String::from_utf8(output.stdout).ok()?;
+        let parsed = json::parse(&raw).ok()?;
 
         let format = &parsed["format"];
         let duration = format["duration"]
             .as_str()
-            .and_then(|s| s.parse::<f32>().ok())
-            .unwrap();
+            .and_then(|s| s.parse::<f32>().ok())?;
+
+        let stream = parsed["streams"]
+            .members()
+            .find(|s| s["codec_type"].as_str() == Some("audio"));
+
+        let sample_rate = stream
+            .and_then(|s| s["sample_rate"].as_str())
+            .and_then(|s| s.parse::<usize>().ok());
+
+        let tags = format["tags"]
+            .entries()
+            .filter_map(|(key, value)| Some((key.to_lowercase(), value.as_str()?.to_string())))
+            .collect();
+
+        Some(Probe {
+            duration,
+            sample_rate,
+            codec: stream
+                .and_then(|s| s["codec_name"].as_str())
+                .map(str::to_string),
+            tags,
+        })
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::{check_sample_rate, Operation, Resampling, SampleRatePolicy};
+        use std::process::Command;
+
+        #[test]
+        fn extreme_sample_rates_follow_the_policy() {
+            let extreme = 10_000_000;
+
+            assert!(check_sample_rate(extreme, SampleRatePolicy::Reject).is_err());
+            assert_eq!(
+                check_sample_rate(extreme, SampleRatePolicy::Fallback).unwrap(),
+                Resampling::Clamped
+            );
+            assert_eq!(
+                check_sample_rate(48000, SampleRatePolicy::Reject).unwrap(),
+                Resampling::Direct
+            );
+
+            // The fallback resamples before the output rate is applied
+            let mut command = Command::new("ffmpeg");
+            Operation::to_raw("input".into(), Resampling::Clamped).apply(&mut command);
 
-        Probe { duration }
+            let args: Vec<_> = command.get_args().map(|a| a.to_str().unwrap()).collect();
+            let filter = args.iter().position(|a| *a == "aresample=384000").unwrap();
+
+            assert!(filter < args.iter().position(|a| *a == "-ar").unwrap());
+            assert_eq!(args.last(), Some(&"pipe:"));
+        }
     }
 }
 
@@ -323,3 +494,1947 @@ mod dsp {
         }
     }
 }
+
+/// The chain of effects applied to the output
+pub mod chain {
+    use std::fmt::Display;
+
+    use serde::Serialize;
+
+    use crate::audio::{Sample, CHANNEL_COUNT, SAMPLE_RATE, STREAM_CHUNK_SIZE};
+
+    /// How many frames it takes to fade between the processed and
+    /// unprocessed signal when a stage is toggled, to prevent clicks.
+    const BYPASS_FADE_FRAMES: usize = SAMPLE_RATE / 100;
+
+    /// An effect applied to interleaved samples of the output.
+    pub trait Effect: Send {
+        fn name(&self) -> &'static str;
+
+        /// Returns the approximate cost of processing a sample,
+        /// where 1 is roughly a single multiplication.
+        fn cost(&self) -> usize;
+
+        fn process(&mut self, samples: &mut [Sample]);
+
+        /// Returns the name and current value of each parameter
+        fn parameters(&self) -> Vec<(&'static str, f32)> {
+            vec![]
+        }
+
+        /// Describes the effect so it can be created again, such as from a preset.
+        /// Effects that depend on something else, like a source, return `None`.
+        fn preset(&self) -> Option<EffectPreset> {
+            None
+        }
+    }
+
+    /// An effect of the chain that can be created again
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum EffectPreset {
+        Gain { gain: f32 },
+    }
+
+    impl EffectPreset {
+        fn add_to(self, chain: &mut ProcessingChain) -> Result<(), BudgetExceeded> {
+            match self {
+                EffectPreset::Gain { gain } => chain.add(super::effects::Gain::new(gain)),
+            }
+        }
+    }
+
+    /// Describes a stage of the chain for display
+    #[derive(Debug, Clone, PartialEq, Serialize)]
+    pub struct EffectInfo {
+        pub name: &'static str,
+        pub enabled: bool,
+        pub parameters: Vec<(&'static str, f32)>,
+    }
+
+    struct Stage {
+        effect: Box<dyn Effect>,
+        enabled: bool,
+        /// How much of the processed signal is used, from 0 to 1
+        mix: f32,
+        /// The unprocessed signal while fading, allocated when the stage is toggled
+        dry: Vec<Sample>,
+    }
+
+    impl Stage {
+        fn process(&mut self, samples: &mut [Sample]) {
+            let target = if self.enabled { 1. } else { 0. };
+
+            if self.mix == target {
+                if self.enabled {
+                    self.effect.process(samples);
+                }
+
+                return;
+            }
+
+            self.dry.clear();
+            self.dry.extend_from_slice(samples);
+            self.effect.process(samples);
+
+            let step = 1. / BYPASS_FADE_FRAMES as f32;
+
+            for (frame, dry) in samples
+                .chunks_exact_mut(CHANNEL_COUNT)
+                .zip(self.dry.chunks_exact(CHANNEL_COUNT))
+            {
+                self.mix = match self.enabled {
+                    true => (self.mix + step).min(1.),
+                    false => (self.mix - step).max(0.),
+                };
+
+                for (wet, dry) in frame.iter_mut().zip(dry) {
+                    *wet = dry + (*wet - dry) * self.mix;
+                }
+            }
+        }
+    }
+
+    /// Applies effects in order, limited by an optional cost budget
+    /// to prevent overloading the playback thread.
+    #[derive(Default)]
+    pub struct ProcessingChain {
+        stages: Vec<Stage>,
+        budget: Option<usize>,
+        /// The chain this one replaced, and how many frames it has faded out for
+        outgoing: Option<(Box<ProcessingChain>, usize)>,
+        /// The input of the outgoing chain, allocated when the chain is replaced
+        scratch: Vec<Sample>,
+        /// How many samples are processed at once, which buffers are allocated for ahead of time
+        chunk_size: usize,
+    }
+
+    /// The effect would make the chain exceed its cost budget
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct BudgetExceeded {
+        pub name: &'static str,
+        pub cost: usize,
+        pub budget: usize,
+        /// The name and cost of each stage in the chain
+        pub usage: Vec<(&'static str, usize)>,
+    }
+
+    impl ProcessingChain {
+        pub fn new() -> Self {
+            Self {
+                chunk_size: STREAM_CHUNK_SIZE,
+                ..Default::default()
+            }
+        }
+
+        /// Sets how many samples are processed at once, such as when chunks are longer
+        pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+            self.chunk_size = chunk_size;
+            self
+        }
+
+        /// Adds an effect to the end of the chain
+        pub fn add<E: 'static + Effect>(&mut self, effect: E) -> Result<(), BudgetExceeded> {
+            let cost = effect.cost();
+
+            if let Some(budget) = self.budget {
+                if self.cost() + cost > budget {
+                    return Err(BudgetExceeded {
+                        name: effect.name(),
+                        usage: self.usage(),
+                        cost,
+                        budget,
+                    });
+                }
+            }
+
+            self.stages.push(Stage {
+                effect: Box::new(effect),
+                enabled: true,
+                mix: 1.,
+                dry: vec![],
+            });
+
+            Ok(())
+        }
+
+        /// Enables or bypasses the stage at the index, fading between the
+        /// processed and unprocessed signal. Returns false if there is no such stage.
+        pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+            match self.stages.get_mut(index) {
+                Some(stage) => {
+                    stage.enabled = enabled;
+                    stage.dry.reserve(self.chunk_size);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        /// Sets the maximum total cost, or removes the limit if `None`
+        pub fn set_budget(&mut self, budget: Option<usize>) {
+            self.budget = budget;
+        }
+
+        pub fn budget(&self) -> Option<usize> {
+            self.budget
+        }
+
+        /// Returns the total cost of every stage
+        pub fn cost(&self) -> usize {
+            self.stages.iter().map(|s| s.effect.cost()).sum()
+        }
+
+        pub fn usage(&self) -> Vec<(&'static str, usize)> {
+            self.stages
+                .iter()
+                .map(|s| (s.effect.name(), s.effect.cost()))
+                .collect()
+        }
+
+        /// Returns every stage in the order they are applied
+        pub fn effects(&self) -> Vec<EffectInfo> {
+            self.stages
+                .iter()
+                .map(|s| EffectInfo {
+                    name: s.effect.name(),
+                    enabled: s.enabled,
+                    parameters: s.effect.parameters(),
+                })
+                .collect()
+        }
+
+        /// Returns the stages that can be created again with whether they are enabled,
+        /// in the order they are applied
+        pub fn presets(&self) -> Vec<(EffectPreset, bool)> {
+            self.stages
+                .iter()
+                .filter_map(|s| Some((s.effect.preset()?, s.enabled)))
+                .collect()
+        }
+
+        /// Creates a chain of the stages with a budget, such as one that was exported
+        pub fn from_presets(
+            presets: &[(EffectPreset, bool)],
+            budget: Option<usize>,
+        ) -> Result<Self, BudgetExceeded> {
+            let mut chain = Self::new();
+            chain.set_budget(budget);
+
+            for (i, (preset, enabled)) in presets.iter().enumerate() {
+                preset.add_to(&mut chain)?;
+                chain.set_enabled(i, *enabled);
+            }
+
+            Ok(chain)
+        }
+
+        /// Replaces every stage and the budget with those of another chain,
+        /// fading from the previous chain to the new one.
+        /// A previous chain that is still fading out finishes its fade within it.
+        pub fn replace(&mut self, chain: ProcessingChain) {
+            let previous = std::mem::replace(self, chain);
+
+            self.chunk_size = previous.chunk_size;
+            self.scratch.reserve(self.chunk_size);
+            self.outgoing = Some((Box::new(previous), 0));
+        }
+
+        pub fn process(&mut self, samples: &mut [Sample]) {
+            if self.outgoing.is_some() {
+                self.scratch.clear();
+                self.scratch.extend_from_slice(samples);
+            }
+
+            for stage in self.stages.iter_mut() {
+                stage.process(samples);
+            }
+
+            if let Some((previous, faded)) = &mut self.outgoing {
+                previous.process(&mut self.scratch);
+
+                for (frame, old) in samples
+                    .chunks_exact_mut(CHANNEL_COUNT)
+                    .zip(self.scratch.chunks_exact(CHANNEL_COUNT))
+                {
+                    let mix = (*faded as f32 / BYPASS_FADE_FRAMES as f32).min(1.);
+                    *faded += 1;
+
+                    for (new, old) in frame.iter_mut().zip(old) {
+                        *new = old + (*new - old) * mix;
+                    }
+                }
+
+                if *faded >= BYPASS_FADE_FRAMES {
+                    self.outgoing = None;
+                }
+            }
+        }
+    }
+
+    impl Display for BudgetExceeded {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let usage: Vec<_> = self
+                .usage
+                .iter()
+                .map(|(name, cost)| format!("{} ({})", name, cost))
+                .collect();
+
+            write!(
+                f,
+                "Adding {} ({}) exceeds the budget of {}, currently used by: {}",
+                self.name,
+                self.cost,
+                self.budget,
+                usage.join(", ")
+            )
+        }
+    }
+
+    impl std::error::Error for BudgetExceeded {}
+
+    #[cfg(test)]
+    mod test {
+        use super::ProcessingChain;
+        use crate::audio::{
+            processing::effects::{Ducker, Gain},
+            BufferRegistry,
+        };
+
+        #[test]
+        fn effects_over_budget_are_rejected() {
+            let mut chain = ProcessingChain::new();
+            chain.set_budget(Some(2));
+
+            assert!(chain.add(Gain::new(0.5)).is_ok());
+            assert!(chain.add(Gain::new(0.5)).is_ok());
+
+            let error = chain.add(Gain::new(0.5)).unwrap_err();
+            assert_eq!(error.usage, vec![("Gain", 1), ("Gain", 1)]);
+
+            let mut samples = vec![1.; 4];
+            chain.process(&mut samples);
+            assert_eq!(samples, vec![0.25; 4]);
+        }
+
+        #[test]
+        fn toggling_stages_does_not_click() {
+            let mut chain = ProcessingChain::new();
+            chain.add(Gain::new(0.5)).unwrap();
+
+            let mut output = vec![];
+
+            for chunk in 0..4 {
+                // Toggle in the middle of the signal
+                chain.set_enabled(0, chunk < 2);
+
+                let mut samples = vec![1.; 1000];
+                chain.process(&mut samples);
+                output.extend(samples);
+            }
+
+            let largest_jump = output
+                .windows(2)
+                .map(|w| (w[1] - w[0]).abs())
+                .fold(0., f32::max);
+
+            assert!(largest_jump < 0.01, "Jumped by {}", largest_jump);
+            assert_eq!(output[0], 0.5);
+            assert_eq!(output[output.len() - 1], 1.);
+
+            assert!(!chain.set_enabled(1, false));
+        }
+
+        #[test]
+        fn replacing_the_chain_does_not_click() {
+            let mut chain = ProcessingChain::new();
+            let mut output = vec![];
+
+            for chunk in 0..4 {
+                if chunk == 1 {
+                    let mut replacement = ProcessingChain::new();
+                    replacement.add(Gain::new(0.5)).unwrap();
+
+                    chain.replace(replacement);
+                }
+
+                let mut samples = vec![1.; 1000];
+                chain.process(&mut samples);
+                output.extend(samples);
+            }
+
+            let largest_jump = output
+                .windows(2)
+                .map(|w| (w[1] - w[0]).abs())
+                .fold(0., f32::max);
+
+            assert!(largest_jump < 0.01, "Jumped by {}", largest_jump);
+            assert_eq!(output[999], 1.);
+            assert_eq!(output[output.len() - 1], 0.5);
+            assert_eq!(chain.effects()[0].name, "Gain");
+        }
+
+        #[test]
+        fn replacing_the_chain_while_fading_does_not_click() {
+            let mut chain = ProcessingChain::new();
+            let mut output = vec![];
+
+            // Each replacement happens before the previous one finished fading
+            for gain in [0.5, 0.25, 0.75] {
+                let mut replacement = ProcessingChain::new();
+                replacement.add(Gain::new(gain)).unwrap();
+
+                chain.replace(replacement);
+
+                let mut samples = vec![1.; 200];
+                chain.process(&mut samples);
+                output.extend(samples);
+            }
+
+            for _ in 0..4 {
+                let mut samples = vec![1.; 1000];
+                chain.process(&mut samples);
+                output.extend(samples);
+            }
+
+            let largest_jump = output
+                .windows(2)
+                .map(|w| (w[1] - w[0]).abs())
+                .fold(0., f32::max);
+
+            assert!(largest_jump < 0.01, "Jumped by {}", largest_jump);
+            assert_eq!(output[output.len() - 1], 0.75);
+        }
+
+        #[test]
+        fn effects_are_listed_in_order() {
+            let registry = BufferRegistry::new();
+
+            let mut chain = ProcessingChain::new();
+            chain.add(Gain::new(0.5)).unwrap();
+            chain
+                .add(Ducker::new(registry.get_consumer(), 0.1))
+                .unwrap();
+            chain.set_enabled(1, false);
+
+            let effects = chain.effects();
+
+            assert_eq!(effects.len(), 2);
+            assert_eq!(effects[0].name, "Gain");
+            assert!(effects[0].enabled);
+            assert_eq!(effects[0].parameters, vec![("gain", 0.5)]);
+
+            assert_eq!(effects[1].name, "Ducker");
+            assert!(!effects[1].enabled);
+            assert_eq!(
+                effects[1].parameters,
+                vec![("threshold", 0.1), ("gain", 1.)]
+            );
+        }
+    }
+}
+
+/// Effects that can be added to a [chain::ProcessingChain]
+pub mod effects {
+    use std::{error::Error, f32::consts::TAU, fmt::Display, time::Duration};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{
+        chain::{Effect, EffectPreset},
+        loudness::{Biquad, LoudnessMeter},
+    };
+    use crate::audio::{AudioBufferConsumer, Sample, CHANNEL_COUNT, SAMPLE_RATE};
+
+    /// Multiplies every sample by a constant
+    pub struct Gain {
+        gain: f32,
+    }
+
+    impl Gain {
+        pub fn new(gain: f32) -> Self {
+            Self { gain }
+        }
+    }
+
+    impl Effect for Gain {
+        fn name(&self) -> &'static str {
+            "Gain"
+        }
+
+        fn cost(&self) -> usize {
+            1
+        }
+
+        fn process(&mut self, samples: &mut [Sample]) {
+            for sample in samples.iter_mut() {
+                *sample *= self.gain;
+            }
+        }
+
+        fn parameters(&self) -> Vec<(&'static str, f32)> {
+            vec![("gain", self.gain)]
+        }
+
+        fn preset(&self) -> Option<EffectPreset> {
+            Some(EffectPreset::Gain { gain: self.gain })
+        }
+    }
+
+    /// Lowers the volume while another source, such as a microphone,
+    /// is louder than a threshold.
+    pub struct Ducker {
+        source: AudioBufferConsumer,
+        /// The RMS level of the source that causes ducking
+        threshold: f32,
+        gain: f32,
+    }
+
+    impl Ducker {
+        /// The gain while ducked, about -12 dB
+        const DUCKED_GAIN: f32 = 0.25;
+
+        /// How many frames it takes to duck, and to return to full volume
+        const ATTACK_FRAMES: usize = SAMPLE_RATE / 100;
+        const RELEASE_FRAMES: usize = SAMPLE_RATE * 3 / 10;
+
+        pub fn new(source: AudioBufferConsumer, threshold: f32) -> Self {
+            Self {
+                source,
+                threshold,
+                gain: 1.,
+            }
+        }
+
+        /// Returns the RMS level of what the source received since last time
+        fn source_level(&mut self) -> f32 {
+            let bytes = self.source.read_available();
+            let samples = self.source.format().decode(&bytes);
+
+            if samples.is_empty() {
+                return 0.;
+            }
+
+            let sum: f32 = samples.iter().map(|s| s * s).sum();
+            (sum / samples.len() as f32).sqrt()
+        }
+    }
+
+    impl Effect for Ducker {
+        fn name(&self) -> &'static str {
+            "Ducker"
+        }
+
+        fn cost(&self) -> usize {
+            2
+        }
+
+        fn process(&mut self, samples: &mut [Sample]) {
+            let range = 1. - Self::DUCKED_GAIN;
+
+            let (target, step) = if self.source_level() > self.threshold {
+                (Self::DUCKED_GAIN, range / Self::ATTACK_FRAMES as f32)
+            } else {
+                (1., range / Self::RELEASE_FRAMES as f32)
+            };
+
+            for frame in samples.chunks_exact_mut(CHANNEL_COUNT) {
+                self.gain = match self.gain > target {
+                    true => (self.gain - step).max(target),
+                    false => (self.gain + step).min(target),
+                };
+
+                for sample in frame.iter_mut() {
+                    *sample *= self.gain;
+                }
+            }
+        }
+
+        fn parameters(&self) -> Vec<(&'static str, f32)> {
+            vec![("threshold", self.threshold), ("gain", self.gain)]
+        }
+    }
+
+    /// Configures a [NoiseGate]
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct NoiseGateConfig {
+        /// The peak level below which the gate closes
+        pub threshold: f32,
+        /// How long it takes to open once the level is above the threshold
+        pub attack: Duration,
+        /// How long it stays open after the level drops below the threshold
+        pub hold: Duration,
+        /// How long it takes to close after holding
+        pub release: Duration,
+    }
+
+    impl Default for NoiseGateConfig {
+        fn default() -> Self {
+            Self {
+                threshold: 0.01,
+                attack: Duration::from_millis(1),
+                hold: Duration::from_millis(50),
+                release: Duration::from_millis(100),
+            }
+        }
+    }
+
+    /// Silences audio while it is quieter than a threshold, such as noise in between sounds.
+    /// Unlike trimming, this applies anywhere in a track.
+    pub struct NoiseGate {
+        config: NoiseGateConfig,
+        gain: f32,
+        /// Frames left until the gate starts closing
+        held: usize,
+    }
+
+    impl NoiseGate {
+        pub fn new(config: NoiseGateConfig) -> Self {
+            Self {
+                config,
+                gain: 1.,
+                held: 0,
+            }
+        }
+
+        pub fn config(&self) -> NoiseGateConfig {
+            self.config
+        }
+
+        fn frames(duration: Duration) -> usize {
+            ((duration.as_secs_f32() * SAMPLE_RATE as f32) as usize).max(1)
+        }
+    }
+
+    impl Effect for NoiseGate {
+        fn name(&self) -> &'static str {
+            "Noise gate"
+        }
+
+        fn cost(&self) -> usize {
+            2
+        }
+
+        fn process(&mut self, samples: &mut [Sample]) {
+            let attack = 1. / Self::frames(self.config.attack) as f32;
+            let release = 1. / Self::frames(self.config.release) as f32;
+            let hold = Self::frames(self.config.hold);
+
+            for frame in samples.chunks_exact_mut(CHANNEL_COUNT) {
+                let level = frame.iter().fold(0_f32, |peak, s| peak.max(s.abs()));
+
+                if level >= self.config.threshold {
+                    self.held = hold;
+                    self.gain = (self.gain + attack).min(1.);
+                } else if self.held > 0 {
+                    self.held -= 1;
+                } else {
+                    self.gain = (self.gain - release).max(0.);
+                }
+
+                for sample in frame.iter_mut() {
+                    *sample *= self.gain;
+                }
+            }
+        }
+
+        fn parameters(&self) -> Vec<(&'static str, f32)> {
+            vec![("threshold", self.config.threshold), ("gain", self.gain)]
+        }
+    }
+
+    /// Configures a [Crossfeed]
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct CrossfeedConfig {
+        /// How much of each channel is blended into the other, from 0 to 1
+        pub amount: f32,
+        /// The frequency in Hz above which less is blended, as the head shadows high frequencies
+        pub cutoff: f32,
+    }
+
+    impl Default for CrossfeedConfig {
+        fn default() -> Self {
+            Self {
+                amount: 0.3,
+                cutoff: 700.,
+            }
+        }
+    }
+
+    /// Blends a low passed portion of each channel into the other,
+    /// so hard panned audio sounds less separated on headphones.
+    pub struct Crossfeed {
+        config: CrossfeedConfig,
+        /// The low passed left and right channel
+        filtered: [f32; 2],
+    }
+
+    impl Crossfeed {
+        pub fn new(config: CrossfeedConfig) -> Self {
+            Self {
+                config: CrossfeedConfig {
+                    amount: config.amount.clamp(0., 1.),
+                    ..config
+                },
+                filtered: [0.; 2],
+            }
+        }
+
+        pub fn config(&self) -> CrossfeedConfig {
+            self.config
+        }
+
+        /// The smoothing of a one pole low pass filter at the cutoff
+        fn coefficient(&self) -> f32 {
+            1. - (-TAU * self.config.cutoff / SAMPLE_RATE as f32).exp()
+        }
+    }
+
+    impl Effect for Crossfeed {
+        fn name(&self) -> &'static str {
+            "Crossfeed"
+        }
+
+        fn cost(&self) -> usize {
+            1
+        }
+
+        fn process(&mut self, samples: &mut [Sample]) {
+            let coefficient = self.coefficient();
+            let amount = self.config.amount;
+
+            // Keeps the level the same when both channels are equal
+            let normalize = 1. / (1. + amount);
+
+            for frame in samples.chunks_exact_mut(CHANNEL_COUNT) {
+                let (left, right) = (frame[0], frame[1]);
+
+                self.filtered[0] += (left - self.filtered[0]) * coefficient;
+                self.filtered[1] += (right - self.filtered[1]) * coefficient;
+
+                frame[0] = (left + self.filtered[1] * amount) * normalize;
+                frame[1] = (right + self.filtered[0] * amount) * normalize;
+            }
+        }
+
+        fn parameters(&self) -> Vec<(&'static str, f32)> {
+            vec![
+                ("amount", self.config.amount),
+                ("cutoff", self.config.cutoff),
+            ]
+        }
+    }
+
+    /// Slowly adjusts the gain of the final mix so its loudness approaches a target,
+    /// with a limiter that turns down the peaks the gain would make clip.
+    ///
+    /// Loudness is measured in LUFS as in BS.1770, over the last few seconds.
+    pub struct LoudnessNormalizer {
+        /// The loudness to reach in LUFS, such as -14
+        target: f32,
+        meter: LoudnessMeter,
+        /// The current gain in dB
+        gain: f32,
+        /// How much the limiter turns the signal down by, where 1 is not at all
+        reduction: f32,
+    }
+
+    impl LoudnessNormalizer {
+        /// How much audio the loudness is measured over
+        const WINDOW_FRAMES: usize = SAMPLE_RATE * 3;
+        /// How long it roughly takes for the gain to reach what is needed
+        const ADJUST_FRAMES: usize = SAMPLE_RATE;
+        /// How long the limiter takes to stop turning the signal down after a peak
+        const RELEASE_FRAMES: usize = SAMPLE_RATE / 10;
+        /// The most the gain is raised or lowered by in dB
+        const MAX_GAIN: f32 = 20.;
+        const CEILING: f32 = 1.;
+
+        pub fn new(target: f32) -> Self {
+            Self {
+                target,
+                meter: LoudnessMeter::windowed(Self::WINDOW_FRAMES),
+                gain: 0.,
+                reduction: 1.,
+            }
+        }
+
+        pub fn target(&self) -> f32 {
+            self.target
+        }
+    }
+
+    impl Effect for LoudnessNormalizer {
+        fn name(&self) -> &'static str {
+            "Loudness normalizer"
+        }
+
+        fn cost(&self) -> usize {
+            8
+        }
+
+        fn process(&mut self, samples: &mut [Sample]) {
+            self.meter.measure(samples);
+
+            let frames = samples.len() / CHANNEL_COUNT;
+            let previous = self.gain;
+
+            if let Some(measured) = self.meter.integrated() {
+                let needed = (self.target - measured).clamp(-Self::MAX_GAIN, Self::MAX_GAIN);
+                let amount = (frames as f32 / Self::ADJUST_FRAMES as f32).min(1.);
+
+                self.gain += (needed - self.gain) * amount;
+            }
+
+            let release = 1. / Self::RELEASE_FRAMES as f32;
+
+            // Move to the new gain over the block so it does not step
+            for (i, frame) in samples.chunks_exact_mut(CHANNEL_COUNT).enumerate() {
+                let progress = i as f32 / frames as f32;
+                let gain_db = previous + (self.gain - previous) * progress;
+                let gain = 10_f32.powf(gain_db / 20.);
+
+                let peak = frame
+                    .iter()
+                    .fold(0., |peak: f32, s| peak.max((s * gain).abs()));
+
+                // Peaks are turned down at once, and the signal slowly comes back up after
+                self.reduction = (self.reduction + release).min(1.);
+
+                if peak * self.reduction > Self::CEILING {
+                    self.reduction = Self::CEILING / peak;
+                }
+
+                for sample in frame.iter_mut() {
+                    *sample *= gain * self.reduction;
+                }
+            }
+        }
+
+        fn parameters(&self) -> Vec<(&'static str, f32)> {
+            vec![("target", self.target), ("gain", self.gain)]
+        }
+    }
+
+    /// A peaking filter of an [Equalizer]
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+    pub struct EqBand {
+        /// The center frequency in Hz
+        pub frequency: f32,
+        /// The boost or cut in dB
+        pub gain: f32,
+        /// How narrow the band is, higher is narrower
+        pub q: f32,
+    }
+
+    impl EqBand {
+        /// The most a band boosts or cuts by in dB
+        pub const MAX_GAIN: f32 = 24.;
+
+        /// Checks that the band is one the equalizer can filter
+        pub fn validate(&self) -> Result<(), InvalidBand> {
+            let nyquist = SAMPLE_RATE as f32 / 2.;
+
+            // Written so NaN is invalid too
+            if !(self.frequency > 0. && self.frequency < nyquist) {
+                return Err(InvalidBand::Frequency(self.frequency));
+            }
+
+            if !(self.q > 0.) {
+                return Err(InvalidBand::Q(self.q));
+            }
+
+            if !(self.gain.abs() <= Self::MAX_GAIN) {
+                return Err(InvalidBand::Gain(self.gain));
+            }
+
+            Ok(())
+        }
+    }
+
+    /// A band cannot be filtered by an [Equalizer]
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum InvalidBand {
+        /// The frequency is not between 0 Hz and half the sample rate
+        Frequency(f32),
+        /// The q is not above 0
+        Q(f32),
+        /// The gain is more than [EqBand::MAX_GAIN] dB
+        Gain(f32),
+    }
+
+    impl Display for InvalidBand {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                InvalidBand::Frequency(frequency) => write!(
+                    f,
+                    "The frequency must be between 0 and {} Hz, not {}.",
+                    SAMPLE_RATE / 2,
+                    frequency
+                ),
+                InvalidBand::Q(q) => write!(f, "The q must be above 0, not {}.", q),
+                InvalidBand::Gain(gain) => write!(
+                    f,
+                    "The gain must be between -{0} and +{0} dB, not {1}.",
+                    EqBand::MAX_GAIN,
+                    gain
+                ),
+            }
+        }
+    }
+
+    impl Error for InvalidBand {}
+
+    struct Filter {
+        band: EqBand,
+        /// The gain currently applied, which moves towards the gain of the band
+        gain: f32,
+        /// The filter of each channel, which keeps its state when it is tuned
+        channels: [Biquad; CHANNEL_COUNT],
+        removed: bool,
+    }
+
+    impl Filter {
+        fn new(band: EqBand) -> Self {
+            Self {
+                band,
+                gain: 0.,
+                channels: [Biquad::default(); CHANNEL_COUNT],
+                removed: false,
+            }
+        }
+
+        /// Returns true if the filter neither does anything nor is fading
+        fn is_flat(&self) -> bool {
+            self.gain == 0. && self.target() == 0.
+        }
+
+        fn biquad(&self) -> Biquad {
+            Biquad::peaking(
+                self.band.frequency as f64,
+                self.gain as f64,
+                self.band.q as f64,
+            )
+        }
+
+        fn target(&self) -> f32 {
+            if self.removed {
+                0.
+            } else {
+                self.band.gain
+            }
+        }
+    }
+
+    /// A parametric equalizer made of peaking bands.
+    /// Bands fade in and out when they are added or removed, so changes do not click.
+    #[derive(Default)]
+    pub struct Equalizer {
+        filters: Vec<Filter>,
+    }
+
+    impl Equalizer {
+        /// How many frames are processed between updates of the coefficients
+        const UPDATE_FRAMES: usize = 32;
+        /// How fast a band fades, in dB per frame
+        const FADE: f32 = 1000. / SAMPLE_RATE as f32;
+        /// The center frequencies of the bands it starts with
+        const FLAT_FREQUENCIES: [f32; 5] = [60., 230., 910., 3600., 14000.];
+
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Creates an equalizer with five bands that do nothing until they are set
+        pub fn flat() -> Self {
+            let mut equalizer = Self::new();
+            equalizer.clear();
+            equalizer
+        }
+
+        /// Replaces every band, leaving them as they are if any is invalid
+        pub fn set_bands(&mut self, bands: Vec<EqBand>) -> Result<(), InvalidBand> {
+            bands.iter().try_for_each(EqBand::validate)?;
+
+            for filter in self.filters.iter_mut() {
+                filter.removed = true;
+            }
+
+            self.filters.extend(bands.into_iter().map(Filter::new));
+            Ok(())
+        }
+
+        /// Resets the bands to the five flat ones it starts with
+        pub fn clear(&mut self) {
+            let bands = Self::FLAT_FREQUENCIES.map(|frequency| EqBand {
+                frequency,
+                gain: 0.,
+                q: 1.,
+            });
+
+            self.set_bands(bands.to_vec())
+                .expect("The flat bands are valid");
+        }
+
+        /// Adds a band, returning its index
+        pub fn add_band(&mut self, band: EqBand) -> Result<usize, InvalidBand> {
+            band.validate()?;
+
+            self.filters.push(Filter::new(band));
+            Ok(self.bands().len() - 1)
+        }
+
+        /// Changes the band at an index, returning false if there is none.
+        /// A band that moves fades out while the moved one fades in in its place.
+        pub fn set_band(&mut self, index: usize, band: EqBand) -> Result<bool, InvalidBand> {
+            band.validate()?;
+
+            let position = self
+                .filters
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| !f.removed)
+                .nth(index)
+                .map(|(i, _)| i);
+
+            let position = match position {
+                Some(position) => position,
+                None => return Ok(false),
+            };
+
+            let filter = &mut self.filters[position];
+
+            // Only the gain changes, which fades on its own
+            if filter.band.frequency == band.frequency && filter.band.q == band.q {
+                filter.band = band;
+                return Ok(true);
+            }
+
+            filter.removed = true;
+            self.filters.insert(position + 1, Filter::new(band));
+
+            Ok(true)
+        }
+
+        /// Removes the band at an index, returning it if it exists
+        pub fn remove_band(&mut self, index: usize) -> Option<EqBand> {
+            let filter = self.filters.iter_mut().filter(|f| !f.removed).nth(index)?;
+
+            filter.removed = true;
+            Some(filter.band)
+        }
+
+        /// Returns the bands that have not been removed
+        pub fn bands(&self) -> Vec<EqBand> {
+            self.filters
+                .iter()
+                .filter(|f| !f.removed)
+                .map(|f| f.band)
+                .collect()
+        }
+
+        /// Returns the gain in dB at a frequency, as currently applied
+        pub fn response(&self, frequency: f32) -> f32 {
+            self.filters
+                .iter()
+                .map(|f| f.biquad().response(frequency as f64) as f32)
+                .sum()
+        }
+    }
+
+    impl Effect for Equalizer {
+        fn name(&self) -> &'static str {
+            "Equalizer"
+        }
+
+        fn cost(&self) -> usize {
+            self.filters.iter().filter(|f| !f.is_flat()).count() * 5
+        }
+
+        fn process(&mut self, samples: &mut [Sample]) {
+            for block in samples.chunks_mut(CHANNEL_COUNT * Self::UPDATE_FRAMES) {
+                let frames = block.len() / CHANNEL_COUNT;
+
+                for filter in self.filters.iter_mut() {
+                    // Flat bands are skipped, so they do not color the samples with rounding
+                    if filter.is_flat() {
+                        filter.channels.iter_mut().for_each(Biquad::reset);
+                        continue;
+                    }
+
+                    let fade = Self::FADE * frames as f32;
+                    let change = (filter.target() - filter.gain).clamp(-fade, fade);
+
+                    filter.gain += change;
+
+                    let biquad = filter.biquad();
+
+                    for channel in filter.channels.iter_mut() {
+                        channel.tune(&biquad);
+                    }
+
+                    for frame in block.chunks_exact_mut(CHANNEL_COUNT) {
+                        for (sample, channel) in frame.iter_mut().zip(filter.channels.iter_mut()) {
+                            *sample = channel.process(*sample as f64) as Sample;
+                        }
+                    }
+                }
+            }
+
+            // Removed bands are dropped once they have faded out
+            self.filters.retain(|f| !f.removed || f.gain != 0.);
+        }
+
+        fn parameters(&self) -> Vec<(&'static str, f32)> {
+            vec![("bands", self.bands().len() as f32)]
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::f32::consts::TAU;
+
+        use super::{
+            Crossfeed, CrossfeedConfig, Ducker, Effect, EqBand, Equalizer, InvalidBand,
+            LoudnessNormalizer, NoiseGate, NoiseGateConfig,
+        };
+        use crate::audio::processing::loudness::LoudnessMeter;
+        use crate::audio::{
+            BufferRegistry, CHANNEL_COUNT, SAMPLES_PER_SEC, SAMPLE_RATE, STREAM_CHUNK_SIZE,
+        };
+
+        #[test]
+        fn loud_sources_duck_the_music() {
+            let mic = BufferRegistry::new();
+            let mut ducker = Ducker::new(mic.get_consumer(), 0.1);
+
+            // The microphone is quiet
+            mic.write_samples(&[0.01; 1000]);
+
+            let mut music = vec![1.; 1000];
+            ducker.process(&mut music);
+            assert_eq!(music[999], 1.);
+
+            // Someone is talking
+            mic.write_samples(&[0.5; 1000]);
+
+            let mut music = vec![1.; 1000];
+            ducker.process(&mut music);
+
+            assert!(music[0] < 1.);
+            assert_eq!(music[999], 0.25);
+        }
+
+        #[test]
+        fn crossfeed_bleeds_into_the_other_channel() {
+            let config = CrossfeedConfig {
+                amount: 0.5,
+                ..Default::default()
+            };
+            let mut crossfeed = Crossfeed::new(config);
+
+            // Only the left channel, low enough to pass the filter entirely
+            let mut samples: Vec<_> = (0..SAMPLES_PER_SEC)
+                .map(|i| if i % CHANNEL_COUNT == 0 { 1. } else { 0. })
+                .collect();
+
+            crossfeed.process(&mut samples);
+
+            let last = &samples[SAMPLES_PER_SEC - CHANNEL_COUNT..];
+            assert!((last[0] - 1. / 1.5).abs() < 0.001, "Left is {}", last[0]);
+            assert!((last[1] - 0.5 / 1.5).abs() < 0.001, "Right is {}", last[1]);
+
+            // High frequencies bleed less
+            let mut crossfeed = Crossfeed::new(config);
+            let mut samples: Vec<_> = (0..SAMPLES_PER_SEC)
+                .map(|i| match i % CHANNEL_COUNT {
+                    0 => ((i / CHANNEL_COUNT) as f32 * TAU * 8000. / SAMPLE_RATE as f32).sin(),
+                    _ => 0.,
+                })
+                .collect();
+
+            crossfeed.process(&mut samples);
+
+            let bleed = samples[SAMPLES_PER_SEC / 2..]
+                .chunks_exact(CHANNEL_COUNT)
+                .fold(0_f32, |peak, frame| peak.max(frame[1].abs()));
+
+            assert!(bleed > 0. && bleed < 0.05, "Bleed is {}", bleed);
+        }
+
+        #[test]
+        fn quiet_parts_are_gated() {
+            let mut gate = NoiseGate::new(NoiseGateConfig::default());
+
+            // Noise in between two loud segments, a second each
+            let mut samples = [
+                vec![0.5; SAMPLES_PER_SEC],
+                vec![0.005; SAMPLES_PER_SEC],
+                vec![0.5; SAMPLES_PER_SEC],
+            ]
+            .concat();
+
+            gate.process(&mut samples);
+
+            let noise = &samples[SAMPLES_PER_SEC..SAMPLES_PER_SEC * 2];
+            let loud = &samples[SAMPLES_PER_SEC * 2..];
+
+            // The noise is only heard while the gate holds and releases
+            assert_eq!(noise[0], 0.005);
+            assert!(noise[SAMPLES_PER_SEC / 2..].iter().all(|s| *s == 0.));
+
+            // The loud part is back to full level after the attack
+            assert!(loud[0] < 0.5);
+            assert!(loud[SAMPLES_PER_SEC / 100..].iter().all(|s| *s == 0.5));
+            assert_eq!(samples[SAMPLES_PER_SEC / 2], 0.5);
+        }
+
+        #[test]
+        fn output_loudness_converges_to_the_target() {
+            let mut normalizer = LoudnessNormalizer::new(-14.);
+
+            // Plays a sine wave for some seconds, returning the loudness of the last one
+            let mut play = |amplitude: f32, seconds: usize| {
+                let mut last = vec![];
+
+                for chunk in 0..seconds * SAMPLES_PER_SEC / STREAM_CHUNK_SIZE {
+                    let mut samples: Vec<_> = (0..STREAM_CHUNK_SIZE)
+                        .map(|i| {
+                            let frame = (chunk * STREAM_CHUNK_SIZE + i) / CHANNEL_COUNT;
+                            amplitude * (frame as f32 * 440. / SAMPLE_RATE as f32 * TAU).sin()
+                        })
+                        .collect();
+
+                    normalizer.process(&mut samples);
+                    last.extend(samples);
+                }
+
+                let mut meter = LoudnessMeter::new();
+                meter.measure(&last[last.len() - SAMPLES_PER_SEC..]);
+
+                meter.integrated().unwrap()
+            };
+
+            // Both quiet and loud signals end up at about the same loudness
+            for amplitude in [0.1, 0.8, 0.3] {
+                let loudness = play(amplitude, 10);
+                assert!(
+                    (loudness + 14.).abs() < 1.,
+                    "{} became {} LUFS",
+                    amplitude,
+                    loudness
+                );
+            }
+        }
+
+        #[test]
+        fn peaks_are_limited_without_clipping() {
+            let mut normalizer = LoudnessNormalizer::new(0.);
+            let mut output = vec![];
+
+            // The sine is raised by about 9 dB, which would make it clip
+            for chunk in 0..5 * SAMPLES_PER_SEC / STREAM_CHUNK_SIZE {
+                let mut samples: Vec<_> = (0..STREAM_CHUNK_SIZE)
+                    .map(|i| {
+                        let frame = (chunk * STREAM_CHUNK_SIZE + i) / CHANNEL_COUNT;
+                        0.5 * (frame as f32 * 440. / SAMPLE_RATE as f32 * TAU).sin()
+                    })
+                    .collect();
+
+                normalizer.process(&mut samples);
+                output.extend(samples);
+            }
+
+            let last = &output[output.len() - SAMPLES_PER_SEC..];
+            let at_ceiling = last.iter().filter(|s| s.abs() >= 0.999).count();
+
+            assert!(last.iter().all(|s| s.abs() <= 1.));
+            assert!(last.iter().any(|s| s.abs() > 0.9));
+
+            // A hard clip would flatten about half of the wave
+            assert!(
+                at_ceiling < last.len() / 10,
+                "{} samples are at the ceiling",
+                at_ceiling
+            );
+        }
+
+        #[test]
+        fn eq_bands_can_be_added_and_removed() {
+            let mut eq = Equalizer::new();
+            let mut silence = vec![0.; STREAM_CHUNK_SIZE];
+
+            let index = eq
+                .add_band(EqBand {
+                    frequency: 1000.,
+                    gain: 6.,
+                    q: 1.,
+                })
+                .unwrap();
+
+            // The band fades in
+            assert!(eq.response(1000.).abs() < 0.01);
+            eq.process(&mut silence);
+
+            assert!((eq.response(1000.) - 6.).abs() < 0.01);
+            assert!(eq.response(50.).abs() < 0.5);
+
+            assert!(eq.remove_band(index).is_some());
+            assert!(eq.bands().is_empty());
+            eq.process(&mut silence);
+
+            assert!(eq.response(1000.).abs() < 0.01);
+            assert!(eq.remove_band(0).is_none());
+        }
+
+        #[test]
+        fn invalid_eq_bands_are_rejected() {
+            let mut eq = Equalizer::flat();
+            let band = EqBand {
+                frequency: 1000.,
+                gain: 6.,
+                q: 1.,
+            };
+
+            let invalid = [
+                (EqBand { q: 0., ..band }, InvalidBand::Q(0.)),
+                (EqBand { q: -1., ..band }, InvalidBand::Q(-1.)),
+                (
+                    EqBand {
+                        frequency: 0.,
+                        ..band
+                    },
+                    InvalidBand::Frequency(0.),
+                ),
+                (
+                    EqBand {
+                        frequency: SAMPLE_RATE as f32 / 2.,
+                        ..band
+                    },
+                    InvalidBand::Frequency(SAMPLE_RATE as f32 / 2.),
+                ),
+                (EqBand { gain: 100., ..band }, InvalidBand::Gain(100.)),
+            ];
+
+            for (invalid, error) in invalid {
+                assert_eq!(eq.add_band(invalid), Err(error));
+                assert_eq!(eq.set_band(0, invalid), Err(error));
+            }
+
+            // Nothing is replaced if any of the bands is invalid
+            let nan = EqBand {
+                gain: f32::NAN,
+                ..band
+            };
+
+            assert!(eq.set_bands(vec![band, nan]).is_err());
+            assert_eq!(eq.bands().len(), 5);
+        }
+
+        #[test]
+        fn eq_bands_can_be_changed_and_cleared() {
+            let mut eq = Equalizer::flat();
+            let mut samples: Vec<_> = (0..STREAM_CHUNK_SIZE).map(|i| (i as f32).sin()).collect();
+            let original = samples.clone();
+
+            // Flat bands leave the samples exactly as they are
+            assert_eq!(eq.bands().len(), 5);
+            eq.process(&mut samples);
+            assert_eq!(samples, original);
+
+            let boost = EqBand {
+                frequency: 1000.,
+                gain: 6.,
+                q: 1.,
+            };
+
+            assert!(eq.set_band(2, boost).unwrap());
+            assert!(!eq.set_band(5, boost).unwrap());
+            eq.process(&mut samples);
+
+            assert_eq!(eq.bands()[2], boost);
+            assert_eq!(eq.bands().len(), 5);
+            assert!((eq.response(1000.) - 6.).abs() < 0.1);
+
+            eq.clear();
+            eq.process(&mut samples);
+
+            assert!(eq.bands().iter().all(|b| b.gain == 0.));
+            assert!(eq.response(1000.).abs() < 0.01);
+        }
+    }
+}
+
+pub mod loudness {
+    use std::f64::consts::TAU;
+
+    use crate::audio::{Sample, CHANNEL_COUNT, SAMPLE_RATE};
+
+    /// The most a track is raised or lowered by in dB to reach the target loudness
+    pub const MAX_TRACK_GAIN: f32 = 20.;
+
+    /// A biquad filter in direct form I, with its coefficients normalized so a0 is 1
+    #[derive(Debug, Clone, Copy, Default)]
+    pub(super) struct Biquad {
+        b: [f64; 3],
+        a: [f64; 2],
+        /// The previous two inputs and outputs
+        state: [f64; 4],
+    }
+
+    impl Biquad {
+        fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+            Self {
+                b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+                a: [a[1] / a[0], a[2] / a[0]],
+                state: [0.; 4],
+            }
+        }
+
+        /// The first stage of K-weighting, which models the acoustic effect of the head
+        fn high_shelf() -> Self {
+            let gain = 3.999_843_853_973_347;
+            let q = 0.707_175_236_955_419_3;
+            let frequency = 1_681.974_450_955_531_9;
+
+            let a = 10_f64.powf(gain / 40.);
+            let w0 = TAU * frequency / SAMPLE_RATE as f64;
+            let alpha = w0.sin() / (2. * q);
+            let (cos, sqrt) = (w0.cos(), a.sqrt());
+
+            Self::new(
+                [
+                    a * ((a + 1.) + (a - 1.) * cos + 2. * sqrt * alpha),
+                    -2. * a * ((a - 1.) + (a + 1.) * cos),
+                    a * ((a + 1.) + (a - 1.) * cos - 2. * sqrt * alpha),
+                ],
+                [
+                    (a + 1.) - (a - 1.) * cos + 2. * sqrt * alpha,
+                    2. * ((a - 1.) - (a + 1.) * cos),
+                    (a + 1.) - (a - 1.) * cos - 2. * sqrt * alpha,
+                ],
+            )
+        }
+
+        /// The second stage of K-weighting, which removes what is too low to be heard
+        fn high_pass() -> Self {
+            let q = 0.500_327_037_325_395_3;
+            let frequency = 38.135_470_876_139_82;
+
+            let w0 = TAU * frequency / SAMPLE_RATE as f64;
+            let alpha = w0.sin() / (2. * q);
+            let cos = w0.cos();
+
+            Self::new(
+                [(1. + cos) / 2., -(1. + cos), (1. + cos) / 2.],
+                [1. + alpha, -2. * cos, 1. - alpha],
+            )
+        }
+
+        /// A peaking filter from the Audio EQ Cookbook, with a gain in dB
+        pub(super) fn peaking(frequency: f64, gain: f64, q: f64) -> Self {
+            let a = 10_f64.powf(gain / 40.);
+            let w0 = TAU * frequency / SAMPLE_RATE as f64;
+            let alpha = w0.sin() / (2. * q);
+            let cos = w0.cos();
+
+            Self::new(
+                [1. + alpha * a, -2. * cos, 1. - alpha * a],
+                [1. + alpha / a, -2. * cos, 1. - alpha / a],
+            )
+        }
+
+        /// Takes the coefficients of another filter, keeping the state of this one
+        pub(super) fn tune(&mut self, other: &Biquad) {
+            self.b = other.b;
+            self.a = other.a;
+        }
+
+        pub(super) fn reset(&mut self) {
+            self.state = [0.; 4];
+        }
+
+        /// Returns the gain in dB at a frequency
+        pub(super) fn response(&self, frequency: f64) -> f64 {
+            let w = TAU * frequency / SAMPLE_RATE as f64;
+
+            // Evaluates a polynomial in z^-1 on the unit circle
+            let evaluate = |c: [f64; 3]| {
+                let re = c[0] + c[1] * w.cos() + c[2] * (2. * w).cos();
+                let im = -c[1] * w.sin() - c[2] * (2. * w).sin();
+                (re * re + im * im).sqrt()
+            };
+
+            let numerator = evaluate(self.b);
+            let denominator = evaluate([1., self.a[0], self.a[1]]);
+
+            20. * (numerator / denominator).log10()
+        }
+
+        pub(super) fn process(&mut self, input: f64) -> f64 {
+            let [x1, x2, y1, y2] = self.state;
+            let output = self.b[0] * input + self.b[1] * x1 + self.b[2] * x2
+                - self.a[0] * y1
+                - self.a[1] * y2;
+
+            self.state = [input, x1, output, y1];
+            output
+        }
+    }
+
+    /// Measures the integrated loudness of a whole track in LUFS, as in EBU R128.
+    /// Samples are measured as they are loaded, so the track is not read twice.
+    pub struct LoudnessMeter {
+        /// The K-weighting filters of each channel
+        filters: [[Biquad; 2]; CHANNEL_COUNT],
+        /// The sum of squares of the step being measured, over all channels
+        sum: f64,
+        frames: usize,
+        /// The mean square of every step measured so far
+        steps: Vec<f64>,
+        /// How many steps are kept, or `None` to measure everything
+        window: Option<usize>,
+    }
+
+    impl LoudnessMeter {
+        /// Blocks overlap by 75%, so they are measured in steps of a quarter block
+        const STEP_FRAMES: usize = SAMPLE_RATE / 10;
+        const STEPS_PER_BLOCK: usize = 4;
+
+        /// Blocks quieter than this in LUFS are silence
+        const ABSOLUTE_GATE: f64 = -70.;
+        /// Blocks this much quieter than the ungated loudness are not measured
+        const RELATIVE_GATE: f64 = -10.;
+
+        pub fn new() -> Self {
+            Self {
+                filters: [[Biquad::high_shelf(), Biquad::high_pass()]; CHANNEL_COUNT],
+                sum: 0.,
+                frames: 0,
+                steps: vec![],
+                window: None,
+            }
+        }
+
+        /// Measures only the last frames, such as for the loudness of what is playing.
+        /// Nothing is allocated while measuring.
+        pub fn windowed(frames: usize) -> Self {
+            let window = (frames / Self::STEP_FRAMES).max(Self::STEPS_PER_BLOCK);
+
+            Self {
+                steps: Vec::with_capacity(window + 1),
+                window: Some(window),
+                ..Self::new()
+            }
+        }
+
+        fn loudness(mean_square: f64) -> f64 {
+            -0.691 + 10. * mean_square.log10()
+        }
+
+        pub fn measure(&mut self, samples: &[Sample]) {
+            for frame in samples.chunks_exact(CHANNEL_COUNT) {
+                for (sample, filters) in frame.iter().zip(self.filters.iter_mut()) {
+                    let weighted = filters
+                        .iter_mut()
+                        .fold(*sample as f64, |input, filter| filter.process(input));
+
+                    self.sum += weighted * weighted;
+                }
+
+                self.frames += 1;
+
+                if self.frames == Self::STEP_FRAMES {
+                    self.steps.push(self.sum / self.frames as f64);
+                    self.sum = 0.;
+                    self.frames = 0;
+
+                    if let Some(window) = self.window {
+                        let excess = self.steps.len().saturating_sub(window);
+                        self.steps.drain(..excess);
+                    }
+                }
+            }
+        }
+
+        /// Returns the integrated loudness of everything measured,
+        /// or `None` if it is silent or shorter than a block.
+        pub fn integrated(&self) -> Option<f32> {
+            let blocks = || {
+                self.steps
+                    .windows(Self::STEPS_PER_BLOCK)
+                    .map(|steps| steps.iter().sum::<f64>() / Self::STEPS_PER_BLOCK as f64)
+                    .filter(|ms| Self::loudness(*ms) > Self::ABSOLUTE_GATE)
+            };
+
+            // Returns the mean of the mean squares, or None if there are none
+            let mean = |blocks: &mut dyn Iterator<Item = f64>| {
+                let (sum, count) = blocks.fold((0., 0), |(sum, count), ms| (sum + ms, count + 1));
+                (count > 0).then(|| sum / count as f64)
+            };
+
+            let ungated = mean(&mut blocks())?;
+            let threshold = Self::loudness(ungated) + Self::RELATIVE_GATE;
+
+            let gated = mean(&mut blocks().filter(|ms| Self::loudness(*ms) > threshold))?;
+            Some(Self::loudness(gated) as f32)
+        }
+    }
+
+    impl Default for LoudnessMeter {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// Returns the gain that brings a track of the measured loudness to the target
+    pub fn track_gain(loudness: f32, target: f32) -> f32 {
+        let gain = (target - loudness).clamp(-MAX_TRACK_GAIN, MAX_TRACK_GAIN);
+        10_f32.powf(gain / 20.)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::f32::consts::TAU;
+
+        use super::LoudnessMeter;
+        use crate::audio::{CHANNEL_COUNT, SAMPLES_PER_SEC, SAMPLE_RATE};
+
+        fn sine(amplitude: f32, seconds: usize) -> Vec<f32> {
+            (0..SAMPLES_PER_SEC * seconds)
+                .map(|i| {
+                    let t = (i / CHANNEL_COUNT) as f32 / SAMPLE_RATE as f32;
+                    (t * TAU * 1000.).sin() * amplitude
+                })
+                .collect()
+        }
+
+        fn measure(samples: &[f32]) -> Option<f32> {
+            let mut meter = LoudnessMeter::new();
+
+            // Measured in several loads, as a loader does
+            for chunk in samples.chunks(4410) {
+                meter.measure(chunk);
+            }
+
+            meter.integrated()
+        }
+
+        #[test]
+        fn sines_measure_at_their_level() {
+            let loud = measure(&sine(0.1, 3)).unwrap();
+            let quiet = measure(&sine(0.05, 3)).unwrap();
+
+            assert!((loud + 20.).abs() < 0.5, "Measured {} LUFS", loud);
+            assert!((loud - quiet - 6.02).abs() < 0.01);
+        }
+
+        #[test]
+        fn quiet_parts_are_gated() {
+            let tone = measure(&sine(0.1, 2)).unwrap();
+
+            let mut samples = sine(0.1, 2);
+            samples.extend(vec![0.; SAMPLES_PER_SEC * 10]);
+
+            let loudness = measure(&samples).unwrap();
+            assert!((loudness - tone).abs() < 0.5, "Measured {} LUFS", loudness);
+
+            assert_eq!(measure(&vec![0.; SAMPLES_PER_SEC * 2]), None);
+        }
+    }
+}
+
+/// Changes how fast audio plays without changing its pitch
+pub mod stretching {
+    use std::{collections::VecDeque, f32::consts::TAU};
+
+    use crate::audio::{Sample, CHANNEL_COUNT};
+
+    /// How many frames each segment is, which is about 21ms
+    const WINDOW: usize = 1024;
+
+    /// How many frames segments are apart in the output, so each overlaps half of the previous one
+    const OVERLAP: usize = WINDOW / 2;
+
+    /// How many frames a segment can be moved to line up with the previous one
+    const TOLERANCE: usize = 128;
+
+    /// Time-stretches audio with WSOLA, taking overlapping segments from the input further apart
+    /// or closer together than they are written to the output. Every segment is moved to where
+    /// it lines up best with the previous one, so they do not cancel out where they overlap.
+    pub struct TimeStretcher {
+        /// Input that segments can still be taken from
+        input: Vec<Sample>,
+        /// Where the next segment is taken from before it is moved, in frames into the input
+        position: f64,
+        /// Where the previous segment goes on in the input, in frames, if there was one
+        continuation: Option<usize>,
+        /// The second half of the previous segment, which the next one is added to
+        tail: Vec<Sample>,
+        /// Output that did not fit in what was asked for
+        output: VecDeque<Sample>,
+        window: Vec<f32>,
+    }
+
+    impl TimeStretcher {
+        pub fn new() -> Self {
+            Self {
+                input: vec![],
+                position: 0.,
+                continuation: None,
+                tail: vec![],
+                output: VecDeque::new(),
+                window: (0..WINDOW)
+                    .map(|i| 0.5 - 0.5 * (TAU * i as f32 / WINDOW as f32).cos())
+                    .collect(),
+            }
+        }
+
+        /// Returns how many samples of input [TimeStretcher::process] needs
+        /// to write `amount` samples at a speed.
+        pub fn needed_input(&self, amount: usize, speed: f32) -> usize {
+            let missing = amount.saturating_sub(self.output.len()) / CHANNEL_COUNT;
+            let steps = missing.div_ceil(OVERLAP);
+
+            if steps == 0 {
+                return 0;
+            }
+
+            // One more frame covers rounding the position differently when it is stepped
+            let last = self.position + ((steps - 1) * OVERLAP) as f64 * speed as f64;
+            let needed = last.round() as usize + TOLERANCE + WINDOW + 1;
+
+            needed.saturating_sub(self.input.len() / CHANNEL_COUNT) * CHANNEL_COUNT
+        }
+
+        /// Stretches the input to fill `buf`, playing it at a speed.
+        /// What is left of the input is kept for the next call.
+        pub fn process(&mut self, input: &[Sample], buf: &mut [Sample], speed: f32) {
+            self.input.extend_from_slice(input);
+
+            while self.output.len() < buf.len() && self.can_step() {
+                self.step(speed);
+            }
+
+            let amount = buf.len().min(self.output.len());
+
+            for (sample, stretched) in buf.iter_mut().zip(self.output.drain(..amount)) {
+                *sample = stretched;
+            }
+
+            buf[amount..].fill(0.);
+        }
+
+        /// Writes what is left of the stretched audio, for when the speed goes back to normal.
+        /// It ends with the input that was not used yet, so nothing is skipped or repeated,
+        /// and returns how many samples were written.
+        pub fn drain(&mut self, buf: &mut [Sample]) -> usize {
+            if let Some(continuation) = self.continuation.take() {
+                // The segment that would follow without being moved lines up perfectly
+                let next = continuation * CHANNEL_COUNT;
+                let overlapped = OVERLAP * CHANNEL_COUNT;
+
+                for (i, tail) in self.tail.drain(..).enumerate() {
+                    let gain = self.window[i / CHANNEL_COUNT];
+                    self.output.push_back(tail + self.input[next + i] * gain);
+                }
+
+                self.input.drain(..next + overlapped);
+                self.position = 0.;
+            }
+
+            self.output.extend(self.input.drain(..));
+
+            let amount = buf.len().min(self.output.len());
+
+            for (sample, stretched) in buf.iter_mut().zip(self.output.drain(..amount)) {
+                *sample = stretched;
+            }
+
+            amount
+        }
+
+        fn can_step(&self) -> bool {
+            let needed = self.position.round() as usize + TOLERANCE + WINDOW;
+            self.input.len() / CHANNEL_COUNT >= needed
+        }
+
+        /// Adds the next segment to the output, and moves on by the speed
+        fn step(&mut self, speed: f32) {
+            let nominal = self.position.round() as usize;
+
+            let start = match self.continuation {
+                Some(continuation) => self.best_start(nominal, continuation),
+                None => nominal,
+            };
+
+            let overlapped = OVERLAP * CHANNEL_COUNT;
+            let segment = &self.input[start * CHANNEL_COUNT..(start + WINDOW) * CHANNEL_COUNT];
+            let (head, rest) = segment.split_at(overlapped);
+
+            match self.continuation {
+                Some(_) => {
+                    for (i, (sample, tail)) in head.iter().zip(self.tail.iter()).enumerate() {
+                        let gain = self.window[i / CHANNEL_COUNT];
+                        self.output.push_back(tail + sample * gain);
+                    }
+                }
+                // Nothing came before, so the first half is used as it is
+                None => self.output.extend(head.iter()),
+            }
+
+            self.tail.clear();
+            self.tail.extend(
+                rest.iter()
+                    .enumerate()
+                    .map(|(i, sample)| sample * self.window[OVERLAP + i / CHANNEL_COUNT]),
+            );
+
+            self.position += OVERLAP as f64 * speed as f64;
+
+            // What comes before the next segment could be taken from is not needed anymore
+            let unused = (self.position.round() as usize)
+                .saturating_sub(TOLERANCE)
+                .min(start + OVERLAP);
+
+            self.input.drain(..unused * CHANNEL_COUNT);
+            self.position -= unused as f64;
+            self.continuation = Some(start + OVERLAP - unused);
+        }
+
+        /// Returns where around `nominal` a segment starts that is most like the one at `natural`,
+        /// which is what would have followed the previous segment in the input.
+        fn best_start(&self, nominal: usize, natural: usize) -> usize {
+            let target = &self.input[natural * CHANNEL_COUNT..(natural + OVERLAP) * CHANNEL_COUNT];
+            let mut best = (nominal, f32::MIN);
+
+            for start in nominal.saturating_sub(TOLERANCE)..=nominal + TOLERANCE {
+                let candidate = &self.input[start * CHANNEL_COUNT..][..target.len()];
+                let (mut correlation, mut energy) = (0., 0.);
+
+                // Every other frame is compared, which is plenty to line up waveforms
+                for (a, b) in candidate
+                    .chunks(CHANNEL_COUNT)
+                    .zip(target.chunks(CHANNEL_COUNT))
+                    .step_by(2)
+                {
+                    for (a, b) in a.iter().zip(b) {
+                        correlation += a * b;
+                        energy += a * a;
+                    }
+                }
+
+                let similarity = correlation / energy.sqrt().max(f32::EPSILON);
+
+                if similarity > best.1 {
+                    best = (start, similarity);
+                }
+            }
+
+            best.0
+        }
+    }
+
+    impl Default for TimeStretcher {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::f32::consts::TAU;
+
+        use super::TimeStretcher;
+        use crate::audio::{CHANNEL_COUNT, SAMPLES_PER_SEC, SAMPLE_RATE};
+
+        fn sine(frequency: f32, samples: usize) -> Vec<f32> {
+            (0..samples)
+                .map(|i| {
+                    let t = (i / CHANNEL_COUNT) as f32 / SAMPLE_RATE as f32;
+                    (t * TAU * frequency).sin() * 0.5
+                })
+                .collect()
+        }
+
+        /// Counts how often the left channel crosses from negative to positive
+        fn crossings(samples: &[f32]) -> usize {
+            samples
+                .iter()
+                .step_by(CHANNEL_COUNT)
+                .collect::<Vec<_>>()
+                .windows(2)
+                .filter(|w| *w[0] < 0. && *w[1] >= 0.)
+                .count()
+        }
+
+        #[test]
+        fn stretching_keeps_the_pitch() {
+            for speed in [0.5, 1.5, 2.] {
+                let mut stretcher = TimeStretcher::new();
+                let mut input = sine(440., SAMPLES_PER_SEC * 4).into_iter();
+                let mut output = vec![];
+                let mut consumed = 0;
+
+                for _ in 0..10 {
+                    let mut buf = vec![0.; SAMPLES_PER_SEC / 10];
+                    let needed: Vec<_> = input
+                        .by_ref()
+                        .take(stretcher.needed_input(buf.len(), speed))
+                        .collect();
+
+                    consumed += needed.len();
+                    stretcher.process(&needed, &mut buf, speed);
+                    output.extend(buf);
+                }
+
+                // A second of output has the same pitch, but used up more or less of the input
+                let pitch = crossings(&output);
+                assert!(
+                    (pitch as i32 - 440).abs() <= 3,
+                    "{}x had {}Hz",
+                    speed,
+                    pitch
+                );
+
+                let ratio = consumed as f32 / SAMPLES_PER_SEC as f32;
+                assert!((ratio - speed).abs() < 0.1, "{}x read {}s", speed, ratio);
+            }
+        }
+
+        #[test]
+        fn draining_continues_where_the_input_was_used_up_to() {
+            // Noise only lines up with itself, unlike a tone that repeats every period
+            let mut seed = 1_u32;
+            let noise: Vec<_> = (0..SAMPLES_PER_SEC)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+                })
+                .collect();
+
+            let mut stretcher = TimeStretcher::new();
+
+            let mut buf = vec![0.; 4096];
+            let needed = stretcher.needed_input(buf.len(), 1.);
+            stretcher.process(&noise[..needed], &mut buf, 1.);
+
+            let mut rest = vec![0.; SAMPLES_PER_SEC];
+            let drained = stretcher.drain(&mut rest);
+
+            let mut played = buf;
+            played.extend(&rest[..drained]);
+
+            // At normal speed, segments line up where they were, so this is the input as it was
+            assert_eq!(played.len(), needed);
+            for (i, (sample, input)) in played.iter().zip(&noise).enumerate() {
+                assert!(
+                    (sample - input).abs() < 1e-4,
+                    "{} instead of {} at {}",
+                    sample,
+                    input,
+                    i
+                );
+            }
+        }
+    }
+}
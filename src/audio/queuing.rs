@@ -1,6 +1,8 @@
+use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
 
-use super::{AudioEvent, AudioEventChannel, Track};
+use super::{autoplay::Random, AudioEvent, AudioEventChannel, LoaderId, Track, TrackId};
+use crate::util::model::Identified;
 
 pub struct Queue {
     events: AudioEventChannel,
@@ -18,6 +20,20 @@ pub enum QueueEvent {
     Update,
 }
 
+/// What plays once a track has finished
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RepeatMode {
+    /// The next track plays, until the last one has finished
+    #[default]
+    Off,
+    /// The current track plays again
+    Track,
+    /// The current track plays one more time, then repeating is turned off
+    TrackOnce,
+    /// The next track plays, and the first one plays after the last one
+    Queue,
+}
+
 pub enum QueuePosition {
     Next,
     Add,
@@ -49,12 +65,203 @@ impl Queue {
         self.events.emit(QueueEvent::Update);
     }
 
+    /// Adds several tracks to the end in order, emitting a single update
+    pub fn add_tracks(&self, new_tracks: Vec<Track>) {
+        self.tracks.lock().unwrap().extend(new_tracks);
+        self.events.emit(QueueEvent::Update);
+    }
+
+    /// Replaces the current track, returning the replaced track
+    pub fn replace_current(&self, track: Track) -> Option<Track> {
+        let index = self.current_index();
+
+        let replaced = {
+            let mut tracks = self.tracks.lock().unwrap();
+            let current = tracks.get_mut(index)?;
+
+            std::mem::replace(current, track)
+        };
+
+        self.events.emit(QueueEvent::Update);
+        Some(replaced)
+    }
+
+    /// Removes a track, returning it if it was in the queue.
+    /// The track after a removed current track becomes the current one.
+    pub fn remove(&self, id: TrackId) -> Option<Track> {
+        let (position, removed) = {
+            let mut tracks = self.tracks.lock().unwrap();
+            let position = tracks.iter().position(|t| t.id() == id)?;
+
+            (position, tracks.remove(position))
+        };
+
+        // Keep the same track current, wrapping around if the last one was removed
+        let index = self.current_index();
+        match position < index {
+            true => self.set_index(index - 1),
+            false => self.set_index(index),
+        }
+
+        self.events.emit(QueueEvent::Update);
+        Some(removed)
+    }
+
+    /// Moves a track to an index in the queue, clamped to the end of it.
+    /// The current track stays current. Returns false if the track is not in the queue.
+    pub fn move_track(&self, id: TrackId, new_index: usize) -> bool {
+        let current = self.peek_ahead(1).first().map(|t| t.id());
+
+        let current_index = {
+            let mut tracks = self.tracks.lock().unwrap();
+
+            let position = match tracks.iter().position(|t| t.id() == id) {
+                Some(position) => position,
+                None => return false,
+            };
+
+            let track = tracks.remove(position);
+            let new_index = new_index.min(tracks.len());
+            tracks.insert(new_index, track);
+
+            tracks.iter().position(|t| Some(t.id()) == current)
+        };
+
+        if let Some(index) = current_index {
+            self.set_index(index);
+        }
+
+        self.events.emit(QueueEvent::Update);
+        true
+    }
+
+    /// Shuffles the tracks after the current one, using the seed if given so the order
+    /// is the same every time. The current track and the ones before it stay in place.
+    pub fn shuffle(&self, seed: Option<u64>) {
+        let mut random = seed.map(Random::new).unwrap_or_else(Random::from_time);
+        let index = self.current_index();
+
+        {
+            let mut tracks = self.tracks.lock().unwrap();
+            let pending = tracks.get_mut(index + 1..).unwrap_or_default();
+
+            // Fisher-Yates, from the back
+            for i in (1..pending.len()).rev() {
+                let j = (random.next() % (i as u64 + 1)) as usize;
+                pending.swap(i, j);
+            }
+        }
+
+        self.events.emit(QueueEvent::Update);
+    }
+
+    /// Removes every track, or every track but the current one, returning the removed tracks.
+    /// Only a single update is emitted, no matter how many tracks were removed.
+    pub fn clear(&self, include_current: bool) -> Vec<Track> {
+        let index = self.current_index();
+
+        let removed = {
+            let mut tracks = self.tracks.lock().unwrap();
+            let mut removed: Vec<_> = tracks.drain(..).collect();
+
+            if !include_current && index < removed.len() {
+                tracks.push(removed.remove(index));
+            }
+
+            removed
+        };
+
+        self.set_index(0);
+        self.events.emit(QueueEvent::Update);
+
+        removed
+    }
+
+    /// Moves the current track to the end, so the track after it becomes the current one.
+    /// Returns false if the queue is empty.
+    pub fn requeue_current(&self) -> bool {
+        let index = self.current_index();
+
+        {
+            let mut tracks = self.tracks.lock().unwrap();
+
+            if index >= tracks.len() {
+                return false;
+            }
+
+            let track = tracks.remove(index);
+            tracks.push(track);
+        }
+
+        // The index now points at the next track, wrapping around if the last one was current
+        self.set_index(index);
+
+        self.events.emit(QueueEvent::Update);
+        true
+    }
+
     /// Advance the queue, returning the next track
     pub fn next(&self) -> Track {
         self.advance_index(1);
         self.current_track()
     }
 
+    /// Moves back to the track that played before the current one, returning it.
+    /// Played tracks stay in the queue before the current one, so they can be returned to.
+    /// From the first track, this wraps around to the last if `wrap` is set.
+    pub fn previous(&self, wrap: bool) -> Option<Track> {
+        let index = self.current_index();
+
+        if self.tracks.lock().unwrap().is_empty() || (index == 0 && !wrap) {
+            return None;
+        }
+
+        self.advance_index(-1);
+        Some(self.current_track())
+    }
+
+    /// Returns the current and upcoming tracks in the order they play with the repeat mode,
+    /// which goes on past the end of the queue unless repeating is off.
+    pub fn peek_repeating(&self, amount: usize, mode: RepeatMode) -> Vec<Track> {
+        let current_index = self.current_index();
+        let tracks = self.tracks.lock().unwrap();
+
+        match mode {
+            RepeatMode::Off => tracks
+                .iter()
+                .skip(current_index)
+                .take(amount)
+                .cloned()
+                .collect(),
+            RepeatMode::Track => tracks
+                .get(current_index)
+                .map(|track| vec![track.clone(); amount])
+                .unwrap_or_default(),
+            RepeatMode::TrackOnce => tracks
+                .get(current_index)
+                .into_iter()
+                .chain(tracks.iter().skip(current_index))
+                .take(amount)
+                .cloned()
+                .collect(),
+            RepeatMode::Queue => tracks
+                .iter()
+                .cycle()
+                .skip(current_index)
+                .take(amount)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Returns the tracks before the current one, the most recently played last
+    pub fn played(&self) -> Vec<Track> {
+        let current_index = self.current_index();
+        let tracks = self.tracks.lock().unwrap();
+
+        tracks.iter().take(current_index).cloned().collect()
+    }
+
     pub fn peek_ahead(&self, amount: usize) -> Vec<Track> {
         let current_index = self.current_index();
         let tracks = self.tracks.lock().unwrap();
@@ -67,6 +274,12 @@ impl Queue {
             .collect()
     }
 
+    /// Returns the track that plays the loader, anywhere in the queue
+    pub fn find_by_loader(&self, id: LoaderId) -> Option<Track> {
+        let tracks = self.tracks.lock().unwrap();
+        tracks.iter().find(|t| t.loader.id() == id).cloned()
+    }
+
     pub fn current_track(&self) -> Track {
         let tracks = self.tracks.lock().unwrap();
 
@@ -79,8 +292,11 @@ impl Queue {
     fn advance_index(&self, advance: isize) {
         let mut current_index = self.index.lock().unwrap();
 
-        let advanced_index = *current_index as isize + advance;
-        let new_index = self.index_at(advanced_index as usize);
+        // Going back from the first track wraps around to the last
+        let length = self.tracks.lock().unwrap().len() as isize;
+        let new_index = (*current_index as isize + advance)
+            .checked_rem_euclid(length)
+            .unwrap_or_default() as usize;
 
         *current_index = new_index;
 
@@ -0,0 +1,409 @@
+use std::{
+    f64::consts::PI,
+    sync::{Arc, Mutex},
+};
+
+use crossbeam::atomic::AtomicCell;
+use log::debug;
+
+use super::{
+    pipeline::{SampleReader, SamplesRead},
+    AudioConfig, Sample, CHANNEL_COUNT, SAMPLE_RATE,
+};
+
+/// The resamplers used when decoding, shared by every track
+pub static RESAMPLERS: ResamplerCache = ResamplerCache::new();
+
+/// Identifies a conversion that a filter was set up for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResamplerKey {
+    pub input_rate: usize,
+    pub output_rate: usize,
+    pub channels: usize,
+}
+
+/// A windowed sinc filter, computed once for every fractional position
+struct Filter {
+    /// [Filter::PHASES] rows of [Filter::TAPS] coefficients
+    coefficients: Vec<f32>,
+}
+
+impl Filter {
+    const TAPS: usize = 32;
+    const PHASES: usize = 256;
+
+    fn new(key: ResamplerKey) -> Self {
+        // Lower the cutoff when downsampling, so nothing above the new nyquist aliases
+        let cutoff = (key.output_rate as f64 / key.input_rate as f64).min(1.) * 0.95;
+        let half = (Self::TAPS / 2) as f64;
+
+        let mut coefficients = Vec::with_capacity(Self::PHASES * Self::TAPS);
+
+        for phase in 0..Self::PHASES {
+            let fraction = phase as f64 / Self::PHASES as f64;
+
+            let row: Vec<_> = (0..Self::TAPS)
+                .map(|tap| {
+                    let x = tap as f64 - (half - 1.) - fraction;
+                    let sinc = match x * cutoff {
+                        y if y.abs() < 1e-9 => 1.,
+                        y => (PI * y).sin() / (PI * y),
+                    };
+
+                    // Blackman window over the taps
+                    let w = (x + half) / (half * 2.);
+                    let window = 0.42 - 0.5 * (2. * PI * w).cos() + 0.08 * (4. * PI * w).cos();
+
+                    sinc * window
+                })
+                .collect();
+
+            // Every row passes a constant signal unchanged
+            let sum: f64 = row.iter().sum();
+            coefficients.extend(row.iter().map(|c| (c / sum) as f32));
+        }
+
+        Self { coefficients }
+    }
+
+    fn row(&self, fraction: f64) -> &[f32] {
+        let phase = ((fraction * Self::PHASES as f64) as usize).min(Self::PHASES - 1);
+        &self.coefficients[phase * Self::TAPS..(phase + 1) * Self::TAPS]
+    }
+}
+
+/// Keeps the filters of conversions that were set up before,
+/// so tracks at the same rate do not set up their own.
+pub struct ResamplerCache {
+    filters: Mutex<Vec<(ResamplerKey, Arc<Filter>)>>,
+    setups: AtomicCell<usize>,
+}
+
+impl ResamplerCache {
+    pub const fn new() -> Self {
+        Self {
+            filters: Mutex::new(Vec::new()),
+            setups: AtomicCell::new(0),
+        }
+    }
+
+    /// Returns a resampler for the conversion with no samples in it
+    pub fn get(&self, input_rate: usize, output_rate: usize, channels: usize) -> Resampler {
+        let key = ResamplerKey {
+            input_rate,
+            output_rate,
+            channels,
+        };
+
+        let mut filters = self.filters.lock().unwrap();
+
+        let filter = match filters.iter().find(|(k, _)| *k == key) {
+            Some((_, filter)) => filter.clone(),
+            None => {
+                debug!("Setting up a resampler for {:?}", key);
+
+                let filter = Arc::new(Filter::new(key));
+                filters.push((key, filter.clone()));
+                self.setups.fetch_add(1);
+
+                filter
+            }
+        };
+
+        Resampler::new(key, filter)
+    }
+
+    /// Returns how many times a filter had to be set up
+    pub fn setups(&self) -> usize {
+        self.setups.load()
+    }
+}
+
+impl Default for ResamplerCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Converts interleaved samples from one sample rate to another
+pub struct Resampler {
+    key: ResamplerKey,
+    filter: Arc<Filter>,
+    /// Input frames that are still needed for the next outputs
+    pending: Vec<Sample>,
+    /// The position of the next output in frames of `pending`
+    position: f64,
+    /// How many input frames each output frame moves
+    step: f64,
+}
+
+impl Resampler {
+    /// The frames needed before the position of an output
+    const HISTORY: usize = Filter::TAPS / 2 - 1;
+
+    fn new(key: ResamplerKey, filter: Arc<Filter>) -> Self {
+        Self {
+            pending: vec![0.; Self::HISTORY * key.channels],
+            position: Self::HISTORY as f64,
+            step: key.input_rate as f64 / key.output_rate as f64,
+            filter,
+            key,
+        }
+    }
+
+    pub fn key(&self) -> ResamplerKey {
+        self.key
+    }
+
+    /// Resamples the input, appending whatever can be computed to the output.
+    /// The last samples are held back until more input or [Resampler::finish].
+    pub fn process(&mut self, input: &[Sample], output: &mut Vec<Sample>) {
+        let channels = self.key.channels;
+        self.pending.extend_from_slice(input);
+
+        let frames = self.pending.len() / channels;
+
+        while (self.position as usize) + Filter::TAPS - Self::HISTORY <= frames {
+            let start = self.position as usize - Self::HISTORY;
+            let row = self.filter.row(self.position.fract());
+
+            for channel in 0..channels {
+                let sample: f32 = row
+                    .iter()
+                    .enumerate()
+                    .map(|(tap, c)| c * self.pending[(start + tap) * channels + channel])
+                    .sum();
+
+                output.push(sample);
+            }
+
+            self.position += self.step;
+        }
+
+        let consumed = (self.position as usize)
+            .saturating_sub(Self::HISTORY)
+            .min(frames);
+
+        self.pending.drain(..consumed * channels);
+        self.position -= consumed as f64;
+    }
+
+    /// Forgets the input held back, such as after the reader seeked elsewhere
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.pending.resize(Self::HISTORY * self.key.channels, 0.);
+        self.position = Self::HISTORY as f64;
+    }
+
+    /// Resamples what is held back, as if the input ended in silence
+    pub fn finish(&mut self, output: &mut Vec<Sample>) {
+        let silence = vec![0.; (Filter::TAPS - Self::HISTORY) * self.key.channels];
+        self.process(&silence, output);
+    }
+}
+
+/// Resamples the samples of a reader as they are read
+pub struct Resampled<R> {
+    reader: R,
+    resampler: Resampler,
+    /// What is read from the reader before it is resampled
+    input: Vec<Sample>,
+    output: Vec<Sample>,
+    ended: bool,
+}
+
+impl<R: SampleReader> Resampled<R> {
+    /// How many samples are read from the reader at once
+    const READ_SIZE: usize = 4096;
+
+    pub fn new(reader: R, resampler: Resampler) -> Self {
+        Self {
+            reader,
+            resampler,
+            input: vec![0.; Self::READ_SIZE],
+            output: vec![],
+            ended: false,
+        }
+    }
+}
+
+impl<R: SampleReader> SampleReader for Resampled<R> {
+    fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+        while self.output.len() < buf.len() && !self.ended {
+            let result = self.reader.read_samples(&mut self.input);
+            let amount = result.amount();
+
+            self.resampler
+                .process(&self.input[..amount], &mut self.output);
+
+            if result.is_empty() {
+                self.resampler.finish(&mut self.output);
+                self.ended = true;
+            } else if amount < self.input.len() {
+                // The rest is not available yet
+                break;
+            }
+        }
+
+        let channels = self.resampler.key.channels;
+        let amount = buf.len().min(self.output.len()) / channels * channels;
+
+        buf[..amount].copy_from_slice(&self.output[..amount]);
+        self.output.drain(..amount);
+
+        SamplesRead::empty_if(self.ended && self.output.is_empty(), amount)
+    }
+
+    fn length(&self) -> Option<usize> {
+        let key = self.resampler.key;
+
+        self.reader.length().map(|length| {
+            let frames = length / key.channels * key.output_rate / key.input_rate;
+            frames * key.channels
+        })
+    }
+
+    fn seek_samples(&mut self, offset: usize) -> bool {
+        let key = self.resampler.key;
+        let frames = offset / key.channels * key.input_rate / key.output_rate;
+
+        if !self.reader.seek_samples(frames * key.channels) {
+            return false;
+        }
+
+        self.resampler.reset();
+        self.output.clear();
+        self.ended = false;
+
+        true
+    }
+}
+
+/// Converts what is mixed to the format of an [AudioConfig], as it is written to streams
+pub struct OutputConverter {
+    channel_count: usize,
+    resampler: Option<Resampler>,
+    resampled: Vec<Sample>,
+    converted: Vec<Sample>,
+}
+
+impl OutputConverter {
+    pub fn new(config: AudioConfig) -> Self {
+        let resampler = (config.sample_rate() != SAMPLE_RATE)
+            .then(|| RESAMPLERS.get(SAMPLE_RATE, config.sample_rate(), CHANNEL_COUNT));
+
+        Self {
+            channel_count: config.channel_count(),
+            resampler,
+            resampled: vec![],
+            converted: vec![],
+        }
+    }
+
+    /// Converts mixed samples, which are returned as they are if the formats are the same.
+    /// The last few samples are held back by the resampler until the next call.
+    pub fn convert<'a>(&'a mut self, samples: &'a [Sample]) -> &'a [Sample] {
+        let samples = match self.resampler.as_mut() {
+            Some(resampler) => {
+                self.resampled.clear();
+                resampler.process(samples, &mut self.resampled);
+                &self.resampled[..]
+            }
+            None => samples,
+        };
+
+        if self.channel_count == CHANNEL_COUNT {
+            return samples;
+        }
+
+        self.converted.clear();
+
+        for frame in samples.chunks_exact(CHANNEL_COUNT) {
+            match self.channel_count {
+                1 => self
+                    .converted
+                    .push(frame.iter().sum::<Sample>() / CHANNEL_COUNT as Sample),
+                // There is nothing to put in the channels beyond the mixed ones
+                count => {
+                    self.converted.extend(frame);
+                    self.converted.extend((CHANNEL_COUNT..count).map(|_| 0.));
+                }
+            }
+        }
+
+        &self.converted
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{f32::consts::TAU, time::Duration};
+
+    use super::{OutputConverter, Resampled, ResamplerCache};
+    use crate::audio::{
+        pipeline::{IntoSampleReader, SampleReader},
+        AudioConfig,
+    };
+
+    /// A second of a stereo tone at 48 kHz
+    fn tone() -> Vec<f32> {
+        (0..48000)
+            .flat_map(|i| {
+                let sample = (i as f32 * 440. / 48000. * TAU).sin() * 0.5;
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    fn resample(cache: &ResamplerCache, samples: Vec<f32>) -> Vec<f32> {
+        let resampler = cache.get(48000, 44100, 2);
+        let mut reader = Resampled::new(samples.into_sample_reader(), resampler);
+
+        let (_, mut output) = reader.read_samples_to_vec(48000 * 2);
+        output.truncate(44100 * 2);
+        output
+    }
+
+    #[test]
+    fn tracks_at_the_same_rate_share_a_setup() {
+        let cache = ResamplerCache::new();
+
+        let first = resample(&cache, tone());
+        let second = resample(&cache, tone());
+
+        assert_eq!(cache.setups(), 1);
+
+        // The second track starts without anything left over from the first
+        assert_eq!(first, second);
+
+        let peak = first.iter().fold(0_f32, |peak, s| peak.max(s.abs()));
+        assert!((peak - 0.5).abs() < 0.01, "Peak was {}", peak);
+
+        cache.get(44100, 48000, 2);
+        assert_eq!(cache.setups(), 2);
+    }
+
+    #[test]
+    fn output_is_converted_to_the_configured_format() {
+        let mut native = OutputConverter::new(AudioConfig::DEFAULT);
+        assert_eq!(native.convert(&[0.5, 0.25]), &[0.5, 0.25]);
+
+        let mono = AudioConfig::new(44100, 1, Duration::from_millis(100)).unwrap();
+        let mut converter = OutputConverter::new(mono);
+        assert_eq!(converter.convert(&[0.5, 0.25, 1., 0.]), &[0.375, 0.5]);
+
+        let surround = AudioConfig::new(44100, 4, Duration::from_millis(100)).unwrap();
+        let mut converter = OutputConverter::new(surround);
+        assert_eq!(converter.convert(&[0.5, 0.25]), &[0.5, 0.25, 0., 0.]);
+
+        // A second at 44.1 kHz becomes about a second at 48 kHz
+        let resampled = AudioConfig::new(48000, 2, Duration::from_millis(100)).unwrap();
+        let mut converter = OutputConverter::new(resampled);
+        let converted = converter.convert(&vec![0.5; 44100 * 2]).len();
+        assert!(
+            (converted as i32 - 48000 * 2).abs() < 64,
+            "Converted {}",
+            converted
+        );
+    }
+}
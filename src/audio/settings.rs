@@ -0,0 +1,134 @@
+use crossbeam::channel::{unbounded, Sender};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use super::{EqBand, RepeatMode};
+
+/// How long the store waits for more changes before saving, so a burst of changes is saved once
+pub const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+/// Settings that are remembered for a guild across restarts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GuildSettings {
+    pub volume: f32,
+    /// The bands of the equalizer, or `None` if it was never changed
+    #[serde(default)]
+    pub eq: Option<Vec<EqBand>>,
+    #[serde(default)]
+    pub repeat: RepeatMode,
+}
+
+impl Default for GuildSettings {
+    fn default() -> Self {
+        Self {
+            volume: 1.,
+            eq: None,
+            repeat: RepeatMode::Off,
+        }
+    }
+}
+
+/// Stores the settings of every guild on disk.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SettingsStore {
+    guilds: BTreeMap<u64, GuildSettings>,
+
+    #[serde(skip)]
+    path: PathBuf,
+    /// Notifies the thread that saves the store of changes, see [SettingsStore::save_in_background]
+    #[serde(skip)]
+    changes: Option<Sender<()>>,
+}
+
+impl SettingsStore {
+    const SETTINGS_FILE: &'static str = "./settings.ron";
+
+    pub fn restore() -> io::Result<Self> {
+        Self::restore_from(Self::SETTINGS_FILE)
+    }
+
+    /// Restores the store from a file, or creates an empty one if there is no file yet
+    pub fn restore_from<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self {
+                    path,
+                    ..Default::default()
+                })
+            }
+            Err(err) => return Err(err),
+        };
+
+        let store = ron::from_str::<Self>(&data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        Ok(Self { path, ..store })
+    }
+
+    /// Saves the store on a separate thread once it has not changed for [SAVE_DELAY],
+    /// so changing a setting never waits for the disk.
+    pub fn save_in_background(store: &Arc<Mutex<Self>>) {
+        let (sender, receiver) = unbounded();
+        store.lock().unwrap().changes = Some(sender);
+
+        let store = Arc::downgrade(store);
+
+        thread::Builder::new()
+            .name("settings_saver".to_string())
+            .spawn(move || {
+                // This ends once the store is dropped, along with the sender
+                while receiver.recv().is_ok() {
+                    while receiver.recv_timeout(SAVE_DELAY).is_ok() {}
+
+                    let store = match store.upgrade() {
+                        Some(store) => store,
+                        None => return,
+                    };
+
+                    let result = store.lock().unwrap().save();
+
+                    if let Err(err) = result {
+                        warn!("Cannot save settings: {}", err);
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    /// Returns the settings of a guild, or the defaults if there are none
+    pub fn get(&self, guild: u64) -> GuildSettings {
+        self.guilds.get(&guild).cloned().unwrap_or_default()
+    }
+
+    /// Changes the settings of a guild. They are saved with [SettingsStore::save],
+    /// or in the background if [SettingsStore::save_in_background] was used.
+    pub fn update<F: FnOnce(&mut GuildSettings)>(&mut self, guild: u64, update: F) {
+        update(self.guilds.entry(guild).or_default());
+
+        if let Some(changes) = &self.changes {
+            let _ = changes.send(());
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let data =
+            ron::to_string(&self).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+        if let Some(folder) = self.path.parent() {
+            fs::create_dir_all(folder)?;
+        }
+
+        fs::write(&self.path, data)
+    }
+}
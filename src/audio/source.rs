@@ -1,16 +1,60 @@
-use super::Sample;
-use std::fmt::Display;
+use super::{
+    decoding::RawFile,
+    pipeline::{SampleReader, SamplesRead},
+    util::pcm::PcmCodec,
+    Sample, SAMPLE_IN_BYTES, TRUNCATION_TOLERANCE,
+};
 
+use log::{trace, warn};
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, fs, path::PathBuf};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+};
 
+/// A decoded source stored in the cache folder.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Source;
+pub struct Source {
+    file_name: String,
+    /// Amount of samples stored in the file
+    length: usize,
+    /// Used to evict the least recently used sources first
+    last_used: u64,
+}
+
+impl Source {
+    fn size(&self) -> usize {
+        HEADER_LENGTH + self.length * SAMPLE_IN_BYTES
+    }
+}
+
+/// Used to check that a cached file is a decoded source
+const MAGIC: [u8; 4] = *b"GCTS";
 
+/// The magic followed by the amount of samples as a little endian u64
+const HEADER_LENGTH: usize = MAGIC.len() + 8;
+
+/// An on-disk cache of decoded sources, keyed by their fingerprint.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Cache {
     entries: BTreeMap<String, Source>,
     size: usize,
+    /// Not in caches saved before sources were evicted by when they were used
+    #[serde(default)]
+    clock: u64,
+
+    #[serde(skip)]
+    folder: PathBuf,
+    #[serde(skip)]
+    max_size: usize,
+    /// Numbers the temporary files, so sources that are written at once do not share one
+    #[serde(skip)]
+    writers: u64,
 }
 
 impl Cache {
@@ -19,34 +63,533 @@ impl Cache {
 
     const MAX_SIZE: usize = 1024_usize.pow(3);
 
-    fn new() -> Self {
+    fn new(folder: PathBuf) -> Self {
         Self {
             entries: Default::default(),
             size: Default::default(),
+            clock: Default::default(),
+            folder,
+            max_size: Self::MAX_SIZE,
+            writers: Default::default(),
         }
     }
 
-    pub fn restore() -> Self {
-        let mut path = PathBuf::from(Self::CACHE_FOLDER);
+    pub fn restore() -> io::Result<Self> {
+        Self::restore_from(Self::CACHE_FOLDER)
+    }
+
+    /// Restores the cache of a folder, or creates an empty one if the folder has none.
+    /// Fails if the cache cannot be read, such as when it is corrupt.
+    pub fn restore_from<P: AsRef<Path>>(folder: P) -> io::Result<Self> {
+        let folder = folder.as_ref().to_path_buf();
+        let path = folder.join(Self::CACHE_DATA_FILE);
+
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::new(folder)),
+            Err(err) => return Err(err),
+        };
 
-        fs::create_dir_all(&path).expect("create cache folder");
-        path.push(Self::CACHE_DATA_FILE);
+        let cache = ron::from_str::<Self>(&data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
 
-        fs::read_to_string(&path)
-            .map(|data| ron::from_str(&data).expect("cache file is valid"))
-            .unwrap_or_else(|_| Self::new())
+        Ok(Self {
+            folder,
+            max_size: Self::MAX_SIZE,
+            ..cache
+        })
     }
 
-    fn save(&self) {
-        let data = ron::to_string(&self).expect("serialize cache");
+    /// Sets the maximum size of the cache in bytes
+    pub fn with_max_size(self, max_size: usize) -> Self {
+        Self { max_size, ..self }
+    }
 
-        let mut path = PathBuf::from(Self::CACHE_FOLDER);
-        path.push(Self::CACHE_DATA_FILE);
+    fn save(&self) -> io::Result<()> {
+        let data =
+            ron::to_string(&self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
 
-        fs::write(path, data).expect("save serialized cache to file");
+        fs::create_dir_all(&self.folder)?;
+        fs::write(self.folder.join(Self::CACHE_DATA_FILE), data)
     }
 
     pub fn get_by_fingerprint(&self, fingerprint: &str) -> Option<Source> {
         self.entries.get(fingerprint).cloned()
     }
+
+    /// Opens a cached source for reading, if it exists.
+    pub fn open(&mut self, fingerprint: &str) -> Option<RawFile> {
+        let source = self.entries.get_mut(fingerprint)?;
+
+        self.clock += 1;
+        source.last_used = self.clock;
+
+        let path = self.folder.join(&source.file_name);
+
+        match open_source(&path) {
+            Ok(cached) => {
+                trace!("Reading {} from cache", fingerprint);
+                Some(cached)
+            }
+            Err(err) => {
+                warn!("Cached source for {} is unusable: {}", fingerprint, err);
+                self.evict(fingerprint);
+                None
+            }
+        }
+    }
+
+    /// Moves a fully written temporary file into the cache,
+    /// evicting the least recently used sources if it is full.
+    fn insert(&mut self, fingerprint: &str, temp_path: &Path, length: usize) -> io::Result<()> {
+        self.evict(fingerprint);
+        self.clock += 1;
+
+        let source = Source {
+            file_name: Self::file_name(fingerprint),
+            last_used: self.clock,
+            length,
+        };
+
+        fs::rename(temp_path, self.folder.join(&source.file_name))?;
+
+        self.size += source.size();
+        self.entries.insert(fingerprint.to_string(), source);
+
+        while self.size > self.max_size {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, s)| s.last_used)
+                .map(|(f, _)| f.clone());
+
+            match oldest {
+                Some(oldest) => self.evict(&oldest),
+                None => break,
+            }
+        }
+
+        self.save()
+    }
+
+    fn evict(&mut self, fingerprint: &str) {
+        if let Some(source) = self.entries.remove(fingerprint) {
+            let _ = fs::remove_file(self.folder.join(&source.file_name));
+            self.size -= source.size();
+
+            trace!("Evicted {} from cache", fingerprint);
+        }
+    }
+
+    /// Moves a source that turned out to be incomplete out of the cache,
+    /// so it can be written to until it is complete.
+    fn reopen(&mut self, fingerprint: &str, temp_path: &Path) -> io::Result<BufWriter<File>> {
+        let source = self
+            .entries
+            .remove(fingerprint)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Source was evicted"))?;
+
+        self.size -= source.size();
+        self.save()?;
+
+        fs::rename(self.folder.join(&source.file_name), temp_path)?;
+
+        let mut file = OpenOptions::new().write(true).open(temp_path)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(BufWriter::new(file))
+    }
+
+    /// Returns a path for a source to be written to before it is complete,
+    /// which is different every time, even between processes.
+    fn temp_path(&mut self, fingerprint: &str) -> PathBuf {
+        self.writers += 1;
+
+        let file_name = Self::file_name(fingerprint);
+        let unique = format!("{}.{}-{}.tmp", file_name, process::id(), self.writers);
+
+        self.folder.join(unique)
+    }
+
+    fn file_name(fingerprint: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        fingerprint.hash(&mut hasher);
+
+        format!("{:016x}.raw", hasher.finish())
+    }
+}
+
+/// Opens a cached source for reading, checking its header.
+/// It is read as a [RawFile], so it can seek without reading what is before the offset.
+fn open_source(path: &Path) -> io::Result<RawFile> {
+    let mut file = File::open(path)?;
+    let mut header = [0; HEADER_LENGTH];
+
+    file.read_exact(&mut header)?;
+
+    if header[..MAGIC.len()] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid header"));
+    }
+
+    let mut length = [0; 8];
+    length.copy_from_slice(&header[MAGIC.len()..]);
+
+    RawFile::from_file(file, u64::from_le_bytes(length) as usize)
+}
+
+/// Writes the samples of a reader to the cache as they are read.
+/// The source is added to the cache once it was read to its end or expected length,
+/// as loaders do not read further than that. A source that ends the way a failed
+/// download does, with nothing or far short of its expected length, is not cached.
+pub struct CacheWriter<R> {
+    reader: R,
+    cache: Arc<Mutex<Cache>>,
+    fingerprint: String,
+    expected: usize,
+
+    file: Option<BufWriter<File>>,
+    temp_path: PathBuf,
+    written: usize,
+    /// Whether the source was added to the cache
+    committed: bool,
+}
+
+impl<R> CacheWriter<R>
+where
+    R: SampleReader,
+{
+    pub fn new(reader: R, cache: Arc<Mutex<Cache>>, fingerprint: &str, expected: usize) -> Self {
+        let temp_path = cache.lock().unwrap().temp_path(fingerprint);
+
+        let file = Self::create_file(&temp_path)
+            .map_err(|err| warn!("Cannot cache {}: {}", fingerprint, err))
+            .ok();
+
+        Self {
+            reader,
+            cache,
+            fingerprint: fingerprint.to_string(),
+            expected,
+            file,
+            temp_path,
+            written: 0,
+            committed: false,
+        }
+    }
+
+    fn create_file(path: &Path) -> io::Result<BufWriter<File>> {
+        if let Some(folder) = path.parent() {
+            fs::create_dir_all(folder)?;
+        }
+
+        let mut file = BufWriter::new(File::create(path)?);
+
+        // The length is written when the source has been read to the end
+        file.write_all(&[0; HEADER_LENGTH])?;
+        Ok(file)
+    }
+
+    fn write(&mut self, samples: &[Sample]) {
+        let result = match self.file.as_mut() {
+            Some(file) => file.write_all(&PcmCodec::FLOAT.encode(samples)),
+            None => return,
+        };
+
+        match result {
+            Ok(_) => self.written += samples.len(),
+            Err(err) => {
+                warn!("Failed to write {} to cache: {}", self.fingerprint, err);
+                self.discard();
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        let mut file = file.into_inner()?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&MAGIC)?;
+        file.write_all(&(self.written as u64).to_le_bytes())?;
+        drop(file);
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert(&self.fingerprint, &self.temp_path, self.written)?;
+
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl<R> SampleReader for CacheWriter<R>
+where
+    R: SampleReader,
+{
+    fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+        let result = self.reader.read_samples(buf);
+
+        if self.committed && result.amount() > 0 {
+            self.reopen();
+        }
+
+        self.write(&buf[..result.amount()]);
+
+        // Sources that end far from their expected length are only complete at their end
+        let complete = self.expected > 0 && self.written >= self.expected;
+
+        if result.is_empty() && !complete && self.ended_early() {
+            warn!(
+                "{} ended after {} of {} samples, so it is not cached",
+                self.fingerprint, self.written, self.expected
+            );
+            self.discard();
+        } else if result.is_empty() || complete {
+            if let Err(err) = self.finish() {
+                warn!("Failed to add {} to cache: {}", self.fingerprint, err);
+                self.discard();
+            }
+        }
+
+        result
+    }
+
+    fn length(&self) -> Option<usize> {
+        self.reader.length()
+    }
+
+    /// The samples before the offset are skipped, so the source is not cached
+    fn seek_samples(&mut self, offset: usize) -> bool {
+        let seeked = self.reader.seek_samples(offset);
+
+        if seeked {
+            self.discard();
+        }
+
+        seeked
+    }
+}
+
+impl<R> CacheWriter<R> {
+    /// Returns true if the source ended like a loader that failed, see [super::LoadFailure]
+    fn ended_early(&self) -> bool {
+        self.written == 0 || self.written + TRUNCATION_TOLERANCE < self.expected
+    }
+
+    /// Continues writing a source that is longer than expected, until it ends
+    fn reopen(&mut self) {
+        trace!(
+            "{} is longer than expected, so it is cached once it ends",
+            self.fingerprint
+        );
+
+        let mut cache = self.cache.lock().unwrap();
+
+        self.committed = false;
+        self.expected = 0;
+        self.file = cache
+            .reopen(&self.fingerprint, &self.temp_path)
+            .map_err(|err| warn!("Cannot cache {}: {}", self.fingerprint, err))
+            .ok();
+    }
+
+    fn discard(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+// A source that was not read to the end must not end up in the cache
+impl<R> Drop for CacheWriter<R> {
+    fn drop(&mut self) {
+        self.discard();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs, process,
+        sync::{Arc, Mutex},
+    };
+
+    use super::Cache;
+    use crate::{
+        audio::{
+            pipeline::{IntoSampleReader, SampleReader, SamplesRead},
+            Loader, Pool, Sample, READY_AMOUNT, TRUNCATION_TOLERANCE,
+        },
+        util::model::Identified,
+    };
+
+    /// A download that has sent some samples, and is waiting for the rest
+    struct Stalled(Vec<Sample>);
+
+    impl SampleReader for Stalled {
+        fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+            let amount = buf.len().min(self.0.len());
+            buf[..amount].copy_from_slice(&self.0[..amount]);
+            self.0.drain(..amount);
+
+            SamplesRead::More(amount)
+        }
+    }
+
+    /// Adds and loads a source, counting how often its reader is created
+    fn add(
+        pool: &Pool,
+        fingerprint: &str,
+        samples: Vec<Sample>,
+        decodes: &Arc<Mutex<usize>>,
+    ) -> Arc<Loader> {
+        let decodes = decodes.clone();
+        let length = samples.len();
+
+        let loader = pool.add_fingerprinted(
+            fingerprint,
+            move || {
+                *decodes.lock().unwrap() += 1;
+                samples.into_sample_reader()
+            },
+            length,
+        );
+
+        pool.load(loader.id(), length);
+        loader
+    }
+
+    #[test]
+    fn cached_sources_are_not_decoded_again() {
+        let folder = env::temp_dir().join(format!("gct-cache-{}", process::id()));
+        let pool = Pool::with_cache(Cache::restore_from(&folder).unwrap());
+
+        let samples = vec![1., 2., 3., 4., 5., 6.];
+        let decodes = Default::default();
+
+        add(&pool, "jingle", samples.clone(), &decodes);
+        let second = add(&pool, "jingle", samples.clone(), &decodes);
+
+        let mut buf = vec![0.; samples.len()];
+        second.read(0, &mut buf);
+
+        assert_eq!(*decodes.lock().unwrap(), 1);
+        assert_eq!(buf, samples);
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn cached_sources_seek_without_reading_the_prefix() {
+        let folder = env::temp_dir().join(format!("gct-cache-seek-{}", process::id()));
+        let pool = Pool::with_cache(Cache::restore_from(&folder).unwrap());
+
+        let samples: Vec<Sample> = (0..READY_AMOUNT * 4).map(|i| i as Sample).collect();
+        let middle = samples.len() / 2;
+        let decodes = Default::default();
+
+        add(&pool, "long", samples.clone(), &decodes);
+
+        let loader = pool.add_fingerprinted("long", || vec![].into_sample_reader(), samples.len());
+        assert!(loader.seek(middle));
+        assert_eq!(loader.loaded(), middle..middle + READY_AMOUNT);
+
+        let mut buf = [0.; 4];
+        loader.read(middle, &mut buf);
+
+        assert_eq!(buf, samples[middle..middle + 4]);
+        assert_eq!(*decodes.lock().unwrap(), 1);
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn sources_are_cached_once_they_reach_their_expected_length() {
+        let folder = env::temp_dir().join(format!("gct-cache-expected-{}", process::id()));
+        let pool = Pool::with_cache(Cache::restore_from(&folder).unwrap());
+
+        // Nothing ends the source, but nothing after the expected length is played either
+        let loader = pool.add_fingerprinted("station", || Stalled(vec![1.; 8]), 8);
+        pool.load(loader.id(), 8);
+
+        let cache = Cache::restore_from(&folder).unwrap();
+        assert_eq!(cache.get_by_fingerprint("station").unwrap().length, 8);
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn sources_longer_than_expected_are_cached_in_full() {
+        let folder = env::temp_dir().join(format!("gct-cache-longer-{}", process::id()));
+        let pool = Pool::with_cache(Cache::restore_from(&folder).unwrap());
+
+        let loader = pool.add_fingerprinted("long", || vec![1.; 16].into_sample_reader(), 8);
+        pool.load(loader.id(), 8);
+
+        let cache = Cache::restore_from(&folder).unwrap();
+        assert_eq!(cache.get_by_fingerprint("long").unwrap().length, 16);
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn sources_that_fail_while_loading_are_not_cached() {
+        let folder = env::temp_dir().join(format!("gct-cache-failed-{}", process::id()));
+        let pool = Pool::with_cache(Cache::restore_from(&folder).unwrap());
+        let expected = TRUNCATION_TOLERANCE * 2;
+
+        // The download is cut off a few samples in
+        let loader =
+            pool.add_fingerprinted("cut off", || vec![1.; 8].into_sample_reader(), expected);
+        pool.load(loader.id(), expected);
+
+        // A decode that fails before producing anything
+        let loader = pool.add_fingerprinted("corrupt", || vec![].into_sample_reader(), expected);
+        pool.load(loader.id(), expected);
+
+        let cache = Cache::restore_from(&folder).unwrap();
+        assert!(cache.get_by_fingerprint("cut off").is_none());
+        assert!(cache.get_by_fingerprint("corrupt").is_none());
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_sources_are_evicted() {
+        let folder = env::temp_dir().join(format!("gct-cache-evict-{}", process::id()));
+
+        // Only fits one of the sources
+        let cache = Cache::restore_from(&folder).unwrap().with_max_size(64);
+        let pool = Pool::with_cache(cache);
+        let decodes = Default::default();
+
+        for fingerprint in ["first", "second", "second", "first"] {
+            add(&pool, fingerprint, vec![1.; 8], &decodes);
+        }
+
+        // The first was decoded again, as it was evicted when the second was cached
+        assert_eq!(*decodes.lock().unwrap(), 3);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn unreadable_caches_are_errors() {
+        let folder = env::temp_dir().join(format!("gct-cache-corrupt-{}", process::id()));
+        fs::create_dir_all(&folder).unwrap();
+
+        fs::write(folder.join(Cache::CACHE_DATA_FILE), "not a cache").unwrap();
+        assert!(Cache::restore_from(&folder).is_err());
+
+        // Caches saved before sources were evicted by when they were used are still valid
+        fs::write(
+            folder.join(Cache::CACHE_DATA_FILE),
+            "(entries: {}, size: 0)",
+        )
+        .unwrap();
+        assert!(Cache::restore_from(&folder).is_ok());
+
+        fs::remove_dir_all(folder).unwrap();
+    }
 }
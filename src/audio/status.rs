@@ -0,0 +1,120 @@
+use json::{object, JsonValue};
+
+use super::{AudioSystem, TrackInfo, SAMPLES_PER_SEC};
+
+impl TrackInfo {
+    pub fn to_json(&self) -> JsonValue {
+        object! {
+            "id" => *self.id,
+            "title" => self.title.as_str(),
+            "artist" => self.metadata.artist.as_deref(),
+            "album" => self.metadata.album.as_deref(),
+            "artwork" => self.metadata.artwork.as_deref(),
+            "duration" => self.duration.as_secs_f64(),
+            "requested_by" => self.requested_by,
+        }
+    }
+}
+
+impl AudioSystem {
+    /// Returns a snapshot of the queue and playback state
+    pub fn status(&self) -> JsonValue {
+        // Read the queue once, so the tracks are consistent with each other
+        let tracks: Vec<_> = self
+            .queue
+            .peek_ahead(usize::MAX)
+            .iter()
+            .map(|t| t.info())
+            .collect();
+        let position = self.scheduler.offset() as f64 / SAMPLES_PER_SEC as f64;
+
+        let autoplay = self
+            .autoplay
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|a| a.is_enabled())
+            .unwrap_or_default();
+
+        let served = self.total_served();
+        let load_budget = self
+            .threads
+            .load_budget
+            .load()
+            .map(|b| b as f64 / SAMPLES_PER_SEC as f64);
+
+        object! {
+            "current" => tracks.first().map(TrackInfo::to_json),
+            "position" => position,
+            "queue" => tracks.iter().skip(1).map(TrackInfo::to_json).collect::<Vec<_>>(),
+            "format" => format!("{:?}", self.registry.format()),
+            "modes" => object! {
+                "crossfade" => self.crossfade().map(|d| d.as_secs_f64()),
+                "trim_boundaries" => self.scheduler.trim_boundaries(),
+                "autoplay" => autoplay,
+                "repeat" => format!("{:?}", self.repeat()),
+                "desync_policy" => format!("{:?}", self.desync_policy.load()),
+            },
+            "muted" => self.is_muted(),
+            "speed" => self.speed(),
+            "health" => object! {
+                "running" => self.threads.is_running(),
+                "draining" => self.is_draining(),
+                "phase_correlation" => self.metering.correlation(),
+            },
+            "loading" => object! {
+                "budget" => load_budget,
+                "last_cycle" => self.last_load().as_secs_f64(),
+            },
+            "served" => object! {
+                "samples" => served.samples,
+                "bytes" => served.bytes,
+                "duration" => served.duration.as_secs_f64(),
+            },
+        }
+    }
+
+    /// Returns the status as a JSON string, see [AudioSystem::status]
+    pub fn status_json(&self) -> String {
+        self.status().dump()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::audio::{pipeline::IntoSampleReader, AudioSystem, SAMPLES_PER_SEC};
+
+    #[test]
+    fn status_describes_the_queue() {
+        let system = AudioSystem::new();
+        system.set_crossfade(Some(Duration::from_secs(2)));
+
+        for title in ["First", "Second", "Third"] {
+            let loader = system.pool.add(
+                vec![0.; SAMPLES_PER_SEC].into_sample_reader(),
+                SAMPLES_PER_SEC,
+            );
+
+            system.add_loader(loader, title.to_string());
+        }
+
+        let status = system.status();
+
+        assert_eq!(status["current"]["title"].as_str(), Some("First"));
+        assert_eq!(status["current"]["duration"].as_f64(), Some(1.));
+        assert_eq!(status["position"].as_f64(), Some(0.));
+
+        assert_eq!(status["queue"].len(), 2);
+        assert_eq!(status["queue"][0]["title"].as_str(), Some("Second"));
+        assert_eq!(status["queue"][1]["title"].as_str(), Some("Third"));
+
+        assert_eq!(status["format"].as_str(), Some("Float32"));
+        assert_eq!(status["modes"]["crossfade"].as_f64(), Some(2.));
+        assert_eq!(status["modes"]["autoplay"].as_bool(), Some(false));
+        assert_eq!(status["modes"]["repeat"].as_str(), Some("Off"));
+        assert_eq!(status["health"]["running"].as_bool(), Some(false));
+        assert_eq!(status["served"]["samples"].as_usize(), Some(0));
+    }
+}
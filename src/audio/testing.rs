@@ -0,0 +1,46 @@
+/// A mono wave file with 16-bit samples, tagged with RIFF INFO entries
+pub fn wave(rate: u32, samples: &[i16], info: &[(&[u8; 4], &str)]) -> Vec<u8> {
+    let mut list = vec![];
+
+    for (id, value) in info {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+
+        list.extend(*id);
+        list.extend((value.len() as u32).to_le_bytes());
+        list.extend(&value);
+
+        if value.len() % 2 == 1 {
+            list.push(0);
+        }
+    }
+
+    if !list.is_empty() {
+        let entries = list;
+
+        list = b"LIST".to_vec();
+        list.extend((entries.len() as u32 + 4).to_le_bytes());
+        list.extend(b"INFO");
+        list.extend(entries);
+    }
+
+    let size = samples.len() as u32 * 2;
+    let mut bytes = vec![];
+
+    bytes.extend(b"RIFF");
+    bytes.extend((36 + list.len() as u32 + size).to_le_bytes());
+    bytes.extend(b"WAVEfmt ");
+    bytes.extend(16_u32.to_le_bytes());
+    bytes.extend(1_u16.to_le_bytes());
+    bytes.extend(1_u16.to_le_bytes());
+    bytes.extend(rate.to_le_bytes());
+    bytes.extend((rate * 2).to_le_bytes());
+    bytes.extend(2_u16.to_le_bytes());
+    bytes.extend(16_u16.to_le_bytes());
+    bytes.extend(list);
+    bytes.extend(b"data");
+    bytes.extend(size.to_le_bytes());
+    bytes.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+
+    bytes
+}
@@ -0,0 +1,94 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use super::AudioSystem;
+use crate::util::model::{Id, Identified};
+
+pub type TimerId = Id<Timer>;
+
+/// What happens when a timer is due
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerAction {
+    Pause,
+    Stop,
+}
+
+/// An action that happens at a later time, such as stopping at the end of a show
+#[derive(Debug)]
+pub struct Timer {
+    id: TimerId,
+    at: Instant,
+    action: TimerAction,
+}
+
+impl Identified for Timer {
+    const NAME: &'static str = "Timer";
+
+    fn id(&self) -> Id<Self> {
+        self.id
+    }
+}
+
+#[derive(Default)]
+pub struct Timers {
+    pending: Mutex<Vec<Timer>>,
+}
+
+impl AudioSystem {
+    /// Pauses playback once the duration has passed, see [AudioSystem::cancel_timer]
+    pub fn schedule_pause(&self, after: Duration) -> TimerId {
+        self.schedule(after, TimerAction::Pause)
+    }
+
+    /// Stops playback once the duration has passed, see [AudioSystem::cancel_timer]
+    pub fn schedule_stop(&self, after: Duration) -> TimerId {
+        self.schedule(after, TimerAction::Stop)
+    }
+
+    fn schedule(&self, after: Duration, action: TimerAction) -> TimerId {
+        let id = TimerId::new();
+
+        info!("Scheduled {:?} in {}s", action, after.as_secs());
+
+        self.timers.pending.lock().unwrap().push(Timer {
+            id,
+            at: Instant::now() + after,
+            action,
+        });
+
+        id
+    }
+
+    /// Cancels a timer, returning false if it already happened or was cancelled
+    pub fn cancel_timer(&self, id: TimerId) -> bool {
+        let mut pending = self.timers.pending.lock().unwrap();
+        let before = pending.len();
+
+        pending.retain(|t| t.id != id);
+        pending.len() < before
+    }
+
+    /// Runs the timers that are due by now
+    pub(super) fn run_timers(&self, now: Instant) {
+        let due = {
+            let mut pending = self.timers.pending.lock().unwrap();
+            let (due, rest): (Vec<_>, Vec<_>) = pending.drain(..).partition(|t| t.at <= now);
+
+            *pending = rest;
+            due
+        };
+
+        for timer in due {
+            info!("Timer {:?} is due", timer.action);
+
+            match timer.action {
+                TimerAction::Pause => self.pause(),
+                TimerAction::Stop => self.stop(),
+            }
+        }
+    }
+}
@@ -1,16 +1,196 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use super::{Input, Loader, LoaderId};
+use super::{LoadFailure, Loader, SAMPLES_PER_SEC};
+use crate::util::model::{Id, Identified};
+
+pub type TrackId = Id<Track>;
 
 /// A playable audio track, which can be queued.
 /// It may provide metadata as well.
 #[derive(Clone)]
 pub struct Track {
+    id: TrackId,
+    title: String,
+    /// The user who queued this track, if it was queued by one
+    requested_by: Option<u64>,
+    metadata: Metadata,
+    overrides: TrackMetaOverrides,
+    crossfade: TrackCrossfade,
+    /// The loudness in LUFS this track is brought to, used over the normalization of the system
+    loudness_target: Option<f32>,
     pub loader: Arc<Loader>,
 }
 
+/// Metadata given when a track is queued, which is used over what the source says
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackMetaOverrides {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    /// Used as the expected length, such as for streams that do not know theirs
+    pub duration: Option<Duration>,
+    pub artwork: Option<String>,
+}
+
+/// How long a track fades in and out when crossfading, used over the crossfade of the system.
+/// A transition uses the fade out of the track that ends and the fade in of the one that starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrackCrossfade {
+    pub fade_in: Option<Duration>,
+    pub fade_out: Option<Duration>,
+}
+
+/// Describes where a track comes from, every field is optional as sources may not be tagged
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// A url to an image of the cover art
+    pub artwork: Option<String>,
+    /// The name of the codec the source is encoded with
+    pub codec: Option<String>,
+}
+
+/// Describes a track for display
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackInfo {
+    pub id: TrackId,
+    pub title: String,
+    pub metadata: Metadata,
+    pub duration: Duration,
+    pub requested_by: Option<u64>,
+}
+
+/// A track that played until its end, and when it did
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayedTrackInfo {
+    pub track: TrackInfo,
+    pub finished_at: SystemTime,
+}
+
+/// A queued track that could not be loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedTrackInfo {
+    pub track: TrackInfo,
+    pub reason: LoadFailure,
+}
+
+/// Everything known about a track that is playing, for displaying it in full
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackDetails {
+    pub id: TrackId,
+    pub title: String,
+    pub metadata: Metadata,
+    /// How far into the track playback is
+    pub position: Duration,
+    pub duration: Duration,
+    pub requested_by: Option<u64>,
+}
+
 impl Track {
-    pub fn new(loader: Arc<Loader>) -> Self {
-        Self { loader }
+    pub fn new(loader: Arc<Loader>, title: String) -> Self {
+        Self {
+            id: TrackId::new(),
+            title,
+            requested_by: None,
+            metadata: Default::default(),
+            overrides: Default::default(),
+            crossfade: Default::default(),
+            loudness_target: None,
+            loader,
+        }
+    }
+
+    /// Marks the track as queued by a user, such as a Discord user id
+    pub fn with_requester(self, requested_by: u64) -> Self {
+        Self {
+            requested_by: Some(requested_by),
+            ..self
+        }
+    }
+
+    pub fn with_metadata(self, metadata: Metadata) -> Self {
+        Self { metadata, ..self }
+    }
+
+    pub fn with_overrides(self, overrides: TrackMetaOverrides) -> Self {
+        Self { overrides, ..self }
+    }
+
+    pub fn with_crossfade(self, crossfade: TrackCrossfade) -> Self {
+        Self { crossfade, ..self }
+    }
+
+    pub fn crossfade(&self) -> TrackCrossfade {
+        self.crossfade
+    }
+
+    /// Normalizes this track to its own loudness in LUFS, such as -23 for speech
+    /// queued between songs, even when the system does not normalize at all.
+    pub fn with_loudness_target(self, target: f32) -> Self {
+        Self {
+            loudness_target: Some(target),
+            ..self
+        }
+    }
+
+    pub fn loudness_target(&self) -> Option<f32> {
+        self.loudness_target
+    }
+
+    /// Returns the metadata of the source, with the overrides applied
+    pub fn metadata(&self) -> Metadata {
+        let overrides = self.overrides.clone();
+
+        Metadata {
+            artist: overrides.artist.or_else(|| self.metadata.artist.clone()),
+            artwork: overrides.artwork.or_else(|| self.metadata.artwork.clone()),
+            ..self.metadata.clone()
+        }
+    }
+
+    pub fn requested_by(&self) -> Option<u64> {
+        self.requested_by
+    }
+
+    pub fn title(&self) -> &str {
+        self.overrides.title.as_ref().unwrap_or(&self.title)
+    }
+
+    /// Returns the duration of the track as currently known
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.loader.expected() as f64 / SAMPLES_PER_SEC as f64)
+    }
+
+    pub fn info(&self) -> TrackInfo {
+        TrackInfo {
+            id: self.id,
+            title: self.title().to_string(),
+            metadata: self.metadata(),
+            duration: self.duration(),
+            requested_by: self.requested_by,
+        }
+    }
+
+    /// Describes the track in full, given how far into it playback is
+    pub fn details(&self, position: Duration) -> TrackDetails {
+        TrackDetails {
+            id: self.id,
+            title: self.title().to_string(),
+            metadata: self.metadata(),
+            position,
+            duration: self.duration(),
+            requested_by: self.requested_by,
+        }
+    }
+}
+
+impl Identified for Track {
+    const NAME: &'static str = "Track";
+
+    fn id(&self) -> Id<Self> {
+        self.id
     }
 }
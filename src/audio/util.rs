@@ -1,11 +1,14 @@
 /// Types and structs to streamline pipelining of audio processing
 pub mod pipeline {
     use std::{
+        error::Error,
+        fmt::Display,
         io::Read,
         ops::{Deref, DerefMut},
     };
 
-    use crate::audio::{Sample, SAMPLE_IN_BYTES};
+    use super::pcm::PcmCodec;
+    use crate::audio::{Sample, CHANNEL_COUNT, SAMPLES_PER_SEC, SAMPLE_IN_BYTES};
     use log::error;
 
     /// Reads [Sample] into the provided buffer, returning an enum
@@ -25,6 +28,12 @@ pub mod pipeline {
             None
         }
 
+        /// Moves the reader to a sample offset without reading the samples before it.
+        /// Returns false if the reader cannot seek, which is the case for streams.
+        fn seek_samples(&mut self, _offset: usize) -> bool {
+            false
+        }
+
         /// Convert this reader into an opaque source stored on the heap.
         fn wrap(self) -> SampleSource
         where
@@ -101,33 +110,28 @@ pub mod pipeline {
         /// Interprets the data as [Sample].
         fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
             let mut internal_buf = vec![0; buf.len() * SAMPLE_IN_BYTES];
-
-            match self.read(&mut internal_buf) {
-                Ok(read) => {
-                    let samples: Vec<Sample> = internal_buf[..read]
-                        .chunks_exact(SAMPLE_IN_BYTES)
-                        .map(|b| {
-                            let arr: [u8; SAMPLE_IN_BYTES] = [b[0], b[1], b[2], b[3]];
-                            Sample::from_le_bytes(arr)
-                        })
-                        .collect();
-
-                    let samples_read = samples.len();
-                    buf[..samples_read].copy_from_slice(&samples);
-
-                    if read < internal_buf.len() {
-                        SamplesRead::Empty(samples_read)
-                    } else {
-                        SamplesRead::More(samples_read)
-                    }
-                }
-                Err(err) => match err.kind() {
-                    std::io::ErrorKind::Interrupted => SamplesRead::More(0),
-                    _ => {
+            let mut read = 0;
+
+            // Reads can end in the middle of a sample, so keep reading until the buffer
+            // is full, or the end is reached, to not misalign the samples after it.
+            while read < internal_buf.len() {
+                match self.read(&mut internal_buf[read..]) {
+                    Ok(0) => break,
+                    Ok(amount) => read += amount,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
                         error!("Sample conversion failed: {}", err);
-                        SamplesRead::Empty(0)
+                        break;
                     }
-                },
+                }
+            }
+
+            let samples_read = PcmCodec::FLOAT.decode_into(&internal_buf[..read], buf);
+
+            if read < internal_buf.len() {
+                SamplesRead::Empty(samples_read)
+            } else {
+                SamplesRead::More(samples_read)
             }
         }
     }
@@ -153,6 +157,10 @@ pub mod pipeline {
         fn length(&self) -> Option<usize> {
             self.reader.length()
         }
+
+        fn seek_samples(&mut self, offset: usize) -> bool {
+            self.reader.seek_samples(offset)
+        }
     }
 
     /// A vec of samples
@@ -203,9 +211,186 @@ pub mod pipeline {
         }
     }
 
+    /// Produces silence for a known amount of samples
+    #[derive(Debug)]
+    pub struct Silence {
+        remaining: usize,
+        length: usize,
+    }
+
+    impl Silence {
+        pub fn new(length: usize) -> Self {
+            Self {
+                remaining: length,
+                length,
+            }
+        }
+    }
+
+    impl SampleReader for Silence {
+        fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+            let amount = buf.len().min(self.remaining);
+
+            buf[..amount].fill(Sample::default());
+            self.remaining -= amount;
+
+            SamplesRead::empty_if(self.remaining == 0 && amount < buf.len(), amount)
+        }
+
+        fn length(&self) -> Option<usize> {
+            Some(self.length)
+        }
+    }
+
+    /// Reads a region of another reader, seeking to its start if possible,
+    /// so only the region is decoded. Readers that cannot seek are decoded
+    /// from their start instead, discarding what comes before the region.
+    pub struct Clip<R> {
+        reader: R,
+        start: usize,
+        length: usize,
+        /// Whether the reader still has to skip to the start, which happens on the first read
+        skipped: bool,
+        remaining: usize,
+    }
+
+    impl<R: SampleReader> Clip<R> {
+        pub fn new(reader: R, start: usize, length: usize) -> Self {
+            Self {
+                reader,
+                start,
+                length,
+                skipped: false,
+                remaining: length,
+            }
+        }
+
+        fn skip(&mut self, amount: usize) {
+            if self.reader.seek_samples(amount) {
+                return;
+            }
+
+            let mut buf = vec![Sample::default(); amount.min(SAMPLES_PER_SEC)];
+            let mut skipped = 0;
+
+            while skipped < amount {
+                let wanted = buf.len().min(amount - skipped);
+                let result = self.reader.read_samples(&mut buf[..wanted]);
+
+                skipped += result.amount();
+
+                if result.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+
+    impl<R: SampleReader> SampleReader for Clip<R> {
+        fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+            if !self.skipped {
+                self.skipped = true;
+                self.skip(self.start);
+            }
+
+            let amount = buf.len().min(self.remaining);
+            let result = self.reader.read_samples(&mut buf[..amount]);
+
+            self.remaining -= result.amount();
+            SamplesRead::empty_if(result.is_empty() || self.remaining == 0, result.amount())
+        }
+
+        fn length(&self) -> Option<usize> {
+            Some(self.length)
+        }
+
+        /// Seeks within the region, so the offset is relative to its start
+        fn seek_samples(&mut self, offset: usize) -> bool {
+            let offset = offset.min(self.length);
+
+            if !self.reader.seek_samples(self.start + offset) {
+                return false;
+            }
+
+            self.skipped = true;
+            self.remaining = self.length - offset;
+
+            true
+        }
+    }
+
+    /// Converts a reader of interleaved samples with some channel count to stereo
+    pub struct Stereo<R> {
+        reader: R,
+        channels: usize,
+        input: Vec<Sample>,
+    }
+
+    impl<R: SampleReader> Stereo<R> {
+        /// Mono is duplicated to both channels, and surround is reduced
+        /// to its front left and right channels, which come first.
+        pub fn new(reader: R, channels: usize) -> Result<Self, UnsupportedChannelCount> {
+            if channels == 0 {
+                return Err(UnsupportedChannelCount(channels));
+            }
+
+            Ok(Self {
+                reader,
+                channels,
+                input: vec![],
+            })
+        }
+    }
+
+    impl<R: SampleReader> SampleReader for Stereo<R> {
+        fn read_samples(&mut self, buf: &mut [Sample]) -> SamplesRead {
+            if self.channels == CHANNEL_COUNT {
+                return self.reader.read_samples(buf);
+            }
+
+            let frames = buf.len() / CHANNEL_COUNT;
+            self.input.resize(frames * self.channels, Sample::default());
+
+            let result = self.reader.read_samples(&mut self.input);
+            let input = self.input[..result.amount()].chunks_exact(self.channels);
+
+            for (frame, samples) in buf.chunks_exact_mut(CHANNEL_COUNT).zip(input) {
+                match samples {
+                    [mono] => frame.fill(*mono),
+                    _ => frame.copy_from_slice(&samples[..CHANNEL_COUNT]),
+                }
+            }
+
+            result.map(|amount| amount / self.channels * CHANNEL_COUNT)
+        }
+
+        fn length(&self) -> Option<usize> {
+            self.reader
+                .length()
+                .map(|length| length / self.channels * CHANNEL_COUNT)
+        }
+
+        fn seek_samples(&mut self, offset: usize) -> bool {
+            self.reader
+                .seek_samples(offset / CHANNEL_COUNT * self.channels)
+        }
+    }
+
+    /// A reader has a channel count that cannot be converted to stereo
+    #[derive(Debug)]
+    pub struct UnsupportedChannelCount(pub usize);
+
+    impl Display for UnsupportedChannelCount {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Audio with {} channels is not supported", self.0)
+        }
+    }
+
+    impl Error for UnsupportedChannelCount {}
+
     #[cfg(test)]
     mod test {
-        use super::{IntoSampleReader, SampleReader, SamplesRead};
+        use super::{IntoSampleReader, SampleReader, SamplesRead, Stereo};
 
         #[test]
         fn sample_vec() {
@@ -218,6 +403,237 @@ pub mod pipeline {
             let result = samples.read_samples(&mut buffer[..6]);
             assert_eq!(result, SamplesRead::Empty(3));
         }
+
+        #[test]
+        fn mono_is_duplicated_to_both_channels() {
+            let mono = vec![0.1, 0.2, 0.3].into_sample_reader();
+            let mut stereo = Stereo::new(mono, 1).unwrap();
+            let mut buffer = vec![0.; 8];
+
+            assert_eq!(stereo.read_samples(&mut buffer), SamplesRead::Empty(6));
+            assert_eq!(&buffer[..6], &[0.1, 0.1, 0.2, 0.2, 0.3, 0.3]);
+
+            assert!(Stereo::new(vec![0.].into_sample_reader(), 0).is_err());
+        }
+
+        #[test]
+        fn surround_keeps_the_front_channels() {
+            let surround = vec![0.1, 0.2, 0.5, 0.9, 0.3, 0.4, 0.5, 0.9].into_sample_reader();
+            let mut stereo = Stereo::new(surround, 4).unwrap();
+            let mut buffer = vec![0.; 8];
+
+            assert_eq!(stereo.read_samples(&mut buffer), SamplesRead::Empty(4));
+            assert_eq!(&buffer[..4], &[0.1, 0.2, 0.3, 0.4]);
+        }
+    }
+}
+
+/// Conversions between samples and the bytes they are decoded, cached and streamed as,
+/// so byte order and alignment are handled the same way everywhere.
+pub mod pcm {
+    use crate::audio::{Sample, SampleFormat, CHANNEL_COUNT};
+
+    /// Converts samples of a format to and from interleaved little endian bytes,
+    /// regardless of the byte order of the host.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PcmCodec {
+        format: SampleFormat,
+        channels: usize,
+    }
+
+    impl PcmCodec {
+        /// How samples are decoded, cached and streamed raw
+        pub const FLOAT: Self = Self::new(SampleFormat::Float32, CHANNEL_COUNT);
+
+        pub const fn new(format: SampleFormat, channels: usize) -> Self {
+            Self { format, channels }
+        }
+
+        pub const fn format(&self) -> SampleFormat {
+            self.format
+        }
+
+        /// Returns the size of a frame in bytes
+        pub const fn frame_size(&self) -> usize {
+            self.format.sample_size() * self.channels
+        }
+
+        /// Returns how many of an amount of bytes make up whole samples
+        pub const fn whole_samples(&self, bytes: usize) -> usize {
+            bytes - bytes % self.format.sample_size()
+        }
+
+        /// Returns how many of an amount of bytes make up whole frames
+        pub const fn whole_frames(&self, bytes: usize) -> usize {
+            bytes - bytes % self.frame_size()
+        }
+
+        /// Appends the samples as bytes, which are clamped to full scale for integer formats
+        pub fn encode_into(&self, samples: &[Sample], bytes: &mut Vec<u8>) {
+            bytes.reserve(samples.len() * self.format.sample_size());
+
+            match self.format {
+                SampleFormat::Float32 => {
+                    for sample in samples {
+                        bytes.extend_from_slice(&sample.to_le_bytes());
+                    }
+                }
+                SampleFormat::Int16 => {
+                    for sample in samples {
+                        let sample = (sample.clamp(-1., 1.) * i16::MAX as Sample) as i16;
+                        bytes.extend_from_slice(&sample.to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        pub fn encode(&self, samples: &[Sample]) -> Vec<u8> {
+            let mut bytes = Vec::new();
+            self.encode_into(samples, &mut bytes);
+            bytes
+        }
+
+        /// Decodes bytes into a buffer, returning how many samples were decoded.
+        /// Decoding stops when the buffer is full, and a sample that is cut off is left out.
+        pub fn decode_into(&self, bytes: &[u8], samples: &mut [Sample]) -> usize {
+            let sample_size = self.format.sample_size();
+            let chunks = bytes.chunks_exact(sample_size).zip(samples.iter_mut());
+
+            let mut decoded = 0;
+
+            for (b, sample) in chunks {
+                *sample = match self.format {
+                    SampleFormat::Float32 => Sample::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                    SampleFormat::Int16 => {
+                        i16::from_le_bytes([b[0], b[1]]) as Sample / i16::MAX as Sample
+                    }
+                };
+
+                decoded += 1;
+            }
+
+            decoded
+        }
+
+        pub fn decode(&self, bytes: &[u8]) -> Vec<Sample> {
+            let mut samples = vec![0.; bytes.len() / self.format.sample_size()];
+            self.decode_into(bytes, &mut samples);
+            samples
+        }
+
+        /// Interleaves separate channels into frames, stopping at the end of the shortest
+        pub fn interleave(&self, channels: &[&[Sample]]) -> Vec<Sample> {
+            debug_assert_eq!(channels.len(), self.channels);
+
+            let frames = channels.iter().map(|c| c.len()).min().unwrap_or_default();
+
+            (0..frames)
+                .flat_map(|i| channels.iter().map(move |c| c[i]))
+                .collect()
+        }
+
+        /// Splits frames into separate channels, leaving out a frame that is cut off
+        pub fn deinterleave(&self, samples: &[Sample]) -> Vec<Vec<Sample>> {
+            (0..self.channels)
+                .map(|channel| {
+                    samples
+                        .chunks_exact(self.channels)
+                        .map(|frame| frame[channel])
+                        .collect()
+                })
+                .collect()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::PcmCodec;
+        use crate::audio::{autoplay::Random, Sample, SampleFormat, CHANNEL_COUNT};
+
+        /// Samples anywhere within full scale, and some beyond it
+        fn random_samples(random: &mut Random, amount: usize) -> Vec<Sample> {
+            (0..amount)
+                .map(|_| (random.next() % 40_000) as Sample / 10_000. - 2.)
+                .collect()
+        }
+
+        #[test]
+        fn float_samples_round_trip_exactly() {
+            let mut random = Random::new(42);
+
+            for amount in [0, 1, 2, 7, 1000, 4096] {
+                let samples = random_samples(&mut random, amount);
+                let bytes = PcmCodec::FLOAT.encode(&samples);
+
+                assert_eq!(bytes.len(), amount * 4);
+
+                let decoded = PcmCodec::FLOAT.decode(&bytes);
+                let bits = |s: &[Sample]| s.iter().map(|s| s.to_bits()).collect::<Vec<_>>();
+
+                assert_eq!(bits(&decoded), bits(&samples));
+            }
+        }
+
+        #[test]
+        fn bytes_are_little_endian() {
+            let bytes = PcmCodec::FLOAT.encode(&[1., -0.5]);
+            assert_eq!(bytes, [0, 0, 128, 63, 0, 0, 0, 191]);
+
+            let codec = PcmCodec::new(SampleFormat::Int16, CHANNEL_COUNT);
+            assert_eq!(codec.encode(&[1., -1.]), [255, 127, 1, 128]);
+        }
+
+        #[test]
+        fn int_samples_round_trip_within_full_scale() {
+            let mut random = Random::new(7);
+            let codec = PcmCodec::new(SampleFormat::Int16, CHANNEL_COUNT);
+
+            let samples = random_samples(&mut random, 4096);
+            let decoded = codec.decode(&codec.encode(&samples));
+
+            assert_eq!(decoded.len(), samples.len());
+
+            for (sample, decoded) in samples.iter().zip(&decoded) {
+                assert!((sample.clamp(-1., 1.) - decoded).abs() < 2. / i16::MAX as Sample);
+            }
+        }
+
+        #[test]
+        fn cut_off_samples_and_frames_are_left_out() {
+            let bytes = PcmCodec::FLOAT.encode(&[0.25, 0.5, 0.75]);
+
+            assert_eq!(PcmCodec::FLOAT.decode(&bytes[..10]), [0.25, 0.5]);
+            assert_eq!(PcmCodec::FLOAT.whole_samples(10), 8);
+            assert_eq!(PcmCodec::FLOAT.whole_frames(10), 8);
+            assert_eq!(PcmCodec::FLOAT.whole_frames(7), 0);
+
+            // Decoding stops once the buffer is full
+            let mut buf = [0.; 2];
+            assert_eq!(PcmCodec::FLOAT.decode_into(&bytes, &mut buf), 2);
+            assert_eq!(buf, [0.25, 0.5]);
+        }
+
+        #[test]
+        fn channels_interleave_and_deinterleave_exactly() {
+            let mut random = Random::new(3);
+
+            let left = random_samples(&mut random, 1000);
+            let right = random_samples(&mut random, 1000);
+
+            let interleaved = PcmCodec::FLOAT.interleave(&[&left, &right]);
+
+            assert_eq!(interleaved.len(), 2000);
+            assert_eq!(&interleaved[..4], &[left[0], right[0], left[1], right[1]]);
+
+            let bytes = PcmCodec::FLOAT.encode(&interleaved);
+            let channels = PcmCodec::FLOAT.deinterleave(&PcmCodec::FLOAT.decode(&bytes));
+
+            assert_eq!(channels, vec![left, right]);
+
+            // A frame that is cut off is not split
+            let channels = PcmCodec::FLOAT.deinterleave(&interleaved[..3]);
+            assert_eq!(channels, vec![vec![interleaved[0]], vec![interleaved[1]]]);
+        }
     }
 }
 
@@ -225,13 +641,13 @@ mod buffering {
     use crossbeam::atomic::AtomicCell;
 
     use crate::audio::{config::SAMPLES_PER_SEC, Sample};
-    use std::sync::RwLock;
+    use std::{ops::Range, sync::RwLock};
 
     /// A thread-safe buffer of [Sample] that can be read from and written to.
     pub struct Buffer {
         samples: RwLock<Vec<Sample>>,
         current_size: AtomicCell<usize>,
-        max_size: usize,
+        max_size: AtomicCell<usize>,
     }
 
     impl Buffer {
@@ -245,7 +661,7 @@ mod buffering {
             Self {
                 samples: RwLock::new(samples),
                 current_size: AtomicCell::default(),
-                max_size,
+                max_size: max_size.into(),
             }
         }
 
@@ -272,7 +688,7 @@ mod buffering {
             let mut samples = self.samples.write().unwrap();
 
             let amount = buf.len();
-            let capacity = self.max_size;
+            let capacity = self.max_size.load();
 
             let safe_start = offset.min(capacity);
             let safe_end = (safe_start + amount).min(capacity);
@@ -287,22 +703,62 @@ mod buffering {
             self.current_size.fetch_add(amount_written);
         }
 
+        /// Removes all samples, keeping the allocation
+        pub fn clear(&self) {
+            self.samples.write().unwrap().clear();
+            self.current_size.store(0);
+        }
+
+        /// Removes all samples along with the allocation, so the memory is given back
+        pub fn free(&self) {
+            *self.samples.write().unwrap() = Vec::new();
+            self.current_size.store(0);
+        }
+
+        /// Returns how many samples the buffer has room for without allocating
+        pub fn allocated(&self) -> usize {
+            self.samples.read().unwrap().capacity()
+        }
+
         pub fn write_at_end(&self, buf: &[Sample]) {
             self.write(self.current_size.load(), buf);
         }
 
         pub fn max_length(&self) -> usize {
-            self.max_size
+            self.max_size.load()
+        }
+
+        /// Allows more samples to be written, for sources that turn out to be longer
+        pub fn grow(&self, max_size: usize) {
+            self.max_size.fetch_max(max_size);
         }
 
         pub fn length(&self) -> usize {
             self.samples.read().unwrap().len()
         }
 
+        /// Returns the amount of samples at the start and the end of the range that are
+        /// not louder than the threshold, which are both its length if all of it is silent
+        pub fn silence_in(&self, range: Range<usize>, threshold: Sample) -> (usize, usize) {
+            let samples = self.samples.read().unwrap();
+            let end = range.end.min(samples.len());
+            let samples = &samples[range.start.min(end)..end];
+
+            let is_audible = |s: &Sample| s.abs() > threshold;
+            let leading = samples.iter().position(is_audible).unwrap_or(samples.len());
+            let trailing = samples
+                .iter()
+                .rev()
+                .position(is_audible)
+                .unwrap_or(samples.len());
+
+            (leading, trailing)
+        }
+
         fn allocate_if_necessary(&self, samples: &mut Vec<Sample>, end_offset: usize) {
             let allocated = samples.capacity();
 
-            let safe_offset = end_offset.min(self.max_size);
+            let safe_offset = end_offset.min(self.max_size.load());
             let overflow = safe_offset.checked_sub(allocated).unwrap_or_default();
 
             let chunks_to_allocate = overflow / Self::CHUNK_SIZE;
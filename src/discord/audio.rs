@@ -1,3 +1,5 @@
+use std::time::{Duration, UNIX_EPOCH};
+
 use super::CommandList;
 use crate::{
     audio,
@@ -14,18 +16,381 @@ async fn play(
     ctx.defer().await?;
 
     let bot = ctx.data();
-    let input = audio::Input::parse(&source);
+    // Resolving a page waits for yt-dlp, which must not hold up the other commands
+    let input = tokio::task::spawn_blocking(move || audio::Input::parse(&source)).await?;
 
-    if let Some(input) = input {
-        ctx.say(&input).await?;
-        bot.audio.add(input);
-    } else {
-        ctx.say("No suitable source was found.").await?;
+    match input {
+        Ok(input) => {
+            ctx.say(&input).await?;
+            bot.audio.add_requested(input, ctx.author().id.into())?;
+        }
+        Err(err) => {
+            ctx.say(err.to_string()).await?;
+        }
     }
 
     Ok(())
 }
 
+/// Hold the current track where it is
+#[poise::command(slash_command)]
+async fn pause(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().audio.pause();
+    ctx.say("Paused.").await?;
+
+    Ok(())
+}
+
+/// Continue playing after a pause
+#[poise::command(slash_command)]
+async fn resume(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().audio.resume();
+    ctx.say("Resumed.").await?;
+
+    Ok(())
+}
+
+/// Stop playing and empty the queue
+#[poise::command(slash_command)]
+async fn stop(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().audio.stop();
+    ctx.say("Stopped.").await?;
+
+    Ok(())
+}
+
+/// Play the upcoming tracks in a random order
+#[poise::command(slash_command)]
+async fn shuffle(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().audio.shuffle(None);
+    ctx.say("Shuffled the queue.").await?;
+
+    Ok(())
+}
+
+/// Formats a duration as minutes and seconds, such as 3:07
+fn format_time(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Show the track that is playing
+#[poise::command(slash_command)]
+async fn nowplaying(ctx: Context<'_>) -> Result<(), Error> {
+    let details = match ctx.data().audio.current_track_detailed() {
+        Some(details) => details,
+        None => {
+            ctx.say("Nothing is playing.").await?;
+            return Ok(());
+        }
+    };
+
+    let mut lines = vec![format!("**{}**", details.title)];
+
+    if let Some(artist) = &details.metadata.artist {
+        lines.push(format!("by {}", artist));
+    }
+
+    if let Some(album) = &details.metadata.album {
+        lines.push(format!("on {}", album));
+    }
+
+    lines.push(format!(
+        "{} / {}",
+        format_time(details.position),
+        format_time(details.duration)
+    ));
+
+    if let Some(user) = details.requested_by {
+        lines.push(format!("Requested by <@{}>", user));
+    }
+
+    if let Some(artwork) = &details.metadata.artwork {
+        lines.push(artwork.clone());
+    }
+
+    ctx.say(lines.join("\n")).await?;
+    Ok(())
+}
+
+/// How many tracks the history shows, unless asked for another amount
+const DEFAULT_HISTORY_LENGTH: usize = 10;
+
+/// The most tracks the history shows, so the message stays short enough to send
+const MAX_HISTORY_LENGTH: usize = 25;
+
+/// Show the tracks that played most recently
+#[poise::command(slash_command)]
+async fn history(
+    ctx: Context<'_>,
+    #[description = "How many tracks to show"] amount: Option<usize>,
+) -> Result<(), Error> {
+    let amount = amount
+        .unwrap_or(DEFAULT_HISTORY_LENGTH)
+        .clamp(1, MAX_HISTORY_LENGTH);
+
+    let played = ctx.data().audio.recently_played(amount);
+
+    if played.is_empty() {
+        ctx.say("Nothing has been played yet.").await?;
+        return Ok(());
+    }
+
+    let lines: Vec<_> = played
+        .iter()
+        .enumerate()
+        .map(|(i, played)| {
+            let finished = played
+                .finished_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+
+            let mut line = format!(
+                "{}. **{}** <t:{}:R>",
+                i + 1,
+                played.track.title,
+                finished.as_secs()
+            );
+
+            if let Some(user) = played.track.requested_by {
+                line.push_str(&format!(", requested by <@{}>", user));
+            }
+
+            line
+        })
+        .collect();
+
+    ctx.say(lines.join("\n")).await?;
+    Ok(())
+}
+
+/// The longest crossfade that can be set, in seconds
+const MAX_CROSSFADE: f32 = 12.;
+
+/// Parses a crossfade in seconds, or "off" to disable it
+fn parse_crossfade(argument: &str) -> Result<Option<Duration>, String> {
+    let argument = argument.trim();
+
+    if argument.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+
+    let seconds: f32 = argument
+        .parse()
+        .map_err(|_| format!("\"{}\" is not a number of seconds.", argument))?;
+
+    if !(0. ..=MAX_CROSSFADE).contains(&seconds) {
+        return Err(format!(
+            "Crossfade must be between 0 and {} seconds.",
+            MAX_CROSSFADE
+        ));
+    }
+
+    Ok((seconds > 0.).then(|| Duration::from_secs_f32(seconds)))
+}
+
+/// Set how long tracks overlap when transitioning
+#[poise::command(slash_command)]
+async fn crossfade(
+    ctx: Context<'_>,
+    #[description = "Seconds, or \"off\""] duration: String,
+) -> Result<(), Error> {
+    let bot = ctx.data();
+
+    match parse_crossfade(&duration) {
+        Ok(Some(duration)) => {
+            bot.audio.set_crossfade(Some(duration));
+            ctx.say(format!("Crossfade set to {:.1}s.", duration.as_secs_f32()))
+                .await?;
+        }
+        Ok(None) => {
+            bot.audio.set_crossfade(None);
+            ctx.say("Crossfade disabled.").await?;
+        }
+        Err(message) => {
+            ctx.say(message).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// List the effects that are applied to the audio
+#[poise::command(slash_command)]
+async fn effects(ctx: Context<'_>) -> Result<(), Error> {
+    let effects = ctx.data().audio.list_effects();
+
+    if effects.is_empty() {
+        ctx.say("No effects are applied.").await?;
+        return Ok(());
+    }
+
+    let lines: Vec<_> = effects
+        .iter()
+        .enumerate()
+        .map(|(i, effect)| {
+            let parameters: Vec<_> = effect
+                .parameters
+                .iter()
+                .map(|(name, value)| format!("{}: {:.2}", name, value))
+                .collect();
+
+            let state = if effect.enabled { "" } else { " (bypassed)" };
+            format!(
+                "{}. {}{} {}",
+                i + 1,
+                effect.name,
+                state,
+                parameters.join(", ")
+            )
+        })
+        .collect();
+
+    ctx.say(lines.join("\n")).await?;
+    Ok(())
+}
+
+/// Add a band to the equalizer
+#[poise::command(slash_command)]
+async fn eq_add(
+    ctx: Context<'_>,
+    #[description = "Center frequency in Hz"] frequency: f32,
+    #[description = "Boost or cut in dB"] gain: f32,
+    #[description = "Width of the band, higher is narrower"] q: Option<f32>,
+) -> Result<(), Error> {
+    let band = audio::EqBand {
+        frequency,
+        gain,
+        q: q.unwrap_or(1.),
+    };
+
+    match ctx.data().audio.add_eq_band(band) {
+        Ok(index) => {
+            ctx.say(format!(
+                "Added band {} at {} Hz ({:+.1} dB).",
+                index + 1,
+                frequency,
+                gain
+            ))
+            .await?
+        }
+        Err(err) => ctx.say(err.to_string()).await?,
+    };
+
+    Ok(())
+}
+
+/// Remove a band from the equalizer
+#[poise::command(slash_command)]
+async fn eq_remove(
+    ctx: Context<'_>,
+    #[description = "The number of the band"] band: usize,
+) -> Result<(), Error> {
+    let removed = band
+        .checked_sub(1)
+        .and_then(|index| ctx.data().audio.remove_eq_band(index));
+
+    match removed {
+        Some(removed) => {
+            ctx.say(format!("Removed the band at {} Hz.", removed.frequency))
+                .await?
+        }
+        None => ctx.say(format!("There is no band {}.", band)).await?,
+    };
+
+    Ok(())
+}
+
+/// Change a band of the equalizer
+#[poise::command(slash_command)]
+async fn eq_set(
+    ctx: Context<'_>,
+    #[description = "The number of the band"] band: usize,
+    #[description = "Center frequency in Hz"] frequency: f32,
+    #[description = "Boost or cut in dB"] gain: f32,
+    #[description = "Width of the band, higher is narrower"] q: Option<f32>,
+) -> Result<(), Error> {
+    let new_band = audio::EqBand {
+        frequency,
+        gain,
+        q: q.unwrap_or(1.),
+    };
+
+    let changed = band
+        .checked_sub(1)
+        .map(|index| ctx.data().audio.set_eq_band(index, new_band))
+        .unwrap_or(Ok(false));
+
+    match changed {
+        Ok(true) => {
+            ctx.say(format!(
+                "Set band {} to {} Hz ({:+.1} dB).",
+                band, frequency, gain
+            ))
+            .await?
+        }
+        Ok(false) => ctx.say(format!("There is no band {}.", band)).await?,
+        Err(err) => ctx.say(err.to_string()).await?,
+    };
+
+    Ok(())
+}
+
+/// Reset the equalizer to its flat bands
+#[poise::command(slash_command)]
+async fn eq_clear(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().audio.clear_eq();
+    ctx.say("The equalizer is flat again.").await?;
+
+    Ok(())
+}
+
 pub fn commands() -> CommandList {
-    vec![play()]
+    vec![
+        play(),
+        pause(),
+        resume(),
+        stop(),
+        shuffle(),
+        nowplaying(),
+        history(),
+        crossfade(),
+        effects(),
+        eq_add(),
+        eq_remove(),
+        eq_set(),
+        eq_clear(),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{format_time, parse_crossfade};
+
+    #[test]
+    fn times_are_formatted_as_minutes() {
+        assert_eq!(format_time(Duration::from_secs(7)), "0:07");
+        assert_eq!(format_time(Duration::from_millis(187_900)), "3:07");
+    }
+
+    #[test]
+    fn crossfade_arguments_are_parsed() {
+        assert_eq!(parse_crossfade("3"), Ok(Some(Duration::from_secs(3))));
+        assert_eq!(
+            parse_crossfade(" 1.5 "),
+            Ok(Some(Duration::from_millis(1500)))
+        );
+        assert_eq!(parse_crossfade("12"), Ok(Some(Duration::from_secs(12))));
+
+        assert_eq!(parse_crossfade("off"), Ok(None));
+        assert_eq!(parse_crossfade("OFF"), Ok(None));
+        assert_eq!(parse_crossfade("0"), Ok(None));
+
+        assert!(parse_crossfade("12.5").is_err());
+        assert!(parse_crossfade("-1").is_err());
+        assert!(parse_crossfade("NaN").is_err());
+        assert!(parse_crossfade("soon").is_err());
+    }
 }
@@ -1,12 +1,13 @@
-use std::{env, sync::Arc};
+use std::{env, sync::Arc, thread};
 
-use crate::audio::AudioSystem;
+use crate::audio::{AudioEvent, AudioSystem};
 use log::info;
 use poise::{
     serenity_prelude::{ChannelId, Context as SerenityContext, GatewayIntents, GuildId},
     Event,
 };
 use songbird::{SerenityInit, Songbird};
+use tokio::runtime::Handle;
 
 use super::{audio, util, voice, Context, Error, FrameworkContext};
 
@@ -17,7 +18,7 @@ pub struct Bot {
 
 impl Bot {
     // This is GCT's Discords server
-    const HOME_GUILD_ID: u64 = 671811819597201421;
+    pub const HOME_GUILD_ID: u64 = 671811819597201421;
 
     // The channel to join for streaming
     const VOICE_CHANNEL_ID: u64 = 671859933876191265;
@@ -43,6 +44,7 @@ impl Bot {
             audio,
         };
 
+        bot.follow_output_format();
         let voice = bot.voice.clone();
 
         let framework = poise::Framework::build()
@@ -78,6 +80,29 @@ impl Bot {
         }
     }
 
+    /// Plays a new input whenever the output format changes,
+    /// since the current one ends when it does.
+    fn follow_output_format(&self) {
+        let runtime = Handle::current();
+        let events = self.audio.subscribe();
+        let audio = self.audio.clone();
+        let voice = self.voice.clone();
+        let guild = self.home_guild();
+
+        thread::spawn(move || loop {
+            if let AudioEvent::OutputFormatChanged(format) = events.wait() {
+                if let Some(call) = voice.get(guild) {
+                    runtime.block_on(async {
+                        let mut call = call.lock().await;
+                        call.play_only_input(audio.create_input());
+                    });
+
+                    info!("Switched the voice input to {:?}", format);
+                }
+            }
+        });
+    }
+
     pub async fn handle_event(
         _ctx: &SerenityContext,
         event: &poise::Event<'_>,
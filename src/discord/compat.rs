@@ -1,11 +1,20 @@
 //! This file adds compatibility between Songbird and the decoupled audio engine
 
-use std::io::Seek;
+use std::io::{Cursor, Read, Seek};
 
-use crate::audio::{AudioBufferConsumer, AudioSystem, CHANNEL_COUNT, PCM_MIME, SAMPLE_RATE};
+use crate::audio::{
+    AudioBufferConsumer, AudioSystem, FiniteBufferConsumer, OpusConsumer, OpusEncoder,
+    OutputFormat, SampleFormat, OPUS_SAMPLE_RATE,
+};
+use json::object;
+use log::warn;
 use songbird::input::{Input, LiveInput, RawAdapter};
 use symphonia::core::{io::MediaSource, probe::Hint};
 
+/// The channels Discord plays. Other sample rates than Discord's are fine,
+/// since Songbird resamples them.
+const DISCORD_CHANNEL_COUNT: usize = 2;
+
 impl MediaSource for AudioBufferConsumer {
     fn byte_len(&self) -> Option<u64> {
         None
@@ -16,19 +25,136 @@ impl MediaSource for AudioBufferConsumer {
     }
 }
 
+impl MediaSource for FiniteBufferConsumer {
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+
+    fn is_seekable(&self) -> bool {
+        true
+    }
+}
+
+/// Opus frames in the DCA container Songbird reads, which prefixes
+/// them with a header and uses the same length prefix as [OpusConsumer].
+struct DcaStream {
+    header: Cursor<Vec<u8>>,
+    frames: OpusConsumer,
+}
+
+impl DcaStream {
+    fn new(frames: OpusConsumer) -> Self {
+        let metadata = object! {
+            dca: {
+                version: 1,
+                tool: {
+                    name: "gct",
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+            },
+            opus: {
+                mode: "music",
+                sample_rate: OPUS_SAMPLE_RATE,
+                frame_size: OPUS_SAMPLE_RATE / 50,
+                vbr: true,
+                channels: DISCORD_CHANNEL_COUNT,
+            },
+        }
+        .dump();
+
+        let mut header = b"DCA1".to_vec();
+        header.extend((metadata.len() as i32).to_le_bytes());
+        header.extend(metadata.into_bytes());
+
+        Self {
+            header: Cursor::new(header),
+            frames,
+        }
+    }
+}
+
+impl Read for DcaStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.header.read(buf)? {
+            0 => self.frames.read(buf),
+            read => Ok(read),
+        }
+    }
+}
+
+impl Seek for DcaStream {
+    fn seek(&mut self, _seek: std::io::SeekFrom) -> std::io::Result<u64> {
+        // This is a no op
+        Ok(0)
+    }
+}
+
+impl MediaSource for DcaStream {
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn is_seekable(&self) -> bool {
+        false
+    }
+}
+
 impl AudioSystem {
-    fn source(&self) -> Box<dyn MediaSource> {
-        let adapter = RawAdapter::new(self.stream(), SAMPLE_RATE as u32, CHANNEL_COUNT as u32);
+    fn source(&self) -> (Box<dyn MediaSource>, Hint) {
+        let config = self.config();
+        let mut hint = Hint::new();
+
+        match self.output_format() {
+            OutputFormat::Opus => {
+                let frames = OpusConsumer::new(OpusEncoder::new(
+                    self.following_stream(SampleFormat::Float32),
+                ));
 
-        Box::new(adapter)
+                hint.with_extension("dca");
+                (Box::new(DcaStream::new(frames)), hint)
+            }
+            OutputFormat::Pcm => {
+                // RawAdapter only understands floating point samples, so this
+                // ignores the stream format but still ends when the output changes.
+                let stream = self.following_stream(SampleFormat::Float32);
+                let adapter = RawAdapter::new(
+                    stream,
+                    config.sample_rate() as u32,
+                    config.channel_count() as u32,
+                );
+
+                hint.mime_type(&config.pcm_mime());
+                (Box::new(adapter), hint)
+            }
+        }
     }
 
+    /// Returns a description of each way the output differs from what Discord can play
+    fn input_mismatches(&self) -> Vec<String> {
+        let channel_count = self.config().channel_count();
+        let mut mismatches = vec![];
+
+        if channel_count > DISCORD_CHANNEL_COUNT {
+            mismatches.push(format!(
+                "{} channels cannot be played correctly, Discord expects {}",
+                channel_count, DISCORD_CHANNEL_COUNT
+            ));
+        }
+
+        mismatches
+    }
+
+    /// Creates an input in the current output format. It ends when the output
+    /// format changes, so the bot plays a new one, see [crate::audio::AudioEvent::OutputFormatChanged].
     pub(super) fn create_input(&self) -> Input {
-        let mut hint = Hint::new();
-        hint.mime_type(PCM_MIME);
+        for mismatch in self.input_mismatches() {
+            warn!("{}", mismatch);
+        }
+
+        let (input, hint) = self.source();
 
         let stream = songbird::input::AudioStream {
-            input: self.source(),
+            input,
             hint: Some(hint),
         };
 
@@ -44,3 +170,45 @@ impl Seek for AudioBufferConsumer {
         Ok(0)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{io::Read, time::Duration};
+
+    use songbird::input::{Input, LiveInput};
+
+    use crate::audio::{AudioConfig, AudioSystem, OutputFormat};
+
+    #[test]
+    fn inputs_only_report_real_mismatches() {
+        // Songbird resamples the default sample rate, so nothing is reported
+        let system = AudioSystem::with_config(AudioConfig::DEFAULT);
+        let input = system.create_input();
+
+        assert!(matches!(input, Input::Live(LiveInput::Raw(_), None)));
+        assert!(system.input_mismatches().is_empty());
+
+        let config = AudioConfig::new(44100, 6, Duration::from_millis(200)).unwrap();
+        let system = AudioSystem::with_config(config);
+        system.create_input();
+
+        let mismatches = system.input_mismatches();
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("6 channels"));
+    }
+
+    #[test]
+    fn opus_inputs_are_dca() {
+        let system = AudioSystem::with_config(AudioConfig::DEFAULT);
+        system.set_output_format(OutputFormat::Opus);
+
+        let mut stream = match system.create_input() {
+            Input::Live(LiveInput::Raw(stream), None) => stream.input,
+            _ => panic!("Input is not a raw stream"),
+        };
+
+        let mut magic = [0; 4];
+        stream.read_exact(&mut magic).unwrap();
+        assert_eq!(&magic, b"DCA1");
+    }
+}
@@ -15,6 +15,28 @@ async fn ping(ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
+/// Shows measurements of the audio that is playing
+#[poise::command(slash_command)]
+async fn debug(ctx: Context<'_>) -> Result<(), Error> {
+    let audio = &ctx.data().audio;
+    let latency = audio.estimated_latency();
+
+    let bitrate = match audio.measured_bitrate() {
+        Some(bitrate) => format!("{:.1} kbps", bitrate as f32 / 1000.),
+        None => "Raw".to_string(),
+    };
+
+    ctx.say(format!(
+        "Phase correlation: {:+.2}\nEstimated latency: {}ms\nMeasured bitrate: {}",
+        audio.metering().correlation(),
+        latency.as_millis(),
+        bitrate
+    ))
+    .await?;
+
+    Ok(())
+}
+
 pub fn commands() -> CommandList {
-    vec![ping()]
+    vec![ping(), debug()]
 }
@@ -1,9 +1,9 @@
-use std::{env, str::FromStr, sync::Arc, thread};
+use std::{env, error::Error, net::ToSocketAddrs, str::FromStr, sync::Arc, thread};
 
 use log::info;
-use tiny_http::{Header, Response, Server, StatusCode};
+use tiny_http::{Header, Request, Response, Server, StatusCode};
 
-use crate::audio::{AudioSystem, WaveStream};
+use crate::audio::{AudioSystem, SampleFormat, WaveStream};
 
 pub fn run(audio: Arc<AudioSystem>) {
     let port: u16 = env::var("GCT_HTTP_PORT")
@@ -11,30 +11,200 @@ pub fn run(audio: Arc<AudioSystem>) {
         .parse()
         .expect("GCT_HTTP_PORT must be a number");
 
-    let addr = format!("127.0.0.1:{}", port);
-    let server = Server::http(addr).unwrap();
-
     info!("Server listening on port {}", port);
 
+    audio
+        .serve_http(format!("127.0.0.1:{}", port))
+        .expect("Server can listen on the port");
+}
+
+impl AudioSystem {
+    /// Serves the audio over HTTP, see [serve]
+    pub fn serve_http<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        serve(self, addr)
+    }
+}
+
+/// Serves the audio over HTTP, blocking for as long as the server runs.
+/// Every connection gets its own stream, so a slow client does not hold back the others.
+pub fn serve<A: ToSocketAddrs>(
+    audio: &AudioSystem,
+    addr: A,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let server = Server::http(addr)?;
+    handle_requests(audio, &server);
+
+    Ok(())
+}
+
+fn handle_requests(audio: &AudioSystem, server: &Server) {
     for req in server.incoming_requests() {
-        let audio = Arc::clone(&audio);
-        let addr = req.remote_addr().to_string();
+        let audio = audio.clone();
 
-        thread::spawn(move || {
-            info!("Audio stream connection opened for {}", &addr);
+        if req.url() == "/health" {
+            let health = audio.health();
+            let status = if health.is_healthy() { 200 } else { 503 };
 
-            let stream = audio.stream();
+            let res = Response::from_string(health.to_json().dump())
+                .with_status_code(StatusCode(status))
+                .with_header(Header::from_str("Content-Type: application/json").unwrap());
 
-            let stream = WaveStream::new(stream);
-            let mut res = Response::new(StatusCode(200), vec![], stream, None, None);
+            let _ = req.respond(res);
+            continue;
+        }
 
-            res.add_header(
-                Header::from_str(format!("Content-Type: {}", WaveStream::MIME).as_str()).unwrap(),
-            );
+        if req.url() == "/status" {
+            let res = Response::from_string(audio.status_json())
+                .with_header(Header::from_str("Content-Type: application/json").unwrap());
 
             let _ = req.respond(res);
+            continue;
+        }
+
+        thread::spawn(move || stream(&audio, req));
+    }
+}
+
+/// Streams the audio until the client disconnects, which drops its consumer
+fn stream(audio: &AudioSystem, req: Request) {
+    let addr = req.remote_addr().to_string();
+    info!("Audio stream connection opened for {}", &addr);
+
+    if req.url() == "/pcm" {
+        // The mime type describes floating point samples, so the format is pinned
+        let stream = audio.stream_with_format(SampleFormat::Float32);
+        let mut res = Response::new(StatusCode(200), vec![], stream, None, None);
+
+        let mime = audio.config().pcm_mime();
+        res.add_header(Header::from_str(format!("Content-Type: {}", mime).as_str()).unwrap());
+        let _ = req.respond(res);
+    } else {
+        let stream = WaveStream::new(audio.stream());
+        let mut res = Response::new(StatusCode(200), vec![], stream, None, None);
+
+        res.add_header(
+            Header::from_str(format!("Content-Type: {}", WaveStream::MIME).as_str()).unwrap(),
+        );
+        let _ = req.respond(res);
+    }
+
+    info!("Audio stream connection closed for {}", &addr);
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use tiny_http::Server;
+
+    use super::handle_requests;
+    use crate::audio::AudioSystem;
+
+    /// Serves the system on a free port, returning where it can be reached
+    fn listen(audio: &AudioSystem) -> SocketAddr {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr();
+        let audio = audio.clone();
+
+        thread::spawn(move || handle_requests(&audio, &server));
+        addr
+    }
+
+    /// Sends a request for the path, returning the response head and body
+    fn get(addr: SocketAddr, path: &str) -> (String, String) {
+        let mut socket = TcpStream::connect(addr).unwrap();
+        write!(
+            socket,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        socket.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    }
+
+    /// Opens a stream at the path, returning the connection after the response head
+    fn open(addr: SocketAddr, path: &str) -> (TcpStream, String) {
+        let mut socket = TcpStream::connect(addr).unwrap();
+        write!(socket, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+        let mut head = vec![];
+        let mut byte = [0];
+
+        while !head.ends_with(b"\r\n\r\n") {
+            socket.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+
+        (socket, String::from_utf8(head).unwrap())
+    }
+
+    /// Waits for the condition to be true, giving up after a few seconds
+    fn wait_until(condition: impl Fn() -> bool) -> bool {
+        let started = Instant::now();
+
+        while !condition() {
+            if started.elapsed() > Duration::from_secs(5) {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        true
+    }
+
+    #[test]
+    fn status_and_health_are_served_as_json() {
+        let system = AudioSystem::new();
+        let addr = listen(&system);
+
+        let (head, body) = get(addr, "/status");
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(head.contains("Content-Type: application/json"), "{}", head);
+
+        let status = json::parse(&body).unwrap();
+        assert_eq!(status["health"]["running"].as_bool(), Some(false));
+
+        // The playback thread was never started, so it can not be healthy
+        let (head, body) = get(addr, "/health");
+        assert!(head.starts_with("HTTP/1.1 503"), "{}", head);
+        assert!(json::parse(&body).is_ok(), "{}", body);
+    }
+
+    #[test]
+    fn pcm_connections_have_their_own_stream() {
+        let system = AudioSystem::new();
+        let addr = listen(&system);
+        system.start();
+
+        let (first, head) = open(addr, "/pcm");
+        let content_type = format!("Content-Type: {}", system.config().pcm_mime());
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(head.contains(&content_type), "{}", head);
+
+        let (second, _) = open(addr, "/pcm");
+        assert!(wait_until(|| system.stream_count() == 2));
+
+        // Disconnecting frees the stream of that client only
+        drop(first);
+        assert!(wait_until(|| system.stream_count() == 1));
+
+        drop(second);
+        assert!(wait_until(|| system.stream_count() == 0));
 
-            info!("Audio stream connection closed for {}", &addr);
-        });
+        system.shutdown();
     }
 }
@@ -1,5 +1,12 @@
 use audio::Input;
-use std::{sync::Arc, thread, time::Duration};
+use log::warn;
+use std::{
+    env,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
 use tokio::runtime::Runtime;
 
 mod audio;
@@ -12,13 +19,163 @@ mod ytdl;
 fn main() {
     logging::init_logger();
 
-    let audio = Arc::new(audio::AudioSystem::default());
+    // A comma separated list of mime types to initialize decoders for ahead of time
+    if let Ok(mimes) = env::var("GCT_PREWARM_CODECS") {
+        for mime in mimes.split(',') {
+            if let Err(err) = audio::prewarm_codec(mime.trim()) {
+                warn!("Cannot prewarm {}: {}", mime, err);
+            }
+        }
+    }
+
+    // Either "reject" or "fallback", for inputs with a sample rate that cannot be resampled
+    if let Ok(policy) = env::var("GCT_SAMPLE_RATE_POLICY") {
+        match audio::SampleRatePolicy::parse(policy.trim()) {
+            Some(policy) => audio::set_sample_rate_policy(policy),
+            None => warn!("Unknown sample rate policy {}", policy),
+        }
+    }
+
+    // How many sample buffers are kept around for reuse, instead of allocating new ones
+    if let Ok(count) = env::var("GCT_SAMPLE_BUFFERS") {
+        match count.trim().parse::<usize>() {
+            Ok(count) => audio::preallocate_buffers(count),
+            Err(_) => warn!("GCT_SAMPLE_BUFFERS must be a number"),
+        }
+    }
+
+    // The format the output is streamed in, each part defaulting to how audio is mixed
+    let default = audio::AudioConfig::DEFAULT;
+    let number = |name: &str, default: u64| match env::var(name) {
+        Ok(value) => value.trim().parse::<u64>().unwrap_or_else(|_| {
+            warn!("{} must be a number", name);
+            default
+        }),
+        Err(_) => default,
+    };
+
+    let config = audio::AudioConfig::new(
+        number("GCT_SAMPLE_RATE", default.sample_rate() as u64) as usize,
+        number("GCT_CHANNEL_COUNT", default.channel_count() as u64) as usize,
+        Duration::from_millis(number(
+            "GCT_CHUNK_MILLIS",
+            default.chunk_duration().as_millis() as u64,
+        )),
+    )
+    .unwrap_or_else(|err| {
+        warn!("{}, so the default output format is used", err);
+        default
+    });
+
+    let mut audio = audio::AudioSystem::with_config(config);
+
+    // The file the volume, equalizer and repeat mode are remembered in across restarts
+    let settings = match env::var("GCT_SETTINGS_FILE") {
+        Ok(path) => audio::SettingsStore::restore_from(path.trim()),
+        Err(_) => audio::SettingsStore::restore(),
+    };
+
+    match settings {
+        Ok(store) => {
+            let store = Arc::new(Mutex::new(store));
+            audio::SettingsStore::save_in_background(&store);
+
+            audio = audio.with_settings(store, discord::Bot::HOME_GUILD_ID);
+        }
+        Err(err) => warn!("Cannot restore settings, so they are not saved: {}", err),
+    }
+
+    let audio = Arc::new(audio);
+
+    // The folder decoded sources are cached in, for inputs that are added to be cached
+    let cache = match env::var("GCT_CACHE_DIR") {
+        Ok(dir) => audio::Cache::restore_from(dir.trim()),
+        Err(_) => audio::Cache::restore(),
+    };
+
+    match cache {
+        Ok(cache) => audio.set_cache(Some(cache)),
+        Err(err) => warn!(
+            "Cannot restore the source cache, so nothing is cached: {}",
+            err
+        ),
+    }
+
+    // Seconds without any listeners before processing pauses
+    if let Ok(seconds) = env::var("GCT_IDLE_GRACE_SECS") {
+        match seconds.trim().parse::<u64>() {
+            Ok(seconds) => audio.set_power_saving(Some(Duration::from_secs(seconds))),
+            Err(_) => warn!("GCT_IDLE_GRACE_SECS must be a number of seconds"),
+        }
+    }
+
+    // How many sources can download at once, to avoid being rate limited
+    if let Ok(max) = env::var("GCT_MAX_DOWNLOADS") {
+        match max.trim().parse::<usize>() {
+            Ok(max) => audio.set_max_downloads(max),
+            Err(_) => warn!("GCT_MAX_DOWNLOADS must be a number"),
+        }
+    }
+
+    // Seconds of audio the loading thread requests at most every cycle
+    if let Ok(seconds) = env::var("GCT_LOAD_BUDGET_SECS") {
+        match seconds.trim().parse::<u64>() {
+            Ok(seconds) => audio.set_load_budget(Some(Duration::from_secs(seconds))),
+            Err(_) => warn!("GCT_LOAD_BUDGET_SECS must be a number of seconds"),
+        }
+    }
+
+    // How many tracks load ahead, counting the current one
+    if let Ok(depth) = env::var("GCT_PREFETCH_DEPTH") {
+        match depth.trim().parse::<usize>() {
+            Ok(depth) => audio.set_prefetch_depth(depth),
+            Err(_) => warn!("GCT_PREFETCH_DEPTH must be a number"),
+        }
+    }
+
+    if let Ok(millis) = env::var("GCT_LOADING_INTERVAL_MILLIS") {
+        match millis.trim().parse::<u64>() {
+            Ok(millis) => audio.set_loading_interval(Duration::from_millis(millis)),
+            Err(_) => warn!("GCT_LOADING_INTERVAL_MILLIS must be a number of milliseconds"),
+        }
+    }
+
+    if let Ok(seconds) = env::var("GCT_PREVIOUS_THRESHOLD_SECS") {
+        match seconds.trim().parse::<u64>() {
+            Ok(seconds) => audio.set_previous_threshold(Duration::from_secs(seconds)),
+            Err(_) => warn!("GCT_PREVIOUS_THRESHOLD_SECS must be a number of seconds"),
+        }
+    }
+
+    // Either "wait" or "refuse", for seeking to where a track has not loaded yet
+    if let Ok(policy) = env::var("GCT_SEEK_POLICY") {
+        match audio::SeekPolicy::parse(policy.trim()) {
+            Some(policy) => audio.set_seek_policy(policy),
+            None => warn!("Unknown seek policy {}", policy),
+        }
+    }
+
+    // Either "pcm" or "opus", for how the output is streamed to Discord
+    if let Ok(format) = env::var("GCT_OUTPUT_FORMAT") {
+        match audio::OutputFormat::parse(format.trim()) {
+            Some(format) => audio.set_output_format(format),
+            None => warn!("Unknown output format {}", format),
+        }
+    }
+
     audio.start();
 
     let runtime = Runtime::new().unwrap();
 
     let input = Input::parse("https://www.youtube.com/watch?v=xsgnpOnV58k").unwrap();
-    audio.add(input);
+    audio.add(input).expect("Audio system is not draining");
+
+    // A directory of audio files to queue, including its subdirectories
+    if let Ok(dir) = env::var("GCT_MUSIC_DIR") {
+        if let Err(err) = audio.add_directory(Path::new(&dir), true) {
+            warn!("Cannot add {}: {}", dir, err);
+        }
+    }
 
     thread::spawn({
         let http_audio = Arc::clone(&audio);
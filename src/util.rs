@@ -205,6 +205,11 @@ pub mod model {
                 .unwrap_or_else(|| panic!("{} with id {} does not exist", T::NAME, id))
         }
 
+        /// Get references to every item in the store
+        pub fn all(&self) -> Vec<Arc<T>> {
+            self.items_guard().values().cloned().collect()
+        }
+
         /// Get a vec of optional references to an item.
         pub fn get_many<I: IntoIterator<Item = Id<T>>>(&self, ids: I) -> Vec<Option<Arc<T>>> {
             let items = self.items_guard();
@@ -1,26 +1,57 @@
-use youtube_dl::{YoutubeDl, YoutubeDlOutput};
+use std::{error::Error, fmt::Display, io, process::Command};
+
+use json::JsonValue;
+
+/// Asks yt-dlp for the best audio stream of a page, returning the metadata it outputs.
+/// This waits for yt-dlp to finish, so async code has to call it in `spawn_blocking`.
+pub fn fetch(source: &str) -> Result<JsonValue, FetchError> {
+    let output = Command::new("yt-dlp")
+        .args(["--dump-single-json", "--no-playlist"])
+        .args(["--socket-timeout", "15"])
+        .args(["-f", "bestaudio/best"])
+        // What a user gave is always a page, even if it looks like an option
+        .arg("--")
+        .arg(source)
+        .output()
+        .map_err(FetchError::Spawn)?;
+
+    if !output.status.success() {
+        let reason = String::from_utf8_lossy(&output.stderr);
+        return Err(FetchError::Failed(reason.trim().to_string()));
+    }
+
+    json::parse(&String::from_utf8_lossy(&output.stdout)).map_err(|_| FetchError::Invalid)
+}
 
 pub async fn get_audio_url(source: &str) -> Option<String> {
-    let output = YoutubeDl::new(source)
-        .socket_timeout("15")
-        .extra_arg("-f")
-        .extra_arg("bestaudio")
-        .run();
-
-    output
-        .ok()
-        .and_then(|o| match o {
-            YoutubeDlOutput::SingleVideo(video) => Some(video),
-            YoutubeDlOutput::Playlist(_) => None,
-        })
-        .and_then(|video| {
-            let format_name = video.format.as_ref();
-
-            video.formats.and_then(|formats| {
-                formats
-                    .into_iter()
-                    .find(|f| f.format.as_ref() == format_name)
-            })
-        })
-        .and_then(|format| format.url)
+    let source = source.to_string();
+    let info = tokio::task::spawn_blocking(move || fetch(&source))
+        .await
+        .ok()?
+        .ok()?;
+
+    info["url"].as_str().map(str::to_string)
 }
+
+/// yt-dlp did not output the metadata of a page
+#[derive(Debug)]
+pub enum FetchError {
+    /// yt-dlp could not be started, such as when it is not installed
+    Spawn(io::Error),
+    /// yt-dlp failed, with what it said went wrong
+    Failed(String),
+    /// What yt-dlp output is not JSON
+    Invalid,
+}
+
+impl Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Spawn(err) => write!(f, "yt-dlp could not be started: {}", err),
+            FetchError::Failed(reason) => write!(f, "yt-dlp failed: {}", reason),
+            FetchError::Invalid => write!(f, "The output of yt-dlp is not JSON"),
+        }
+    }
+}
+
+impl Error for FetchError {}